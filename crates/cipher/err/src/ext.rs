@@ -5,3 +5,8 @@ impl From<aead::Error> for Error<String> {
         Error::UnexpectedAEAD(value.to_string())
     }
 }
+
+pub const ERR_AUTHENTICATION: Error<&str> =
+    Error::AuthenticationFailed("ciphertext failed authentication with current key");
+pub const ERR_CONTROL: Error<&str> =
+    Error::InvalidControl("control message is malformed or has unknown kind");
@@ -4,14 +4,63 @@ use std::fmt::{Debug, Display, Formatter};
 
 pub enum Error<U: ToString = String> {
     UnexpectedAEAD(U),
+    AuthenticationFailed(U),
+    NonceLength { expected: usize, actual: usize },
+    TruncatedCiphertext { expected: usize, actual: usize },
+    KeyDerivation(U),
+    UnsupportedConfig(U),
+    UnknownKey { id: u8 },
+    InvalidControl(U),
+    SuiteMismatch { expected: u8, actual: u8 },
+}
+
+/// `Retryable` classification of cipher [`Error`][Error] values.
+///
+/// [`Yes`][Retryable::Yes] marks errors bound to a single packet (tampered or truncated input),
+/// so the caller may drop it and carry on with the next one. [`No`][Retryable::No] marks
+/// errors that will repeat until the cipher configuration is changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retryable {
+    Yes,
+    No,
 }
 
 pub type Result<T, U = String> = core::result::Result<T, Error<U>>;
 
+impl<U: ToString> Error<U> {
+    pub fn retryable(&self) -> Retryable {
+        match self {
+            Error::UnexpectedAEAD(_) => Retryable::Yes,
+            Error::AuthenticationFailed(_) => Retryable::Yes,
+            Error::NonceLength { .. } => Retryable::No,
+            Error::TruncatedCiphertext { .. } => Retryable::Yes,
+            Error::KeyDerivation(_) => Retryable::No,
+            Error::UnsupportedConfig(_) => Retryable::No,
+            Error::UnknownKey { .. } => Retryable::Yes,
+            Error::InvalidControl(_) => Retryable::Yes,
+            Error::SuiteMismatch { .. } => Retryable::No,
+        }
+    }
+}
+
 impl<U: ToString> Display for Error<U> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let error = match self {
             Error::UnexpectedAEAD(error) => error.to_string(),
+            Error::AuthenticationFailed(error) => error.to_string(),
+            Error::NonceLength { expected, actual } => {
+                format!("nonce size '{actual}' is incompatible with '{expected}'")
+            }
+            Error::TruncatedCiphertext { expected, actual } => {
+                format!("ciphertext size '{actual}' is less than required '{expected}'")
+            }
+            Error::KeyDerivation(error) => error.to_string(),
+            Error::UnsupportedConfig(error) => error.to_string(),
+            Error::UnknownKey { id } => format!("key id '{id}' is unknown or retired"),
+            Error::InvalidControl(error) => error.to_string(),
+            Error::SuiteMismatch { expected, actual } => {
+                format!("cipher suite '{actual}' of ciphertext differs from local '{expected}'")
+            }
         };
         write!(f, "{}", error)
     }
@@ -29,6 +78,20 @@ impl From<Error<&str>> for Error {
     fn from(value: Error<&str>) -> Self {
         match value {
             Error::UnexpectedAEAD(e) => Error::UnexpectedAEAD(e.to_string()),
+            Error::AuthenticationFailed(e) => Error::AuthenticationFailed(e.to_string()),
+            Error::NonceLength { expected, actual } => Error::NonceLength { expected, actual },
+            Error::TruncatedCiphertext { expected, actual } => {
+                Error::TruncatedCiphertext { expected, actual }
+            }
+            Error::KeyDerivation(e) => Error::KeyDerivation(e.to_string()),
+            Error::UnsupportedConfig(e) => Error::UnsupportedConfig(e.to_string()),
+            Error::UnknownKey { id } => Error::UnknownKey { id },
+            Error::InvalidControl(e) => Error::InvalidControl(e.to_string()),
+            Error::SuiteMismatch { expected, actual } => Error::SuiteMismatch { expected, actual },
         }
     }
 }
+
+pub mod consts {
+    pub use crate::ext::{ERR_AUTHENTICATION, ERR_CONTROL};
+}
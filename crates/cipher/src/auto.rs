@@ -0,0 +1,132 @@
+use aead::OsRng;
+use err::{Error, Result};
+use log::{info, trace};
+use std::time::{Duration, Instant};
+
+use crate::{get_aes_cipher, get_cha_cipher, AesNonce, AesSpec, ChaSpec, Encryption, IOCipher};
+
+const PROBE_SIZE: usize = 16 * 1024;
+const PROBE_TIME: Duration = Duration::from_millis(20);
+
+/// Size of offers made by [`offer`][offer].
+pub const OFFER_SIZE: usize = 2;
+/// Offer flag of a suite set explicitly.
+const OFFER_EXPLICIT: u8 = 0;
+/// Offer flag of a suite preferred by [`Encryption::Auto`][Encryption::Auto].
+const OFFER_AUTO: u8 = 1;
+
+/// Picks the cipher suite for [`Encryption::Auto`][Encryption::Auto].
+///
+/// `AES-GCM` is picked on hardware with `AES` support, and `ChaCha20-Poly1305` otherwise,
+/// so the choice is the same every time on the same host. Both suites are measured on
+/// throwaway keys for a short time, but their throughput is only logged, as it's too noisy
+/// to decide on.
+///
+/// Note that peers on different hardware may still prefer different suites, see
+/// [`negotiate`][negotiate] for agreeing on one.
+pub fn select_encryption() -> Result<Encryption> {
+    trace!("measuring cipher suites for automatic selection");
+
+    let aes = Encryption::AES {
+        cipher: AesSpec::default(),
+        nonce: AesNonce::default(),
+    };
+    let cha = Encryption::ChaCha {
+        cipher: ChaSpec::default(),
+    };
+
+    let aes_rate = measure(get_aes_cipher(
+        &AesSpec::default(),
+        &AesNonce::default(),
+        OsRng,
+    )?)?;
+    let cha_rate = measure(get_cha_cipher(&ChaSpec::default(), OsRng)?)?;
+    let aes_hw = has_aes_hw();
+    info!(
+        "hardware AES support: {}, measured AES {:.1} MiB/s, ChaCha {:.1} MiB/s",
+        aes_hw, aes_rate, cha_rate
+    );
+
+    let res = if aes_hw { aes } else { cha };
+    info!("automatically selected cipher '{:?}'", res);
+    Ok(res)
+}
+
+/// Makes an offer of `cfg` for the peer to [`negotiate`][negotiate] with, carrying the
+/// [`suite_id`][Encryption::suite_id] and whether it's set explicitly or preferred by
+/// [`Encryption::Auto`][Encryption::Auto].
+pub fn offer(cfg: &Encryption) -> Result<[u8; OFFER_SIZE]> {
+    match cfg {
+        Encryption::Auto { .. } => Ok([OFFER_AUTO, select_encryption()?.suite_id()]),
+        cfg => Ok([OFFER_EXPLICIT, cfg.suite_id()]),
+    }
+}
+
+/// Agrees on the cipher suite of `local` and `remote` offers made by [`offer`][offer].
+///
+/// The outcome is the same on both peers: a suite set explicitly wins over an automatic
+/// one, and automatic ones which differ give way to default `ChaCha20-Poly1305`, as it's
+/// fast enough without hardware support. Suites set explicitly on both sides have to match,
+/// otherwise [`SuiteMismatch`][Error::SuiteMismatch] is returned.
+pub fn negotiate(local: &[u8; OFFER_SIZE], remote: &[u8]) -> Result<Encryption> {
+    let suite = match (*local, remote) {
+        (_, [flag, suite]) if *flag > OFFER_AUTO || Encryption::from_suite_id(*suite).is_none() => {
+            return Err(Error::UnsupportedConfig(format!(
+                "peer offered unknown cipher suite '{suite}'"
+            )))
+        }
+        ([OFFER_EXPLICIT, local], [OFFER_EXPLICIT, remote]) if local != *remote => {
+            return Err(Error::SuiteMismatch {
+                expected: local,
+                actual: *remote,
+            })
+        }
+        ([OFFER_EXPLICIT, local], _) => local,
+        (_, [OFFER_EXPLICIT, remote]) => *remote,
+        ([_, local], [_, remote]) if local == *remote => local,
+        (_, [_, _]) => Encryption::ChaCha {
+            cipher: ChaSpec::default(),
+        }
+        .suite_id(),
+        _ => {
+            return Err(Error::UnsupportedConfig(format!(
+                "peer offer size '{}' differs from '{OFFER_SIZE}'",
+                remote.len()
+            )))
+        }
+    };
+
+    let res = Encryption::from_suite_id(suite).unwrap();
+    info!("negotiated cipher '{:?}' with peer", res);
+    Ok(res)
+}
+
+/// Encryption throughput of `cipher` in MiB per second.
+fn measure(cipher: Box<dyn IOCipher + Sync + Send>) -> Result<f64> {
+    let buf = vec![0u8; PROBE_SIZE];
+    let start = Instant::now();
+    let mut bytes = 0;
+
+    while start.elapsed() < PROBE_TIME {
+        cipher.encrypt(&buf)?;
+        bytes += buf.len();
+    }
+    Ok(bytes as f64 / start.elapsed().as_secs_f64() / (1024.0 * 1024.0))
+}
+
+fn has_aes_hw() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+            && std::arch::is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+            && std::arch::is_aarch64_feature_detected!("pmull")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
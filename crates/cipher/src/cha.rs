@@ -1,7 +1,7 @@
-use aead::{consts::U32, generic_array::ArrayLength, Aead, AeadInPlace, Key, Nonce, OsRng};
+use aead::{consts::U32, generic_array::ArrayLength, Aead, AeadInPlace, Key, Nonce, OsRng, Tag};
 use cha::cipher::{KeyInit, KeyIvInit, StreamCipher, StreamCipherSeek};
 use chacha20poly1305::{AeadCore, ChaChaPoly1305};
-use err::{Error, Result};
+use err::{consts::ERR_AUTHENTICATION, Error, Result};
 use log::{error, trace};
 
 use crate::IOCipher;
@@ -59,7 +59,10 @@ where
                 nonce.len(),
                 spec
             );
-            Err(aead::Error.into())
+            Err(Error::NonceLength {
+                expected: spec,
+                actual: nonce.len(),
+            })
         }
     }
 
@@ -67,19 +70,25 @@ where
         trace!("decrypting {} bytes of ciphertext", ciphertext.len());
 
         let spec = Nonce::<ChaChaPoly1305<C, N>>::default().len();
-        if spec > ciphertext.len() {
+        let tag = Tag::<ChaChaPoly1305<C, N>>::default().len();
+        if spec + tag > ciphertext.len() {
             error!(
-                "'decrypt' error: nonce size '{}' is bigger than 'ciphertext.len()':'{}'",
-                spec,
+                "'decrypt' error: nonce and tag size '{}' is bigger than 'ciphertext.len()':'{}'",
+                spec + tag,
                 ciphertext.len()
             );
-            return Err(aead::Error.into());
+            return Err(Error::TruncatedCiphertext {
+                expected: spec + tag,
+                actual: ciphertext.len(),
+            });
         }
         let (nonce, ciphertext) = {
             let (a, b) = ciphertext.split_at(spec);
             (a.into(), b)
         };
-        self.cipher.decrypt(nonce, ciphertext).map_err(Error::from)
+        self.cipher
+            .decrypt(nonce, ciphertext)
+            .map_err(|_| ERR_AUTHENTICATION.into())
     }
 
     fn decrypt_at(&self, nonce: &[u8], associated_data: &[u8], buffer: &mut Vec<u8>) -> Result<()> {
@@ -90,14 +99,17 @@ where
         if nonce.len() == spec {
             self.cipher
                 .decrypt_in_place(nonce.into(), associated_data, buffer)
-                .map_err(Error::from)
+                .map_err(|_| ERR_AUTHENTICATION.into())
         } else {
             error!(
                 "'decrypt_at' error: nonce size '{}' is incompatible with '{}'",
                 nonce.len(),
                 spec
             );
-            Err(aead::Error.into())
+            Err(Error::NonceLength {
+                expected: spec,
+                actual: nonce.len(),
+            })
         }
     }
 }
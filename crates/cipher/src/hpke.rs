@@ -0,0 +1,521 @@
+use aead::{
+    rand_core::{CryptoRng, RngCore},
+    Aead, AeadCore, KeyInit, Nonce, Payload,
+};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use chacha20poly1305::ChaCha20Poly1305;
+use err::{consts::ERR_AUTHENTICATION, Error, Result};
+use hkdf::Hkdf;
+use log::{error, trace};
+use serde::Deserialize;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const HPKE_VERSION: &[u8] = b"HPKE-v1";
+const KEM_ID: u16 = 0x0020;
+const KDF_ID: u16 = 0x0001;
+const MODE_BASE: u8 = 0x00;
+const MODE_AUTH: u8 = 0x02;
+
+/// Size of `DHKEM(X25519, HKDF-SHA256)` public keys and encapsulated keys.
+pub const HPKE_KEY_SIZE: usize = 32;
+const HPKE_NONCE_SIZE: usize = 12;
+
+/// `HpkeAead` for `.toml` config parsing.
+/// Offers `AEAD` algorithms of RFC 9180 with [`ChaCha20Poly1305`][HpkeAead::default] being
+/// default choice.
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+pub enum HpkeAead {
+    Aes128Gcm,
+    Aes256Gcm,
+    #[default]
+    ChaCha20Poly1305,
+}
+
+impl HpkeAead {
+    fn id(&self) -> u16 {
+        match self {
+            HpkeAead::Aes128Gcm => 0x0001,
+            HpkeAead::Aes256Gcm => 0x0002,
+            HpkeAead::ChaCha20Poly1305 => 0x0003,
+        }
+    }
+
+    fn key_size(&self) -> usize {
+        match self {
+            HpkeAead::Aes128Gcm => 16,
+            HpkeAead::Aes256Gcm => 32,
+            HpkeAead::ChaCha20Poly1305 => 32,
+        }
+    }
+}
+
+/// `HpkeKeyPair` is a long-term or ephemeral `X25519` key pair used by [`seal`][seal] and
+/// [`open`][open].
+pub struct HpkeKeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl HpkeKeyPair {
+    pub fn generate(mut rng: impl CryptoRng + RngCore) -> HpkeKeyPair {
+        let mut ikm = [0u8; HPKE_KEY_SIZE];
+        rng.fill_bytes(&mut ikm);
+        HpkeKeyPair::derive(&ikm)
+    }
+
+    /// Deterministic `DeriveKeyPair` of RFC 9180, section 7.1.3.
+    pub fn derive(ikm: &[u8]) -> HpkeKeyPair {
+        let suite_id = kem_suite_id();
+        let prk = labeled_extract(&suite_id, &[], b"dkp_prk", ikm);
+        let mut sk = [0u8; HPKE_KEY_SIZE];
+        labeled_expand(&suite_id, &prk, b"sk", &[], &mut sk);
+        HpkeKeyPair::from(sk)
+    }
+
+    pub fn public_key(&self) -> [u8; HPKE_KEY_SIZE] {
+        self.public.to_bytes()
+    }
+
+    pub fn secret_key(&self) -> [u8; HPKE_KEY_SIZE] {
+        self.secret.to_bytes()
+    }
+}
+
+impl From<[u8; HPKE_KEY_SIZE]> for HpkeKeyPair {
+    fn from(value: [u8; HPKE_KEY_SIZE]) -> Self {
+        let secret = StaticSecret::from(value);
+        let public = PublicKey::from(&secret);
+        HpkeKeyPair { secret, public }
+    }
+}
+
+/// Encryption context made by the key schedule, see RFC 9180, section 5.2.
+struct Context {
+    aead: HpkeAead,
+    key: Vec<u8>,
+    base_nonce: [u8; HPKE_NONCE_SIZE],
+    seq: u64,
+}
+
+impl Context {
+    fn nonce(&self) -> [u8; HPKE_NONCE_SIZE] {
+        let mut nonce = self.base_nonce;
+        let seq = self.seq.to_be_bytes();
+        for (n, s) in nonce[HPKE_NONCE_SIZE - seq.len()..].iter_mut().zip(seq) {
+            *n ^= s;
+        }
+        nonce
+    }
+
+    fn seal(&mut self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce();
+        let res = match self.aead {
+            HpkeAead::Aes128Gcm => aead_seal::<Aes128Gcm>(&self.key, &nonce, aad, plaintext),
+            HpkeAead::Aes256Gcm => aead_seal::<Aes256Gcm>(&self.key, &nonce, aad, plaintext),
+            HpkeAead::ChaCha20Poly1305 => {
+                aead_seal::<ChaCha20Poly1305>(&self.key, &nonce, aad, plaintext)
+            }
+        }?;
+        self.seq += 1;
+        Ok(res)
+    }
+
+    fn open(&mut self, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce();
+        let res = match self.aead {
+            HpkeAead::Aes128Gcm => aead_open::<Aes128Gcm>(&self.key, &nonce, aad, ciphertext),
+            HpkeAead::Aes256Gcm => aead_open::<Aes256Gcm>(&self.key, &nonce, aad, ciphertext),
+            HpkeAead::ChaCha20Poly1305 => {
+                aead_open::<ChaCha20Poly1305>(&self.key, &nonce, aad, ciphertext)
+            }
+        }?;
+        self.seq += 1;
+        Ok(res)
+    }
+}
+
+/// Seals `plaintext` to the recipient public key `pk_r` in HPKE base mode.
+///
+/// Returns encapsulated key followed by ciphertext, so the result can be stored and delivered
+/// to the recipient later with no live handshake. See [`open`][open] for the reverse.
+pub fn seal(
+    aead: HpkeAead,
+    pk_r: &[u8; HPKE_KEY_SIZE],
+    info: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+    rng: impl CryptoRng + RngCore,
+) -> Result<Vec<u8>> {
+    trace!("sealing {} bytes in HPKE base mode", plaintext.len());
+
+    let eph = HpkeKeyPair::generate(rng);
+    let (enc, mut ctx) = setup_sender(aead, pk_r, None, &eph, info)?;
+    Ok([enc.as_ref(), ctx.seal(aad, plaintext)?.as_ref()].concat())
+}
+
+/// Seals `plaintext` to the recipient public key `pk_r` in HPKE auth mode, proving that
+/// the message was made by the holder of `sender` key pair.
+pub fn seal_auth(
+    aead: HpkeAead,
+    pk_r: &[u8; HPKE_KEY_SIZE],
+    sender: &HpkeKeyPair,
+    info: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+    rng: impl CryptoRng + RngCore,
+) -> Result<Vec<u8>> {
+    trace!("sealing {} bytes in HPKE auth mode", plaintext.len());
+
+    let eph = HpkeKeyPair::generate(rng);
+    let (enc, mut ctx) = setup_sender(aead, pk_r, Some(sender), &eph, info)?;
+    Ok([enc.as_ref(), ctx.seal(aad, plaintext)?.as_ref()].concat())
+}
+
+/// Opens a message made by [`seal`][seal] with the recipient key pair.
+pub fn open(
+    aead: HpkeAead,
+    recipient: &HpkeKeyPair,
+    info: &[u8],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>> {
+    trace!("opening {} bytes in HPKE base mode", sealed.len());
+
+    let (enc, ciphertext) = split_sealed(sealed)?;
+    setup_receiver(aead, enc, recipient, None, info)?.open(aad, ciphertext)
+}
+
+/// Opens a message made by [`seal_auth`][seal_auth], failing if it wasn't made by the holder
+/// of the sender public key `pk_s`.
+pub fn open_auth(
+    aead: HpkeAead,
+    recipient: &HpkeKeyPair,
+    pk_s: &[u8; HPKE_KEY_SIZE],
+    info: &[u8],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>> {
+    trace!("opening {} bytes in HPKE auth mode", sealed.len());
+
+    let (enc, ciphertext) = split_sealed(sealed)?;
+    setup_receiver(aead, enc, recipient, Some(pk_s), info)?.open(aad, ciphertext)
+}
+
+fn split_sealed(sealed: &[u8]) -> Result<(&[u8; HPKE_KEY_SIZE], &[u8])> {
+    match sealed.split_first_chunk::<HPKE_KEY_SIZE>() {
+        Some(res) => Ok(res),
+        None => {
+            error!(
+                "'open' error: encapsulated key size '{}' is bigger than 'sealed.len()':'{}'",
+                HPKE_KEY_SIZE,
+                sealed.len()
+            );
+            Err(Error::TruncatedCiphertext {
+                expected: HPKE_KEY_SIZE,
+                actual: sealed.len(),
+            })
+        }
+    }
+}
+
+fn setup_sender(
+    aead: HpkeAead,
+    pk_r: &[u8; HPKE_KEY_SIZE],
+    sender: Option<&HpkeKeyPair>,
+    eph: &HpkeKeyPair,
+    info: &[u8],
+) -> Result<([u8; HPKE_KEY_SIZE], Context)> {
+    let pk_r = PublicKey::from(*pk_r);
+    let enc = eph.public_key();
+
+    let mut secret = dh(&eph.secret, &pk_r)?.to_vec();
+    let mut kem_context = [enc, pk_r.to_bytes()].concat();
+    if let Some(sender) = sender {
+        secret.extend(dh(&sender.secret, &pk_r)?);
+        kem_context.extend(sender.public_key());
+    }
+
+    let shared_secret = extract_and_expand(&secret, &kem_context);
+    let mode = sender.map_or(MODE_BASE, |_| MODE_AUTH);
+    Ok((enc, key_schedule(aead, mode, &shared_secret, info)))
+}
+
+fn setup_receiver(
+    aead: HpkeAead,
+    enc: &[u8; HPKE_KEY_SIZE],
+    recipient: &HpkeKeyPair,
+    pk_s: Option<&[u8; HPKE_KEY_SIZE]>,
+    info: &[u8],
+) -> Result<Context> {
+    let pk_e = PublicKey::from(*enc);
+
+    let mut secret = dh(&recipient.secret, &pk_e)?.to_vec();
+    let mut kem_context = [*enc, recipient.public_key()].concat();
+    if let Some(pk_s) = pk_s {
+        secret.extend(dh(&recipient.secret, &PublicKey::from(*pk_s))?);
+        kem_context.extend(pk_s);
+    }
+
+    let shared_secret = extract_and_expand(&secret, &kem_context);
+    let mode = pk_s.map_or(MODE_BASE, |_| MODE_AUTH);
+    Ok(key_schedule(aead, mode, &shared_secret, info))
+}
+
+#[inline]
+fn dh(sk: &StaticSecret, pk: &PublicKey) -> Result<[u8; HPKE_KEY_SIZE]> {
+    let shared = sk.diffie_hellman(pk);
+    if shared.was_contributory() {
+        Ok(shared.to_bytes())
+    } else {
+        error!("'dh' error: peer public key produces all-zero shared secret");
+        Err(Error::KeyDerivation(
+            "peer public key is a low order point".to_string(),
+        ))
+    }
+}
+
+fn extract_and_expand(dh: &[u8], kem_context: &[u8]) -> [u8; HPKE_KEY_SIZE] {
+    let suite_id = kem_suite_id();
+    let prk = labeled_extract(&suite_id, &[], b"eae_prk", dh);
+    let mut shared_secret = [0u8; HPKE_KEY_SIZE];
+    labeled_expand(
+        &suite_id,
+        &prk,
+        b"shared_secret",
+        kem_context,
+        &mut shared_secret,
+    );
+    shared_secret
+}
+
+fn key_schedule(aead: HpkeAead, mode: u8, shared_secret: &[u8], info: &[u8]) -> Context {
+    let suite_id = [
+        b"HPKE".as_ref(),
+        &KEM_ID.to_be_bytes(),
+        &KDF_ID.to_be_bytes(),
+        &aead.id().to_be_bytes(),
+    ]
+    .concat();
+
+    let psk_id_hash = labeled_extract(&suite_id, &[], b"psk_id_hash", &[]);
+    let info_hash = labeled_extract(&suite_id, &[], b"info_hash", info);
+    let context = [[mode].as_ref(), &psk_id_hash, &info_hash].concat();
+    let secret = labeled_extract(&suite_id, shared_secret, b"secret", &[]);
+
+    let mut key = vec![0u8; aead.key_size()];
+    let mut base_nonce = [0u8; HPKE_NONCE_SIZE];
+    labeled_expand(&suite_id, &secret, b"key", &context, &mut key);
+    labeled_expand(&suite_id, &secret, b"base_nonce", &context, &mut base_nonce);
+
+    Context {
+        aead,
+        key,
+        base_nonce,
+        seq: 0,
+    }
+}
+
+#[inline]
+fn kem_suite_id() -> Vec<u8> {
+    [b"KEM".as_ref(), &KEM_ID.to_be_bytes()].concat()
+}
+
+fn labeled_extract(suite_id: &[u8], salt: &[u8], label: &[u8], ikm: &[u8]) -> Vec<u8> {
+    let ikm = [HPKE_VERSION, suite_id, label, ikm].concat();
+    Hkdf::<Sha256>::extract(Some(salt), &ikm).0.to_vec()
+}
+
+fn labeled_expand(suite_id: &[u8], prk: &[u8], label: &[u8], info: &[u8], okm: &mut [u8]) {
+    let len = (okm.len() as u16).to_be_bytes();
+    let info = [len.as_ref(), HPKE_VERSION, suite_id, label, info].concat();
+    // `prk` is always a full `Sha256` output and `okm` is far below `255 * HashLen`
+    let hk = Hkdf::<Sha256>::from_prk(prk).unwrap();
+    hk.expand(&info, okm).unwrap();
+}
+
+fn aead_seal<A: Aead + AeadCore + KeyInit>(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>> {
+    let cipher = A::new_from_slice(key).map_err(|e| Error::KeyDerivation(e.to_string()))?;
+    cipher
+        .encrypt(Nonce::<A>::from_slice(nonce), Payload { msg, aad })
+        .map_err(Error::from)
+}
+
+fn aead_open<A: Aead + AeadCore + KeyInit>(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>> {
+    let cipher = A::new_from_slice(key).map_err(|e| Error::KeyDerivation(e.to_string()))?;
+    cipher
+        .decrypt(Nonce::<A>::from_slice(nonce), Payload { msg, aad })
+        .map_err(|_| ERR_AUTHENTICATION.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use aead::OsRng;
+
+    const TEST_STRING: &str = "alpha test string";
+
+    // RFC 9180, appendix A: `info`, `pt` and `aad` shared by all vectors used below
+    const INFO: &str = "4f6465206f6e2061204772656369616e2055726e";
+    const PT: &str = "4265617574792069732074727574682c20747275746820626561757479";
+    const AAD: [&str; 2] = ["436f756e742d30", "436f756e742d31"];
+
+    struct Vector {
+        aead: HpkeAead,
+        ikm_r: &'static str,
+        ikm_s: Option<&'static str>,
+        ikm_e: &'static str,
+        pk_r: &'static str,
+        pk_e: &'static str,
+        key: &'static str,
+        base_nonce: &'static str,
+        ct: [&'static str; 2],
+    }
+
+    const VECTORS: [Vector; 4] = [
+        // A.1.1. DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-128-GCM, base setup
+        Vector {
+            aead: HpkeAead::Aes128Gcm,
+            ikm_r: "6db9df30aa07dd42ee5e8181afdb977e538f5e1fec8a06223f33f7013e525037",
+            ikm_s: None,
+            ikm_e: "7268600d403fce431561aef583ee1613527cff655c1343f29812e66706df3234",
+            pk_r: "3948cfe0ad1ddb695d780e59077195da6c56506b027329794ab02bca80815c4d",
+            pk_e: "37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431",
+            key: "4531685d41d65f03dc48f6b8302c05b0",
+            base_nonce: "56d890e5accaaf011cff4b7d",
+            ct: [
+                "f938558b5d72f1a23810b4be2ab4f84331acc02fc97babc53a52ae8218a355a96d8770ac83d07bea87e13c512a",
+                "af2d7e9ac9ae7e270f46ba1f975be53c09f8d875bdc8535458c2494e8a6eab251c03d0c22a56b8ca42c2063b84",
+            ],
+        },
+        // A.1.3. DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-128-GCM, auth setup
+        Vector {
+            aead: HpkeAead::Aes128Gcm,
+            ikm_r: "f1d4a30a4cef8d6d4e3b016e6fd3799ea057db4f345472ed302a67ce1c20cdec",
+            ikm_s: Some("94b020ce91d73fca4649006c7e7329a67b40c55e9e93cc907d282bbbff386f58"),
+            ikm_e: "6e6d8f200ea2fb20c30b003a8b4f433d2f4ed4c2658d5bc8ce2fef718059c9f7",
+            pk_r: "1632d5c2f71c2b38d0a8fcc359355200caa8b1ffdf28618080466c909cb69b2e",
+            pk_e: "23fb952571a14a25e3d678140cd0e5eb47a0961bb18afcf85896e5453c312e76",
+            key: "b062cb2c4dd4bca0ad7c7a12bbc341e6",
+            base_nonce: "a1bc314c1942ade7051ffed0",
+            ct: [
+                "5fd92cc9d46dbf8943e72a07e42f363ed5f721212cd90bcfd072bfd9f44e06b80fd17824947496e21b680c141b",
+                "d3736bb256c19bfa93d79e8f80b7971262cb7c887e35c26370cfed62254369a1b52e3d505b79dd699f002bc8ed",
+            ],
+        },
+        // A.2.1. DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, ChaCha20Poly1305, base setup
+        Vector {
+            aead: HpkeAead::ChaCha20Poly1305,
+            ikm_r: "1ac01f181fdf9f352797655161c58b75c656a6cc2716dcb66372da835542e1df",
+            ikm_s: None,
+            ikm_e: "909a9b35d3dc4713a5e72a4da274b55d3d3821a37e5d099e74a647db583a904b",
+            pk_r: "4310ee97d88cc1f088a5576c77ab0cf5c3ac797f3d95139c6c84b5429c59662a",
+            pk_e: "1afa08d3dec047a643885163f1180476fa7ddb54c6a8029ea33f95796bf2ac4a",
+            key: "ad2744de8e17f4ebba575b3f5f5a8fa1f69c2a07f6e7500bc60ca6e3e3ec1c91",
+            base_nonce: "5c4d98150661b848853b547f",
+            ct: [
+                "1c5250d8034ec2b784ba2cfd69dbdb8af406cfe3ff938e131f0def8c8b60b4db21993c62ce81883d2dd1b51a28",
+                "6b53c051e4199c518de79594e1c4ab18b96f081549d45ce015be002090bb119e85285337cc95ba5f59992dc98c",
+            ],
+        },
+        // A.2.3. DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, ChaCha20Poly1305, auth setup
+        Vector {
+            aead: HpkeAead::ChaCha20Poly1305,
+            ikm_r: "64835d5ee64aa7aad57c6f2e4f758f7696617f8829e70bc9ac7a5ef95d1c756c",
+            ikm_s: Some("9d8f94537d5a3ddef71234c0baedfad4ca6861634d0b94c3007fed557ad17df6"),
+            ikm_e: "938d3daa5a8904540bc24f48ae90eed3f4f7f11839560597b55e7c9598c996c0",
+            pk_r: "1a478716d63cb2e16786ee93004486dc151e988b34b475043d3e0175bdb01c44",
+            pk_e: "f7674cc8cd7baa5872d1f33dbaffe3314239f6197ddf5ded1746760bfc847e0e",
+            key: "b071fd1136680600eb447a845a967d35e9db20749cdf9ce098bcc4deef4b1356",
+            base_nonce: "d20577dff16d7cea2c4bf780",
+            ct: [
+                "ab1a13c9d4f01a87ec3440dbd756e2677bd2ecf9df0ce7ed73869b98e00c09be111cb9fdf077347aeb88e61bdf",
+                "3265c7807ffff7fdace21659a2c6ccffee52a26d270c76468ed74202a65478bfaedfff9c2b7634e24f10b71016",
+            ],
+        },
+    ];
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|c| u8::from_str_radix(&s[c..c + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn hpke_vectors() {
+        for v in VECTORS {
+            let info = hex(INFO);
+            let recipient = HpkeKeyPair::derive(&hex(v.ikm_r));
+            let sender = v.ikm_s.map(|c| HpkeKeyPair::derive(&hex(c)));
+            let eph = HpkeKeyPair::derive(&hex(v.ikm_e));
+            // `DeriveKeyPair` matches reference keys
+            assert_eq!(recipient.public_key().to_vec(), hex(v.pk_r));
+
+            let pk_r = recipient.public_key();
+            let (enc, mut ctx) = setup_sender(v.aead, &pk_r, sender.as_ref(), &eph, &info).unwrap();
+            // key schedule matches reference values
+            assert_eq!(enc.to_vec(), hex(v.pk_e));
+            assert_eq!(ctx.key, hex(v.key));
+            assert_eq!(ctx.base_nonce.to_vec(), hex(v.base_nonce));
+
+            let pk_s = sender.as_ref().map(|c| c.public_key());
+            let mut rx = setup_receiver(v.aead, &enc, &recipient, pk_s.as_ref(), &info).unwrap();
+            for (aad, ct) in AAD.iter().zip(v.ct) {
+                let res = ctx.seal(&hex(aad), &hex(PT)).unwrap();
+                // sequenced encryptions match reference ciphertext
+                assert_eq!(res, hex(ct));
+                assert_eq!(rx.open(&hex(aad), &res).unwrap(), hex(PT));
+            }
+        }
+    }
+
+    #[test]
+    fn hpke_works() {
+        let recipient = HpkeKeyPair::generate(OsRng);
+        let sender = HpkeKeyPair::generate(OsRng);
+        let pk_r = recipient.public_key();
+        let pk_s = sender.public_key();
+
+        for aead in [
+            HpkeAead::Aes128Gcm,
+            HpkeAead::Aes256Gcm,
+            HpkeAead::ChaCha20Poly1305,
+        ] {
+            let msg = seal(aead, &pk_r, b"info", b"aad", TEST_STRING.as_ref(), OsRng).unwrap();
+            let res = open(aead, &recipient, b"info", b"aad", &msg).unwrap();
+            // base mode makes a round trip
+            assert_eq!(res, TEST_STRING.as_bytes());
+            // context mismatch fails authentication
+            assert!(open(aead, &recipient, b"other", b"aad", &msg).is_err());
+
+            let msg =
+                seal_auth(aead, &pk_r, &sender, b"", b"", TEST_STRING.as_ref(), OsRng).unwrap();
+            let res = open_auth(aead, &recipient, &pk_s, b"", b"", &msg).unwrap();
+            // auth mode makes a round trip
+            assert_eq!(res, TEST_STRING.as_bytes());
+            // auth mode rejects unexpected sender
+            assert!(matches!(
+                open_auth(aead, &recipient, &pk_r, b"", b"", &msg),
+                Err(Error::AuthenticationFailed(_))
+            ));
+        }
+
+        // short input is reported as truncated
+        assert!(matches!(
+            open(HpkeAead::default(), &recipient, b"", b"", &[0u8; 16]),
+            Err(Error::TruncatedCiphertext { .. })
+        ));
+    }
+}
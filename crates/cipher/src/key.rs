@@ -0,0 +1,166 @@
+use async_std::sync::Arc;
+use err::{Error, Result};
+use log::{trace, warn};
+use std::time::{Duration, Instant};
+
+use crate::IOCipher;
+
+pub(super) type ArcCipher = Arc<dyn IOCipher + Sync + Send>;
+
+/// Upper bound of simultaneously held epochs, including the current one.
+pub const MAX_EPOCHS: usize = 4;
+/// Size of the suite and key ids ciphertext is prefixed with.
+const HEADER_SIZE: usize = 2;
+
+#[derive(Clone)]
+struct Epoch {
+    id: u8,
+    cipher: ArcCipher,
+    expires: Option<Instant>,
+}
+
+impl Epoch {
+    fn is_alive(&self, now: Instant) -> bool {
+        self.expires.is_none_or(|c| c > now)
+    }
+}
+
+/// `Keyring` is an immutable snapshot of keyed epochs held by [`CipherHandle`][crate::CipherHandle].
+///
+/// Ciphertext produced by [`encrypt`][IOCipher::encrypt] and [`encrypt_at`][IOCipher::encrypt_at]
+/// is prefixed with one byte ids of the cipher suite and of the current epoch, and
+/// [`decrypt`][IOCipher::decrypt] and [`decrypt_at`][IOCipher::decrypt_at] pick the epoch by
+/// the latter once the former matches. Epochs replaced by [`rotate`][Keyring::rotate] stay
+/// usable for decryption until their grace period is over, and the one installed by
+/// [`propose`][Keyring::propose] is usable for decryption only until it's rotated to.
+#[derive(Clone)]
+pub(super) struct Keyring {
+    suite: u8,
+    current: Epoch,
+    pending: Option<Epoch>,
+    retired: Vec<Epoch>,
+}
+
+impl Keyring {
+    pub fn new(suite: u8, cipher: ArcCipher) -> Keyring {
+        Keyring {
+            suite,
+            current: Epoch {
+                id: 0,
+                cipher,
+                expires: None,
+            },
+            pending: None,
+            retired: vec![],
+        }
+    }
+
+    pub fn key_id(&self) -> u8 {
+        self.current.id
+    }
+
+    /// Makes a new keyring with `cipher` installed as current epoch under `id`, keeping the
+    /// previous epoch for `grace` and dropping already expired ones.
+    pub fn rotate(&self, id: u8, cipher: ArcCipher, grace: Duration) -> Keyring {
+        let now = Instant::now();
+        let mut prev = self.current.clone();
+        prev.expires = Some(now + grace);
+
+        let mut retired = [prev]
+            .into_iter()
+            .chain(self.retired.iter().cloned())
+            .filter(|c| c.id != id && c.is_alive(now))
+            .collect::<Vec<_>>();
+        retired.truncate(MAX_EPOCHS - 1);
+        trace!("rotated key epoch {} -> {}", self.current.id, id);
+
+        Keyring {
+            suite: self.suite,
+            current: Epoch {
+                id,
+                cipher,
+                expires: None,
+            },
+            pending: None,
+            retired,
+        }
+    }
+
+    /// Makes a new keyring accepting ciphertext of `cipher` under `id`, while still
+    /// encrypting with the current epoch, so the peer may switch to it first.
+    pub fn propose(&self, id: u8, cipher: ArcCipher) -> Keyring {
+        trace!("proposed key epoch {} -> {}", self.current.id, id);
+        Keyring {
+            pending: Some(Epoch {
+                id,
+                cipher,
+                expires: None,
+            }),
+            ..self.clone()
+        }
+    }
+
+    fn header(&self) -> [u8; HEADER_SIZE] {
+        [self.suite, self.current.id]
+    }
+
+    fn epoch(&self, id: u8) -> Result<&Epoch> {
+        let now = Instant::now();
+        [&self.current]
+            .into_iter()
+            .chain(self.pending.iter())
+            .chain(self.retired.iter())
+            .find(|c| c.id == id && c.is_alive(now))
+            .ok_or_else(|| {
+                warn!("got ciphertext with unknown or retired key id '{id}'");
+                Error::UnknownKey { id }
+            })
+    }
+
+    /// Epoch of ciphertext starting with `header`, refusing the one of another suite.
+    fn epoch_of(&self, header: &[u8]) -> Result<&Epoch> {
+        match *header {
+            [suite, _] if suite != self.suite => {
+                warn!(
+                    "got ciphertext of cipher suite '{suite}' instead of '{}'",
+                    self.suite
+                );
+                Err(Error::SuiteMismatch {
+                    expected: self.suite,
+                    actual: suite,
+                })
+            }
+            [_, id] => self.epoch(id),
+            _ => Err(Error::TruncatedCiphertext {
+                expected: HEADER_SIZE,
+                actual: header.len(),
+            }),
+        }
+    }
+}
+
+impl IOCipher for Keyring {
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let res = self.current.cipher.encrypt(plaintext)?;
+        Ok([self.header().as_ref(), res.as_ref()].concat())
+    }
+
+    fn encrypt_at(&self, nonce: &[u8], associated_data: &[u8], buffer: &mut Vec<u8>) -> Result<()> {
+        self.current
+            .cipher
+            .encrypt_at(nonce, associated_data, buffer)?;
+        buffer.splice(..0, self.header());
+        Ok(())
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let (header, ciphertext) = ciphertext.split_at(HEADER_SIZE.min(ciphertext.len()));
+        self.epoch_of(header)?.cipher.decrypt(ciphertext)
+    }
+
+    fn decrypt_at(&self, nonce: &[u8], associated_data: &[u8], buffer: &mut Vec<u8>) -> Result<()> {
+        let epoch = self.epoch_of(&buffer[..HEADER_SIZE.min(buffer.len())])?;
+        buffer.drain(..HEADER_SIZE);
+        epoch.cipher.decrypt_at(nonce, associated_data, buffer)
+    }
+}
@@ -1,5 +1,8 @@
 mod aes;
+mod auto;
 mod cha;
+mod hpke;
+mod key;
 mod rng;
 
 use ::aes::{Aes128, Aes192, Aes256};
@@ -7,17 +10,29 @@ use ::cha::{cipher::KeyIvInit, ChaCha20, XChaCha20};
 use aead::{
     consts::{U12, U13, U14, U15, U16},
     rand_core::{block::BlockRng, CryptoRng, RngCore},
+    OsRng,
 };
-use err::{Error, Result};
+use async_std::{sync::Arc, task};
+use err::{consts::ERR_CONTROL, Result};
 use howler::Result as HowlerResult;
 use log::{info, trace};
 use serde::Deserialize;
+use std::{
+    sync::{Mutex, RwLock},
+    thread,
+    time::Duration,
+};
 
 use crate::aes::AesCipher;
 use crate::cha::ChaCipher;
-use crate::rng::AppRngCore;
+use crate::key::Keyring;
+use crate::rng::{AppRngCore, KdfRngCore};
 
-pub use crate::rng::SeedableRng;
+pub use crate::auto::{negotiate, offer, select_encryption, OFFER_SIZE};
+pub use crate::hpke::{open, open_auth, seal, seal_auth, HpkeAead, HpkeKeyPair, HPKE_KEY_SIZE};
+pub use crate::key::MAX_EPOCHS;
+pub use crate::rng::{derive_secret, SeedableRng, SECRET_SIZE};
+pub use err::{Error, Retryable};
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(untagged)]
@@ -32,8 +47,67 @@ pub enum Encryption {
         #[serde(default)]
         cipher: ChaSpec,
     },
+    Auto {
+        cipher: AutoSpec,
+    },
+}
+
+impl Encryption {
+    /// Id of the cipher suite every ciphertext starts with, so peers which don't agree on
+    /// it get [`SuiteMismatch`][Error::SuiteMismatch] rather than failed authentication.
+    ///
+    /// [`Auto`][Encryption::Auto] has none until resolved by [`CipherHandle`][CipherHandle].
+    pub fn suite_id(&self) -> u8 {
+        match self {
+            Encryption::AES { cipher, nonce } => 1 + cipher.clone() as u8 * 5 + nonce.clone() as u8,
+            Encryption::ChaCha { cipher } => 16 + cipher.clone() as u8,
+            Encryption::Auto { .. } => 0,
+        }
+    }
+
+    /// Suite of [`suite_id`][Encryption::suite_id] `id`, none for unknown ids.
+    pub fn from_suite_id(id: u8) -> Option<Encryption> {
+        match id {
+            1..=15 => Some(Encryption::AES {
+                cipher: match (id - 1) / 5 {
+                    0 => AesSpec::Aes128,
+                    1 => AesSpec::Aes192,
+                    _ => AesSpec::Aes256,
+                },
+                nonce: match (id - 1) % 5 {
+                    0 => AesNonce::U12,
+                    1 => AesNonce::U13,
+                    2 => AesNonce::U14,
+                    3 => AesNonce::U15,
+                    _ => AesNonce::U16,
+                },
+            }),
+            16 => Some(Encryption::ChaCha {
+                cipher: ChaSpec::ChaCha20,
+            }),
+            17 => Some(Encryption::ChaCha {
+                cipher: ChaSpec::XChaCha20,
+            }),
+            _ => None,
+        }
+    }
 }
 
+/// Default payload size for [`CipherConfig`][CipherConfig] offloading.
+pub const OFFLOAD_THRESHOLD: usize = 64 * 1024;
+/// Default time in milliseconds for [`CipherConfig`][CipherConfig] to keep replaced keys.
+pub const GRACE_PERIOD: u64 = 5000;
+/// Size of fresh key material carried by rekey control messages.
+pub const REKEY_SEED_SIZE: usize = 32;
+/// Kind of message carrying user data.
+const MSG_DATA: u8 = 0;
+/// Kind of message carrying a control message.
+const MSG_CONTROL: u8 = 1;
+/// Kind of control message proposing a new key epoch.
+const CONTROL_REKEY: u8 = 1;
+/// Kind of control message accepting a proposed key epoch.
+const CONTROL_ACK: u8 = 2;
+
 #[allow(dead_code)]
 pub type AppRng = BlockRng<AppRngCore>;
 
@@ -58,6 +132,15 @@ pub enum ChaSpec {
     XChaCha20,
 }
 
+/// `AutoSpec` for `.toml` config parsing.
+/// Selects [`Encryption::Auto`][Encryption::Auto] with `cipher = "Auto"`, see
+/// [`select_encryption`][select_encryption] for details.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub enum AutoSpec {
+    #[default]
+    Auto,
+}
+
 /// `AesNonce` for `.toml` config parsing.
 /// Offers limited values accepted by [`TagSize`][aes_gcm::TagSize] for available
 /// [`AesSpec`][AesSpec] ciphers.
@@ -86,24 +169,207 @@ pub trait IOCipher {
     fn decrypt_at(&self, nonce: &[u8], associated_data: &[u8], buffer: &mut Vec<u8>) -> Result<()>;
 }
 
+/// `CipherConfig` for `.toml` config parsing.
+/// Sets the payload size in bytes starting from which [`CipherHandle`][CipherHandle] moves
+/// work off the executor thread to the blocking pool, and the time in milliseconds replaced
+/// keys are still accepted after [`rekey`][CipherHandle::rekey].
+#[derive(Debug, Deserialize, Clone)]
+pub struct CipherConfig {
+    #[serde(default = "CipherConfig::default_offload")]
+    offload: usize,
+    #[serde(default = "CipherConfig::default_grace")]
+    grace: u64,
+}
+
+impl CipherConfig {
+    pub fn new(offload: usize, grace: Duration) -> Self {
+        CipherConfig {
+            offload,
+            grace: grace.as_millis() as u64,
+        }
+    }
+
+    fn default_offload() -> usize {
+        OFFLOAD_THRESHOLD
+    }
+
+    fn default_grace() -> u64 {
+        GRACE_PERIOD
+    }
+}
+
+impl Default for CipherConfig {
+    fn default() -> Self {
+        CipherConfig::new(OFFLOAD_THRESHOLD, Duration::from_millis(GRACE_PERIOD))
+    }
+}
+
+/// `Message` decrypted by [`decrypt_msg`][CipherHandle::decrypt_msg].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    /// User data made by [`encrypt_msg`][CipherHandle::encrypt_msg].
+    Data(Vec<u8>),
+    /// Applied control message, along with the reply to send back to the peer if any.
+    Control(Option<Vec<u8>>),
+}
+
 pub struct CipherHandle {
-    cipher: Box<dyn IOCipher + Sync + Send>,
+    cfg: Encryption,
+    keyring: RwLock<Arc<Keyring>>,
+    cipher_cfg: CipherConfig,
+    /// Key epoch id and seed proposed to the peer and not yet accepted by it.
+    proposal: Mutex<Option<(u8, [u8; REKEY_SEED_SIZE])>>,
 }
 
 #[allow(dead_code)]
 impl CipherHandle {
-    pub fn new(cfg: &Encryption, rng: impl CryptoRng + RngCore) -> CipherHandle {
-        let cipher = match cfg {
-            Encryption::AES { cipher, nonce } => get_aes_cipher(cipher, nonce, rng),
-            Encryption::ChaCha { cipher } => get_cha_cipher(cipher, rng),
+    pub fn new(
+        cfg: &Encryption,
+        cipher_cfg: CipherConfig,
+        rng: impl CryptoRng + RngCore,
+    ) -> HowlerResult<CipherHandle> {
+        // suite is picked once, so `rekey` won't switch it mid-session, though peers should
+        // rather `negotiate` it
+        let cfg = match cfg {
+            Encryption::Auto { .. } => select_encryption()?,
+            cfg => cfg.clone(),
         };
+        let keyring = Keyring::new(cfg.suite_id(), get_cipher(&cfg, rng)?.into());
         info!("made instance of cipher handle with parameters '{:?}'", cfg);
 
-        CipherHandle { cipher }
+        Ok(CipherHandle {
+            cfg,
+            keyring: RwLock::new(Arc::new(keyring)),
+            cipher_cfg,
+            proposal: Mutex::new(None),
+        })
+    }
+
+    fn cipher(&self) -> Arc<Keyring> {
+        self.keyring.read().unwrap().clone()
+    }
+
+    /// Id of the key epoch used for outgoing ciphertext.
+    pub fn key_id(&self) -> u8 {
+        self.cipher().key_id()
+    }
+
+    /// Installs a key derived from `seed` as the current epoch under `id`.
+    ///
+    /// Ciphertext of the replaced epoch is accepted for the configured grace period, so both
+    /// peers have time to switch keys without dropping packets in flight.
+    pub fn rekey(&self, id: u8, seed: &[u8]) -> HowlerResult<()> {
+        let cipher = get_epoch_cipher(&self.cfg, id, seed)?;
+        let grace = Duration::from_millis(self.cipher_cfg.grace);
+
+        let mut keyring = self.keyring.write().unwrap();
+        *keyring = Arc::new(keyring.rotate(id, cipher.into(), grace));
+        info!("switched to key epoch '{id}'");
+        Ok(())
+    }
+
+    /// Makes a new key epoch from fresh [`OsRng`][OsRng] material and returns a control
+    /// message proposing it to the peer.
+    ///
+    /// The new epoch is only used for decryption until the peer accepts it, outgoing
+    /// ciphertext stays under the current one. The message is meant to go along with user
+    /// data of [`encrypt_msg`][CipherHandle::encrypt_msg] and to be handled on both sides by
+    /// [`decrypt_msg`][CipherHandle::decrypt_msg]. A proposal that's not accepted yet is
+    /// made again rather than replaced, so lost messages can be resent.
+    pub async fn rekey_request(&self) -> HowlerResult<Vec<u8>> {
+        let (id, seed) = {
+            let mut proposal = self.proposal.lock().unwrap();
+            match *proposal {
+                Some(res) => res,
+                None => {
+                    let id = self.key_id().wrapping_add(1);
+                    let mut seed = [0u8; REKEY_SEED_SIZE];
+                    OsRng.fill_bytes(&mut seed);
+
+                    let cipher = get_epoch_cipher(&self.cfg, id, &seed)?;
+                    let mut keyring = self.keyring.write().unwrap();
+                    *keyring = Arc::new(keyring.propose(id, cipher.into()));
+                    *proposal.insert((id, seed))
+                }
+            }
+        };
+        self.control(CONTROL_REKEY, id, &seed).await
+    }
+
+    /// Encrypts user data to be told apart from control messages by
+    /// [`decrypt_msg`][CipherHandle::decrypt_msg].
+    pub async fn encrypt_msg(&self, plaintext: &[u8]) -> HowlerResult<Vec<u8>> {
+        self.encrypt(&[[MSG_DATA].as_ref(), plaintext].concat())
+            .await
+    }
+
+    /// Decrypts a message made by [`encrypt_msg`][CipherHandle::encrypt_msg] or
+    /// [`rekey_request`][CipherHandle::rekey_request], applying the latter.
+    pub async fn decrypt_msg(&self, ciphertext: &[u8]) -> HowlerResult<Message> {
+        let msg = self.decrypt(ciphertext).await?;
+        match msg.split_first() {
+            Some((&MSG_DATA, data)) => Ok(Message::Data(data.to_vec())),
+            Some((&MSG_CONTROL, msg)) => self.apply_control(msg).await.map(Message::Control),
+            _ => Err(Error::from(ERR_CONTROL).into()),
+        }
+    }
+
+    /// Applies control message `msg`, returning the reply to it if any.
+    ///
+    /// A proposed epoch is switched to and acknowledged under the new key, unless there's a
+    /// pending proposal of our own with a lower seed, which the peer then switches to in
+    /// turn. Acknowledgement of our proposal switches outgoing ciphertext to it.
+    async fn apply_control(&self, msg: &[u8]) -> HowlerResult<Option<Vec<u8>>> {
+        let (kind, id, seed) = match msg {
+            [kind, id, seed @ ..] if seed.len() == REKEY_SEED_SIZE => (*kind, *id, seed),
+            _ => return Err(Error::from(ERR_CONTROL).into()),
+        };
+
+        match kind {
+            CONTROL_REKEY => {
+                {
+                    let mut proposal = self.proposal.lock().unwrap();
+                    // of simultaneous proposals, both peers go with the lower seed
+                    if proposal.is_some_and(|(_, c)| c.as_slice() < seed) {
+                        trace!("ignored key epoch '{id}' proposal in favor of own one");
+                        return Ok(None);
+                    }
+                    *proposal = None;
+                    self.rekey(id, seed)?;
+                }
+                self.control(CONTROL_ACK, id, seed).await.map(Some)
+            }
+            CONTROL_ACK => {
+                let mut proposal = self.proposal.lock().unwrap();
+                if proposal.is_some_and(|(c, s)| c == id && s.as_slice() == seed) {
+                    *proposal = None;
+                    self.rekey(id, seed)?;
+                } else {
+                    trace!("ignored acknowledgement of unknown key epoch '{id}'");
+                }
+                Ok(None)
+            }
+            _ => Err(Error::from(ERR_CONTROL).into()),
+        }
+    }
+
+    /// Encrypts control message of `kind` carrying epoch `id` and its `seed`.
+    async fn control(&self, kind: u8, id: u8, seed: &[u8]) -> HowlerResult<Vec<u8>> {
+        self.encrypt(&[[MSG_CONTROL, kind, id].as_ref(), seed].concat())
+            .await
     }
 
     pub async fn encrypt(&self, plaintext: &[u8]) -> HowlerResult<Vec<u8>> {
-        self.cipher.encrypt(plaintext).map_err(Error::into)
+        if plaintext.len() < self.cipher_cfg.offload {
+            return self.cipher().encrypt(plaintext).map_err(Error::into);
+        }
+        trace!("offloading encryption of {} bytes", plaintext.len());
+
+        let cipher = self.cipher();
+        let plaintext = plaintext.to_vec();
+        task::spawn_blocking(move || cipher.encrypt(&plaintext))
+            .await
+            .map_err(Error::into)
     }
 
     pub async fn encrypt_at(
@@ -112,13 +378,37 @@ impl CipherHandle {
         associated_data: &[u8],
         buffer: &mut Vec<u8>,
     ) -> HowlerResult<()> {
-        self.cipher
-            .encrypt_at(nonce, associated_data, buffer)
-            .map_err(Error::into)
+        if buffer.len() < self.cipher_cfg.offload {
+            return self
+                .cipher()
+                .encrypt_at(nonce, associated_data, buffer)
+                .map_err(Error::into);
+        }
+        trace!("offloading encryption of {} bytes at buffer", buffer.len());
+
+        let cipher = self.cipher();
+        let (nonce, associated_data) = (nonce.to_vec(), associated_data.to_vec());
+        let mut buf = std::mem::take(buffer);
+        let (res, buf) = task::spawn_blocking(move || {
+            let res = cipher.encrypt_at(&nonce, &associated_data, &mut buf);
+            (res, buf)
+        })
+        .await;
+        *buffer = buf;
+        res.map_err(Error::into)
     }
 
     pub async fn decrypt(&self, ciphertext: &[u8]) -> HowlerResult<Vec<u8>> {
-        self.cipher.decrypt(ciphertext).map_err(Error::into)
+        if ciphertext.len() < self.cipher_cfg.offload {
+            return self.cipher().decrypt(ciphertext).map_err(Error::into);
+        }
+        trace!("offloading decryption of {} bytes", ciphertext.len());
+
+        let cipher = self.cipher();
+        let ciphertext = ciphertext.to_vec();
+        task::spawn_blocking(move || cipher.decrypt(&ciphertext))
+            .await
+            .map_err(Error::into)
     }
 
     pub async fn decrypt_at(
@@ -127,10 +417,113 @@ impl CipherHandle {
         associated_data: &[u8],
         buffer: &mut Vec<u8>,
     ) -> HowlerResult<()> {
-        self.cipher
-            .decrypt_at(nonce, associated_data, buffer)
+        if buffer.len() < self.cipher_cfg.offload {
+            return self
+                .cipher()
+                .decrypt_at(nonce, associated_data, buffer)
+                .map_err(Error::into);
+        }
+        trace!("offloading decryption of {} bytes at buffer", buffer.len());
+
+        let cipher = self.cipher();
+        let (nonce, associated_data) = (nonce.to_vec(), associated_data.to_vec());
+        let mut buf = std::mem::take(buffer);
+        let (res, buf) = task::spawn_blocking(move || {
+            let res = cipher.decrypt_at(&nonce, &associated_data, &mut buf);
+            (res, buf)
+        })
+        .await;
+        *buffer = buf;
+        res.map_err(Error::into)
+    }
+
+    /// Encrypts a batch of `frames`, returning ciphertexts in the same order.
+    ///
+    /// Batches under the offload threshold are processed inline, bigger ones are split
+    /// between blocking workers by [`available_parallelism`][thread::available_parallelism].
+    pub async fn encrypt_batch(&self, frames: Vec<Vec<u8>>) -> HowlerResult<Vec<Vec<u8>>> {
+        self.batch(frames, |cipher, frame| cipher.encrypt(frame))
+            .await
             .map_err(Error::into)
     }
+
+    /// Decrypts a batch of `frames`, returning plaintexts in the same order.
+    ///
+    /// See [`encrypt_batch`][CipherHandle::encrypt_batch] for offloading details.
+    pub async fn decrypt_batch(&self, frames: Vec<Vec<u8>>) -> HowlerResult<Vec<Vec<u8>>> {
+        self.batch(frames, |cipher, frame| cipher.decrypt(frame))
+            .await
+            .map_err(Error::into)
+    }
+
+    async fn batch(
+        &self,
+        frames: Vec<Vec<u8>>,
+        op: fn(&Keyring, &[u8]) -> Result<Vec<u8>>,
+    ) -> Result<Vec<Vec<u8>>> {
+        let (count, total) = (frames.len(), frames.iter().map(Vec::len).sum::<usize>());
+        if total < self.cipher_cfg.offload || frames.len() < 2 {
+            let cipher = self.cipher();
+            return frames.iter().map(|c| op(&cipher, c)).collect();
+        }
+
+        let workers = thread::available_parallelism().map_or(1, |c| c.get());
+        let size = frames.len().div_ceil(workers);
+        trace!(
+            "offloading batch of {} frames ({} bytes) to {} workers",
+            frames.len(),
+            total,
+            frames.len().div_ceil(size)
+        );
+
+        let mut frames = frames.into_iter();
+        let mut handles = Vec::with_capacity(workers);
+        loop {
+            let chunk = frames.by_ref().take(size).collect::<Vec<_>>();
+            if chunk.is_empty() {
+                break;
+            }
+            let cipher = self.cipher();
+            handles.push(task::spawn_blocking(move || {
+                chunk
+                    .iter()
+                    .map(|c| op(&cipher, c))
+                    .collect::<Result<Vec<_>>>()
+            }));
+        }
+
+        let mut res = Vec::with_capacity(count);
+        // handles are awaited in spawn order, so frame order is kept
+        for handle in handles {
+            res.append(&mut handle.await?);
+        }
+        Ok(res)
+    }
+}
+
+fn get_cipher(
+    cfg: &Encryption,
+    rng: impl CryptoRng + RngCore,
+) -> Result<Box<dyn IOCipher + Sync + Send>> {
+    match cfg {
+        Encryption::AES { cipher, nonce } => get_aes_cipher(cipher, nonce, rng),
+        Encryption::ChaCha { cipher } => get_cha_cipher(cipher, rng),
+        Encryption::Auto { .. } => get_cipher(&select_encryption()?, rng),
+    }
+}
+
+/// Builds cipher of key epoch `id`, derived from `seed` by [`KdfRngCore`][KdfRngCore].
+fn get_epoch_cipher(
+    cfg: &Encryption,
+    id: u8,
+    seed: &[u8],
+) -> Result<Box<dyn IOCipher + Sync + Send>> {
+    let gen_rng = BlockRng::new(KdfRngCore::new(seed, id));
+    match cfg {
+        Encryption::AES { cipher, nonce } => Ok(aes_cipher(cipher, nonce, gen_rng)),
+        Encryption::ChaCha { cipher } => Ok(cha_cipher(cipher, gen_rng)),
+        Encryption::Auto { .. } => get_epoch_cipher(&select_encryption()?, id, seed),
+    }
 }
 
 /// A thread-safe `AES` cipher constructor.
@@ -146,11 +539,22 @@ pub fn get_aes_cipher(
     cipher: &AesSpec,
     nonce: &AesNonce,
     rng: impl CryptoRng + RngCore,
+) -> Result<Box<dyn IOCipher + Sync + Send>> {
+    trace!("building AES cipher instance");
+
+    let gen_rng =
+        BlockRng::<AppRngCore>::from_rng(rng).map_err(|e| Error::KeyDerivation(e.to_string()))?;
+    Ok(aes_cipher(cipher, nonce, gen_rng))
+}
+
+/// Builds `AES` cipher with a key drawn from `gen_rng` as is.
+fn aes_cipher(
+    cipher: &AesSpec,
+    nonce: &AesNonce,
+    gen_rng: impl CryptoRng + RngCore,
 ) -> Box<dyn IOCipher + Sync + Send> {
     use aes_gcm::KeyInit;
-    trace!("building AES cipher instance");
 
-    let gen_rng = BlockRng::<AppRngCore>::from_rng(rng).unwrap();
     match nonce {
         AesNonce::U12 => match cipher {
             AesSpec::Aes128 => Box::new(AesCipher::<_, U12>::from(Aes128::generate_key(gen_rng))),
@@ -193,10 +597,19 @@ pub fn get_aes_cipher(
 pub fn get_cha_cipher(
     cipher: &ChaSpec,
     rng: impl CryptoRng + RngCore,
-) -> Box<dyn IOCipher + Sync + Send> {
+) -> Result<Box<dyn IOCipher + Sync + Send>> {
     trace!("building CHA cipher instance");
 
-    let gen_rng = BlockRng::<AppRngCore>::from_rng(rng).unwrap();
+    let gen_rng =
+        BlockRng::<AppRngCore>::from_rng(rng).map_err(|e| Error::KeyDerivation(e.to_string()))?;
+    Ok(cha_cipher(cipher, gen_rng))
+}
+
+/// Builds `ChaCha + Poly1305` cipher with a key drawn from `gen_rng` as is.
+fn cha_cipher(
+    cipher: &ChaSpec,
+    gen_rng: impl CryptoRng + RngCore,
+) -> Box<dyn IOCipher + Sync + Send> {
     match cipher {
         ChaSpec::ChaCha20 => Box::new(ChaCipher::<ChaCha20, _>::from(ChaCha20::generate_key(
             gen_rng,
@@ -214,7 +627,9 @@ mod tests {
     use aead::{consts::U24, Nonce, OsRng};
     use aes_gcm::AesGcm;
     use chacha20poly1305::ChaChaPoly1305;
+    use howler::Error as HowlerError;
 
+    const TEST_PHRASE: &str = "alpha test phrase";
     const TEST_STRING: &str = "alpha test string";
 
     #[async_std::test]
@@ -224,8 +639,10 @@ mod tests {
                 cipher: AesSpec::default(),
                 nonce: AesNonce::default(),
             },
+            CipherConfig::default(),
             OsRng,
-        );
+        )
+        .unwrap();
         let res = cipher
             .decrypt(cipher.encrypt(TEST_STRING.as_ref()).await.unwrap().as_ref())
             .await
@@ -239,7 +656,11 @@ mod tests {
                 &mut vec![0u8; 24],
             )
             .await;
-        assert!(res.is_err());
+        // mismatched nonce is reported as misconfiguration
+        assert!(matches!(
+            res,
+            Err(HowlerError::CipherError(Error::NonceLength { .. }))
+        ));
     }
 
     #[async_std::test]
@@ -248,8 +669,10 @@ mod tests {
             &Encryption::ChaCha {
                 cipher: ChaSpec::default(),
             },
+            CipherConfig::default(),
             OsRng,
-        );
+        )
+        .unwrap();
         let res = cipher
             .decrypt(cipher.encrypt(TEST_STRING.as_ref()).await.unwrap().as_ref())
             .await
@@ -263,7 +686,86 @@ mod tests {
                 &mut vec![0u8; 24],
             )
             .await;
-        assert!(res.is_err());
+        // mismatched nonce is reported as misconfiguration
+        assert!(matches!(
+            res,
+            Err(HowlerError::CipherError(Error::NonceLength { .. }))
+        ));
+    }
+
+    #[async_std::test]
+    async fn auto_works() {
+        let res = select_encryption().unwrap();
+        // automatic selection resolves to a concrete suite
+        assert!(!matches!(res, Encryption::Auto { .. }));
+
+        let cipher = CipherHandle::new(
+            &Encryption::Auto {
+                cipher: AutoSpec::default(),
+            },
+            CipherConfig::default(),
+            OsRng,
+        )
+        .unwrap();
+        let res = cipher
+            .decrypt(cipher.encrypt(TEST_STRING.as_ref()).await.unwrap().as_ref())
+            .await
+            .unwrap();
+        assert_eq!(res.as_slice(), TEST_STRING.as_bytes());
+    }
+
+    #[async_std::test]
+    async fn auto_negotiation() {
+        let (aes, cha, auto) = (
+            Encryption::AES {
+                cipher: AesSpec::Aes128,
+                nonce: AesNonce::U16,
+            },
+            Encryption::ChaCha {
+                cipher: ChaSpec::XChaCha20,
+            },
+            Encryption::Auto {
+                cipher: AutoSpec::default(),
+            },
+        );
+        // suite ids are told back
+        for cfg in [&aes, &cha] {
+            let res = Encryption::from_suite_id(cfg.suite_id()).unwrap();
+            assert_eq!(res.suite_id(), cfg.suite_id());
+        }
+
+        let (aes_offer, auto_offer) = (offer(&aes).unwrap(), offer(&auto).unwrap());
+        // explicit suite is adopted by the automatic side, and kept by its own side
+        let res = negotiate(&auto_offer, &aes_offer).unwrap();
+        assert_eq!(res.suite_id(), aes.suite_id());
+        let res = negotiate(&aes_offer, &auto_offer).unwrap();
+        assert_eq!(res.suite_id(), aes.suite_id());
+
+        let (offer_a, offer_b) = ([1, aes.suite_id()], [1, cha.suite_id()]);
+        // differing automatic preferences end up on the same suite for both peers
+        let (res_a, res_b) = (
+            negotiate(&offer_a, &offer_b).unwrap(),
+            negotiate(&offer_b, &offer_a).unwrap(),
+        );
+        assert_eq!(res_a.suite_id(), res_b.suite_id());
+        // as do the same ones
+        let res = negotiate(&offer_a, &offer_a).unwrap();
+        assert_eq!(res.suite_id(), aes.suite_id());
+
+        // explicit suites have to match
+        assert!(matches!(
+            negotiate(&aes_offer, &offer(&cha).unwrap()),
+            Err(Error::SuiteMismatch { .. })
+        ));
+        // malformed offers are rejected
+        assert!(matches!(
+            negotiate(&auto_offer, &[1, 0]),
+            Err(Error::UnsupportedConfig(_))
+        ));
+        assert!(matches!(
+            negotiate(&auto_offer, &[1]),
+            Err(Error::UnsupportedConfig(_))
+        ));
     }
 
     #[async_std::test]
@@ -273,14 +775,18 @@ mod tests {
                 cipher: AesSpec::default(),
                 nonce: AesNonce::default(),
             },
+            CipherConfig::default(),
             OsRng,
-        );
+        )
+        .unwrap();
         let cha = CipherHandle::new(
             &Encryption::ChaCha {
                 cipher: ChaSpec::default(),
             },
+            CipherConfig::default(),
             OsRng,
-        );
+        )
+        .unwrap();
 
         let aes_res = aes.encrypt(TEST_STRING.as_ref()).await.unwrap();
         let cha_res = cha.encrypt(TEST_STRING.as_ref()).await.unwrap();
@@ -298,4 +804,247 @@ mod tests {
         // ciphers are operating on the same data
         assert_eq!(aes_res, cha_res);
     }
+
+    #[async_std::test]
+    async fn cipher_suite_mismatch() {
+        // peers preferring different suites, as `Auto` may resolve on different CPUs
+        let (aes_cfg, cha_cfg) = (
+            Encryption::AES {
+                cipher: AesSpec::default(),
+                nonce: AesNonce::default(),
+            },
+            Encryption::ChaCha {
+                cipher: ChaSpec::default(),
+            },
+        );
+        let aes = CipherHandle::new(
+            &aes_cfg,
+            CipherConfig::default(),
+            AppRng::from_seed(TEST_PHRASE.into()),
+        )
+        .unwrap();
+        let cha = CipherHandle::new(
+            &cha_cfg,
+            CipherConfig::default(),
+            AppRng::from_seed(TEST_PHRASE.into()),
+        )
+        .unwrap();
+        // suites are told apart on the wire
+        assert_ne!(aes_cfg.suite_id(), cha_cfg.suite_id());
+
+        let res = aes.encrypt(TEST_STRING.as_ref()).await.unwrap();
+        // mismatch is reported as such rather than as tampering, and won't go away by itself
+        match cha.decrypt(res.as_ref()).await {
+            Err(HowlerError::CipherError(e @ Error::SuiteMismatch { expected, actual })) => {
+                assert_eq!(expected, cha_cfg.suite_id());
+                assert_eq!(actual, aes_cfg.suite_id());
+                assert_eq!(e.retryable(), Retryable::No);
+            }
+            _ => panic!("expected suite mismatch error"),
+        }
+
+        let mut res = TEST_STRING.as_bytes().to_vec();
+        cha.encrypt_at(&[0u8; 12], &[], &mut res).await.unwrap();
+        // in place too
+        assert!(matches!(
+            aes.decrypt_at(&[0u8; 12], &[], &mut res).await,
+            Err(HowlerError::CipherError(Error::SuiteMismatch { .. }))
+        ));
+    }
+
+    #[async_std::test]
+    async fn cipher_offload() {
+        let cipher = CipherHandle::new(
+            &Encryption::AES {
+                cipher: AesSpec::default(),
+                nonce: AesNonce::default(),
+            },
+            CipherConfig::new(64, Duration::from_millis(GRACE_PERIOD)),
+            OsRng,
+        )
+        .unwrap();
+
+        let big = vec![7u8; 4096];
+        let res = cipher.encrypt(big.as_ref()).await.unwrap();
+        let res = cipher.decrypt(res.as_ref()).await.unwrap();
+        // offloaded payloads make a round trip
+        assert_eq!(res, big);
+
+        let frames = (0..64u8).map(|c| vec![c; 128]).collect::<Vec<_>>();
+        let res = cipher.encrypt_batch(frames.clone()).await.unwrap();
+        let res = cipher.decrypt_batch(res).await.unwrap();
+        // batches keep frame order
+        assert_eq!(res, frames);
+    }
+
+    #[async_std::test]
+    async fn cipher_rekey() {
+        let cfg = Encryption::ChaCha {
+            cipher: ChaSpec::default(),
+        };
+        let a = CipherHandle::new(
+            &cfg,
+            CipherConfig::default(),
+            AppRng::from_seed(TEST_PHRASE.into()),
+        )
+        .unwrap();
+        let b = CipherHandle::new(
+            &cfg,
+            CipherConfig::default(),
+            AppRng::from_seed(TEST_PHRASE.into()),
+        )
+        .unwrap();
+
+        let old = a.encrypt(TEST_STRING.as_ref()).await.unwrap();
+        let mut old_at = TEST_STRING.as_bytes().to_vec();
+        a.encrypt_at(&[0u8; 12], &[], &mut old_at).await.unwrap();
+        let msg = a.rekey_request().await.unwrap();
+        // proposing peer keeps sending under the current epoch until it's accepted
+        assert_eq!(a.key_id(), 0);
+
+        // rekey is delivered as a control message and acknowledged under the new epoch
+        let ack = match b.decrypt_msg(msg.as_ref()).await.unwrap() {
+            Message::Control(Some(ack)) => ack,
+            _ => panic!("expected control message reply"),
+        };
+        assert_eq!(b.key_id(), 1);
+        let new = b.encrypt(TEST_STRING.as_ref()).await.unwrap();
+        // proposing peer already accepts ciphertext of the new epoch
+        assert_eq!(
+            a.decrypt(new.as_ref()).await.unwrap(),
+            TEST_STRING.as_bytes()
+        );
+
+        let res = a.decrypt_msg(ack.as_ref()).await.unwrap();
+        // acknowledgement switches the proposing peer and needs no reply
+        assert_eq!(res, Message::Control(None));
+        assert_eq!(a.key_id(), 1);
+
+        let new = a.encrypt(TEST_STRING.as_ref()).await.unwrap();
+        // both peers switched to the new epoch
+        assert_eq!(
+            b.decrypt(new.as_ref()).await.unwrap(),
+            TEST_STRING.as_bytes()
+        );
+        // packets of the old epoch are accepted during grace period
+        assert_eq!(
+            b.decrypt(old.as_ref()).await.unwrap(),
+            TEST_STRING.as_bytes()
+        );
+        // in place too, as those carry the key id as well
+        b.decrypt_at(&[0u8; 12], &[], &mut old_at).await.unwrap();
+        assert_eq!(old_at, TEST_STRING.as_bytes());
+
+        let msg = a.encrypt(&[MSG_CONTROL, CONTROL_REKEY, 2]).await.unwrap();
+        // malformed control messages are rejected
+        assert!(matches!(
+            b.decrypt_msg(msg.as_ref()).await,
+            Err(HowlerError::CipherError(Error::InvalidControl(_)))
+        ));
+        assert_eq!(b.key_id(), 1);
+
+        let msg = a.encrypt_msg(TEST_STRING.as_ref()).await.unwrap();
+        // user data goes along with control messages
+        assert_eq!(
+            b.decrypt_msg(msg.as_ref()).await.unwrap(),
+            Message::Data(TEST_STRING.as_bytes().to_vec())
+        );
+
+        let c = CipherHandle::new(
+            &cfg,
+            CipherConfig::new(OFFLOAD_THRESHOLD, Duration::ZERO),
+            OsRng,
+        )
+        .unwrap();
+        let old = c.encrypt(TEST_STRING.as_ref()).await.unwrap();
+        c.rekey(1, &[0u8; REKEY_SEED_SIZE]).unwrap();
+        // packets of the old epoch are rejected after grace period
+        assert!(matches!(
+            c.decrypt(old.as_ref()).await,
+            Err(HowlerError::CipherError(Error::UnknownKey { id: 0 }))
+        ));
+    }
+
+    #[async_std::test]
+    async fn cipher_rekey_simultaneous() {
+        let cfg = Encryption::ChaCha {
+            cipher: ChaSpec::default(),
+        };
+        let a = CipherHandle::new(
+            &cfg,
+            CipherConfig::default(),
+            AppRng::from_seed(TEST_PHRASE.into()),
+        )
+        .unwrap();
+        let b = CipherHandle::new(
+            &cfg,
+            CipherConfig::default(),
+            AppRng::from_seed(TEST_PHRASE.into()),
+        )
+        .unwrap();
+
+        // both peers propose an epoch of the same id before getting the other's proposal
+        let (msg_a, msg_b) = (
+            a.rekey_request().await.unwrap(),
+            b.rekey_request().await.unwrap(),
+        );
+        let res_b = b.decrypt_msg(msg_a.as_ref()).await.unwrap();
+        let res_a = a.decrypt_msg(msg_b.as_ref()).await.unwrap();
+
+        // exactly one of the proposals is accepted
+        let (ack, (winner, loser)) = match (res_a, res_b) {
+            (Message::Control(Some(ack)), Message::Control(None)) => (ack, (&b, &a)),
+            (Message::Control(None), Message::Control(Some(ack))) => (ack, (&a, &b)),
+            _ => panic!("expected exactly one control message reply"),
+        };
+        assert_eq!(loser.key_id(), 1);
+        assert_eq!(winner.key_id(), 0);
+        winner.decrypt_msg(ack.as_ref()).await.unwrap();
+        assert_eq!(winner.key_id(), 1);
+
+        // peers end up on the same key
+        let res = a.encrypt(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(
+            b.decrypt(res.as_ref()).await.unwrap(),
+            TEST_STRING.as_bytes()
+        );
+        let res = b.encrypt(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(
+            a.decrypt(res.as_ref()).await.unwrap(),
+            TEST_STRING.as_bytes()
+        );
+    }
+
+    #[async_std::test]
+    async fn cipher_errors() {
+        let cipher = CipherHandle::new(
+            &Encryption::ChaCha {
+                cipher: ChaSpec::default(),
+            },
+            CipherConfig::default(),
+            OsRng,
+        )
+        .unwrap();
+
+        let short = [&cipher.encrypt(&[]).await.unwrap()[..2], &[0u8; 6]].concat();
+        let res = cipher.decrypt(short.as_ref()).await;
+        // short input is reported as truncated and can be skipped
+        match res {
+            Err(HowlerError::CipherError(e)) => {
+                assert!(matches!(e, Error::TruncatedCiphertext { .. }));
+                assert_eq!(e.retryable(), Retryable::Yes);
+            }
+            _ => panic!("expected truncated ciphertext error"),
+        }
+
+        let mut buf = cipher.encrypt(TEST_STRING.as_ref()).await.unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        let res = cipher.decrypt(buf.as_ref()).await;
+        // tampered input fails authentication
+        assert!(matches!(
+            res,
+            Err(HowlerError::CipherError(Error::AuthenticationFailed(_)))
+        ));
+    }
 }
@@ -1,4 +1,5 @@
 use aead::rand_core::{block::BlockRngCore, CryptoRng};
+use hkdf::Hkdf;
 use sha2::{Digest, Sha256};
 
 pub use aead::rand_core::SeedableRng;
@@ -6,6 +7,11 @@ pub use aead::rand_core::SeedableRng;
 const N: usize = 32;
 type SeedArray = [u8; N];
 
+/// Domain separation of keys derived for new epochs.
+const KDF_INFO: &[u8] = b"ensd\0key\0epoch\0";
+/// Size of secrets made by [`derive_secret`][derive_secret].
+pub const SECRET_SIZE: usize = N;
+
 /// `AppRngSeed` for generic usage of [`AppRngCore`][AppRngCore] with `String` and `&str` types.
 pub struct AppRngSeed(pub SeedArray);
 
@@ -29,6 +35,12 @@ impl From<String> for AppRngSeed {
     }
 }
 
+impl From<SeedArray> for AppRngSeed {
+    fn from(value: SeedArray) -> Self {
+        Self(value)
+    }
+}
+
 impl From<&str> for AppRngSeed {
     fn from(value: &str) -> Self {
         let mut hasher = Sha256::new();
@@ -37,6 +49,17 @@ impl From<&str> for AppRngSeed {
     }
 }
 
+/// Derives a secret for the purpose told by `info` from shared `secret` with `HKDF-SHA256`,
+/// so a secret serving several purposes isn't used as is for any of them.
+pub fn derive_secret(secret: &[u8], info: &[u8]) -> [u8; SECRET_SIZE] {
+    let mut okm = [0u8; SECRET_SIZE];
+    // a single block is well within the output limit of `HKDF-SHA256`
+    Hkdf::<Sha256>::new(None, secret)
+        .expand(info, &mut okm)
+        .unwrap();
+    okm
+}
+
 /// `AppRngCore` for generic [`BlockRng`][aead::rand_core::block::BlockRng] instancing.
 ///
 /// Implements [`generate`][AppRngCore::generate] method based on stored [`seed`][AppRngSeed] value.
@@ -71,11 +94,49 @@ impl SeedableRng for AppRngCore {
     }
 }
 
+/// `KdfRngCore` for keys of new epochs made by [`rekey`][crate::CipherHandle::rekey].
+///
+/// Expands fresh `seed` with `HKDF-SHA256` block by block, binding the output to the epoch
+/// `id`, so peers holding the same seed end up with the same key.
+pub struct KdfRngCore {
+    hkdf: Hkdf<Sha256>,
+    id: u8,
+    counter: u64,
+}
+
+impl KdfRngCore {
+    pub fn new(seed: &[u8], id: u8) -> Self {
+        KdfRngCore {
+            hkdf: Hkdf::<Sha256>::new(None, seed),
+            id,
+            counter: 0,
+        }
+    }
+}
+
+impl CryptoRng for KdfRngCore {}
+
+impl BlockRngCore for KdfRngCore {
+    type Item = u32;
+    type Results = [u32; 16];
+
+    fn generate(&mut self, results: &mut Self::Results) {
+        let info = [KDF_INFO, &[self.id], &self.counter.to_be_bytes()].concat();
+        let mut okm = [0u8; 64];
+        // a block is well within the output limit of `HKDF-SHA256`
+        self.hkdf.expand(&info, &mut okm).unwrap();
+        for (r, n) in results.iter_mut().zip(okm.chunks_exact(4)) {
+            *r = u32::from_le_bytes([n[0], n[1], n[2], n[3]]);
+        }
+        self.counter += 1;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::Encryption;
-    use crate::{AesNonce, AesSpec, CipherHandle};
+    use crate::{AesNonce, AesSpec, CipherConfig, CipherHandle};
 
     use aead::{
         rand_core::{block::BlockRng, RngCore},
@@ -97,6 +158,19 @@ mod tests {
         assert_eq!(buf, [0; 16]);
     }
 
+    #[test]
+    fn secrets_derived() {
+        let (a, b) = (
+            derive_secret(TEST_PHRASE.as_bytes(), b"a"),
+            derive_secret(TEST_PHRASE.as_bytes(), b"b"),
+        );
+        // same secret and purpose give the same subkey
+        assert_eq!(a, derive_secret(TEST_PHRASE.as_bytes(), b"a"));
+        // different purposes give unrelated ones
+        assert_ne!(a, b);
+        assert_ne!(a.as_ref(), TEST_PHRASE.as_bytes());
+    }
+
     #[async_std::test]
     async fn seeding_same() {
         let aes_a = CipherHandle::new(
@@ -104,15 +178,19 @@ mod tests {
                 cipher: AesSpec::default(),
                 nonce: AesNonce::default(),
             },
+            CipherConfig::default(),
             BlockRng::<AppRngCore>::from_seed(TEST_PHRASE.into()),
-        );
+        )
+        .unwrap();
         let aes_b = CipherHandle::new(
             &Encryption::AES {
                 cipher: AesSpec::default(),
                 nonce: AesNonce::default(),
             },
+            CipherConfig::default(),
             BlockRng::<AppRngCore>::from_seed(TEST_PHRASE.into()),
-        );
+        )
+        .unwrap();
 
         let txt_a = aes_a.encrypt(TEST_STRING.as_ref()).await.unwrap();
         let txt_b = aes_b.encrypt(TEST_STRING.as_ref()).await.unwrap();
@@ -131,15 +209,19 @@ mod tests {
                 cipher: AesSpec::default(),
                 nonce: AesNonce::default(),
             },
+            CipherConfig::default(),
             OsRng,
-        );
+        )
+        .unwrap();
         let aes_b = CipherHandle::new(
             &Encryption::AES {
                 cipher: AesSpec::default(),
                 nonce: AesNonce::default(),
             },
+            CipherConfig::default(),
             OsRng,
-        );
+        )
+        .unwrap();
 
         let txt_a = aes_a.encrypt(TEST_STRING.as_ref()).await.unwrap();
         let txt_b = aes_b.encrypt(TEST_STRING.as_ref()).await.unwrap();
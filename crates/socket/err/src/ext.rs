@@ -42,6 +42,70 @@ impl From<stun_codec::BrokenMessage> for Error {
     }
 }
 
+impl From<quinn::ConnectError> for Error {
+    fn from(value: quinn::ConnectError) -> Self {
+        Error::InvalidInput(value.to_string())
+    }
+}
+
+impl From<quinn::ConnectionError> for Error {
+    fn from(value: quinn::ConnectionError) -> Self {
+        match value {
+            quinn::ConnectionError::TimedOut => Error::TimedOut(value.to_string()),
+            _ => Error::BrokenPipe(value.to_string()),
+        }
+    }
+}
+
+impl From<quinn::WriteError> for Error {
+    fn from(value: quinn::WriteError) -> Self {
+        Error::BrokenPipe(value.to_string())
+    }
+}
+
+impl From<quinn::ReadExactError> for Error {
+    fn from(value: quinn::ReadExactError) -> Self {
+        match value {
+            quinn::ReadExactError::FinishedEarly(_) => Error::UnexpectedEos(value.to_string()),
+            quinn::ReadExactError::ReadError(_) => Error::BrokenPipe(value.to_string()),
+        }
+    }
+}
+
+impl From<quinn::SendDatagramError> for Error {
+    fn from(value: quinn::SendDatagramError) -> Self {
+        match value {
+            quinn::SendDatagramError::TooLarge => Error::InvalidInput(value.to_string()),
+            quinn::SendDatagramError::ConnectionLost(_) => Error::BrokenPipe(value.to_string()),
+            _ => Error::Other(value.to_string()),
+        }
+    }
+}
+
+impl From<quinn::crypto::rustls::NoInitialCipherSuite> for Error {
+    fn from(value: quinn::crypto::rustls::NoInitialCipherSuite) -> Self {
+        Error::Other(value.to_string())
+    }
+}
+
+impl From<quinn::rustls::Error> for Error {
+    fn from(value: quinn::rustls::Error) -> Self {
+        Error::Other(value.to_string())
+    }
+}
+
+impl From<rcgen::Error> for Error {
+    fn from(value: rcgen::Error) -> Self {
+        Error::Other(value.to_string())
+    }
+}
+
+impl From<str0m::RtcError> for Error {
+    fn from(value: str0m::RtcError) -> Self {
+        Error::Other(value.to_string())
+    }
+}
+
 pub const ERR_CONNECTION: Error<&str> =
     Error::TimedOut("can't reach remote host in required number of attempts");
 pub const ERR_VALIDATION: Error<&str> =
@@ -50,3 +114,30 @@ pub const ERR_PIPE_BROKE: Error<&str> =
     Error::BrokenPipe("incorrect message exchange procedure ordering");
 pub const ERR_STUN_QUERY: Error<&str> =
     Error::Other("can't decode any valid address from STUN message");
+pub const ERR_STUN_UNREACHABLE: Error<&str> =
+    Error::Unreachable("none of STUN servers is reachable");
+pub const ERR_STUN_CONSENSUS: Error<&str> =
+    Error::InconsistentState("STUN servers disagree on the public address");
+pub const ERR_FRAME_SIZE: Error<&str> =
+    Error::InvalidInput("message is too big to fit into frame fragments");
+pub const ERR_DELIVERY: Error<&str> =
+    Error::TimedOut("reliable message wasn't acknowledged in required number of attempts");
+pub const ERR_NOT_BOUND: Error<&str> =
+    Error::BrokenPipe("transport isn't bound to a remote host yet");
+pub const ERR_DATAGRAM: Error<&str> =
+    Error::Other("remote host doesn't support unreliable datagrams");
+pub const ERR_CLOSED: Error<&str> = Error::BrokenPipe("remote host closed the connection");
+pub const ERR_ADDRESS: Error<&str> = Error::InvalidInput("no address to bind socket to");
+pub const ERR_RELAY: Error<&str> = Error::Other("TURN server refused the request");
+pub const ERR_CANDIDATES: Error<&str> =
+    Error::InvalidInput("can't parse ICE candidates of remote host");
+pub const ERR_UNSUPPORTED: Error<&str> =
+    Error::InvalidInput("operation isn't supported by the transport");
+pub const ERR_SIGNAL_AUTH: Error<&str> =
+    Error::BrokenMessage("signaling message of remote peer failed authentication");
+pub const ERR_SIGNAL_TIMEOUT: Error<&str> =
+    Error::TimedOut("no peer joined the signaling room in time");
+pub const ERR_SECRET: Error<&str> =
+    Error::InvalidInput("no secret shared with remote host to authenticate hole punching");
+pub const ERR_PEER_LOST: Error<&str> =
+    Error::BrokenPipe("remote host stopped answering keepalives");
@@ -13,6 +13,7 @@ pub enum Error<U: ToString = String> {
     DecoderTerminated(U),
     IncompleteDecoding(U),
     BrokenMessage(U),
+    Unreachable(U),
 }
 
 pub type Result<T, U = String> = core::result::Result<T, Error<U>>;
@@ -30,6 +31,7 @@ impl<U: ToString> Display for Error<U> {
             Error::DecoderTerminated(error) => error.to_string(),
             Error::IncompleteDecoding(error) => error.to_string(),
             Error::BrokenMessage(error) => error.to_string(),
+            Error::Unreachable(error) => error.to_string(),
         };
         write!(f, "{}", error)
     }
@@ -56,10 +58,16 @@ impl From<Error<&str>> for Error {
             Error::DecoderTerminated(error) => Error::DecoderTerminated(error.to_string()),
             Error::IncompleteDecoding(error) => Error::IncompleteDecoding(error.to_string()),
             Error::BrokenMessage(error) => Error::BrokenMessage(error.to_string()),
+            Error::Unreachable(error) => Error::Unreachable(error.to_string()),
         }
     }
 }
 
 pub mod consts {
-    pub use crate::ext::{ERR_CONNECTION, ERR_PIPE_BROKE, ERR_STUN_QUERY, ERR_VALIDATION};
+    pub use crate::ext::{
+        ERR_ADDRESS, ERR_CANDIDATES, ERR_CLOSED, ERR_CONNECTION, ERR_DATAGRAM, ERR_DELIVERY,
+        ERR_FRAME_SIZE, ERR_NOT_BOUND, ERR_PEER_LOST, ERR_PIPE_BROKE, ERR_RELAY, ERR_SECRET,
+        ERR_SIGNAL_AUTH, ERR_SIGNAL_TIMEOUT, ERR_STUN_CONSENSUS, ERR_STUN_QUERY,
+        ERR_STUN_UNREACHABLE, ERR_UNSUPPORTED, ERR_VALIDATION,
+    };
 }
@@ -0,0 +1,445 @@
+use async_std::{
+    channel::{self, Receiver, Sender, TrySendError},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use err::{consts::ERR_FRAME_SIZE, Result};
+use futures::future::{self, Either};
+use log::{trace, warn};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// Leading byte of every frame. STUN messages always start with two zero bits, so frames
+/// and STUN traffic sharing one socket can't be confused.
+pub const FRAME_MAGIC: u8 = 0xE5;
+pub const FRAME_VERSION: u8 = 2;
+pub const HEADER_SIZE: usize = 13;
+
+/// Upper bound of messages being reassembled at once, oldest ones are dropped first.
+const MAX_PARTIAL: usize = 64;
+/// Number of completed message ids remembered to drop duplicated fragments.
+const MAX_DONE: usize = 256;
+/// Number of reassembled messages queued per stream before new ones are dropped.
+const INBOX_SIZE: usize = 1024;
+
+pub(crate) type Msg = (Vec<u8>, SocketAddr);
+/// Per-stream queues of messages for transports receiving them in background tasks.
+pub(crate) type Inboxes = Arc<std::sync::Mutex<HashMap<u8, (Sender<Vec<u8>>, Receiver<Vec<u8>>)>>>;
+
+/// `Header` of a single datagram carrying one fragment of a message.
+///
+/// Layout is `magic | version | stream: u8 | id: u32 | index: u16 | count: u16 | len: u16`,
+/// all fields are big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub stream: u8,
+    pub id: u32,
+    pub index: u16,
+    pub count: u16,
+    pub len: u16,
+}
+
+impl Header {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend([FRAME_MAGIC, FRAME_VERSION, self.stream]);
+        buf.extend(self.id.to_be_bytes());
+        buf.extend(self.index.to_be_bytes());
+        buf.extend(self.count.to_be_bytes());
+        buf.extend(self.len.to_be_bytes());
+    }
+
+    /// Decodes a header, returning it with the fragment payload.
+    pub fn decode(buf: &[u8]) -> Option<(Header, &[u8])> {
+        let (head, body) = buf.split_first_chunk::<HEADER_SIZE>()?;
+        if head[0] != FRAME_MAGIC || head[1] != FRAME_VERSION {
+            return None;
+        }
+        let header = Header {
+            stream: head[2],
+            id: u32::from_be_bytes([head[3], head[4], head[5], head[6]]),
+            index: u16::from_be_bytes([head[7], head[8]]),
+            count: u16::from_be_bytes([head[9], head[10]]),
+            len: u16::from_be_bytes([head[11], head[12]]),
+        };
+        let valid =
+            header.count > 0 && header.index < header.count && header.len as usize == body.len();
+        valid.then_some((header, body))
+    }
+}
+
+/// Splits `payload` into datagrams of at most `size` bytes, each prefixed with a
+/// [`Header`][Header] of message `id` on logical `stream`.
+///
+/// Datagrams of `size` leaving no room for payload past the header are refused.
+pub(crate) fn fragment(stream: u8, id: u32, payload: &[u8], size: usize) -> Result<Vec<Vec<u8>>> {
+    let chunk = size
+        .checked_sub(HEADER_SIZE)
+        .filter(|c| *c > 0)
+        .ok_or(ERR_FRAME_SIZE)?;
+    let count = payload.len().div_ceil(chunk).max(1);
+    if count > u16::MAX as usize {
+        return Err(ERR_FRAME_SIZE.into());
+    }
+
+    let mut chunks = payload.chunks(chunk).collect::<Vec<_>>();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, c)| {
+            let mut buf = Vec::with_capacity(HEADER_SIZE + c.len());
+            let header = Header {
+                stream,
+                id,
+                index: index as u16,
+                count: count as u16,
+                len: c.len() as u16,
+            };
+            header.encode(&mut buf);
+            buf.extend_from_slice(c);
+            buf
+        })
+        .collect())
+}
+
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    left: usize,
+    started: Instant,
+}
+
+/// `Reassembler` collects fragments made by [`fragment`][fragment] into messages.
+///
+/// Fragments may arrive in any order; duplicates are ignored, and messages still missing
+/// fragments after `timeout` since their first fragment are dropped.
+pub(crate) struct Reassembler {
+    timeout: Duration,
+    partial: HashMap<(SocketAddr, u32), Partial>,
+    done: VecDeque<(SocketAddr, u32)>,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Reassembler {
+        Reassembler {
+            timeout,
+            partial: HashMap::new(),
+            done: VecDeque::with_capacity(MAX_DONE),
+        }
+    }
+
+    /// Feeds a datagram received from `addr`, returning a message with its stream id once
+    /// it is complete.
+    pub fn push(&mut self, datagram: &[u8], addr: SocketAddr) -> Option<(u8, Vec<u8>)> {
+        self.push_at(datagram, addr, Instant::now())
+    }
+
+    fn push_at(
+        &mut self,
+        datagram: &[u8],
+        addr: SocketAddr,
+        now: Instant,
+    ) -> Option<(u8, Vec<u8>)> {
+        self.expire(now);
+
+        let Some((header, body)) = Header::decode(datagram) else {
+            warn!("dropped malformed datagram of {} bytes", datagram.len());
+            return None;
+        };
+        let key = (addr, header.id);
+        if self.done.contains(&key) {
+            trace!("dropped duplicate fragment of message {}", header.id);
+            return None;
+        }
+        if header.count == 1 {
+            self.finish(key);
+            return Some((header.stream, body.to_vec()));
+        }
+
+        if !self.partial.contains_key(&key) && self.partial.len() >= MAX_PARTIAL {
+            self.evict();
+        }
+        let partial = self.partial.entry(key).or_insert_with(|| Partial {
+            fragments: vec![None; header.count as usize],
+            left: header.count as usize,
+            started: now,
+        });
+        if partial.fragments.len() != header.count as usize {
+            warn!(
+                "dropped fragment with inconsistent count of message {}",
+                header.id
+            );
+            return None;
+        }
+        let slot = &mut partial.fragments[header.index as usize];
+        if slot.is_none() {
+            *slot = Some(body.to_vec());
+            partial.left -= 1;
+        }
+        if partial.left > 0 {
+            return None;
+        }
+
+        let partial = self.partial.remove(&key)?;
+        self.finish(key);
+        let res = partial.fragments.into_iter().flatten().flatten().collect();
+        Some((header.stream, res))
+    }
+
+    fn finish(&mut self, key: (SocketAddr, u32)) {
+        if self.done.len() == MAX_DONE {
+            self.done.pop_front();
+        }
+        self.done.push_back(key);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.partial.retain(|(_, id), c| {
+            let alive = now.duration_since(c.started) < timeout;
+            if !alive {
+                warn!(
+                    "dropped incomplete message {id}: {} fragments missing",
+                    c.left
+                );
+            }
+            alive
+        });
+    }
+
+    fn evict(&mut self) {
+        let oldest = self
+            .partial
+            .iter()
+            .min_by_key(|(_, c)| c.started)
+            .map(|(k, _)| *k);
+        if let Some(key) = oldest {
+            warn!(
+                "dropped incomplete message {}: reassembly buffer is full",
+                key.1
+            );
+            self.partial.remove(&key);
+        }
+    }
+}
+
+struct Inbox {
+    tx: Sender<Msg>,
+    rx: Receiver<Msg>,
+    peeked: VecDeque<Msg>,
+}
+
+/// `Demux` splits messages of a single transport between logical streams.
+///
+/// There is no background receiver task: a caller waiting for its stream either gets a
+/// message queued for it, or takes the receive lock and reads the transport itself, handing
+/// messages of other streams over to their queues.
+pub(crate) struct Demux {
+    assembly: Mutex<Reassembler>,
+    inboxes: std::sync::Mutex<HashMap<u8, Inbox>>,
+}
+
+impl Demux {
+    pub fn new(timeout: Duration) -> Demux {
+        Demux {
+            assembly: Mutex::new(Reassembler::new(timeout)),
+            inboxes: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns next message of `stream`, reading datagrams with `recv` when needed.
+    ///
+    /// Peeked message stays in place and is returned again by the next call.
+    pub async fn recv<F, R>(&self, stream: u8, peek: bool, recv: F) -> Result<Msg>
+    where
+        F: Fn() -> R,
+        R: Future<Output = Result<Msg>>,
+    {
+        loop {
+            let rx = {
+                let mut inboxes = self.inboxes.lock().unwrap();
+                let inbox = inboxes.entry(stream).or_insert_with(Inbox::new);
+                if let Some(res) = inbox.take(peek) {
+                    return Ok(res);
+                }
+                inbox.rx.clone()
+            };
+
+            let guard =
+                match future::select(Box::pin(rx.recv()), Box::pin(self.assembly.lock())).await {
+                    Either::Left((Ok(res), _)) => return Ok(self.stash(stream, res, peek)),
+                    Either::Left((Err(_), _)) => continue,
+                    Either::Right((guard, _)) => guard,
+                };
+            // queue could be filled while waiting for the lock
+            if let Ok(res) = rx.try_recv() {
+                return Ok(self.stash(stream, res, peek));
+            }
+
+            let mut assembly = guard;
+            let (datagram, addr) = recv().await?;
+            if let Some((id, res)) = assembly.push(&datagram, addr) {
+                if id == stream {
+                    return Ok(self.stash(stream, (res, addr), peek));
+                }
+                self.dispatch(id, (res, addr));
+            }
+        }
+    }
+
+    fn stash(&self, stream: u8, msg: Msg, peek: bool) -> Msg {
+        if peek {
+            let mut inboxes = self.inboxes.lock().unwrap();
+            let inbox = inboxes.entry(stream).or_insert_with(Inbox::new);
+            inbox.peeked.push_back(msg.clone());
+        }
+        msg
+    }
+
+    fn dispatch(&self, stream: u8, msg: Msg) {
+        let mut inboxes = self.inboxes.lock().unwrap();
+        let inbox = inboxes.entry(stream).or_insert_with(Inbox::new);
+        if let Err(TrySendError::Full(_)) = inbox.tx.try_send(msg) {
+            warn!("dropped message for stream {stream}: queue is full");
+        }
+    }
+}
+
+impl Inbox {
+    fn new() -> Inbox {
+        let (tx, rx) = channel::bounded(INBOX_SIZE);
+        Inbox {
+            tx,
+            rx,
+            peeked: VecDeque::new(),
+        }
+    }
+
+    fn take(&mut self, peek: bool) -> Option<Msg> {
+        if peek {
+            self.peeked.front().cloned()
+        } else {
+            self.peeked.pop_front()
+        }
+    }
+}
+
+/// Returns the queue of `stream`, creating it when missing.
+pub(crate) fn inbox(inboxes: &Inboxes, stream: u8) -> (Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+    inboxes
+        .lock()
+        .unwrap()
+        .entry(stream)
+        .or_insert_with(|| channel::bounded(INBOX_SIZE))
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::net::{IpAddr, Ipv4Addr};
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    const TEST_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 34254);
+    const TEST_TIMEOUT: Duration = Duration::from_millis(25);
+    const TEST_SIZE: usize = 64;
+
+    fn messages(rng: &mut StdRng, n: u32) -> Vec<(u32, Vec<u8>)> {
+        (0..n)
+            .map(|id| {
+                let len = rng.gen_range(0..TEST_SIZE * 8);
+                (id, (0..len).map(|_| rng.gen()).collect())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frame_works() {
+        // payload with the old delimiter at a chunk boundary is kept intact
+        let payload = [
+            vec![0u8; TEST_SIZE - HEADER_SIZE - 4],
+            b"end\0msg\0".repeat(8),
+        ]
+        .concat();
+        let mut rx = Reassembler::new(TEST_TIMEOUT);
+        let res = fragment(0, 0, &payload, TEST_SIZE)
+            .unwrap()
+            .iter()
+            .find_map(|c| rx.push(c, TEST_ADDR));
+        assert_eq!(res, Some((0, payload)));
+
+        let res = fragment(1, 1, &[], TEST_SIZE).unwrap();
+        // empty message still takes one datagram
+        assert_eq!(res.len(), 1);
+        assert_eq!(rx.push(&res[0], TEST_ADDR), Some((1, vec![])));
+        // duplicates of delivered messages are dropped
+        assert_eq!(rx.push(&res[0], TEST_ADDR), None);
+        // foreign and malformed datagrams are dropped
+        assert_eq!(rx.push(&[0x01, 0x01, 0x00, 0x00], TEST_ADDR), None);
+        assert_eq!(rx.push(&res[0][..HEADER_SIZE - 1], TEST_ADDR), None);
+
+        // datagrams with no room for payload are refused rather than overflowing
+        assert!(fragment(0, 2, &[1], HEADER_SIZE).is_err());
+        assert!(fragment(0, 2, &[1], 0).is_err());
+    }
+
+    #[test]
+    fn frame_reorder() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..32 {
+            let msgs = messages(&mut rng, 16);
+            let mut datagrams = msgs
+                .iter()
+                .flat_map(|(id, c)| fragment(0, *id, c, TEST_SIZE).unwrap())
+                .collect::<Vec<_>>();
+            datagrams.shuffle(&mut rng);
+            // every datagram is delivered twice
+            datagrams.extend(datagrams.clone());
+
+            let mut rx = Reassembler::new(Duration::from_secs(60));
+            let mut res = datagrams
+                .iter()
+                .filter_map(|c| rx.push(c, TEST_ADDR).map(|(_, c)| c))
+                .collect::<Vec<_>>();
+            let mut msgs = msgs.into_iter().map(|(_, c)| c).collect::<Vec<_>>();
+            res.sort();
+            msgs.sort();
+            // reordered and duplicated fragments make up the same messages
+            assert_eq!(res, msgs);
+        }
+    }
+
+    #[test]
+    fn frame_loss() {
+        let mut rng = StdRng::seed_from_u64(0x1055);
+        for _ in 0..32 {
+            let msgs = messages(&mut rng, 16);
+            let mut rx = Reassembler::new(TEST_TIMEOUT);
+            let start = Instant::now();
+
+            for (id, msg) in msgs.iter() {
+                let datagrams = fragment(0, *id, msg, TEST_SIZE).unwrap();
+                let lost = rng.gen_bool(0.3).then(|| rng.gen_range(0..datagrams.len()));
+                let res = datagrams
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| Some(*i) != lost)
+                    .find_map(|(_, c)| rx.push_at(c, TEST_ADDR, start).map(|(_, c)| c));
+                // messages with a lost fragment are never delivered
+                match lost {
+                    Some(_) => assert_eq!(res, None),
+                    None => assert_eq!(res.as_ref(), Some(msg)),
+                }
+            }
+
+            let late = fragment(0, u32::MAX, &[1; TEST_SIZE * 2], TEST_SIZE).unwrap();
+            rx.push_at(&late[0], TEST_ADDR, start + TEST_TIMEOUT * 2);
+            // incomplete messages are dropped after timeout
+            assert_eq!(rx.partial.len(), 1);
+            assert!(rx.partial.contains_key(&(TEST_ADDR, u32::MAX)));
+        }
+    }
+}
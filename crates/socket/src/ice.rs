@@ -0,0 +1,725 @@
+use async_std::{net::SocketAddr, sync::Arc};
+use bytecodec::{DecodeExt, EncodeExt};
+use err::{
+    consts::{ERR_CANDIDATES, ERR_CONNECTION, ERR_VALIDATION},
+    Error, Result,
+};
+use log::{info, trace};
+use rand::{distributions::Alphanumeric, Rng};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use stun_codec::{
+    define_attribute_enums,
+    rfc5245::attributes::{IceControlled, IceControlling, Priority, UseCandidate},
+    rfc5389::{
+        attributes::{ErrorCode, Fingerprint, MessageIntegrity, Username, XorMappedAddress},
+        methods::BINDING,
+    },
+    Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId,
+};
+
+use crate::udp::unspecified;
+use crate::{CertHash, IOSocket, StunHandler};
+
+/// Base of candidates gathered on the socket itself, either host or server reflexive.
+pub(crate) const HOST_BASE: usize = 0;
+/// Base of the candidate allocated on the `TURN` server.
+pub(crate) const RELAYED_BASE: usize = 1;
+/// Prefix of the single-line [`Candidates`] description.
+const CANDIDATES_TAG: &str = "ice";
+/// Prefix of the certificate hash in [`Candidates`] description.
+const CERT_TAG: &str = "cert/";
+/// Local preference of IPv6 candidates, IPv4 ones take one less as RFC 8421 prefers IPv6.
+/// Only one network interface is gathered per address family.
+const LOCAL_PREFERENCE: u32 = 65535;
+/// Only one component is negotiated, as `RTCP` has no counterpart here.
+const COMPONENT_ID: u32 = 1;
+const UFRAG_LEN: usize = 8;
+const PWD_LEN: usize = 24;
+const MAGIC_COOKIE: [u8; 4] = 0x2112A442u32.to_be_bytes();
+const STUN_HEADER_SIZE: usize = 20;
+
+define_attribute_enums!(
+    Attribute,
+    AttributeDecoder,
+    AttributeEncoder,
+    [
+        Username,
+        MessageIntegrity,
+        Fingerprint,
+        XorMappedAddress,
+        ErrorCode,
+        Priority,
+        UseCandidate,
+        IceControlled,
+        IceControlling
+    ]
+);
+
+/// `CandidateKind` of RFC 8445, telling how a [`Candidate`] address was learned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidateKind {
+    /// Address of the socket itself.
+    Host,
+    /// Public address the socket is mapped to, as seen by a `STUN` server.
+    ServerReflexive,
+    /// Address learned from connectivity checks of the remote peer.
+    PeerReflexive,
+    /// Address allocated on a `TURN` server.
+    Relayed,
+}
+
+impl CandidateKind {
+    fn preference(self) -> u32 {
+        match self {
+            CandidateKind::Host => 126,
+            CandidateKind::PeerReflexive => 110,
+            CandidateKind::ServerReflexive => 100,
+            CandidateKind::Relayed => 0,
+        }
+    }
+
+    /// Candidate priority of RFC 8445, section 5.1.2.1, for an address of `addr` family.
+    fn priority(self, addr: SocketAddr) -> u32 {
+        let local = LOCAL_PREFERENCE - addr.is_ipv4() as u32;
+        (self.preference() << 24) | (local << 8) | (256 - COMPONENT_ID)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            CandidateKind::Host => "host",
+            CandidateKind::ServerReflexive => "srflx",
+            CandidateKind::PeerReflexive => "prflx",
+            CandidateKind::Relayed => "relay",
+        }
+    }
+}
+
+impl FromStr for CandidateKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [
+            CandidateKind::Host,
+            CandidateKind::ServerReflexive,
+            CandidateKind::PeerReflexive,
+            CandidateKind::Relayed,
+        ]
+        .into_iter()
+        .find(|c| c.name() == s)
+        .ok_or_else(|| ERR_CANDIDATES.into())
+    }
+}
+
+/// `Candidate` address the socket may be reached at, written as `kind/addr/priority`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub addr: SocketAddr,
+    pub priority: u32,
+}
+
+impl Candidate {
+    pub fn new(kind: CandidateKind, addr: SocketAddr) -> Self {
+        Candidate {
+            kind,
+            addr,
+            priority: kind.priority(addr),
+        }
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.kind.name(), self.addr, self.priority)
+    }
+}
+
+impl FromStr for Candidate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut iter = s.split('/');
+        let (Some(kind), Some(addr), Some(priority), None) =
+            (iter.next(), iter.next(), iter.next(), iter.next())
+        else {
+            return Err(ERR_CANDIDATES.into());
+        };
+        Ok(Candidate {
+            kind: kind.parse()?,
+            addr: addr.parse().map_err(|_| ERR_CANDIDATES)?,
+            priority: priority.parse().map_err(|_| ERR_CANDIDATES)?,
+        })
+    }
+}
+
+/// `Candidates` of a peer along with its `ICE` credentials, exchanged out of band as
+/// a single line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidates {
+    pub ufrag: String,
+    pub pwd: String,
+    /// Tie-breaker deciding roles, the peer with a greater one is controlling.
+    pub tie: u64,
+    /// Hash of the transport certificate for the other peer to pin, as no punching hands
+    /// it over when candidates are checked.
+    pub cert: Option<CertHash>,
+    pub list: Vec<Candidate>,
+}
+
+impl Candidates {
+    fn new(list: Vec<Candidate>) -> Self {
+        let random = |len| {
+            rand::thread_rng()
+                .sample_iter(Alphanumeric)
+                .take(len)
+                .map(char::from)
+                .collect()
+        };
+        Candidates {
+            ufrag: random(UFRAG_LEN),
+            pwd: random(PWD_LEN),
+            tie: rand::random(),
+            cert: None,
+            list,
+        }
+    }
+}
+
+impl fmt::Display for Candidates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{CANDIDATES_TAG} {} {} {}",
+            self.ufrag, self.pwd, self.tie
+        )?;
+        if let Some(cert) = self.cert {
+            write!(f, " {CERT_TAG}")?;
+            for c in cert {
+                write!(f, "{c:02x}")?;
+            }
+        }
+        for c in &self.list {
+            write!(f, " {c}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Candidates {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut iter = s.split_whitespace();
+        if iter.next() != Some(CANDIDATES_TAG) {
+            return Err(ERR_CANDIDATES.into());
+        }
+        let (Some(ufrag), Some(pwd), Some(tie)) = (iter.next(), iter.next(), iter.next()) else {
+            return Err(ERR_CANDIDATES.into());
+        };
+        let mut iter = iter.peekable();
+        let cert = match iter.next_if(|c| c.starts_with(CERT_TAG)) {
+            Some(cert) => Some(parse_cert(&cert[CERT_TAG.len()..]).ok_or(ERR_CANDIDATES)?),
+            None => None,
+        };
+        let list = iter.map(str::parse).collect::<Result<Vec<Candidate>>>()?;
+        if list.is_empty() {
+            return Err(ERR_CANDIDATES.into());
+        }
+        Ok(Candidates {
+            ufrag: ufrag.to_owned(),
+            pwd: pwd.to_owned(),
+            tie: tie.parse().map_err(|_| ERR_CANDIDATES)?,
+            cert,
+            list,
+        })
+    }
+}
+
+/// Certificate hash written as hex digits in [`Candidates`] description.
+fn parse_cert(s: &str) -> Option<CertHash> {
+    let mut cert = CertHash::default();
+    if s.len() != cert.len() * 2 || !s.is_ascii() {
+        return None;
+    }
+    for (c, hex) in cert.iter_mut().zip(s.as_bytes().chunks(2)) {
+        *c = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+    }
+    Some(cert)
+}
+
+/// `Pair` of a local base and a remote candidate checked for connectivity.
+struct Pair {
+    base: usize,
+    local: u32,
+    remote: SocketAddr,
+    priority: u32,
+    /// A check of the pair got an authenticated response.
+    valid: bool,
+    /// Checks of the pair carry `USE-CANDIDATE`.
+    nominating: bool,
+    /// The remote peer sent `USE-CANDIDATE` over the pair.
+    nominated: bool,
+    /// A nominating check of the pair got a response.
+    selected: bool,
+}
+
+impl Pair {
+    /// Pair priority of RFC 8445, section 6.1.2.3.
+    fn priority(&self, controlling: bool) -> u64 {
+        let (g, d) = match controlling {
+            true => (self.local as u64, self.priority as u64),
+            false => (self.priority as u64, self.local as u64),
+        };
+        (g.min(d) << 32) + 2 * g.max(d) + (g > d) as u64
+    }
+}
+
+/// `Check` received before local checks started, replayed once they do.
+struct Check {
+    base: usize,
+    addr: SocketAddr,
+    priority: u32,
+    nominate: bool,
+}
+
+#[derive(Default)]
+struct State {
+    /// Username fragment and password of the remote peer, known once checks start.
+    remote: Option<(String, String)>,
+    controlling: bool,
+    /// Candidates of local bases, one per address family, none for unused bases.
+    bases: Vec<Vec<Candidate>>,
+    pairs: Vec<Pair>,
+    pending: HashMap<TransactionId, (usize, bool)>,
+    triggered: VecDeque<usize>,
+    early: Vec<Check>,
+    next: usize,
+    tick: u32,
+    first_valid: Option<u32>,
+}
+
+impl State {
+    fn start(&mut self, local: &Candidates, remote: &Candidates, bases: usize) {
+        self.remote = Some((remote.ufrag.clone(), remote.pwd.clone()));
+        self.controlling = local.tie > remote.tie;
+        self.bases = (0..bases).map(|c| base_candidates(local, c)).collect();
+
+        for base in 0..self.bases.len() {
+            for c in &remote.list {
+                self.add_pair(base, c.addr, c.priority);
+            }
+        }
+        let controlling = self.controlling;
+        self.pairs
+            .sort_by_key(|c| std::cmp::Reverse(c.priority(controlling)));
+
+        for c in std::mem::take(&mut self.early) {
+            self.checked(c.base, c.addr, c.priority, c.nominate);
+        }
+    }
+
+    /// Pairs `remote` with the local candidate of its address family on `base`, if any.
+    fn add_pair(&mut self, base: usize, remote: SocketAddr, priority: u32) -> Option<usize> {
+        if let Some(idx) = self
+            .pairs
+            .iter()
+            .position(|c| c.base == base && c.remote == remote)
+        {
+            return Some(idx);
+        }
+        self.pairs.push(Pair {
+            base,
+            local: self
+                .bases
+                .get(base)?
+                .iter()
+                .find(|c| c.addr.is_ipv4() == remote.is_ipv4())?
+                .priority,
+            remote,
+            priority,
+            valid: false,
+            nominating: false,
+            nominated: false,
+            selected: false,
+        });
+        Some(self.pairs.len() - 1)
+    }
+
+    /// Records a check of the remote peer, scheduling a triggered check of its pair.
+    fn checked(&mut self, base: usize, addr: SocketAddr, priority: u32, nominate: bool) {
+        if self.remote.is_none() {
+            self.early.push(Check {
+                base,
+                addr,
+                priority,
+                nominate,
+            });
+            return;
+        }
+        let Some(idx) = self.add_pair(base, addr, priority) else {
+            return;
+        };
+        let pair = &mut self.pairs[idx];
+        pair.nominated |= nominate;
+        if !pair.valid && !self.triggered.contains(&idx) {
+            self.triggered.push_back(idx);
+        }
+    }
+
+    fn succeeded(&mut self, msg: &Message<Attribute>, source: SocketAddr) {
+        let Some((idx, nominating)) = self.pending.remove(&msg.transaction_id()) else {
+            return;
+        };
+        let Some((_, pwd)) = &self.remote else {
+            return;
+        };
+        let authentic = msg
+            .get_attribute::<MessageIntegrity>()
+            .is_some_and(|c| c.check_short_term_credential(pwd).is_ok());
+        let pair = &mut self.pairs[idx];
+        if !authentic || pair.remote != source {
+            trace!("dropped unauthentic check response from {:?}", source);
+            return;
+        }
+        pair.valid = true;
+        pair.selected |= nominating;
+        self.first_valid.get_or_insert(self.tick);
+    }
+
+    fn best_valid(&self) -> Option<usize> {
+        (0..self.pairs.len())
+            .filter(|c| self.pairs[*c].valid)
+            .max_by_key(|c| self.pairs[*c].priority(self.controlling))
+    }
+
+    /// Picks the pair to check next: a nominated one, then triggered ones, then ones not
+    /// yet valid in turn.
+    fn next_pair(&mut self) -> Option<usize> {
+        if self.controlling && !self.pairs.iter().any(|c| c.nominating) {
+            if let Some(best) = self.best_valid() {
+                // pairs of greater priority had a chance to succeed
+                let first = self.first_valid.unwrap_or(self.tick);
+                let waited = self.tick - first >= self.pairs.len() as u32;
+                if best == 0 || waited {
+                    self.pairs[best].nominating = true;
+                }
+            }
+        }
+        if let Some(idx) = self.pairs.iter().position(|c| c.nominating) {
+            return Some(idx);
+        }
+        if let Some(idx) = self.triggered.pop_front() {
+            return Some(idx);
+        }
+        let len = self.pairs.len();
+        let idx = (0..len)
+            .map(|c| (self.next + c) % len)
+            .find(|c| !self.pairs[*c].valid)?;
+        self.next = idx + 1;
+        Some(idx)
+    }
+
+    fn selected(&self) -> Option<&Pair> {
+        match self.controlling {
+            true => self.pairs.iter().find(|c| c.selected),
+            false => self
+                .pairs
+                .iter()
+                .filter(|c| c.valid && c.nominated)
+                .max_by_key(|c| c.priority(false)),
+        }
+    }
+}
+
+/// `Agent` running `ICE` connectivity checks of RFC 8445 over local candidates, while
+/// answering checks of the remote peer.
+///
+/// Checks are `STUN` binding requests authenticated with short-term credentials of
+/// [`Candidates`], so sockets hand incoming `STUN` messages over to
+/// [`input`][Agent::input] and send back what it returns.
+pub struct Agent {
+    local: Candidates,
+    state: Mutex<State>,
+}
+
+impl Agent {
+    pub(crate) fn new(list: Vec<Candidate>) -> Self {
+        Agent {
+            local: Candidates::new(list),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Hands `cert` hash of the transport over to the remote peer along with candidates.
+    pub(crate) fn with_cert(mut self, cert: Option<CertHash>) -> Self {
+        self.local.cert = cert;
+        self
+    }
+
+    pub(crate) fn local(&self) -> &Candidates {
+        &self.local
+    }
+
+    fn answer(&self, base: usize, msg: &Message<Attribute>, source: SocketAddr) -> Option<Vec<u8>> {
+        let username = msg.get_attribute::<Username>()?.name();
+        let authentic = username.split(':').next() == Some(self.local.ufrag.as_str())
+            && msg
+                .get_attribute::<MessageIntegrity>()?
+                .check_short_term_credential(&self.local.pwd)
+                .is_ok();
+        if !authentic {
+            trace!("dropped unauthentic check from {:?}", source);
+            return None;
+        }
+        let priority = msg.get_attribute::<Priority>()?.prio();
+        let nominate = msg.get_attribute::<UseCandidate>().is_some();
+        self.state
+            .lock()
+            .unwrap()
+            .checked(base, source, priority, nominate);
+
+        let mut res = Message::new(MessageClass::SuccessResponse, BINDING, msg.transaction_id());
+        res.add_attribute(XorMappedAddress::new(source).into());
+        seal(&mut res, &self.local.pwd).ok()?;
+        MessageEncoder::new().encode_into_bytes(res).ok()
+    }
+
+    /// Builds the next check, returning its base, destination and bytes.
+    fn next_check(&self, tick: u32) -> Result<Option<(usize, SocketAddr, Vec<u8>)>> {
+        let mut state = self.state.lock().unwrap();
+        state.tick = tick;
+        let Some(idx) = state.next_pair() else {
+            return Ok(None);
+        };
+        let Some((ufrag, pwd)) = state.remote.clone() else {
+            return Ok(None);
+        };
+        let pair = &state.pairs[idx];
+        let (base, remote, nominating) = (pair.base, pair.remote, pair.nominating);
+        let priority = match base {
+            RELAYED_BASE => pair.local,
+            _ => CandidateKind::PeerReflexive.priority(remote),
+        };
+
+        let id = TransactionId::new(rand::random());
+        let mut msg = Message::new(MessageClass::Request, BINDING, id);
+        msg.add_attribute(Username::new(format!("{ufrag}:{}", self.local.ufrag))?.into());
+        msg.add_attribute(Priority::new(priority).into());
+        match state.controlling {
+            true => msg.add_attribute(IceControlling::new(self.local.tie).into()),
+            false => msg.add_attribute(IceControlled::new(self.local.tie).into()),
+        }
+        if nominating {
+            msg.add_attribute(UseCandidate::new().into());
+        }
+        seal(&mut msg, &pwd)?;
+        state.pending.insert(id, (idx, nominating));
+        Ok(Some((
+            base,
+            remote,
+            MessageEncoder::new().encode_into_bytes(msg)?,
+        )))
+    }
+
+    /// Checks pairs of local `bases` and `remote` candidates a check per `timeout`, until
+    /// one of them is nominated. Returns the base and remote address of the pair.
+    ///
+    /// Roles come from tie-breakers of both descriptions, so peers never conflict.
+    pub(crate) async fn connect(
+        self: &Arc<Self>,
+        remote: &Candidates,
+        bases: &[&(dyn IOSocket + Sync + Send)],
+        retries: u16,
+        timeout: Duration,
+    ) -> Result<(usize, SocketAddr)> {
+        if remote.tie == self.local.tie {
+            return Err(ERR_VALIDATION.into());
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .start(&self.local, remote, bases.len());
+
+        for tick in 0..retries as u32 {
+            if let Some((base, addr, buf)) = self.next_check(tick)? {
+                trace!("checking connectivity of base {base} with {:?}", addr);
+                if let Err(e) = bases[base].push_stun(&buf, addr).await {
+                    trace!("can't send check to {:?}: {e}", addr);
+                }
+            }
+            let polls = bases
+                .iter()
+                .enumerate()
+                .map(|(c, socket)| socket.poll_stun(self.clone(), c, timeout));
+            for res in futures::future::join_all(polls).await {
+                res?;
+            }
+
+            let state = self.state.lock().unwrap();
+            if let Some(pair) = state.selected() {
+                info!(
+                    "ICE selected base {} with {:?} after {} checks",
+                    pair.base,
+                    pair.remote,
+                    tick + 1
+                );
+                return Ok((pair.base, pair.remote));
+            }
+        }
+        Err(ERR_CONNECTION.into())
+    }
+}
+
+/// Answers authentic checks and takes responses to own ones.
+impl StunHandler for Agent {
+    fn input(&self, base: usize, buf: &[u8], source: SocketAddr) -> Option<Vec<u8>> {
+        let msg = MessageDecoder::<Attribute>::new()
+            .decode_from_bytes(buf)
+            .ok()?
+            .ok()?;
+        if msg.method() != BINDING {
+            return None;
+        }
+        match msg.class() {
+            MessageClass::Request => self.answer(base, &msg, source),
+            MessageClass::SuccessResponse => {
+                self.state.lock().unwrap().succeeded(&msg, source);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Tells `STUN` messages apart from frames and `TURN` channel data.
+pub(crate) fn is_stun(buf: &[u8]) -> bool {
+    buf.len() >= STUN_HEADER_SIZE && buf[0] & 0xC0 == 0 && buf[4..8] == MAGIC_COOKIE
+}
+
+/// Resolves an unspecified local address to the one routing to `remote`, as `ICE` needs
+/// concrete host candidates.
+pub(crate) fn route(local: SocketAddr, remote: SocketAddr) -> Result<SocketAddr> {
+    if !local.ip().is_unspecified() {
+        return Ok(local);
+    }
+    let probe = std::net::UdpSocket::bind(SocketAddr::new(unspecified(remote), 0))?;
+    probe.connect(remote)?;
+    Ok(SocketAddr::new(probe.local_addr()?.ip(), local.port()))
+}
+
+/// Gathers candidates of a socket at `loc_ip`, mapped to `pub_ips` of each address family
+/// it reaches and optionally relayed at `relay_ip`.
+///
+/// Unspecified `loc_ip` gets a host candidate per family it has a public address of.
+pub(crate) fn gather(
+    loc_ip: SocketAddr,
+    pub_ips: &[SocketAddr],
+    relay_ip: Option<SocketAddr>,
+) -> Vec<Candidate> {
+    let mut list = vec![];
+    for pub_ip in pub_ips {
+        let host = route(loc_ip, *pub_ip).unwrap_or(loc_ip);
+        if !list.iter().any(|c: &Candidate| c.addr == host) {
+            list.push(Candidate::new(CandidateKind::Host, host));
+        }
+        if *pub_ip != host {
+            list.push(Candidate::new(CandidateKind::ServerReflexive, *pub_ip));
+        }
+    }
+    if list.is_empty() {
+        list.push(Candidate::new(CandidateKind::Host, loc_ip));
+    }
+    list.sort_by_key(|c| std::cmp::Reverse(c.priority));
+    if let Some(addr) = relay_ip {
+        list.push(Candidate::new(CandidateKind::Relayed, addr));
+    }
+    list
+}
+
+fn base_candidates(local: &Candidates, base: usize) -> Vec<Candidate> {
+    let kind = match base {
+        HOST_BASE => CandidateKind::Host,
+        RELAYED_BASE => CandidateKind::Relayed,
+        _ => return vec![],
+    };
+    local
+        .list
+        .iter()
+        .filter(|c| c.kind == kind)
+        .copied()
+        .collect()
+}
+
+/// Appends short-term `MESSAGE-INTEGRITY` keyed with `pwd` and `FINGERPRINT`.
+fn seal(msg: &mut Message<Attribute>, pwd: &str) -> Result<()> {
+    let integrity = MessageIntegrity::new_short_term_credential(msg, pwd)?;
+    msg.add_attribute(integrity.into());
+    let fingerprint = Fingerprint::new(msg)?;
+    msg.add_attribute(fingerprint.into());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn candidates_work() {
+        let list = vec![
+            Candidate::new(
+                CandidateKind::Host,
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2)), 34254),
+            ),
+            Candidate::new(
+                CandidateKind::ServerReflexive,
+                "[2001:db8::1]:34254".parse().unwrap(),
+            ),
+            Candidate::new(CandidateKind::Relayed, "203.0.113.5:49152".parse().unwrap()),
+        ];
+        let mut candidates = Candidates::new(list);
+        let res = candidates.to_string().parse::<Candidates>().unwrap();
+        // description survives the single-line form
+        assert_eq!(res, candidates);
+        candidates.cert = Some([0xa5; 32]);
+        // as does the certificate hash
+        assert_eq!(
+            candidates.to_string().parse::<Candidates>().unwrap(),
+            candidates
+        );
+        // host candidates are preferred over reflexive and relayed ones
+        assert!(res.list[0].priority > res.list[1].priority);
+        assert!(res.list[1].priority > res.list[2].priority);
+
+        assert!("ice ufrag pwd 1".parse::<Candidates>().is_err());
+        assert!("ice ufrag pwd 1 host/127.0.0.1:1/1"
+            .parse::<Candidates>()
+            .is_ok());
+        assert!("ice ufrag pwd 1 host/127.0.0.1:1"
+            .parse::<Candidates>()
+            .is_err());
+        assert!("127.0.0.1:1".parse::<Candidates>().is_err());
+        assert!("ice ufrag pwd 1 cert/a5 host/127.0.0.1:1/1"
+            .parse::<Candidates>()
+            .is_err());
+
+        let list = gather(
+            "[::]:34254".parse().unwrap(),
+            &["127.0.0.1:1".parse().unwrap(), "[::1]:1".parse().unwrap()],
+            None,
+        );
+        let hosts = list
+            .iter()
+            .filter(|c| c.kind == CandidateKind::Host)
+            .map(|c| c.addr.to_string())
+            .collect::<Vec<_>>();
+        // dual-stack socket has a host candidate of each family, IPv6 preferred
+        assert_eq!(hosts, ["[::1]:34254", "127.0.0.1:34254"]);
+        assert_eq!(list.len(), 4);
+    }
+}
@@ -0,0 +1,369 @@
+use async_std::{
+    channel::{self, Receiver, Sender},
+    future,
+    sync::Arc,
+    task,
+};
+use hmac::{Hmac, Mac};
+use log::{info, trace, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{Client, SocketHandle, KEEPALIVE_STREAM};
+
+const ID_SIZE: usize = 8;
+/// Sequence number, stamp, echoed stamp and time it was held for.
+const BODY_SIZE: usize = 4 + 8 + 8 + 4;
+const MAC_SIZE: usize = 32;
+/// Domain separation of the key derived from the application secret.
+const KEY_TAG: &[u8] = b"ensd\0keepalive\0key\0";
+/// Number of state changes queued before new ones are dropped.
+const EVENTS_SIZE: usize = 64;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// `KeepaliveConfig` for `.toml` config parsing.
+/// Sets how often keepalives go to the peer, keeping `NAT` mappings open, and how many
+/// of them may be missed before the peer is considered lost.
+#[derive(Debug, Deserialize, Clone)]
+pub struct KeepaliveConfig {
+    /// Milliseconds between keepalives, to be below `NAT` mapping timeouts.
+    #[serde(default = "default_interval")]
+    interval: u64,
+    /// Intervals with no keepalive from the peer before it's lost.
+    #[serde(default = "default_misses")]
+    misses: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            interval: default_interval(),
+            misses: default_misses(),
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    pub fn new(interval: Duration, misses: u32) -> Self {
+        KeepaliveConfig {
+            interval: interval.as_millis() as u64,
+            misses,
+        }
+    }
+}
+
+/// `PeerState` of the remote peer as seen by keepalives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    Alive,
+    Lost,
+}
+
+/// `Liveness` of the remote peer, waking up pending reads once it's lost.
+///
+/// Waiters hold a channel which is closed and replaced with a new one on every change of
+/// the state, so all of them are woken up at once.
+pub(crate) struct Liveness {
+    state: Mutex<(PeerState, Sender<()>, Receiver<()>)>,
+    events: (Sender<PeerState>, Receiver<PeerState>),
+}
+
+impl Liveness {
+    pub fn new() -> Self {
+        let (tx, rx) = channel::bounded(1);
+        Liveness {
+            state: Mutex::new((PeerState::Alive, tx, rx)),
+            events: channel::bounded(EVENTS_SIZE),
+        }
+    }
+
+    pub fn state(&self) -> PeerState {
+        self.state.lock().unwrap().0
+    }
+
+    /// Current state along with a channel closed once it changes.
+    pub fn watch(&self) -> (PeerState, Receiver<()>) {
+        let state = self.state.lock().unwrap();
+        (state.0, state.2.clone())
+    }
+
+    /// Changes of [`PeerState`], for the application to act on.
+    pub fn events(&self) -> Receiver<PeerState> {
+        self.events.1.clone()
+    }
+
+    pub fn set(&self, new: PeerState) {
+        let mut state = self.state.lock().unwrap();
+        if state.0 == new {
+            return;
+        }
+        state.1.close();
+        let (tx, rx) = channel::bounded(1);
+        *state = (new, tx, rx);
+        if self.events.0.try_send(new).is_err() {
+            trace!(
+                "dropped peer state change to {:?} as nobody reads them",
+                new
+            );
+        }
+    }
+}
+
+/// `Keepalive` of a peer, timestamped in milliseconds since `UNIX` epoch.
+///
+/// The latest stamp of the other peer is echoed back along with how long it was held for,
+/// so the other peer tells the round-trip time with no need for synchronized clocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Keepalive {
+    /// Sender, telling own keepalives reflected back.
+    id: [u8; ID_SIZE],
+    /// Sequence number, telling lost and reordered keepalives.
+    seq: u32,
+    /// Stamp growing to tell replayed keepalives.
+    stamp: u64,
+    echo: u64,
+    held: u32,
+}
+
+/// Spawns a task sending keepalives of `socket` authenticated with `secret` every interval
+/// of `cfg`, and tracking ones of the peer to tell whether it's alive. The task ends once
+/// the socket is dropped.
+///
+/// Authenticated keepalive coming from another address means the peer roamed, or its
+/// `NAT` mapping changed, so the socket is rebound to it. Anyone else can't move the
+/// peer, as forged, replayed and reflected keepalives are dropped. While the peer is
+/// lost, public addresses of the socket are refreshed in case it's the one which moved,
+/// and a hole is punched to the peer again, see [`SocketHandle::repunch`].
+///
+/// Keepalives of the peer make [`Stats`][crate::Stats] of the socket, a snapshot of which
+/// is published every interval.
+pub(crate) fn spawn(socket: &Arc<SocketHandle>, cfg: &KeepaliveConfig, secret: &[u8]) {
+    let key: [u8; MAC_SIZE] = Sha256::new_with_prefix(KEY_TAG)
+        .chain_update(secret)
+        .finalize()
+        .into();
+    let interval = cfg.interval;
+    let limit = interval * cfg.misses as u64;
+    let id = rand::random::<[u8; ID_SIZE]>();
+    let socket = Arc::downgrade(socket);
+
+    task::spawn(async move {
+        let (mut newest, mut seen, mut sent, mut refreshed) = (0, timestamp(), 0, 0);
+        let (mut seq, mut peer) = (0, None);
+        while let Some(socket) = socket.upgrade() {
+            let now = timestamp();
+            if now.saturating_sub(sent) >= interval {
+                seq += 1;
+                let msg = seal(
+                    &key,
+                    &Keepalive {
+                        id,
+                        seq,
+                        stamp: now,
+                        echo: newest,
+                        held: now.saturating_sub(seen) as u32,
+                    },
+                );
+                match socket.io().push_on(KEEPALIVE_STREAM, &msg).await {
+                    Ok(()) => socket.stats.sent(msg.len()),
+                    Err(e) => trace!("can't send keepalive: {e}"),
+                }
+                socket.stats.publish();
+                sent = now;
+            }
+
+            let wait = Duration::from_millis((sent + interval).saturating_sub(now));
+            match future::timeout(wait, socket.io().poll_on_at(KEEPALIVE_STREAM)).await {
+                Ok(Ok((msg, from))) => {
+                    socket.stats.received(msg.len());
+                    match open(&key, &msg) {
+                        Some(remote) if remote.id != id => {
+                            track(&socket, &remote, &mut peer, newest);
+                            if remote.stamp > newest {
+                                (newest, seen) = (remote.stamp, timestamp());
+                                roam(&socket, from).await;
+                                socket.liveness.set(PeerState::Alive);
+                            }
+                        }
+                        _ => warn!("dropped unauthenticated or reflected keepalive"),
+                    }
+                }
+                Ok(Err(e)) => {
+                    trace!("can't receive keepalive: {e}");
+                    task::sleep(wait).await;
+                }
+                Err(_) => {}
+            }
+
+            let silent = timestamp().saturating_sub(seen);
+            if silent > limit && socket.liveness.state() == PeerState::Alive {
+                info!("no keepalive from the peer for {silent}ms, considering it lost");
+                socket.liveness.set(PeerState::Lost);
+            }
+            let now = timestamp();
+            if socket.liveness.state() == PeerState::Lost && now.saturating_sub(refreshed) >= limit
+            {
+                let refresh = socket.refresh_pub_ips();
+                if let Ok(Err(e)) = future::timeout(Duration::from_millis(interval), refresh).await
+                {
+                    trace!("can't refresh public addresses: {e}");
+                }
+                match socket.repunch(Duration::from_millis(limit)).await {
+                    Ok(()) => info!("punched a hole to the lost peer again"),
+                    Err(e) => trace!("can't punch a hole to the lost peer again: {e}"),
+                }
+                refreshed = timestamp();
+            }
+        }
+    });
+}
+
+/// Records `remote` keepalive in stats of `socket`, starting them over once a fresh one
+/// comes from another `peer` instance, which restarted its sequence.
+fn track(socket: &SocketHandle, remote: &Keepalive, peer: &mut Option<[u8; ID_SIZE]>, newest: u64) {
+    if *peer != Some(remote.id) {
+        if remote.stamp <= newest {
+            warn!("dropped replayed keepalive of a former peer");
+            return;
+        }
+        socket.stats.rebase();
+        *peer = Some(remote.id);
+    }
+    let now = timestamp();
+    if !socket
+        .stats
+        .probe(remote.seq, now as i64 - remote.stamp as i64)
+    {
+        warn!("dropped replayed keepalive");
+        return;
+    }
+    if remote.echo > 0 {
+        let rtt = now.saturating_sub(remote.echo + remote.held as u64);
+        socket.stats.rtt(Duration::from_millis(rtt));
+    }
+    if remote.stamp <= newest {
+        trace!("keepalive {} arrived out of order", remote.seq);
+    }
+}
+
+/// Rebinds `socket` to `from` when the authenticated peer sent its keepalive from another
+/// address than the bound one. `QUIC` connections are left alone, as they follow migrating
+/// peers by themselves and their socket is taken over by [`quinn`].
+async fn roam(socket: &SocketHandle, from: SocketAddr) {
+    if matches!(socket.cfg, Client::QUIC { .. }) && !socket.is_fallback() {
+        return;
+    }
+    let io = socket.io();
+    match io.peer().await {
+        Ok(peer) if peer != from => {
+            info!("peer moved from {:?} to {:?}", peer, from);
+            if let Err(e) = io.bind(&[from]).await {
+                warn!("can't follow peer to {:?}: {e}", from);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Builds `id | seq | stamp | echo | held | mac` message of `keepalive`.
+fn seal(key: &[u8], keepalive: &Keepalive) -> Vec<u8> {
+    let mut msg = [
+        keepalive.id.as_slice(),
+        &keepalive.seq.to_be_bytes(),
+        &keepalive.stamp.to_be_bytes(),
+        &keepalive.echo.to_be_bytes(),
+        &keepalive.held.to_be_bytes(),
+    ]
+    .concat();
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(&msg);
+    msg.extend_from_slice(&mac.finalize().into_bytes());
+    msg
+}
+
+/// Keepalive of `msg` built by [`seal`], none if it fails authentication.
+fn open(key: &[u8], msg: &[u8]) -> Option<Keepalive> {
+    if msg.len() != ID_SIZE + BODY_SIZE + MAC_SIZE {
+        return None;
+    }
+    let (msg, tag) = msg.split_at(ID_SIZE + BODY_SIZE);
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(msg);
+    mac.verify_slice(tag).ok()?;
+    let (id, body) = msg.split_at(ID_SIZE);
+    Some(Keepalive {
+        id: id.try_into().ok()?,
+        seq: u32::from_be_bytes(body[..4].try_into().ok()?),
+        stamp: u64::from_be_bytes(body[4..12].try_into().ok()?),
+        echo: u64::from_be_bytes(body[12..20].try_into().ok()?),
+        held: u32::from_be_bytes(body[20..].try_into().ok()?),
+    })
+}
+
+/// Milliseconds since `UNIX` epoch.
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn default_interval() -> u64 {
+    15000
+}
+
+fn default_misses() -> u32 {
+    4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keepalive_auth_works() {
+        let (key, other) = ([1u8; MAC_SIZE], [2u8; MAC_SIZE]);
+        let keepalive = Keepalive {
+            id: [3u8; ID_SIZE],
+            seq: 7,
+            stamp: 42,
+            echo: 40,
+            held: 1,
+        };
+        let msg = seal(&key, &keepalive);
+        // keepalive of the peer is verified and read back
+        assert_eq!(open(&key, &msg), Some(keepalive));
+
+        let mut tampered = msg.clone();
+        tampered[ID_SIZE] ^= 1;
+        // altered keepalive and one under another key are refused
+        assert_eq!(open(&key, &tampered), None);
+        assert_eq!(open(&other, &msg), None);
+    }
+
+    #[async_std::test]
+    async fn liveness_works() {
+        let liveness = Liveness::new();
+        let events = liveness.events();
+        let (_, watch) = liveness.watch();
+
+        liveness.set(PeerState::Lost);
+        // losing the peer wakes up waiters and is reported once
+        assert!(watch.recv().await.is_err());
+        assert_eq!(liveness.state(), PeerState::Lost);
+        liveness.set(PeerState::Lost);
+        assert_eq!(events.recv().await.unwrap(), PeerState::Lost);
+        assert!(events.is_empty());
+
+        let (_, watch) = liveness.watch();
+        liveness.set(PeerState::Alive);
+        // peer coming back wakes up waiters as well
+        assert!(watch.is_closed());
+        assert!(!liveness.watch().1.is_closed());
+        assert_eq!(events.recv().await.unwrap(), PeerState::Alive);
+    }
+}
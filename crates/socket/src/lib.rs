@@ -1,29 +1,116 @@
+mod frame;
+mod ice;
+mod keepalive;
+mod mdns;
+mod nat;
 mod p2p;
+mod punch;
+mod quic;
+mod reliable;
+mod server;
+mod signal;
+mod sim;
+mod stats;
+mod stun;
+mod tcp;
+mod turn;
 mod udp;
+mod webrtc;
 
 use async_std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    channel::Receiver,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
     sync::Arc,
 };
 use async_trait::async_trait;
-use err::{Error, Result};
+use err::{
+    consts::{
+        ERR_ADDRESS, ERR_CONNECTION, ERR_PEER_LOST, ERR_STUN_UNREACHABLE, ERR_UNSUPPORTED,
+        ERR_VALIDATION,
+    },
+    Error, Result,
+};
+use futures::future::{select, select_ok, Either};
 use howler::Result as HowlerResult;
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
 use serde::Deserialize;
+use std::fmt;
+use std::future::Future;
+use std::sync::OnceLock;
 use std::time::Duration;
 
+use crate::ice::{Agent, RELAYED_BASE};
+use crate::keepalive::Liveness;
 use crate::p2p::P2P;
+use crate::quic::QuicSocketHandle;
+use crate::stats::Recorder;
+use crate::tcp::TcpSocketHandle;
+use crate::turn::Relay;
 use crate::udp::UdpSocketHandle;
+use crate::webrtc::WebRtcSocketHandle;
+
+pub use crate::ice::{Candidate, CandidateKind, Candidates};
+pub use crate::keepalive::{KeepaliveConfig, PeerState};
+pub use crate::mdns::{LanPeer, MdnsConfig, MDNS_ADDR, MDNS_ADDR6};
+pub use crate::nat::{NatBehavior, NatType, BEHAVIOR_STUN_ADDRESS};
+pub use crate::punch::PunchConfig;
+pub use crate::server::{StunServer, StunServerConfig};
+pub use crate::signal::{SignalConfig, SignalServer, SignalServerConfig};
+pub use crate::sim::{Impairment, Network};
+pub use crate::stats::Stats;
+pub use crate::stun::{StunConfig, STUN_ADDRESS};
+pub use crate::tcp::TcpRole;
+pub use crate::turn::TurnConfig;
 
 pub const LOOPBACK_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+pub const LOOPBACK_IP6: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+/// Logical stream used by plain [`IOSocket`][IOSocket] methods and NAT traversal.
+pub const CONTROL_STREAM: u8 = 0;
+/// Logical stream reserved for keepalives of [`SocketHandle::keepalive`], the last one
+/// being taken by transports internally.
+pub const KEEPALIVE_STREAM: u8 = u8::MAX - 1;
+
+/// `SHA-256` hash of the self-signed certificate a transport presents to the peer, which
+/// the peer pins once it's handed over.
+pub type CertHash = [u8; 32];
 
+/// `Client` for `.toml` config parsing.
+/// Offers plain [`UDP`][Client::UDP], [`QUIC`][Client::QUIC], [`TCP`][Client::TCP] and
+/// [`WebRTC`][Client::WebRTC] transports, told apart by the required `server_name`, `role`
+/// and `label` fields.
 #[derive(Debug, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum Client {
+    QUIC {
+        addr: ClientAddress,
+        ttl: Option<u32>,
+        sw_tag: Option<String>,
+        /// Logical streams sent over `QUIC` streams, others go as unreliable datagrams.
+        reliable: Option<Vec<u8>>,
+        /// Name in self-signed certificates of both peers.
+        server_name: String,
+    },
+    TCP {
+        addr: ClientAddress,
+        ttl: Option<u32>,
+        sw_tag: Option<String>,
+        role: TcpRole,
+    },
+    WebRTC {
+        addr: ClientAddress,
+        ttl: Option<u32>,
+        sw_tag: Option<String>,
+        /// Logical streams sent over the reliable data channel, others go unordered.
+        reliable: Option<Vec<u8>>,
+        /// Label of data channels, as seen by the remote peer.
+        label: String,
+    },
     UDP {
         addr: ClientAddress,
         ttl: Option<u32>,
         sw_tag: Option<String>,
+        /// Logical streams delivered reliably and in order, others are best-effort.
+        reliable: Option<Vec<u8>>,
     },
 }
 
@@ -34,15 +121,93 @@ pub enum ClientAddress {
     Vector(Vec<SocketAddr>),
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Deserialize, Clone)]
 pub struct SocketConfig {
     retries: u16,
     timeout: Duration,
+    /// `STUN` servers, [`STUN_ADDRESS`] when not set.
+    stun: Option<StunConfig>,
+    /// Port prediction and spraying, defaults when not set.
+    punch: Option<PunchConfig>,
+    /// Secret shared by peers authenticating hole punching, which is refused when it's
+    /// not set or empty.
+    #[serde(skip)]
+    secret: Option<Vec<u8>>,
 }
 
 impl SocketConfig {
     pub fn new(retries: u16, timeout: Duration) -> Self {
-        SocketConfig { retries, timeout }
+        SocketConfig {
+            retries,
+            timeout,
+            stun: None,
+            punch: None,
+            secret: None,
+        }
+    }
+
+    pub fn with_stun(self, stun: StunConfig) -> Self {
+        SocketConfig {
+            stun: Some(stun),
+            ..self
+        }
+    }
+
+    pub fn with_punch(self, punch: PunchConfig) -> Self {
+        SocketConfig {
+            punch: Some(punch),
+            ..self
+        }
+    }
+
+    pub fn with_secret(self, secret: &[u8]) -> Self {
+        SocketConfig {
+            secret: Some(secret.to_vec()),
+            ..self
+        }
+    }
+
+    fn stun(&self) -> StunConfig {
+        self.stun.clone().unwrap_or_default()
+    }
+
+    fn punch(&self) -> PunchConfig {
+        self.punch.clone().unwrap_or_default()
+    }
+
+    fn secret(&self) -> &[u8] {
+        self.secret.as_deref().unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for SocketConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SocketConfig")
+            .field("retries", &self.retries)
+            .field("timeout", &self.timeout)
+            .field("stun", &self.stun)
+            .field("punch", &self.punch)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Client {
+    fn sw_tag(&self) -> Option<String> {
+        match self {
+            Client::QUIC { sw_tag, .. }
+            | Client::TCP { sw_tag, .. }
+            | Client::WebRTC { sw_tag, .. }
+            | Client::UDP { sw_tag, .. } => sw_tag.clone(),
+        }
+    }
+
+    fn reliable(&self) -> Vec<u8> {
+        match self {
+            Client::QUIC { reliable, .. }
+            | Client::WebRTC { reliable, .. }
+            | Client::UDP { reliable, .. } => reliable.clone().unwrap_or_default(),
+            Client::TCP { .. } => vec![],
+        }
     }
 }
 
@@ -58,6 +223,10 @@ impl From<ClientAddress> for Vec<SocketAddr> {
 /// `IOSocket` trait for heterogeneous transport implementation.
 /// Assumes method implementations to [`connect`][IOSocket::connect], [`poll`][IOSocket::poll]
 /// and [`push`][IOSocket::push] data through channel.
+///
+/// Plain methods work on [`CONTROL_STREAM`][CONTROL_STREAM], while
+/// [`poll_on`][IOSocket::poll_on] and [`push_on`][IOSocket::push_on] multiplex several
+/// logical streams over the same connection.
 #[async_trait]
 pub trait IOSocket {
     async fn bind(&self, addr: &[SocketAddr]) -> Result<()>;
@@ -68,6 +237,15 @@ pub trait IOSocket {
 
     async fn poll_at(&self) -> Result<(Vec<u8>, SocketAddr)>;
 
+    async fn poll_on(&self, stream: u8) -> Result<Vec<u8>>;
+
+    /// Polls `stream` as [`poll_on`][IOSocket::poll_on] does, along with the address the
+    /// message came from.
+    async fn poll_on_at(&self, stream: u8) -> Result<(Vec<u8>, SocketAddr)> {
+        let res = self.poll_on(stream).await?;
+        Ok((res, self.peer().await?))
+    }
+
     async fn peek(&self) -> Result<Vec<u8>>;
 
     async fn peek_at(&self) -> Result<(Vec<u8>, SocketAddr)>;
@@ -76,6 +254,20 @@ pub trait IOSocket {
 
     async fn push_to(&self, buf: &[u8], addr: &[SocketAddr]) -> Result<()>;
 
+    async fn push_on(&self, stream: u8, buf: &[u8]) -> Result<()>;
+
+    /// Sends raw `STUN` message `buf` to `addr`, bypassing frames.
+    async fn push_stun(&self, buf: &[u8], addr: SocketAddr) -> Result<()>;
+
+    /// Reads the socket for `wait`, handing `STUN` messages over to `handler` as received on
+    /// its `base`. The handler keeps taking them in reads made later.
+    async fn poll_stun(
+        &self,
+        handler: Arc<dyn StunHandler>,
+        base: usize,
+        wait: Duration,
+    ) -> Result<()>;
+
     async fn get_ttl(&self) -> Result<u32>;
 
     async fn set_ttl(&self, ttl: u32) -> Result<()>;
@@ -83,25 +275,162 @@ pub trait IOSocket {
     async fn get_lan_ip(&self) -> Result<SocketAddr>;
 
     async fn get_wan_ip(&self) -> Result<SocketAddr>;
+
+    /// Public addresses of every address family the socket reaches, IPv4 first, see
+    /// [`get_wan_ip`][IOSocket::get_wan_ip].
+    async fn get_wan_ips(&self) -> Result<Vec<SocketAddr>> {
+        self.get_wan_ip().await.map(|c| vec![c])
+    }
+
+    /// Hash of the certificate presented to the peer, handed over while punching, none for
+    /// transports without one.
+    fn cert_hash(&self) -> Option<CertHash> {
+        None
+    }
+
+    /// Pins the certificate `hash` of the peer, the only one [`bind`][IOSocket::bind]
+    /// accepts then.
+    fn pin_cert(&self, _hash: CertHash) {}
+}
+
+/// `StunHandler` takes `STUN` messages sockets receive outside of frames, see
+/// [`poll_stun`][IOSocket::poll_stun].
+pub trait StunHandler: Send + Sync {
+    /// Handles message `buf` received from `source` on local `base`, returning a response
+    /// to send back if there is one.
+    fn input(&self, base: usize, buf: &[u8], source: SocketAddr) -> Option<Vec<u8>>;
 }
 
 pub struct SocketHandle {
     socket: Box<dyn IOSocket + Sync + Send>,
+    /// `TURN` relayed or `TCP` socket taking over once `UDP` traversal fails in
+    /// [`bind`][SocketHandle::bind].
+    fallback: OnceLock<Box<dyn IOSocket + Sync + Send>>,
+    relay: std::sync::Mutex<Option<Relay>>,
+    ice: Option<Arc<Agent>>,
+    cfg: Client,
     socket_cfg: Arc<SocketConfig>,
+    liveness: Liveness,
+    stats: Recorder,
+    /// Public address at creation, same as [`loc_ip`][SocketHandle::loc_ip] when no `STUN`
+    /// server is reachable and the socket is LAN-only.
     pub pub_ip: SocketAddr,
     pub loc_ip: SocketAddr,
+    /// Public addresses of every address family the socket reaches, IPv4 first.
+    pub_ips: std::sync::Mutex<Vec<SocketAddr>>,
+    /// Addresses the peer was bound at, punched to again once it's lost.
+    peer_ips: std::sync::Mutex<Vec<SocketAddr>>,
+    /// Address on the `TURN` server peers may reach this socket at.
+    pub relay_ip: Option<SocketAddr>,
 }
 
 #[allow(dead_code)]
 impl SocketHandle {
-    pub async fn new(cfg: Client, socket_cfg: SocketConfig) -> HowlerResult<SocketHandle> {
+    pub async fn new(
+        cfg: Client,
+        socket_cfg: SocketConfig,
+        turn: Option<TurnConfig>,
+    ) -> HowlerResult<SocketHandle> {
         let socket_cfg = Arc::new(socket_cfg);
-        // TODO: WebRTC socket backend implementation
         let socket = match cfg.clone() {
-            Client::UDP { addr, ttl, sw_tag } => {
-                get_udp_socket(addr.into(), ttl, sw_tag, socket_cfg.clone()).await
+            Client::QUIC {
+                addr,
+                ttl,
+                sw_tag,
+                reliable,
+                server_name,
+            } => {
+                get_quic_socket(
+                    addr.into(),
+                    ttl,
+                    sw_tag,
+                    reliable.unwrap_or_default(),
+                    server_name,
+                    socket_cfg.clone(),
+                )
+                .await
+            }
+            Client::TCP {
+                addr,
+                ttl,
+                sw_tag,
+                role,
+            } => get_tcp_socket(addr.into(), ttl, sw_tag, role, socket_cfg.clone()).await,
+            Client::WebRTC {
+                addr,
+                ttl,
+                sw_tag,
+                reliable,
+                label,
+            } => {
+                get_webrtc_socket(
+                    addr.into(),
+                    ttl,
+                    sw_tag,
+                    reliable.unwrap_or_default(),
+                    label,
+                    socket_cfg.clone(),
+                )
+                .await
+            }
+            Client::UDP {
+                addr,
+                ttl,
+                sw_tag,
+                reliable,
+            } => {
+                get_udp_socket(
+                    addr.into(),
+                    ttl,
+                    sw_tag,
+                    reliable.unwrap_or_default(),
+                    socket_cfg.clone(),
+                )
+                .await
             }
         };
+        let socket = socket?;
+        SocketHandle::with_socket(socket, cfg, socket_cfg, turn).await
+    }
+
+    /// Makes the socket of `UDP` transport `cfg` on simulated `network` rather than a real
+    /// one, behind the `NAT` at `nat` if given, so traversal and everything on top of it
+    /// runs with no ports bound. `STUN` servers of `socket_cfg` are to be added to the
+    /// network.
+    pub async fn simulated(
+        cfg: Client,
+        socket_cfg: SocketConfig,
+        network: &Network,
+        nat: Option<IpAddr>,
+    ) -> HowlerResult<SocketHandle> {
+        let socket_cfg = Arc::new(socket_cfg);
+        let Client::UDP {
+            addr,
+            ttl,
+            sw_tag,
+            reliable,
+        } = cfg.clone()
+        else {
+            return Err(Error::from(ERR_UNSUPPORTED).into());
+        };
+        let socket = get_simulated_socket(
+            network,
+            nat,
+            addr.into(),
+            ttl,
+            sw_tag,
+            reliable.unwrap_or_default(),
+            socket_cfg.clone(),
+        )?;
+        SocketHandle::with_socket(socket, cfg, socket_cfg, None).await
+    }
+
+    async fn with_socket(
+        socket: Box<dyn IOSocket + Sync + Send>,
+        cfg: Client,
+        socket_cfg: Arc<SocketConfig>,
+        turn: Option<TurnConfig>,
+    ) -> HowlerResult<SocketHandle> {
         let loc_ip = match socket.get_lan_ip().await {
             Ok(ip) => ip,
             Err(e) => {
@@ -109,62 +438,591 @@ impl SocketHandle {
                 return Err(e.into());
             }
         };
-        let pub_ip = match socket.get_wan_ip().await {
+        let pub_ips = match socket.get_wan_ips().await {
             Ok(ip) => {
-                trace!("socket on {:?} is up with public address {:?}", loc_ip, ip);
+                trace!(
+                    "socket on {:?} is up with public addresses {:?}",
+                    loc_ip,
+                    ip
+                );
                 ip
             }
+            Err(Error::Unreachable(e)) => {
+                warn!("{e}, socket on {:?} is LAN-only", loc_ip);
+                vec![]
+            }
             Err(e) => {
                 error!("can't query external address for socket on {:?}", loc_ip);
                 return Err(e.into());
             }
         };
+        let relay = match (turn, &cfg) {
+            (None, _) | (_, Client::TCP { .. }) => None,
+            (Some(turn), _) => {
+                match Relay::allocate(turn, loc_ip.ip(), cfg.sw_tag(), socket_cfg.clone()).await {
+                    Ok(relay) => Some(relay),
+                    Err(e) => {
+                        warn!("can't allocate TURN relay, going without it: {e}");
+                        None
+                    }
+                }
+            }
+        };
+        let relay_ip = relay.as_ref().map(Relay::relayed_addr);
+        let ice = match cfg {
+            Client::TCP { .. } => None,
+            _ => Some(Arc::new(
+                Agent::new(ice::gather(loc_ip, &pub_ips, relay_ip)).with_cert(socket.cert_hash()),
+            )),
+        };
         info!("made instance of socket handle with parameters '{:?}'", cfg);
 
         Ok(SocketHandle {
             socket,
+            fallback: OnceLock::new(),
+            relay_ip,
+            relay: std::sync::Mutex::new(relay),
+            ice,
+            cfg,
             socket_cfg,
-            pub_ip,
+            liveness: Liveness::new(),
+            stats: Recorder::new(),
+            pub_ip: pub_ips.first().copied().unwrap_or(loc_ip),
             loc_ip,
+            pub_ips: std::sync::Mutex::new(pub_ips),
+            peer_ips: std::sync::Mutex::new(vec![]),
         })
     }
 
+    /// Connects to `addr`, punching a hole through `NAT` first for `UDP` based transports.
+    /// Addresses of both families may be given to dual-stack sockets, IPv6 ones are bound
+    /// when they answer.
+    ///
+    /// When `UDP` traversal runs out of attempts, birthday punching of [`PunchConfig`] is
+    /// tried if it's set. Then traffic goes as plain `UDP` through the `TURN` relay if
+    /// there is one, with `addr` being the peer's own relayed or public address. Failing
+    /// that, `TCP` simultaneous open from the same port number is tried, and all traffic
+    /// goes through it on success.
     pub async fn bind<A: ToSocketAddrs>(&self, addr: &A) -> HowlerResult<()> {
-        let addr_vec = &addr.to_socket_addrs().await.unwrap().collect::<Vec<_>>();
-        self.try_nat_tr(addr_vec, self.socket_cfg.retries, self.socket_cfg.timeout)
-            .await
-            .map_err::<howler::Error, _>(Error::into)?;
         let addr = &addr.to_socket_addrs().await.unwrap().collect::<Vec<_>>();
-        self.socket.bind(addr).await.map_err(Error::into)
+        *self.peer_ips.lock().unwrap() = addr.clone();
+        if let Client::TCP { .. } = self.cfg {
+            return self.socket.bind(addr).await.map_err(Error::into);
+        }
+
+        match self
+            .socket
+            .try_nat_tr(
+                addr,
+                self.socket_cfg.retries,
+                self.socket_cfg.timeout,
+                self.socket_cfg.punch().rate(),
+                self.socket_cfg.secret(),
+            )
+            .await
+        {
+            Ok(peer) => self.socket.bind(&[peer]).await.map_err(Error::into),
+            Err(Error::TimedOut(e)) => {
+                let punch = self.socket_cfg.punch();
+                if punch.is_birthday() {
+                    warn!("UDP traversal failed: {e}, trying birthday punching");
+                    match self.bind_birthday(&punch, addr).await {
+                        Ok(_) => return Ok(()),
+                        Err(e) => warn!("birthday punching failed: {e}"),
+                    }
+                }
+                let relay = self.relay.lock().unwrap().take();
+                if let Some(relay) = relay {
+                    warn!("UDP traversal failed: {e}, relaying through TURN");
+                    match self.bind_relay(relay, addr).await {
+                        Ok(_) => return Ok(()),
+                        Err(e) => warn!("TURN relaying failed: {e}, falling back to TCP"),
+                    }
+                } else {
+                    warn!("UDP traversal failed: {e}, falling back to TCP");
+                }
+                self.bind_fallback(addr).await.map_err(Error::into)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Local candidates and credentials to hand over to the remote peer for
+    /// [`bind_candidates`][SocketHandle::bind_candidates], none for `TCP` transport.
+    pub fn candidates(&self) -> Option<Candidates> {
+        self.ice.as_ref().map(|c| c.local().clone())
+    }
+
+    /// Connects to the peer described by `remote`, running `ICE` connectivity checks over
+    /// local candidates and binding the pair nominated by the controlling peer.
+    ///
+    /// The `TURN` relay becomes the fallback when its pair is nominated. When no pair
+    /// works, `TCP` simultaneous open to addresses of `remote` is tried as in
+    /// [`bind`][SocketHandle::bind].
+    pub async fn bind_candidates(&self, remote: &Candidates) -> HowlerResult<()> {
+        let Some(agent) = &self.ice else {
+            return Err(Error::from(ERR_UNSUPPORTED).into());
+        };
+        if let Some(cert) = remote.cert {
+            self.socket.pin_cert(cert);
+        }
+        *self.peer_ips.lock().unwrap() = remote
+            .list
+            .iter()
+            .filter(|c| c.kind != CandidateKind::Relayed)
+            .map(|c| c.addr)
+            .collect();
+        let relay = self.relay.lock().unwrap().take();
+        let relayed = match relay {
+            Some(relay) => match self.relayed_base(relay, remote).await {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    warn!("can't relay ICE checks through TURN: {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let mut bases: Vec<&(dyn IOSocket + Sync + Send)> = vec![self.socket.as_ref()];
+        if let Some(socket) = &relayed {
+            bases.push(socket);
+        }
+        let res = agent
+            .connect(
+                remote,
+                &bases,
+                self.socket_cfg.retries,
+                self.socket_cfg.timeout,
+            )
+            .await;
+        match (res, relayed) {
+            (Ok((RELAYED_BASE, addr)), Some(socket)) => {
+                socket.bind(&[addr]).await?;
+                self.fallback
+                    .set(Box::new(socket))
+                    .map_err(|_| Error::from(ERR_CONNECTION).into())
+            }
+            (Ok((_, addr)), _) => self.socket.bind(&[addr]).await.map_err(Error::into),
+            (Err(Error::TimedOut(e)), _) => {
+                warn!("ICE checks failed: {e}, falling back to TCP");
+                let addr = remote
+                    .list
+                    .iter()
+                    .filter(|c| c.kind != CandidateKind::Relayed)
+                    .map(|c| c.addr)
+                    .collect::<Vec<_>>();
+                self.bind_fallback(&addr).await.map_err(Error::into)
+            }
+            (Err(e), _) => Err(e.into()),
+        }
+    }
+
+    /// Connects to the peer joining the room of `signal`, exchanging candidates, or
+    /// addresses for `TCP` transport, through the signaling server instead of asking the
+    /// user for them, and binding them as [`bind_candidates`][SocketHandle::bind_candidates]
+    /// and [`bind`][SocketHandle::bind] do.
+    pub async fn bind_signaled(&self, signal: &SignalConfig) -> HowlerResult<()> {
+        let local = match self.candidates() {
+            Some(candidates) => candidates.to_string(),
+            None => ice::gather(self.loc_ip, &self.pub_ips(), None)
+                .iter()
+                .map(|c| c.addr.to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        let remote = signal::exchange(signal, local.as_bytes()).await?;
+        let remote = String::from_utf8(remote).map_err(|_| Error::from(ERR_VALIDATION))?;
+        trace!("got remote peer description '{remote}' through signaling server");
+
+        match self.ice {
+            Some(_) => self.bind_candidates(&remote.parse()?).await,
+            None => {
+                let addr = remote
+                    .split_whitespace()
+                    .map(str::parse)
+                    .collect::<std::result::Result<Vec<SocketAddr>, _>>()
+                    .map_err(|_| Error::from(ERR_VALIDATION))?;
+                self.bind(&addr.as_slice()).await
+            }
+        }
+    }
+
+    /// Advertises the socket to peers on the local network under the name of `cfg`,
+    /// answering their queries until the multicast socket fails.
+    pub async fn advertise(&self, cfg: &MdnsConfig) -> HowlerResult<()> {
+        mdns::advertise(cfg, &mdns::host_addrs(self.loc_ip))
+            .await
+            .map_err(Error::into)
+    }
+
+    /// Peers advertised on the local network, which [`bind`][SocketHandle::bind] reaches
+    /// directly at their local addresses.
+    pub async fn browse(&self, cfg: &MdnsConfig) -> HowlerResult<Vec<LanPeer>> {
+        mdns::browse(cfg).await.map_err(Error::into)
+    }
+
+    /// Whether no `STUN` server was reachable, so peers may only reach the socket at
+    /// [`loc_ip`][SocketHandle::loc_ip].
+    pub fn is_lan_only(&self) -> bool {
+        self.pub_ips.lock().unwrap().is_empty()
+    }
+
+    /// Public addresses of every address family the socket reaches, IPv4 first, with
+    /// [`pub_ip`][SocketHandle::pub_ip] being the first of them until they're refreshed.
+    pub fn pub_ips(&self) -> Vec<SocketAddr> {
+        self.pub_ips.lock().unwrap().clone()
+    }
+
+    /// Queries `STUN` servers for public addresses again, as they change when the host
+    /// roams to another network or `NAT` drops its mapping.
+    pub async fn refresh_pub_ips(&self) -> HowlerResult<Vec<SocketAddr>> {
+        let ips = self.io().get_wan_ips().await?;
+        let mut pub_ips = self.pub_ips.lock().unwrap();
+        if *pub_ips != ips {
+            info!("public addresses changed from {:?} to {:?}", *pub_ips, ips);
+            *pub_ips = ips.clone();
+        }
+        Ok(ips)
+    }
+
+    /// Predicts public addresses the socket is mapped to toward its next peers, from
+    /// mappings sampled with distinct `STUN` servers as set by [`PunchConfig`]. Handed over
+    /// to the peer for [`bind`][SocketHandle::bind], they get through `NAT`s allocating a
+    /// port per destination in sequence, which refuse the peer at
+    /// [`pub_ip`][SocketHandle::pub_ip].
+    pub async fn predict(&self) -> HowlerResult<Vec<SocketAddr>> {
+        let (stun, punch) = (self.socket_cfg.stun(), self.socket_cfg.punch());
+        let servers = stun::resolve(stun.servers(), self.loc_ip)
+            .await
+            .into_iter()
+            .filter(SocketAddr::is_ipv4)
+            .collect::<Vec<_>>();
+        let samples = punch::sample(
+            self.socket.as_ref(),
+            &servers,
+            punch.samples(),
+            self.cfg.sw_tag(),
+            self.socket_cfg.timeout,
+        )
+        .await?;
+        if samples.len() < 2 {
+            return Err(Error::from(ERR_STUN_UNREACHABLE).into());
+        }
+        Ok(punch::predict(&samples, punch.spread()))
+    }
+
+    /// Classifies `NAT` mapping and filtering behaviour of the socket per RFC 5780 with
+    /// `STUN` server supporting it, [`BEHAVIOR_STUN_ADDRESS`] unless configured otherwise,
+    /// telling whether hole punching of [`bind`][SocketHandle::bind] is likely to work
+    /// before it spends its attempts.
+    pub async fn detect_nat(&self) -> HowlerResult<NatType> {
+        let res = async {
+            let stun = self.socket_cfg.stun();
+            let server = stun::resolve(&[stun.behavior().to_owned()], self.loc_ip)
+                .await
+                .into_iter()
+                .next()
+                .ok_or(ERR_STUN_UNREACHABLE)?;
+            nat::detect(
+                self.socket.as_ref(),
+                server,
+                self.cfg.sw_tag(),
+                self.socket_cfg.timeout,
+            )
+            .await
+        };
+        res.await.map_err(Error::into)
     }
 
     pub async fn peer(&self) -> HowlerResult<SocketAddr> {
-        self.socket.peer().await.map_err(Error::into)
+        self.io().peer().await.map_err(Error::into)
     }
 
     pub async fn poll(&self) -> HowlerResult<Vec<u8>> {
-        self.socket.poll().await.map_err(Error::into)
+        self.alive(self.io().poll())
+            .await
+            .inspect(|c| self.stats.received(c.len()))
+            .map_err(Error::into)
     }
 
     pub async fn poll_at(&self) -> HowlerResult<(Vec<u8>, SocketAddr)> {
-        self.socket.poll_at().await.map_err(Error::into)
+        self.alive(self.io().poll_at())
+            .await
+            .inspect(|c| self.stats.received(c.0.len()))
+            .map_err(Error::into)
     }
 
     pub async fn peek(&self) -> HowlerResult<Vec<u8>> {
-        self.socket.peek().await.map_err(Error::into)
+        self.alive(self.io().peek()).await.map_err(Error::into)
     }
 
     pub async fn peek_at(&self) -> HowlerResult<(Vec<u8>, SocketAddr)> {
-        self.socket.peek_at().await.map_err(Error::into)
+        self.alive(self.io().peek_at()).await.map_err(Error::into)
     }
 
     pub async fn push(&self, buf: &[u8]) -> HowlerResult<()> {
-        self.socket.push(buf).await.map_err(Error::into)
+        self.io()
+            .push(buf)
+            .await
+            .inspect(|_| self.stats.sent(buf.len()))
+            .map_err(Error::into)
     }
 
     pub async fn push_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: &A) -> HowlerResult<()> {
         let addr = &addr.to_socket_addrs().await.unwrap().collect::<Vec<_>>();
-        self.socket.push_to(buf, addr).await.map_err(Error::into)
+        self.io()
+            .push_to(buf, addr)
+            .await
+            .inspect(|_| addr.iter().for_each(|_| self.stats.sent(buf.len())))
+            .map_err(Error::into)
+    }
+
+    /// Starts sending keepalives to the bound peer every interval of `cfg`, keeping `NAT`
+    /// mappings open, and tracking ones of the peer. Keepalives are authenticated with
+    /// `secret` both peers share, so only the peer itself keeps it alive.
+    ///
+    /// Once the peer misses enough of them it's [`Lost`][PeerState::Lost], and reads of the
+    /// socket and its streams fail until it's back.
+    ///
+    /// Keepalives also measure round-trip time, jitter, loss and reordering of
+    /// [`stats`][SocketHandle::stats], published as they're sent.
+    pub fn keepalive(self: &Arc<Self>, cfg: &KeepaliveConfig, secret: &[u8]) {
+        keepalive::spawn(self, cfg, secret)
+    }
+
+    /// Current state of the peer, which stays [`Alive`][PeerState::Alive] with no
+    /// [`keepalive`][SocketHandle::keepalive] running.
+    pub fn peer_state(&self) -> PeerState {
+        self.liveness.state()
+    }
+
+    /// Changes of the peer state as keepalives see them.
+    pub fn peer_states(&self) -> Receiver<PeerState> {
+        self.liveness.events()
+    }
+
+    /// Snapshot of [`Stats`] of the connection to the peer, with rates measured since the
+    /// previous periodic one.
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot()
+    }
+
+    /// Snapshots of [`Stats`] published every interval of
+    /// [`keepalive`][SocketHandle::keepalive].
+    pub fn stats_events(&self) -> Receiver<Stats> {
+        self.stats.events()
+    }
+
+    /// Waits for the peer to be [`Alive`][PeerState::Alive], e.g. before reading again
+    /// after reads failed as it was lost.
+    pub async fn wait_alive(&self) {
+        loop {
+            let (state, change) = self.liveness.watch();
+            if state == PeerState::Alive {
+                break;
+            }
+            let _ = change.recv().await;
+        }
+    }
+
+    /// Runs read `fut` until the peer is lost.
+    async fn alive<T>(&self, fut: impl Future<Output = Result<T>>) -> Result<T> {
+        let (state, change) = self.liveness.watch();
+        if state == PeerState::Lost {
+            return Err(ERR_PEER_LOST.into());
+        }
+        match select(Box::pin(fut), Box::pin(change.recv())).await {
+            Either::Left((res, _)) => res,
+            Either::Right(_) => Err(ERR_PEER_LOST.into()),
+        }
+    }
+
+    /// Punches a hole to the lost peer again, at addresses it was bound at and the one it
+    /// was last heard from, rebinding to the one answering in attempts fitting `within`.
+    /// Peers lost to each other punch at once, as when a `NAT` in between drops mappings.
+    ///
+    /// Only plain `UDP` sockets are punched again, as other transports own their sockets
+    /// once bound.
+    async fn repunch(&self, within: Duration) -> Result<()> {
+        if !matches!(self.cfg, Client::UDP { .. }) || self.is_fallback() {
+            return Err(ERR_UNSUPPORTED.into());
+        }
+        let mut addr = self.peer_ips.lock().unwrap().clone();
+        if let Ok(peer) = self.socket.peer().await {
+            if !addr.contains(&peer) {
+                addr.push(peer);
+            }
+        }
+        let timeout = self.socket_cfg.timeout;
+        let retries = (within.as_millis() / timeout.as_millis().max(1)).clamp(1, u16::MAX as u128);
+        let peer = self
+            .socket
+            .try_nat_tr(
+                &addr,
+                retries as u16,
+                timeout,
+                self.socket_cfg.punch().rate(),
+                self.socket_cfg.secret(),
+            )
+            .await?;
+        self.socket.bind(&[peer]).await
+    }
+
+    /// Whether traffic goes through the `TURN` relay, a socket of birthday punching or `TCP`
+    /// fallback.
+    pub fn is_fallback(&self) -> bool {
+        self.fallback.get().is_some()
+    }
+
+    fn io(&self) -> &(dyn IOSocket + Sync + Send) {
+        match self.fallback.get() {
+            Some(socket) => socket.as_ref(),
+            None => self.socket.as_ref(),
+        }
+    }
+
+    async fn bind_relay(&self, relay: Relay, addr: &[SocketAddr]) -> Result<()> {
+        relay.permit(addr).await?;
+        let socket = UdpSocketHandle::relayed(
+            relay,
+            None,
+            self.cfg.sw_tag(),
+            self.cfg.reliable(),
+            self.socket_cfg.clone(),
+        )?;
+        let peer = socket
+            .try_nat_tr(
+                addr,
+                self.socket_cfg.retries,
+                self.socket_cfg.timeout,
+                self.socket_cfg.punch().rate(),
+                self.socket_cfg.secret(),
+            )
+            .await?;
+        socket.bind(&[peer]).await?;
+        self.fallback
+            .set(Box::new(socket))
+            .map_err(|_| ERR_CONNECTION.into())
+    }
+
+    /// Punches holes over many mappings at once, either opening sockets of `punch` toward
+    /// `addr` and going on with the first one answered, or spraying random ports of `addr`
+    /// from this socket.
+    async fn bind_birthday(&self, punch: &PunchConfig, addr: &[SocketAddr]) -> Result<()> {
+        let (retries, timeout) = (self.socket_cfg.retries, self.socket_cfg.timeout);
+        let secret = self.socket_cfg.secret();
+        if punch.sockets() == 0 {
+            let spray = punch.spray(addr);
+            info!("spraying {} ports for birthday punching", spray.len());
+            let peer = self
+                .socket
+                .try_nat_tr(&spray, retries, timeout, punch.rate(), secret)
+                .await?;
+            return self.socket.bind(&[peer]).await;
+        }
+
+        let mut sockets = vec![];
+        for _ in 0..punch.sockets() {
+            let socket = UdpSocketHandle::new(
+                vec![SocketAddr::new(self.loc_ip.ip(), 0)],
+                None,
+                self.cfg.sw_tag(),
+                self.cfg.reliable(),
+                self.socket_cfg.clone(),
+            )
+            .await?;
+            sockets.push(socket);
+        }
+        info!("opening {} mappings for birthday punching", sockets.len());
+        // sockets share the rate
+        let rate = (punch.rate() / sockets.len() as u32).max(1);
+        let punches = sockets.iter().enumerate().map(|(idx, socket)| {
+            Box::pin(async move {
+                let peer = socket
+                    .try_nat_tr(addr, retries, timeout, rate, secret)
+                    .await?;
+                Ok::<_, Error>((idx, peer))
+            })
+        });
+        let ((idx, peer), _) = select_ok(punches).await?;
+
+        let socket = sockets.swap_remove(idx);
+        socket.bind(&[peer]).await?;
+        self.fallback
+            .set(Box::new(socket))
+            .map_err(|_| ERR_CONNECTION.into())
+    }
+
+    /// Makes a relayed socket for `ICE` checks, permitting all candidates of `remote`.
+    async fn relayed_base(&self, relay: Relay, remote: &Candidates) -> Result<UdpSocketHandle> {
+        let addr = remote.list.iter().map(|c| c.addr).collect::<Vec<_>>();
+        relay.permit(&addr).await?;
+        UdpSocketHandle::relayed(
+            relay,
+            None,
+            self.cfg.sw_tag(),
+            self.cfg.reliable(),
+            self.socket_cfg.clone(),
+        )
+    }
+
+    async fn bind_fallback(&self, addr: &[SocketAddr]) -> Result<()> {
+        let socket = TcpSocketHandle::new(
+            vec![self.loc_ip],
+            None,
+            None,
+            TcpRole::Punch,
+            self.socket_cfg.clone(),
+        )
+        .await?;
+        socket.bind(addr).await?;
+        self.fallback
+            .set(Box::new(socket))
+            .map_err(|_| ERR_CONNECTION.into())
+    }
+
+    /// Makes a handle for logical stream `id` sharing this socket and its NAT mapping.
+    pub fn stream(self: &Arc<Self>, id: u8) -> SocketStream {
+        SocketStream {
+            socket: self.clone(),
+            id,
+        }
+    }
+}
+
+/// `SocketStream` is a logical stream multiplexed over a single [`SocketHandle`][SocketHandle].
+#[derive(Clone)]
+pub struct SocketStream {
+    socket: Arc<SocketHandle>,
+    id: u8,
+}
+
+impl SocketStream {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn socket(&self) -> &Arc<SocketHandle> {
+        &self.socket
+    }
+
+    pub async fn poll(&self) -> HowlerResult<Vec<u8>> {
+        let socket = &self.socket;
+        socket
+            .alive(socket.io().poll_on(self.id))
+            .await
+            .inspect(|c| socket.stats.received(c.len()))
+            .map_err(Error::into)
+    }
+
+    pub async fn push(&self, buf: &[u8]) -> HowlerResult<()> {
+        self.socket
+            .io()
+            .push_on(self.id, buf)
+            .await
+            .inspect(|_| self.socket.stats.sent(buf.len()))
+            .map_err(Error::into)
     }
 }
 
@@ -177,51 +1035,163 @@ impl SocketHandle {
 ///
 /// Current implementation relies on `UDP`'s [`UdpSocket`][std::net::UdpSocket]
 /// opened with any address of [`SocketAddr`][std::net::SocketAddr] type.
+///
+/// Streams listed in `reliable` get acknowledged, retransmitted and ordered delivery.
+/// Retransmissions are driven by [`poll_on`][IOSocket::poll_on] of the stream, so
+/// a reliable stream must be polled for its outgoing messages to be delivered.
 pub async fn get_udp_socket(
     addr: Vec<SocketAddr>,
     ttl: Option<u32>,
     sw_tag: Option<String>,
+    reliable: Vec<u8>,
     socket_cfg: Arc<SocketConfig>,
-) -> Box<dyn IOSocket + Sync + Send> {
+) -> Result<Box<dyn IOSocket + Sync + Send>> {
     trace!("building UDP socket instance");
 
-    Box::new(
-        UdpSocketHandle::new(addr, ttl, sw_tag, socket_cfg)
-            .await
-            .unwrap(),
-    )
+    Ok(Box::new(
+        UdpSocketHandle::new(addr, ttl, sw_tag, reliable, socket_cfg).await?,
+    ))
+}
+
+/// A thread-safe simulated `UDP` socket constructor.
+/// Returns [`Box`][Box] wrapped trait object interfaced with abstract [`IOSocket`][IOSocket]
+/// trait.
+///
+/// Socket behaves as the `UDP` one, bound on the first of `addr` on simulated `network`
+/// behind the `NAT` at `nat` if given, with datagrams impaired as the network is set to.
+pub fn get_simulated_socket(
+    network: &Network,
+    nat: Option<IpAddr>,
+    addr: Vec<SocketAddr>,
+    ttl: Option<u32>,
+    sw_tag: Option<String>,
+    reliable: Vec<u8>,
+    socket_cfg: Arc<SocketConfig>,
+) -> Result<Box<dyn IOSocket + Sync + Send>> {
+    trace!("building simulated UDP socket instance");
+
+    let endpoint = network.bind(*addr.first().ok_or(ERR_ADDRESS)?, nat)?;
+    Ok(Box::new(UdpSocketHandle::simulated(
+        endpoint, ttl, sw_tag, reliable, socket_cfg,
+    )?))
+}
+
+/// A thread-safe `QUIC` socket constructor.
+/// Returns [`Box`][Box] wrapped trait object interfaced with abstract [`IOSocket`][IOSocket]
+/// trait.
+///
+/// Socket behaves as the `UDP` one until [`bind`][IOSocket::bind], which starts `QUIC`
+/// connections on the same hole-punched port. Streams listed in `reliable` are delivered
+/// over `QUIC` streams, others use unreliable datagrams.
+pub async fn get_quic_socket(
+    addr: Vec<SocketAddr>,
+    ttl: Option<u32>,
+    sw_tag: Option<String>,
+    reliable: Vec<u8>,
+    server_name: String,
+    socket_cfg: Arc<SocketConfig>,
+) -> Result<Box<dyn IOSocket + Sync + Send>> {
+    trace!("building QUIC socket instance");
+
+    Ok(Box::new(
+        QuicSocketHandle::new(addr, ttl, sw_tag, reliable, server_name, socket_cfg).await?,
+    ))
+}
+
+/// A thread-safe `TCP` socket constructor.
+/// Returns [`Box`][Box] wrapped trait object interfaced with abstract [`IOSocket`][IOSocket]
+/// trait.
+///
+/// Messages are carried as length-prefixed frames over a single connection set up in
+/// [`bind`][IOSocket::bind] according to `role`.
+pub async fn get_tcp_socket(
+    addr: Vec<SocketAddr>,
+    ttl: Option<u32>,
+    sw_tag: Option<String>,
+    role: TcpRole,
+    socket_cfg: Arc<SocketConfig>,
+) -> Result<Box<dyn IOSocket + Sync + Send>> {
+    trace!("building TCP socket instance");
+
+    Ok(Box::new(
+        TcpSocketHandle::new(addr, ttl, sw_tag, role, socket_cfg).await?,
+    ))
+}
+
+/// A thread-safe `WebRTC` socket constructor.
+/// Returns [`Box`][Box] wrapped trait object interfaced with abstract [`IOSocket`][IOSocket]
+/// trait.
+///
+/// Socket behaves as the `UDP` one until [`bind`][IOSocket::bind], which starts a `WebRTC`
+/// session on the same hole-punched port. Streams listed in `reliable` are delivered over
+/// an ordered reliable data channel, others over an unordered one without retransmits.
+pub async fn get_webrtc_socket(
+    addr: Vec<SocketAddr>,
+    ttl: Option<u32>,
+    sw_tag: Option<String>,
+    reliable: Vec<u8>,
+    label: String,
+    socket_cfg: Arc<SocketConfig>,
+) -> Result<Box<dyn IOSocket + Sync + Send>> {
+    trace!("building WebRTC socket instance");
+
+    Ok(Box::new(
+        WebRtcSocketHandle::new(addr, ttl, sw_tag, reliable, label, socket_cfg).await?,
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use async_std::net::UdpSocket;
+    use rand::Rng;
+    use std::collections::HashMap;
+
+    use crate::ice::Agent;
+    use crate::turn::{self, tests::TurnServer};
+
+    use crate::tcp::FRAME_SIZE;
+
     const SOCKET_CFG: SocketConfig = SocketConfig {
         retries: 1000,
         timeout: Duration::from_millis(25),
+        stun: None,
+        punch: None,
+        secret: None,
     };
+    /// Hole punching datagrams per second, plenty for a few addresses.
+    const RATE: u32 = 1000;
+    const TEST_SECRET: &[u8] = b"alpha test secret";
     const TEST_STRING: &str = "alpha test string";
     const TEST_MACHINE_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
-    const PORT_A: u16 = 34254;
-    const PORT_B: u16 = 34250;
-
-    static TEST_MUTEX: async_std::sync::Mutex<Option<bool>> = async_std::sync::Mutex::new(None);
+    const TEST_SERVER_NAME: &str = "ensd";
+    const TEST_LABEL: &str = "ensd";
+    const SIM_IP_A: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const SIM_IP_B: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1));
+    const SIM_NAT_A: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+    const SIM_NAT_B: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 2));
+    const SIM_STUN: [&str; 3] = [
+        "198.51.100.1:3478",
+        "198.51.100.2:3478",
+        "198.51.100.3:3478",
+    ];
 
     #[async_std::test]
     #[cfg_attr(feature = "ci", ignore)]
     async fn socket_works() {
-        let _guard = TEST_MUTEX.lock().await;
-
-        let addr_a = SocketAddr::new(TEST_MACHINE_IP, PORT_A);
-        let addr_b = SocketAddr::new(TEST_MACHINE_IP, PORT_B);
+        let addr_a = SocketAddr::new(TEST_MACHINE_IP, 0);
+        let addr_b = SocketAddr::new(TEST_MACHINE_IP, 0);
 
         let socket_a = SocketHandle::new(
             Client::UDP {
                 addr: ClientAddress::Single(addr_a),
                 ttl: None,
                 sw_tag: None,
+                reliable: None,
             },
-            SOCKET_CFG,
+            SOCKET_CFG.with_secret(TEST_SECRET),
+            None,
         )
         .await
         .unwrap();
@@ -230,14 +1200,16 @@ mod tests {
                 addr: ClientAddress::Single(addr_b),
                 ttl: None,
                 sw_tag: None,
+                reliable: None,
             },
-            SOCKET_CFG,
+            SOCKET_CFG.with_secret(TEST_SECRET),
+            None,
         )
         .await
         .unwrap();
 
-        let addr_a = SocketAddr::new(LOOPBACK_IP, PORT_B);
-        let addr_b = SocketAddr::new(LOOPBACK_IP, PORT_A);
+        let addr_a = SocketAddr::new(LOOPBACK_IP, socket_b.loc_ip.port());
+        let addr_b = SocketAddr::new(LOOPBACK_IP, socket_a.loc_ip.port());
 
         let handle = futures::try_join!(socket_a.bind(&addr_a), socket_b.bind(&addr_b));
         // hole punching through NAT in `bind` works
@@ -255,18 +1227,1171 @@ mod tests {
 
     #[async_std::test]
     #[cfg_attr(feature = "ci", ignore)]
-    async fn stun_works() {
-        let _guard = TEST_MUTEX.lock().await;
-
-        let socket = UdpSocketHandle::new(
-            vec![SocketAddr::new(TEST_MACHINE_IP, PORT_A)],
+    async fn socket_v6_works() {
+        let socket_a = SocketHandle::new(
+            Client::UDP {
+                addr: ClientAddress::Single(SocketAddr::new(LOOPBACK_IP6, 0)),
+                ttl: None,
+                sw_tag: None,
+                reliable: None,
+            },
+            SOCKET_CFG.with_secret(TEST_SECRET),
             None,
+        )
+        .await
+        .unwrap();
+        let socket_b = SocketHandle::new(
+            Client::UDP {
+                addr: ClientAddress::Single(SocketAddr::new(LOOPBACK_IP6, 0)),
+                ttl: None,
+                sw_tag: None,
+                reliable: None,
+            },
+            SOCKET_CFG.with_secret(TEST_SECRET),
             None,
-            Arc::new(SOCKET_CFG),
         )
         .await
         .unwrap();
+        let (addr_a, addr_b) = (socket_a.loc_ip, socket_b.loc_ip);
+
+        let handle = futures::try_join!(socket_a.bind(&addr_b), socket_b.bind(&addr_a));
+        // hole punching in `bind` works over IPv6
+        assert!(handle.is_ok());
+
+        socket_a.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket_b.poll().await.unwrap(), TEST_STRING.as_bytes());
+        assert_eq!(socket_b.peer().await.unwrap(), addr_a);
+    }
+
+    #[async_std::test]
+    async fn punch_auth_works() {
+        let udp = || {
+            UdpSocketHandle::new(
+                vec![SocketAddr::new(LOOPBACK_IP, 0)],
+                None,
+                None,
+                vec![],
+                Arc::new(SOCKET_CFG),
+            )
+        };
+        let (socket_a, socket_b, rogue) = futures::try_join!(udp(), udp(), udp()).unwrap();
+        let addr_a = socket_a.get_lan_ip().await.unwrap();
+        let addr_b = socket_b.get_lan_ip().await.unwrap();
+        let addr_r = rogue.get_lan_ip().await.unwrap();
+
+        for msg in [
+            [b"c\0".as_ref(), b"p2p\0req\0"].concat(),
+            b"garbage".to_vec(),
+        ] {
+            rogue.push_to(&msg, &[addr_a]).await.unwrap();
+        }
+        let (cfg, secret) = (SOCKET_CFG, TEST_SECRET);
+        let (peers_a, peers_b) = ([addr_r, addr_b], [addr_a]);
+        let res = futures::join!(
+            socket_a.try_nat_tr(&peers_a, cfg.retries, cfg.timeout, RATE, secret),
+            socket_b.try_nat_tr(&peers_b, cfg.retries, cfg.timeout, RATE, secret),
+            async_std::future::timeout(
+                Duration::from_millis(500),
+                rogue.try_nat_tr(&peers_b, cfg.retries, cfg.timeout, RATE, b"guess")
+            )
+        );
+        // plaintext stages, garbage and guessed secret don't get through nor break punching
+        assert_eq!(res.0.unwrap(), addr_b);
+        assert_eq!(res.1.unwrap(), addr_a);
+        assert!(!matches!(res.2, Ok(Ok(_))));
+        // punching with no secret is refused right away
+        let res = rogue.try_nat_tr(&peers_b, cfg.retries, cfg.timeout, RATE, b"");
+        assert!(res.await.is_err());
+    }
+
+    #[async_std::test]
+    async fn dual_stack_works() {
+        let udp = |ip| {
+            UdpSocketHandle::new(
+                vec![SocketAddr::new(ip, 0)],
+                None,
+                None,
+                vec![],
+                Arc::new(SOCKET_CFG),
+            )
+        };
+        let unspecified = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+        let (socket_a, socket_b, socket_c) =
+            futures::try_join!(udp(unspecified), udp(unspecified), udp(LOOPBACK_IP)).unwrap();
+        let mut addr = vec![];
+        for socket in [&socket_a, &socket_b, &socket_c] {
+            let port = socket.get_lan_ip().await.unwrap().port();
+            addr.push([
+                SocketAddr::new(LOOPBACK_IP, port),
+                SocketAddr::new(LOOPBACK_IP6, port),
+            ]);
+        }
+        let (addr_a, addr_b, addr_c) = (addr[0], addr[1], addr[2]);
+
+        let res = futures::try_join!(
+            socket_a.try_nat_tr(
+                &addr_b,
+                SOCKET_CFG.retries,
+                SOCKET_CFG.timeout,
+                RATE,
+                TEST_SECRET
+            ),
+            socket_b.try_nat_tr(
+                &addr_a,
+                SOCKET_CFG.retries,
+                SOCKET_CFG.timeout,
+                RATE,
+                TEST_SECRET
+            )
+        )
+        .unwrap();
+        // dual-stack peers settle on IPv6
+        assert_eq!(res, (addr_b[1], addr_a[1]));
+
+        let res = futures::try_join!(
+            socket_a.try_nat_tr(
+                &addr_c,
+                SOCKET_CFG.retries,
+                SOCKET_CFG.timeout,
+                RATE,
+                TEST_SECRET
+            ),
+            socket_c.try_nat_tr(
+                &addr_a,
+                SOCKET_CFG.retries,
+                SOCKET_CFG.timeout,
+                RATE,
+                TEST_SECRET
+            )
+        )
+        .unwrap();
+        // IPv4-only peer is reached over IPv4 by its plain address
+        assert_eq!(res, (addr_c[0], addr_a[0]));
+
+        socket_a.bind(&[res.0]).await.unwrap();
+        socket_c.bind(&[res.1]).await.unwrap();
+        socket_c.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket_a.poll().await.unwrap(), TEST_STRING.as_bytes());
+        assert_eq!(socket_a.peer().await.unwrap(), addr_c[0]);
+    }
+
+    #[async_std::test]
+    async fn punch_retries_work() {
+        let udp = |ip| {
+            UdpSocketHandle::new(
+                vec![SocketAddr::new(ip, 0)],
+                None,
+                None,
+                vec![],
+                Arc::new(SOCKET_CFG),
+            )
+        };
+        let unspecified = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+        let (socket_a, quiet, chatty) =
+            futures::try_join!(udp(unspecified), udp(unspecified), udp(LOOPBACK_IP)).unwrap();
+        let port = |socket: UdpSocketHandle| async move {
+            let res = socket.get_lan_ip().await.unwrap().port();
+            (socket, res)
+        };
+        let ((socket_a, port_a), (quiet, port_q), (chatty, port_c)) =
+            futures::join!(port(socket_a), port(quiet), port(chatty));
+        let addr = [
+            SocketAddr::new(LOOPBACK_IP6, port_q),
+            SocketAddr::new(LOOPBACK_IP, port_c),
+        ];
+
+        // preferred address answers once and goes quiet, while the other one keeps on
+        let (cfg, secret) = (SOCKET_CFG, TEST_SECRET);
+        let addr_q = [SocketAddr::new(LOOPBACK_IP6, port_a)];
+        let once = quiet.try_nat_tr(&addr_q, cfg.retries, cfg.timeout, RATE, secret);
+        assert!(async_std::future::timeout(Duration::from_millis(10), once)
+            .await
+            .is_err());
+        let addr_c = [SocketAddr::new(LOOPBACK_IP, port_a)];
+        let res = futures::join!(
+            async_std::future::timeout(
+                Duration::from_secs(1),
+                socket_a.try_nat_tr(&addr, 20, cfg.timeout, RATE, secret)
+            ),
+            async_std::future::timeout(
+                Duration::from_secs(1),
+                chatty.try_nat_tr(&addr_c, cfg.retries, cfg.timeout, RATE, secret)
+            )
+        );
+        // messages over the other address count against retries, so punching gives up
+        assert!(matches!(res.0, Ok(Err(_))));
+    }
+
+    #[async_std::test]
+    async fn streams_work() {
+        let network = sim_network(Impairment::default());
+        let (socket_a, socket_b) = sim_pair(&network, vec![]).await;
+
+        socket_a.push_on(2, b"snd a").await.unwrap();
+        socket_a.push_on(1, TEST_STRING.as_ref()).await.unwrap();
+        socket_a.push_on(2, b"snd b").await.unwrap();
+
+        let (msg, snd) = futures::join!(socket_b.poll_on(1), socket_b.poll_on(2));
+        // concurrent pollers get messages of their own streams
+        assert_eq!(msg.unwrap().as_slice(), TEST_STRING.as_bytes());
+        assert_eq!(snd.unwrap().as_slice(), b"snd a");
+        // messages of other streams are kept in order
+        assert_eq!(socket_b.poll_on(2).await.unwrap().as_slice(), b"snd b");
+    }
+
+    #[async_std::test]
+    async fn reliable_streams_work() {
+        let network = sim_network(Impairment::default());
+        let (socket_a, socket_b) = sim_pair(&network, vec![1]).await;
+
+        for c in 0..4u8 {
+            socket_a.push_on(1, &[c]).await.unwrap();
+        }
+        socket_a.push_on(2, b"snd").await.unwrap();
+
+        for c in 0..4u8 {
+            // reliable stream delivers messages in order
+            assert_eq!(socket_b.poll_on(1).await.unwrap(), vec![c]);
+        }
+        // best-effort stream works alongside the reliable one
+        assert_eq!(socket_b.poll_on(2).await.unwrap().as_slice(), b"snd");
+
+        socket_b.push_on(1, TEST_STRING.as_ref()).await.unwrap();
+        let res = socket_a.poll_on(1).await.unwrap();
+        // acknowledgements are consumed by the poller
+        assert_eq!(res.as_slice(), TEST_STRING.as_bytes());
+    }
+
+    #[async_std::test]
+    #[cfg_attr(feature = "ci", ignore)]
+    async fn quic_works() {
+        let socket_a = QuicSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            vec![1],
+            TEST_SERVER_NAME.to_owned(),
+            Arc::new(SOCKET_CFG),
+        )
+        .await
+        .unwrap();
+        let socket_b = QuicSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            vec![1],
+            TEST_SERVER_NAME.to_owned(),
+            Arc::new(SOCKET_CFG),
+        )
+        .await
+        .unwrap();
+
+        let addr_a = [socket_b.get_lan_ip().await.unwrap()];
+        let addr_b = [socket_a.get_lan_ip().await.unwrap()];
+        // peers refuse to connect with no certificate pinned
+        assert!(socket_a.bind(&addr_a).await.is_err());
+
+        socket_a.pin_cert(socket_b.cert_hash().unwrap());
+        socket_b.pin_cert(socket_a.cert_hash().unwrap());
+        let handle = futures::try_join!(socket_a.bind(&addr_a), socket_b.bind(&addr_b));
+        // peers connect to each other with pinned self-signed certificates
+        assert!(handle.is_ok());
+
+        let large = (0..4096).map(|c| c as u8).collect::<Vec<_>>();
+        for c in 0..4u8 {
+            socket_a.push_on(1, &[c]).await.unwrap();
+        }
+        socket_a.push_on(1, &large).await.unwrap();
+        socket_a.push_on(2, &large).await.unwrap();
+        socket_a.push(TEST_STRING.as_ref()).await.unwrap();
+
+        for c in 0..4u8 {
+            // reliable stream delivers messages in order
+            assert_eq!(socket_b.poll_on(1).await.unwrap(), vec![c]);
+        }
+        assert_eq!(socket_b.poll_on(1).await.unwrap(), large);
+        // datagrams larger than path MTU are fragmented
+        assert_eq!(socket_b.poll_on(2).await.unwrap(), large);
+        assert_eq!(socket_b.peek().await.unwrap(), TEST_STRING.as_bytes());
+        assert_eq!(socket_b.poll().await.unwrap(), TEST_STRING.as_bytes());
+
+        socket_b.push_on(1, TEST_STRING.as_ref()).await.unwrap();
+        // connection works both ways
+        assert_eq!(socket_a.poll_on(1).await.unwrap(), TEST_STRING.as_bytes());
+    }
+
+    #[async_std::test]
+    async fn quic_pinning_works() {
+        let new = || {
+            QuicSocketHandle::new(
+                vec![SocketAddr::new(LOOPBACK_IP, 0)],
+                None,
+                None,
+                vec![],
+                TEST_SERVER_NAME.to_owned(),
+                Arc::new(SOCKET_CFG),
+            )
+        };
+        let (socket_a, socket_b) = (new().await.unwrap(), new().await.unwrap());
+        let addr_a = [socket_b.get_lan_ip().await.unwrap()];
+        let addr_b = [socket_a.get_lan_ip().await.unwrap()];
+
+        socket_a.pin_cert(socket_b.cert_hash().unwrap());
+        socket_b.pin_cert(socket_b.cert_hash().unwrap());
+        let handle = futures::join!(socket_a.bind(&addr_a), socket_b.bind(&addr_b));
+        // a peer presenting a certificate other than the pinned one is refused
+        assert!(handle.0.is_err() && handle.1.is_err());
+    }
+
+    #[async_std::test]
+    #[cfg_attr(feature = "ci", ignore)]
+    async fn webrtc_works() {
+        let socket_a = WebRtcSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            vec![1],
+            TEST_LABEL.to_owned(),
+            Arc::new(SOCKET_CFG),
+        )
+        .await
+        .unwrap();
+        let socket_b = WebRtcSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            vec![1],
+            TEST_LABEL.to_owned(),
+            Arc::new(SOCKET_CFG),
+        )
+        .await
+        .unwrap();
+
+        let addr_a = [socket_b.get_lan_ip().await.unwrap()];
+        let addr_b = [socket_a.get_lan_ip().await.unwrap()];
+        let handle = futures::try_join!(socket_a.bind(&addr_a), socket_b.bind(&addr_b));
+        // peers agree on roles and open both data channels
+        assert!(handle.is_ok());
+
+        let large = (0..4096).map(|c| c as u8).collect::<Vec<_>>();
+        for c in 0..4u8 {
+            socket_a.push_on(1, &[c]).await.unwrap();
+        }
+        socket_a.push_on(1, &large).await.unwrap();
+        socket_a.push_on(2, TEST_STRING.as_ref()).await.unwrap();
+        socket_a.push(TEST_STRING.as_ref()).await.unwrap();
+
+        for c in 0..4u8 {
+            // reliable channel delivers messages in order
+            assert_eq!(socket_b.poll_on(1).await.unwrap(), vec![c]);
+        }
+        assert_eq!(socket_b.poll_on(1).await.unwrap(), large);
+        assert_eq!(socket_b.poll_on(2).await.unwrap(), TEST_STRING.as_bytes());
+        assert_eq!(socket_b.peek().await.unwrap(), TEST_STRING.as_bytes());
+        assert_eq!(socket_b.poll().await.unwrap(), TEST_STRING.as_bytes());
+
+        socket_b.push_on(1, TEST_STRING.as_ref()).await.unwrap();
+        // session works both ways
+        assert_eq!(socket_a.poll_on(1).await.unwrap(), TEST_STRING.as_bytes());
+    }
+
+    #[async_std::test]
+    async fn turn_works() {
+        let server = TurnServer::spawn().await;
+        let relay_a = turn::tests::allocate(&server).await;
+        let relay_b = turn::tests::allocate(&server).await;
+        let addr_a = [relay_b.relayed_addr()];
+        let addr_b = [relay_a.relayed_addr()];
+        futures::try_join!(relay_a.permit(&addr_a), relay_b.permit(&addr_b)).unwrap();
+
+        let socket_a =
+            UdpSocketHandle::relayed(relay_a, None, None, vec![1], Arc::new(SOCKET_CFG)).unwrap();
+        let socket_b =
+            UdpSocketHandle::relayed(relay_b, None, None, vec![1], Arc::new(SOCKET_CFG)).unwrap();
+
+        let handle = futures::try_join!(
+            socket_a.try_nat_tr(
+                &addr_a,
+                SOCKET_CFG.retries,
+                SOCKET_CFG.timeout,
+                RATE,
+                TEST_SECRET
+            ),
+            socket_b.try_nat_tr(
+                &addr_b,
+                SOCKET_CFG.retries,
+                SOCKET_CFG.timeout,
+                RATE,
+                TEST_SECRET
+            )
+        );
+        // hole punching stages pass through both relays
+        assert!(handle.is_ok());
+        socket_a.bind(&addr_a).await.unwrap();
+        socket_b.bind(&addr_b).await.unwrap();
+        // peers see each other by relayed addresses
+        assert_eq!(socket_a.peer().await.unwrap(), addr_a[0]);
+        assert_eq!(socket_a.get_wan_ip().await.unwrap(), addr_b[0]);
+
+        let large = (0..4096).map(|c| c as u8).collect::<Vec<_>>();
+        socket_a.push_on(1, &large).await.unwrap();
+        socket_a.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket_b.poll_on(1).await.unwrap(), large);
+        assert_eq!(socket_b.poll().await.unwrap(), TEST_STRING.as_bytes());
+    }
+
+    #[async_std::test]
+    async fn ice_works() {
+        let socket_a = UdpSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            vec![1],
+            Arc::new(SOCKET_CFG),
+        )
+        .await
+        .unwrap();
+        let socket_b = UdpSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            vec![1],
+            Arc::new(SOCKET_CFG),
+        )
+        .await
+        .unwrap();
+        let addr_a = socket_a.get_lan_ip().await.unwrap();
+        let addr_b = socket_b.get_lan_ip().await.unwrap();
+        let unreachable = SocketAddr::new(LOOPBACK_IP, 9);
+
+        let agent_a = Arc::new(Agent::new(vec![
+            Candidate::new(CandidateKind::Host, addr_a),
+            Candidate::new(CandidateKind::ServerReflexive, unreachable),
+        ]));
+        let agent_b = Arc::new(Agent::new(vec![
+            Candidate::new(CandidateKind::Host, addr_b),
+            Candidate::new(CandidateKind::ServerReflexive, unreachable),
+        ]));
+        let (desc_a, desc_b) = (agent_a.local().clone(), agent_b.local().clone());
+        let bases_a: [&(dyn IOSocket + Sync + Send); 1] = [&socket_a];
+        let bases_b: [&(dyn IOSocket + Sync + Send); 1] = [&socket_b];
+
+        let res = futures::try_join!(
+            agent_a.connect(&desc_b, &bases_a, SOCKET_CFG.retries, SOCKET_CFG.timeout),
+            agent_b.connect(&desc_a, &bases_b, SOCKET_CFG.retries, SOCKET_CFG.timeout)
+        )
+        .unwrap();
+        // peers nominate the same working pair
+        assert_eq!(res, ((0, addr_b), (0, addr_a)));
+
+        socket_a.bind(&[addr_b]).await.unwrap();
+        socket_b.bind(&[addr_a]).await.unwrap();
+        socket_a.push_on(1, TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket_b.poll_on(1).await.unwrap(), TEST_STRING.as_bytes());
+    }
+
+    #[async_std::test]
+    async fn ice_relay_works() {
+        let server = TurnServer::spawn().await;
+        let relay = turn::tests::allocate(&server).await;
+        let relay_ip = relay.relayed_addr();
+
+        let socket_a = UdpSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            vec![],
+            Arc::new(SOCKET_CFG),
+        )
+        .await
+        .unwrap();
+        let socket_b = UdpSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            vec![],
+            Arc::new(SOCKET_CFG),
+        )
+        .await
+        .unwrap();
+        let addr_b = socket_b.get_lan_ip().await.unwrap();
+        relay.permit(&[addr_b]).await.unwrap();
+        let relayed_a =
+            UdpSocketHandle::relayed(relay, None, None, vec![], Arc::new(SOCKET_CFG)).unwrap();
+
+        // only the relayed candidate is advertised by the first peer
+        let agent_a = Arc::new(Agent::new(vec![Candidate::new(
+            CandidateKind::Relayed,
+            relay_ip,
+        )]));
+        let agent_b = Arc::new(Agent::new(vec![Candidate::new(
+            CandidateKind::Host,
+            addr_b,
+        )]));
+        let (desc_a, desc_b) = (agent_a.local().clone(), agent_b.local().clone());
+        let bases_a: [&(dyn IOSocket + Sync + Send); 2] = [&socket_a, &relayed_a];
+        let bases_b: [&(dyn IOSocket + Sync + Send); 1] = [&socket_b];
+
+        let res = futures::try_join!(
+            agent_a.connect(&desc_b, &bases_a, SOCKET_CFG.retries, SOCKET_CFG.timeout),
+            agent_b.connect(&desc_a, &bases_b, SOCKET_CFG.retries, SOCKET_CFG.timeout)
+        )
+        .unwrap();
+        // checks pass through the relay
+        assert_eq!(res, ((RELAYED_BASE, addr_b), (0, relay_ip)));
+
+        relayed_a.bind(&[addr_b]).await.unwrap();
+        socket_b.bind(&[relay_ip]).await.unwrap();
+        socket_b.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(relayed_a.poll().await.unwrap(), TEST_STRING.as_bytes());
+    }
+
+    #[async_std::test]
+    #[cfg_attr(feature = "ci", ignore)]
+    async fn tcp_works() {
+        let socket_a = TcpSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            TcpRole::Listen,
+            Arc::new(SOCKET_CFG),
+        )
+        .await
+        .unwrap();
+        let socket_b = TcpSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            TcpRole::Connect,
+            Arc::new(SOCKET_CFG),
+        )
+        .await
+        .unwrap();
+
+        let addr_a = [socket_b.get_lan_ip().await.unwrap()];
+        let addr_b = [socket_a.get_lan_ip().await.unwrap()];
+        let handle = futures::try_join!(socket_a.bind(&addr_a), socket_b.bind(&addr_b));
+        // listening and connecting roles meet
+        assert!(handle.is_ok());
+
+        let large = (0..FRAME_SIZE * 2).map(|c| c as u8).collect::<Vec<_>>();
+        socket_b.push_on(2, &large).await.unwrap();
+        socket_b.push_on(1, TEST_STRING.as_ref()).await.unwrap();
+
+        let (msg, snd) = futures::join!(socket_a.poll_on(1), socket_a.poll_on(2));
+        // messages of any size are framed and demultiplexed
+        assert_eq!(msg.unwrap().as_slice(), TEST_STRING.as_bytes());
+        assert_eq!(snd.unwrap(), large);
+    }
+
+    #[async_std::test]
+    #[cfg_attr(feature = "ci", ignore)]
+    async fn tcp_punch_works() {
+        // connecting to own port needs a free one known upfront
+        let addr = std::net::TcpListener::bind(SocketAddr::new(LOOPBACK_IP, 0))
+            .and_then(|c| c.local_addr())
+            .unwrap();
+        let socket =
+            TcpSocketHandle::new(vec![addr], None, None, TcpRole::Punch, Arc::new(SOCKET_CFG))
+                .await
+                .unwrap();
+
+        let res = socket.bind(&[addr]).await;
+        // connecting to own port is a simultaneous open
+        assert!(res.is_ok());
+
+        socket.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket.poll().await.unwrap(), TEST_STRING.as_bytes());
+    }
+
+    #[async_std::test]
+    async fn stun_works() {
+        let cfg = StunServerConfig::new(SocketAddr::new(LOOPBACK_IP, 0), None, None);
+        let server = StunServer::bind(cfg).await.unwrap();
+        let server_addr = server.local_addr().unwrap().to_string();
+        async_std::task::spawn(async move { server.run().await });
+
+        let stun = StunConfig::new(vec![server_addr], 1, false, STUN_ADDRESS.to_owned());
+        let socket = SocketHandle::new(
+            Client::UDP {
+                addr: ClientAddress::Single(SocketAddr::new(LOOPBACK_IP, 0)),
+                ttl: None,
+                sw_tag: None,
+                reliable: None,
+            },
+            SOCKET_CFG.with_stun(stun).with_secret(TEST_SECRET),
+            None,
+        )
+        .await
+        .unwrap();
+        // built-in server reports the address of the socket
+        assert!(!socket.is_lan_only());
+        assert_eq!(socket.pub_ip, socket.loc_ip);
+    }
+
+    #[async_std::test]
+    async fn signaled_bind_works() {
+        let cfg = SignalServerConfig::new(SocketAddr::new(LOOPBACK_IP, 0));
+        let server = SignalServer::bind(cfg).await.unwrap();
+        let signal = SignalConfig::new(server.local_addr().unwrap().to_string(), "room".into(), 5);
+        async_std::task::spawn(async move { server.run().await });
+
+        // no `STUN` servers keep sockets LAN-only
+        let stun = StunConfig::new(vec![], 1, false, STUN_ADDRESS.to_owned());
+        let socket = || {
+            SocketHandle::new(
+                Client::UDP {
+                    addr: ClientAddress::Single(SocketAddr::new(LOOPBACK_IP, 0)),
+                    ttl: None,
+                    sw_tag: None,
+                    reliable: None,
+                },
+                SOCKET_CFG.with_stun(stun.clone()).with_secret(TEST_SECRET),
+                None,
+            )
+        };
+        let (socket_a, socket_b) = futures::try_join!(socket(), socket()).unwrap();
+
+        let handle = futures::try_join!(
+            socket_a.bind_signaled(&signal),
+            socket_b.bind_signaled(&signal)
+        );
+        // peers find each other through the signaling server
+        assert!(handle.is_ok());
+
+        socket_a.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket_b.poll().await.unwrap(), TEST_STRING.as_bytes());
+        assert_eq!(socket_b.peer().await.unwrap(), socket_a.loc_ip);
+    }
+
+    #[async_std::test]
+    async fn keepalive_works() {
+        let ms = Duration::from_millis;
+        let network = sim_network(Impairment::new(0.0, ms(1), ms(0), 0.0, 0.0, 0));
+        let (socket_a, socket_b) = futures::try_join!(
+            sim_socket(&network, SIM_IP_A, None),
+            sim_socket(&network, SIM_IP_B, None)
+        )
+        .unwrap();
+        let (socket_a, socket_b) = (Arc::new(socket_a), Arc::new(socket_b));
+        futures::try_join!(
+            socket_a.bind(&socket_b.loc_ip),
+            socket_b.bind(&socket_a.loc_ip)
+        )
+        .unwrap();
+
+        let cfg = KeepaliveConfig::new(ms(20), 3);
+        let (states, events) = (socket_a.peer_states(), socket_a.stats_events());
+        socket_a.keepalive(&cfg, TEST_SECRET);
+        socket_b.keepalive(&cfg, TEST_SECRET);
+        let probes = async {
+            // twice as many keepalives as may be missed come in over more than the limit
+            while events.recv().await.unwrap().probes < 6 {}
+        };
+        async_std::future::timeout(Duration::from_secs(5), probes)
+            .await
+            .unwrap();
+        // peers keep each other alive
+        assert_eq!(socket_a.peer_state(), PeerState::Alive);
+        assert!(states.is_empty());
+
+        drop(socket_b);
+        let res = async_std::future::timeout(Duration::from_secs(5), states.recv()).await;
+        // silent peer gets lost, failing reads instead of blocking them
+        assert_eq!(res.unwrap().unwrap(), PeerState::Lost);
+        let res = async_std::future::timeout(Duration::from_secs(1), socket_a.poll()).await;
+        assert!(res.unwrap().is_err());
+        assert!(socket_a.stream(1).poll().await.is_err());
+    }
+
+    #[async_std::test]
+    async fn stats_works() {
+        let ms = Duration::from_millis;
+        let network = sim_network(Impairment::new(0.0, ms(5), ms(0), 0.0, 0.0, 0));
+        let (socket_a, socket_b) = futures::try_join!(
+            sim_socket(&network, SIM_IP_A, None),
+            sim_socket(&network, SIM_IP_B, None)
+        )
+        .unwrap();
+        let (socket_a, socket_b) = (Arc::new(socket_a), Arc::new(socket_b));
+        futures::try_join!(
+            socket_a.bind(&socket_b.loc_ip),
+            socket_b.bind(&socket_a.loc_ip)
+        )
+        .unwrap();
+
+        let events = socket_a.stats_events();
+        let cfg = KeepaliveConfig::new(ms(20), 3);
+        socket_a.keepalive(&cfg, TEST_SECRET);
+        socket_b.keepalive(&cfg, TEST_SECRET);
+        for _ in 0..4 {
+            socket_a.stream(2).push(b"Hello").await.unwrap();
+            socket_b.stream(2).poll().await.unwrap();
+        }
+        let measured = async {
+            loop {
+                let stats = events.recv().await.unwrap();
+                if stats.rtt.is_some() && stats.probes > 1 {
+                    break stats;
+                }
+            }
+        };
+        // snapshots are published periodically
+        let stats_a = async_std::future::timeout(Duration::from_secs(5), measured)
+            .await
+            .unwrap();
+
+        let stats_b = socket_b.stats();
+        // peers measure the round trip and loss over keepalives
+        assert!(stats_a.rtt.is_some_and(|c| c > ms(5) && c < ms(100)));
+        assert_eq!((stats_a.lost, stats_a.reordered), (0, 0));
+        // messages are counted both ways, keepalives included
+        assert!(stats_a.packets_sent > 4 && stats_a.bytes_sent > 20);
+        assert!(stats_b.packets_received > 4 && stats_b.bytes_received > 20);
+    }
+
+    /// LAN-only `UDP` socket on ephemeral loopback port.
+    async fn lan_socket() -> HowlerResult<SocketHandle> {
+        let stun = StunConfig::new(vec![], 1, false, STUN_ADDRESS.to_owned());
+        SocketHandle::new(
+            Client::UDP {
+                addr: ClientAddress::Single(SocketAddr::new(LOOPBACK_IP, 0)),
+                ttl: None,
+                sw_tag: None,
+                reliable: None,
+            },
+            SOCKET_CFG.with_stun(stun).with_secret(TEST_SECRET),
+            None,
+        )
+        .await
+    }
+
+    /// Forwards datagrams of `from` to `to`, standing for one direction of `NAT` mapping.
+    fn forward(from: Arc<UdpSocket>, to: Arc<std::sync::Mutex<Arc<UdpSocket>>>, dst: SocketAddr) {
+        async_std::task::spawn(async move {
+            let mut buf = [0; 2048];
+            while let Ok((len, _)) = from.recv_from(&mut buf).await {
+                let to = to.lock().unwrap().clone();
+                let _ = to.send_to(&buf[..len], dst).await;
+            }
+        });
+    }
+
+    #[async_std::test]
+    async fn roaming_works() {
+        let (socket_a, socket_b) = futures::try_join!(lan_socket(), lan_socket()).unwrap();
+        let (socket_a, socket_b) = (Arc::new(socket_a), Arc::new(socket_b));
+
+        // `NAT` in front of b, mapping it to `outer` as seen by a
+        let bind = || UdpSocket::bind(SocketAddr::new(LOOPBACK_IP, 0));
+        let (inner, outer) = futures::try_join!(bind(), bind()).unwrap();
+        let (inner, outer) = (Arc::new(inner), Arc::new(outer));
+        let mapped = Arc::new(std::sync::Mutex::new(outer.clone()));
+        forward(inner.clone(), mapped.clone(), socket_a.loc_ip);
+        forward(
+            outer.clone(),
+            Arc::new(std::sync::Mutex::new(inner.clone())),
+            socket_b.loc_ip,
+        );
+
+        let (outer_ip, inner_ip) = (outer.local_addr().unwrap(), inner.local_addr().unwrap());
+        futures::try_join!(socket_a.bind(&outer_ip), socket_b.bind(&inner_ip)).unwrap();
+        let cfg = KeepaliveConfig::new(Duration::from_millis(20), 10);
+        socket_a.keepalive(&cfg, b"secret");
+        socket_b.keepalive(&cfg, b"secret");
+
+        let rogue = Arc::new(lan_socket().await.unwrap());
+        rogue.io().bind(&[socket_a.loc_ip]).await.unwrap();
+        rogue.keepalive(&cfg, b"guess");
+        async_std::task::sleep(Duration::from_millis(200)).await;
+        // keepalives under another secret don't move the peer
+        assert_eq!(socket_a.peer().await.unwrap(), outer_ip);
+
+        let moved = Arc::new(bind().await.unwrap());
+        forward(
+            moved.clone(),
+            Arc::new(std::sync::Mutex::new(inner.clone())),
+            socket_b.loc_ip,
+        );
+        *mapped.lock().unwrap() = moved.clone();
+        async_std::task::sleep(Duration::from_millis(200)).await;
+        // peer is followed to its new mapping and traffic flows both ways
+        assert_eq!(socket_a.peer().await.unwrap(), moved.local_addr().unwrap());
+        assert_eq!(socket_a.peer_state(), PeerState::Alive);
+        socket_b.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket_a.poll().await.unwrap(), TEST_STRING.as_bytes());
+        socket_a.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket_b.poll().await.unwrap(), TEST_STRING.as_bytes());
+    }
+
+    /// `NAT` in front of clients, mapping each of them to a new public port per
+    /// destination and letting in datagrams of that destination only.
+    ///
+    /// Clients reach `dest[i]` at `inner[i]`, a socket of the `NAT` standing for it. Public
+    /// ports come from `alloc`, skipping those taken.
+    struct Nat {
+        inner: Vec<SocketAddr>,
+    }
+
+    impl Nat {
+        async fn spawn<F>(dest: Vec<SocketAddr>, alloc: F) -> Nat
+        where
+            F: FnMut() -> u16 + Send + 'static,
+        {
+            let alloc = Arc::new(std::sync::Mutex::new(alloc));
+            let mut inner = vec![];
+            for dest in dest {
+                let socket = Arc::new(UdpSocket::bind((LOOPBACK_IP, 0)).await.unwrap());
+                inner.push(socket.local_addr().unwrap());
+                let alloc = alloc.clone();
+                async_std::task::spawn(async move {
+                    let mut mappings = HashMap::new();
+                    let mut buf = [0; 2048];
+                    while let Ok((len, client)) = socket.recv_from(&mut buf).await {
+                        let mapping = match mappings.get(&client) {
+                            Some(mapping) => Arc::clone(mapping),
+                            None => {
+                                let mapping = loop {
+                                    let port = (*alloc.lock().unwrap())();
+                                    if let Ok(c) = UdpSocket::bind((LOOPBACK_IP, port)).await {
+                                        break Arc::new(c);
+                                    }
+                                };
+                                mappings.insert(client, mapping.clone());
+                                let (back, socket) = (mapping.clone(), socket.clone());
+                                async_std::task::spawn(async move {
+                                    let mut buf = [0; 2048];
+                                    while let Ok((len, from)) = back.recv_from(&mut buf).await {
+                                        if from == dest {
+                                            let _ = socket.send_to(&buf[..len], client).await;
+                                        }
+                                    }
+                                });
+                                mapping
+                            }
+                        };
+                        let _ = mapping.send_to(&buf[..len], dest).await;
+                    }
+                });
+            }
+            Nat { inner }
+        }
+    }
+
+    #[async_std::test]
+    async fn prediction_works() {
+        let servers = futures::join!(
+            nat::tests::StunServer::spawn(None),
+            nat::tests::StunServer::spawn(None),
+            nat::tests::StunServer::spawn(None)
+        );
+        let socket_b = lan_socket().await.unwrap();
+        let dest = vec![
+            servers.0.addr(),
+            servers.1.addr(),
+            servers.2.addr(),
+            socket_b.loc_ip,
+        ];
+        // ports go up by two from below ephemeral ones
+        let mut port = rand::thread_rng().gen_range(20000..30000);
+        let nat = Nat::spawn(dest, move || {
+            port += 2;
+            port
+        })
+        .await;
+
+        let servers = nat.inner[..3].iter().map(ToString::to_string).collect();
+        let stun = StunConfig::new(servers, 1, false, STUN_ADDRESS.to_owned());
+        let socket_a = SocketHandle::new(
+            Client::UDP {
+                addr: ClientAddress::Single(SocketAddr::new(LOOPBACK_IP, 0)),
+                ttl: None,
+                sw_tag: None,
+                reliable: None,
+            },
+            SOCKET_CFG.with_stun(stun).with_secret(TEST_SECRET),
+            None,
+        )
+        .await
+        .unwrap();
+        let predicted = socket_a.predict().await.unwrap();
+        // mappings toward the next peers differ from the one seen by `STUN`
+        assert!(!predicted.contains(&socket_a.pub_ip));
+
+        let addr = predicted.as_slice();
+        futures::try_join!(socket_a.bind(&nat.inner[3]), socket_b.bind(&addr)).unwrap();
+        // peer meets the predicted mapping and traffic flows both ways
+        assert!(predicted.contains(&socket_b.peer().await.unwrap()));
+        socket_b.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket_a.poll().await.unwrap(), TEST_STRING.as_bytes());
+        socket_a.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket_b.poll().await.unwrap(), TEST_STRING.as_bytes());
+    }
+
+    #[async_std::test]
+    #[cfg_attr(feature = "ci", ignore)]
+    async fn birthday_works() {
+        // `NAT` allocates random ports of a block above ephemeral ones
+        const BLOCK: (u16, u16) = (61000, 61511);
+        let (socket_a, socket_b) = futures::try_join!(lan_socket(), lan_socket()).unwrap();
+        let nat = Nat::spawn(vec![socket_b.loc_ip], || {
+            rand::thread_rng().gen_range(BLOCK.0..=BLOCK.1)
+        })
+        .await;
+
+        // half of the block is sprayed, so one of mappings misses it by 2^-16 chance
+        let punch_a = PunchConfig::new(3, 8, 16, 0, BLOCK, 5000);
+        let punch_b = PunchConfig::new(3, 8, 0, 256, BLOCK, 5000);
+        let addr = [socket_a.pub_ip];
+        futures::try_join!(
+            socket_a.bind_birthday(&punch_a, &nat.inner),
+            socket_b.bind_birthday(&punch_b, &addr)
+        )
+        .unwrap();
+        // mapping met by sprayed ports carries traffic both ways
+        assert!(socket_a.is_fallback());
+        socket_b.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket_a.poll().await.unwrap(), TEST_STRING.as_bytes());
+        socket_a.push(TEST_STRING.as_ref()).await.unwrap();
+        assert_eq!(socket_b.poll().await.unwrap(), TEST_STRING.as_bytes());
+    }
+
+    /// Simulated `UDP` socket on `network` behind the `NAT` at `nat`, with stream 1 being
+    /// reliable.
+    async fn sim_socket(
+        network: &Network,
+        ip: IpAddr,
+        nat: Option<IpAddr>,
+    ) -> HowlerResult<SocketHandle> {
+        let servers = SIM_STUN.iter().map(|c| c.to_string()).collect();
+        let stun = StunConfig::new(servers, 1, false, STUN_ADDRESS.to_owned());
+        SocketHandle::simulated(
+            Client::UDP {
+                addr: ClientAddress::Single(SocketAddr::new(ip, 0)),
+                ttl: None,
+                sw_tag: None,
+                reliable: Some(vec![1]),
+            },
+            SOCKET_CFG.with_stun(stun).with_secret(TEST_SECRET),
+            network,
+            nat,
+        )
+        .await
+    }
+
+    /// Simulated network with `STUN` servers, impairing datagrams as `cfg` sets.
+    fn sim_network(cfg: Impairment) -> Network {
+        let network = Network::new(cfg, 1);
+        for c in SIM_STUN {
+            network.stun(c.parse().unwrap());
+        }
+        network
+    }
+
+    /// Pair of simulated `UDP` sockets on `network` bound to each other, with `reliable`
+    /// streams.
+    async fn sim_pair(
+        network: &Network,
+        reliable: Vec<u8>,
+    ) -> (
+        Box<dyn IOSocket + Sync + Send>,
+        Box<dyn IOSocket + Sync + Send>,
+    ) {
+        let udp = |ip| {
+            let addr = vec![SocketAddr::new(ip, 0)];
+            let cfg = Arc::new(SOCKET_CFG);
+            get_simulated_socket(network, None, addr, None, None, reliable.clone(), cfg)
+        };
+        let (socket_a, socket_b) = (udp(SIM_IP_A).unwrap(), udp(SIM_IP_B).unwrap());
+        let addr_a = socket_a.get_lan_ip().await.unwrap();
+        let addr_b = socket_b.get_lan_ip().await.unwrap();
+        socket_a.bind(&[addr_b]).await.unwrap();
+        socket_b.bind(&[addr_a]).await.unwrap();
+        (socket_a, socket_b)
+    }
+
+    #[async_std::test]
+    async fn simulated_traversal_works() {
+        use NatBehavior::*;
+
+        let ms = Duration::from_millis;
+        let network = sim_network(Impairment::new(0.2, ms(5), ms(5), 0.1, 0.1, 0));
+        network.nat(SIM_NAT_A, EndpointIndependent, AddressAndPortDependent);
+        network.nat(SIM_NAT_B, EndpointIndependent, AddressDependent);
+        let (socket_a, socket_b) = futures::try_join!(
+            sim_socket(&network, SIM_IP_A, Some(SIM_NAT_A)),
+            sim_socket(&network, SIM_IP_B, Some(SIM_NAT_B))
+        )
+        .unwrap();
+        // public addresses are learned from `STUN` through `NAT`s
+        assert_eq!(socket_a.pub_ip.ip(), SIM_NAT_A);
+        assert_eq!(socket_b.pub_ip.ip(), SIM_NAT_B);
+
+        futures::try_join!(
+            socket_a.bind(&socket_b.pub_ip),
+            socket_b.bind(&socket_a.pub_ip)
+        )
+        .unwrap();
+        // hole punching gets through cone `NAT`s over lossy network
+        assert_eq!(socket_a.peer().await.unwrap(), socket_b.pub_ip);
+        assert_eq!(socket_b.peer().await.unwrap(), socket_a.pub_ip);
+        assert!(!socket_a.is_fallback());
+
+        network.impair(Impairment::new(0.0, ms(5), ms(5), 0.3, 0.0, 0));
+        let msg = (0..4000).map(|c| c as u8).collect::<Vec<_>>();
+        socket_a.push(&msg).await.unwrap();
+        // fragments of a message are put back together out of order
+        assert_eq!(socket_b.poll().await.unwrap(), msg);
+    }
+
+    #[async_std::test]
+    async fn simulated_reliable_works() {
+        let ms = Duration::from_millis;
+        let network = sim_network(Impairment::default());
+        let (socket_a, socket_b) = futures::try_join!(
+            sim_socket(&network, SIM_IP_A, None),
+            sim_socket(&network, SIM_IP_B, None)
+        )
+        .unwrap();
+        futures::try_join!(
+            socket_a.bind(&socket_b.pub_ip),
+            socket_b.bind(&socket_a.pub_ip)
+        )
+        .unwrap();
+
+        network.impair(Impairment::new(0.3, ms(5), ms(10), 0.2, 0.2, 0));
+        let (socket_a, socket_b) = (Arc::new(socket_a), Arc::new(socket_b));
+        let stream = socket_a.stream(1);
+        // reliable stream is polled by the sender to retransmit
+        let driver = async_std::task::spawn(async move { stream.poll().await });
+        for c in 0..10u8 {
+            socket_a.stream(1).push(&[c; 100]).await.unwrap();
+        }
+        let stream = socket_b.stream(1);
+        for c in 0..10u8 {
+            let res = async_std::future::timeout(Duration::from_secs(10), stream.poll());
+            // messages arrive once and in order despite loss, reordering and duplicates
+            assert_eq!(res.await.unwrap().unwrap(), [c; 100]);
+        }
+        driver.cancel().await;
+    }
+
+    #[async_std::test]
+    async fn simulated_refresh_works() {
+        let network = sim_network(Impairment::default());
+        let (socket_a, socket_b) = futures::try_join!(
+            sim_socket(&network, SIM_IP_A, None),
+            sim_socket(&network, SIM_IP_B, None)
+        )
+        .unwrap();
+        futures::try_join!(
+            socket_a.bind(&socket_b.pub_ip),
+            socket_b.bind(&socket_a.pub_ip)
+        )
+        .unwrap();
+
+        socket_b.push(TEST_STRING.as_ref()).await.unwrap();
+        // responses of `STUN` are told by their requests
+        assert_eq!(socket_a.refresh_pub_ips().await.unwrap(), [socket_a.pub_ip]);
+        let res = async_std::future::timeout(Duration::from_secs(1), socket_a.poll());
+        // while the message of the peer read along the way is kept for its stream
+        assert_eq!(res.await.unwrap().unwrap(), TEST_STRING.as_bytes());
+    }
+
+    #[async_std::test]
+    async fn simulated_rebinding_works() {
+        use NatBehavior::*;
+
+        let ms = Duration::from_millis;
+        let network = sim_network(Impairment::default());
+        network.nat(SIM_NAT_A, EndpointIndependent, AddressAndPortDependent);
+        network.nat(SIM_NAT_B, EndpointIndependent, AddressDependent);
+        let (socket_a, socket_b) = futures::try_join!(
+            sim_socket(&network, SIM_IP_A, Some(SIM_NAT_A)),
+            sim_socket(&network, SIM_IP_B, Some(SIM_NAT_B))
+        )
+        .unwrap();
+        let (socket_a, socket_b) = (Arc::new(socket_a), Arc::new(socket_b));
+        futures::try_join!(
+            socket_a.bind(&socket_b.pub_ip),
+            socket_b.bind(&socket_a.pub_ip)
+        )
+        .unwrap();
+        let cfg = KeepaliveConfig::new(ms(20), 5);
+        socket_a.keepalive(&cfg, TEST_SECRET);
+        socket_b.keepalive(&cfg, TEST_SECRET);
+
+        // `NAT` of `A` maps it to another port while peers can't reach each other
+        let (a, b) = (&socket_a, &socket_b);
+        let until = |state: PeerState| async move {
+            while a.peer_state() != state || b.peer_state() != state {
+                async_std::task::sleep(ms(10)).await;
+            }
+        };
+        network.impair(Impairment::new(1.0, ms(0), ms(0), 0.0, 0.0, 0));
+        until(PeerState::Lost).await;
+        network.rebind(SIM_NAT_A);
+        network.impair(Impairment::default());
+
+        // peers punch holes to each other again and get alive
+        let res = async_std::future::timeout(Duration::from_secs(5), until(PeerState::Alive));
+        res.await.unwrap();
+        let (stream_a, stream_b) = (socket_a.stream(2), socket_b.stream(2));
+        stream_b.push(TEST_STRING.as_ref()).await.unwrap();
+        let res = async_std::future::timeout(Duration::from_secs(1), stream_a.poll());
+        assert_eq!(res.await.unwrap().unwrap(), TEST_STRING.as_bytes());
+        stream_a.push(TEST_STRING.as_ref()).await.unwrap();
+        let res = async_std::future::timeout(Duration::from_secs(1), stream_b.poll());
+        assert_eq!(res.await.unwrap().unwrap(), TEST_STRING.as_bytes());
+    }
+
+    #[async_std::test]
+    async fn simulated_symmetric_fails() {
+        use NatBehavior::*;
+
+        let network = sim_network(Impairment::default());
+        network.nat(SIM_NAT_A, AddressAndPortDependent, AddressAndPortDependent);
+        network.nat(SIM_NAT_B, AddressAndPortDependent, AddressAndPortDependent);
+        let (socket_a, socket_b) = futures::try_join!(
+            sim_socket(&network, SIM_IP_A, Some(SIM_NAT_A)),
+            sim_socket(&network, SIM_IP_B, Some(SIM_NAT_B))
+        )
+        .unwrap();
+
+        let (addr_a, addr_b) = ([socket_a.pub_ip], [socket_b.pub_ip]);
+        let timeout = SOCKET_CFG.timeout;
+        let res = futures::join!(
+            socket_a
+                .io()
+                .try_nat_tr(&addr_b, 20, timeout, RATE, TEST_SECRET),
+            socket_b
+                .io()
+                .try_nat_tr(&addr_a, 20, timeout, RATE, TEST_SECRET)
+        );
+        // mappings toward the peer differ from ones `STUN` saw, so holes never meet
+        assert!(res.0.is_err() && res.1.is_err());
+    }
+
+    #[async_std::test]
+    async fn simulated_prediction_works() {
+        use NatBehavior::*;
+
+        let network = sim_network(Impairment::default());
+        network.nat(SIM_NAT_A, AddressAndPortDependent, AddressAndPortDependent);
+        let (socket_a, socket_b) = futures::try_join!(
+            sim_socket(&network, SIM_IP_A, Some(SIM_NAT_A)),
+            sim_socket(&network, SIM_IP_B, None)
+        )
+        .unwrap();
+
+        let predicted = socket_a.predict().await.unwrap();
+        let next = SocketAddr::new(SIM_NAT_A, socket_a.pub_ip.port() + 3);
+        // mappings handed out in sequence are predicted past the sampled ones
+        assert_eq!(predicted[0], next);
 
-        assert!(socket.get_wan_ip().await.is_ok());
+        let predicted = predicted.as_slice();
+        futures::try_join!(socket_a.bind(&socket_b.pub_ip), socket_b.bind(&predicted)).unwrap();
+        // peer punches to the predicted mapping of the symmetric `NAT`
+        assert_eq!(socket_b.peer().await.unwrap(), next);
     }
 }
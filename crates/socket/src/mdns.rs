@@ -0,0 +1,462 @@
+use async_std::{
+    future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+use err::{consts::ERR_ADDRESS, Result};
+use log::{info, trace};
+use serde::Deserialize;
+use socket2::{Domain, Socket, Type};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::ice::route;
+use crate::udp::{canonical, families, unspecified};
+
+/// Multicast group of `mDNS` over IPv4.
+pub const MDNS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+/// Multicast group of `mDNS` over IPv6.
+pub const MDNS_ADDR6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb)),
+    5353,
+);
+/// `DNS-SD` service type of `ensd` peers.
+const SERVICE: &str = "_ensd._udp.local";
+
+const HEADER_SIZE: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Flags of authoritative responses, the only ones `mDNS` responders send.
+const FLAGS_RESPONSE: u16 = 0x8400;
+const RECORD_TTL: u32 = 120;
+/// Compression pointers followed per name before giving up on looping ones.
+const MAX_NAME_JUMPS: usize = 16;
+const RECV_BUF_SIZE: usize = 9000;
+
+/// `MdnsConfig` for `.toml` config parsing.
+/// Sets instance name the socket is advertised under and multicast group it's discovered
+/// through by peers on the local network.
+#[derive(Debug, Deserialize, Clone)]
+pub struct MdnsConfig {
+    /// Instance name shown to peers, random when not set.
+    #[serde(default = "default_name")]
+    name: String,
+    #[serde(default = "default_group")]
+    group: SocketAddr,
+    /// Address of IPv4 interface to join the group on, system's choice when not set.
+    interface: Option<Ipv4Addr>,
+    /// Milliseconds to collect answers for when browsing.
+    #[serde(default = "default_wait")]
+    wait: u64,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        MdnsConfig {
+            name: default_name(),
+            group: default_group(),
+            interface: None,
+            wait: default_wait(),
+        }
+    }
+}
+
+impl MdnsConfig {
+    pub fn new(name: String, group: SocketAddr, interface: Option<Ipv4Addr>, wait: u64) -> Self {
+        MdnsConfig {
+            name,
+            group,
+            interface,
+            wait,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn interface(&self) -> Ipv4Addr {
+        self.interface.unwrap_or(Ipv4Addr::UNSPECIFIED)
+    }
+}
+
+/// `LanPeer` advertising itself on the local network, reached directly at `addr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanPeer {
+    pub name: String,
+    pub addr: Vec<SocketAddr>,
+}
+
+impl fmt::Display for LanPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {:?}", self.name, self.addr)
+    }
+}
+
+/// Record data of interest for `DNS-SD`, others are skipped.
+#[derive(Debug, PartialEq, Eq)]
+enum Data {
+    Ptr(String),
+    Srv(u16, String),
+    Ip(IpAddr),
+    Other,
+}
+
+/// Decoded `DNS` message with questions and records of all sections.
+#[derive(Debug)]
+struct Message {
+    response: bool,
+    questions: Vec<(String, u16)>,
+    records: Vec<(String, Data)>,
+}
+
+/// Addresses of the socket at `loc_ip` peers on the local network reach it at, resolving
+/// the unspecified one to addresses of default multicast interfaces.
+pub(crate) fn host_addrs(loc_ip: SocketAddr) -> Vec<SocketAddr> {
+    let mut res = vec![];
+    for family in families(loc_ip) {
+        let group = match family {
+            IpAddr::V4(_) => MDNS_ADDR,
+            IpAddr::V6(_) => MDNS_ADDR6,
+        };
+        match route(loc_ip, group) {
+            Ok(addr) if !addr.ip().is_unspecified() && !res.contains(&addr) => res.push(addr),
+            Ok(_) => {}
+            Err(e) => trace!("no {family} interface to advertise on: {e}"),
+        }
+    }
+    res
+}
+
+/// Announces `addr` under the name of `cfg`, then answers queries for `ensd` peers until
+/// the socket fails.
+pub(crate) async fn advertise(cfg: &MdnsConfig, addr: &[SocketAddr]) -> Result<()> {
+    if addr.is_empty() {
+        return Err(ERR_ADDRESS.into());
+    }
+    let socket = join(cfg)?;
+    let res = answer(&cfg.name, addr);
+    socket.send_to(&res, cfg.group).await?;
+    info!("advertising '{}' at {:?} on LAN", cfg.name, addr);
+
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf).await?;
+        let Some(msg) = parse(&buf[..len]) else {
+            trace!("dropped malformed mDNS message from {:?}", peer);
+            continue;
+        };
+        let asked = msg.questions.iter().any(|(name, kind)| {
+            name.eq_ignore_ascii_case(SERVICE) && [TYPE_PTR, TYPE_ANY].contains(kind)
+        });
+        if msg.response || !asked {
+            continue;
+        }
+        // queries from ports other than the group one expect unicast answers
+        let dest = match canonical(peer).port() == cfg.group.port() {
+            true => cfg.group,
+            false => peer,
+        };
+        trace!("answering mDNS query of {:?}", peer);
+        socket.send_to(&res, dest).await?;
+    }
+}
+
+/// Queries the group of `cfg` for `ensd` peers, collecting answers other than own ones
+/// for the configured time.
+pub(crate) async fn browse(cfg: &MdnsConfig) -> Result<Vec<LanPeer>> {
+    let socket = Socket::new(Domain::for_address(cfg.group), Type::DGRAM, None)?;
+    match cfg.group {
+        SocketAddr::V4(_) => {
+            socket.set_multicast_if_v4(&cfg.interface())?;
+            socket.set_multicast_loop_v4(true)?;
+        }
+        SocketAddr::V6(_) => socket.set_multicast_loop_v6(true)?,
+    }
+    socket.bind(&SocketAddr::new(unspecified(cfg.group), 0).into())?;
+    let socket = UdpSocket::from(std::net::UdpSocket::from(socket));
+    socket.send_to(&query(), cfg.group).await?;
+
+    let mut res: Vec<LanPeer> = vec![];
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let deadline = Instant::now() + Duration::from_millis(cfg.wait);
+    while let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+        let Ok(recv) = future::timeout(wait, socket.recv_from(&mut buf)).await else {
+            break;
+        };
+        let (len, peer) = recv?;
+        let Some(msg) = parse(&buf[..len]).filter(|c| c.response) else {
+            trace!("dropped mDNS message from {:?} as not an answer", peer);
+            continue;
+        };
+        for peer in peers(&msg) {
+            if peer.name != cfg.name && !res.iter().any(|c| c.name == peer.name) {
+                res.push(peer);
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Binds a socket receiving traffic of the group of `cfg` along with other responders on
+/// the host.
+fn join(cfg: &MdnsConfig) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(cfg.group), Type::DGRAM, None)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    match cfg.group.ip() {
+        IpAddr::V4(group) => {
+            socket.join_multicast_v4(&group, &cfg.interface())?;
+            socket.set_multicast_if_v4(&cfg.interface())?;
+            socket.set_multicast_loop_v4(true)?;
+            socket.set_multicast_ttl_v4(255)?;
+        }
+        IpAddr::V6(group) => {
+            socket.set_only_v6(true)?;
+            socket.join_multicast_v6(&group, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+    }
+    socket.bind(&SocketAddr::new(unspecified(cfg.group), cfg.group.port()).into())?;
+    Ok(UdpSocket::from(std::net::UdpSocket::from(socket)))
+}
+
+/// Query of `PTR` records of the service.
+fn query() -> Vec<u8> {
+    let mut buf = header(0, 1, 0);
+    write_name(&mut buf, SERVICE);
+    buf.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    buf
+}
+
+/// Response describing instance `name` at `addr`, which share the same port.
+fn answer(name: &str, addr: &[SocketAddr]) -> Vec<u8> {
+    let name = name.replace('.', "-");
+    let instance = format!("{name}.{SERVICE}");
+    let host = format!("{name}.local");
+
+    let mut buf = header(FLAGS_RESPONSE, 0, 3 + addr.len() as u16);
+    let mut data = vec![];
+    write_name(&mut data, &instance);
+    write_record(&mut buf, SERVICE, TYPE_PTR, &data);
+
+    let mut data = [0u16, 0, addr[0].port()].map(u16::to_be_bytes).concat();
+    write_name(&mut data, &host);
+    write_record(&mut buf, &instance, TYPE_SRV, &data);
+    write_record(&mut buf, &instance, TYPE_TXT, &[0]);
+
+    for addr in addr {
+        match addr.ip() {
+            IpAddr::V4(ip) => write_record(&mut buf, &host, TYPE_A, &ip.octets()),
+            IpAddr::V6(ip) => write_record(&mut buf, &host, TYPE_AAAA, &ip.octets()),
+        }
+    }
+    buf
+}
+
+/// Peers described by records of `msg`.
+fn peers(msg: &Message) -> Vec<LanPeer> {
+    fn find<'a>(msg: &'a Message, name: &'a str) -> impl Iterator<Item = &'a Data> {
+        msg.records
+            .iter()
+            .filter(move |(c, _)| c.eq_ignore_ascii_case(name))
+            .map(|(_, c)| c)
+    }
+    let mut res = vec![];
+    for data in find(msg, SERVICE) {
+        let Data::Ptr(instance) = data else { continue };
+        let Some(name) = instance
+            .len()
+            .checked_sub(SERVICE.len() + 1)
+            .and_then(|c| Some((instance.get(..c)?, instance.get(c..)?)))
+            .filter(|(_, c)| c.eq_ignore_ascii_case(&format!(".{SERVICE}")))
+            .map(|(c, _)| c.to_owned())
+        else {
+            continue;
+        };
+        let Some((port, host)) = find(msg, instance).find_map(|c| match c {
+            Data::Srv(port, host) => Some((*port, host)),
+            _ => None,
+        }) else {
+            continue;
+        };
+        let addr = find(msg, host)
+            .filter_map(|c| match c {
+                Data::Ip(ip) => Some(SocketAddr::new(*ip, port)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if !addr.is_empty() {
+            res.push(LanPeer { name, addr });
+        }
+    }
+    res
+}
+
+fn header(flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_SIZE);
+    for field in [0, flags, questions, answers, 0, 0] {
+        buf.extend_from_slice(&field.to_be_bytes());
+    }
+    buf
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|c| !c.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+fn write_record(buf: &mut Vec<u8>, name: &str, kind: u16, data: &[u8]) {
+    write_name(buf, name);
+    buf.extend_from_slice(&kind.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    buf.extend_from_slice(&RECORD_TTL.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn parse(buf: &[u8]) -> Option<Message> {
+    let read_u16 = |pos: usize| Some(u16::from_be_bytes(buf.get(pos..pos + 2)?.try_into().ok()?));
+    let flags = read_u16(2)?;
+    let questions = read_u16(4)?;
+    let records = [read_u16(6)?, read_u16(8)?, read_u16(10)?];
+
+    let mut res = Message {
+        response: flags & 0x8000 != 0,
+        questions: vec![],
+        records: vec![],
+    };
+    let mut pos = HEADER_SIZE;
+    for _ in 0..questions {
+        let (name, next) = read_name(buf, pos)?;
+        res.questions.push((name, read_u16(next)?));
+        pos = next + 4;
+    }
+    for _ in 0..records.iter().sum::<u16>() {
+        let (name, next) = read_name(buf, pos)?;
+        let kind = read_u16(next)?;
+        let start = next + 10;
+        let end = start + read_u16(next + 8)? as usize;
+        let data = buf.get(start..end)?;
+        let data = match (kind, data.len()) {
+            (TYPE_PTR, _) => Data::Ptr(read_name(buf, start)?.0),
+            (TYPE_SRV, _) => Data::Srv(read_u16(start + 4)?, read_name(buf, start + 6)?.0),
+            (TYPE_A, 4) => Data::Ip(IpAddr::from(<[u8; 4]>::try_from(data).ok()?)),
+            (TYPE_AAAA, 16) => Data::Ip(IpAddr::from(<[u8; 16]>::try_from(data).ok()?)),
+            _ => Data::Other,
+        };
+        res.records.push((name, data));
+        pos = end;
+    }
+    Some(res)
+}
+
+/// Reads a possibly compressed name at `pos`, returning it with the position past it.
+fn read_name(buf: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut next = None;
+    let mut jumps = 0;
+    loop {
+        let len = *buf.get(pos)? as usize;
+        match len & 0xC0 {
+            _ if len == 0 => break,
+            0xC0 => {
+                jumps += 1;
+                if jumps > MAX_NAME_JUMPS {
+                    return None;
+                }
+                next.get_or_insert(pos + 2);
+                pos = (len & 0x3F) << 8 | *buf.get(pos + 1)? as usize;
+            }
+            0 => {
+                labels.push(String::from_utf8_lossy(buf.get(pos + 1..pos + 1 + len)?).into_owned());
+                pos += 1 + len;
+            }
+            _ => return None,
+        }
+    }
+    Some((labels.join("."), next.unwrap_or(pos + 1)))
+}
+
+fn default_name() -> String {
+    format!("ensd-{:08x}", rand::random::<u32>())
+}
+
+fn default_group() -> SocketAddr {
+    MDNS_ADDR
+}
+
+fn default_wait() -> u64 {
+    1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::task;
+
+    use crate::LOOPBACK_IP;
+
+    const TEST_GROUP: SocketAddr =
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 34353);
+
+    #[test]
+    fn mdns_codec_works() {
+        let addr = [SocketAddr::new(LOOPBACK_IP, 34254)];
+        let msg = parse(&answer("peer.a", &addr)).unwrap();
+        let peer = LanPeer {
+            name: "peer-a".to_owned(),
+            addr: addr.to_vec(),
+        };
+        // answer describes the instance with dots of its name replaced
+        assert!(msg.response);
+        assert_eq!(peers(&msg), vec![peer]);
+
+        let msg = parse(&query()).unwrap();
+        // query asks for peers of the service
+        assert!(!msg.response);
+        assert_eq!(msg.questions, vec![(SERVICE.to_owned(), TYPE_PTR)]);
+
+        let mut buf = header(FLAGS_RESPONSE, 0, 1);
+        write_name(&mut buf, SERVICE);
+        buf.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0, 120, 0, 2, 0xC0, 12]);
+        // compressed names are followed
+        let msg = parse(&buf).unwrap();
+        assert_eq!(msg.records[0].1, Data::Ptr(SERVICE.to_owned()));
+
+        buf.truncate(HEADER_SIZE);
+        buf.extend_from_slice(&[0xC0, 12]);
+        // looping pointers and truncated messages are refused
+        assert!(parse(&buf).is_none());
+        assert!(parse(&answer("peer", &addr)[..40]).is_none());
+    }
+
+    #[async_std::test]
+    async fn mdns_works() {
+        let cfg = |name: &str| {
+            MdnsConfig::new(name.to_owned(), TEST_GROUP, Some(Ipv4Addr::LOCALHOST), 500)
+        };
+        let addr_a = vec![SocketAddr::new(LOOPBACK_IP, 34254)];
+        let addr_b = vec![SocketAddr::new(LOOPBACK_IP, 34250)];
+        task::spawn(async move { advertise(&cfg("peer-a"), &addr_a).await });
+        task::spawn(async move { advertise(&cfg("peer-b"), &addr_b).await });
+        task::sleep(Duration::from_millis(100)).await;
+
+        let res = browse(&cfg("peer-a")).await.unwrap();
+        // peer finds the other one on loopback multicast, skipping itself
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].name, "peer-b");
+        assert_eq!(res[0].addr, vec![SocketAddr::new(LOOPBACK_IP, 34250)]);
+    }
+}
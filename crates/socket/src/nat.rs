@@ -0,0 +1,414 @@
+use async_std::{net::SocketAddr, sync::Arc};
+use bytecodec::{DecodeExt, EncodeExt};
+use err::{consts::ERR_CONNECTION, Result};
+use log::{info, trace};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+use stun_codec::{
+    define_attribute_enums,
+    rfc5389::{
+        attributes::{MappedAddress, Software, XorMappedAddress},
+        methods::BINDING,
+    },
+    rfc5780::attributes::{ChangeRequest, OtherAddress, ResponseOrigin},
+    Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId,
+};
+
+use crate::ice::route;
+use crate::{IOSocket, StunHandler};
+
+/// `STUN` server supporting RFC 5780, as behaviour tests need its alternate address.
+pub const BEHAVIOR_STUN_ADDRESS: &str = "stun.stunprotocol.org:3478";
+/// Transmissions of a request per RFC 5389, with the interval doubling after each.
+const PROBE_ATTEMPTS: u32 = 7;
+
+define_attribute_enums!(
+    Attribute,
+    AttributeDecoder,
+    AttributeEncoder,
+    [
+        Software,
+        MappedAddress,
+        XorMappedAddress,
+        ChangeRequest,
+        ResponseOrigin,
+        OtherAddress
+    ]
+);
+
+/// `NatBehavior` of RFC 4787, telling which destinations `NAT` mappings or filters
+/// depend on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatBehavior {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+/// `NatType` of a socket, as classified with tests of RFC 5780.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatType {
+    /// Public address the socket is mapped to by the primary address of the server.
+    pub mapped: SocketAddr,
+    /// The socket is reachable at its own address, with no translation in between.
+    pub open: bool,
+    /// Mapping behaviour, unknown when the server lacks an alternate address.
+    pub mapping: Option<NatBehavior>,
+    /// Filtering behaviour, unknown when the server lacks an alternate address.
+    pub filtering: Option<NatBehavior>,
+}
+
+impl NatType {
+    /// Whether direct hole punching is likely to work, as it needs the mapping toward the
+    /// peer to match the one learned from `STUN`. Unknown when mapping behaviour is.
+    pub fn is_punchable(&self) -> Option<bool> {
+        self.mapping.map(|c| c == NatBehavior::EndpointIndependent)
+    }
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let behavior = |c: Option<NatBehavior>| match c {
+            Some(NatBehavior::EndpointIndependent) => "endpoint-independent",
+            Some(NatBehavior::AddressDependent) => "address-dependent",
+            Some(NatBehavior::AddressAndPortDependent) => "address and port-dependent",
+            None => "unknown",
+        };
+        write!(
+            f,
+            "{} at {}, {} mapping, {} filtering",
+            if self.open { "open" } else { "NAT" },
+            self.mapped,
+            behavior(self.mapping),
+            behavior(self.filtering)
+        )
+    }
+}
+
+/// `Response` to a behaviour test.
+#[derive(Clone, Copy)]
+pub(crate) struct Response {
+    pub mapped: SocketAddr,
+    other: Option<SocketAddr>,
+    source: SocketAddr,
+}
+
+/// `Probe` running behaviour tests, taking responses to its requests.
+pub(crate) struct Probe {
+    sw_tag: Option<String>,
+    timeout: Duration,
+    pending: Mutex<HashMap<TransactionId, Option<Response>>>,
+}
+
+impl StunHandler for Probe {
+    fn input(&self, _base: usize, buf: &[u8], source: SocketAddr) -> Option<Vec<u8>> {
+        let msg = MessageDecoder::<Attribute>::new()
+            .decode_from_bytes(buf)
+            .ok()?
+            .ok()?;
+        if msg.class() != MessageClass::SuccessResponse {
+            return None;
+        }
+        let mapped = msg
+            .get_attribute::<XorMappedAddress>()
+            .map(XorMappedAddress::address)
+            .or_else(|| msg.get_attribute::<MappedAddress>().map(|c| c.address()))?;
+        let other = msg.get_attribute::<OtherAddress>().map(|c| c.address());
+
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(res) = pending.get_mut(&msg.transaction_id()) {
+            *res = Some(Response {
+                mapped,
+                other,
+                source,
+            });
+        }
+        None
+    }
+}
+
+impl Probe {
+    pub fn new(sw_tag: Option<String>, timeout: Duration) -> Arc<Self> {
+        Arc::new(Probe {
+            sw_tag,
+            timeout,
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Sends a binding request to `server` until it's answered, asking for the response to
+    /// come from another address when `change` is set.
+    pub async fn transact(
+        self: &Arc<Self>,
+        socket: &(dyn IOSocket + Sync + Send),
+        server: SocketAddr,
+        change: Option<ChangeRequest>,
+    ) -> Result<Option<Response>> {
+        let id = TransactionId::new(rand::random());
+        let mut msg = Message::<Attribute>::new(MessageClass::Request, BINDING, id);
+        if let Some(s) = &self.sw_tag {
+            msg.add_attribute(Software::new(s.to_owned())?.into());
+        }
+        if let Some(change) = change {
+            msg.add_attribute(change.into());
+        }
+        let buf = MessageEncoder::new().encode_into_bytes(msg)?;
+        self.pending.lock().unwrap().insert(id, None);
+
+        let mut wait = self.timeout;
+        let mut res = None;
+        for _ in 0..PROBE_ATTEMPTS {
+            socket.push_stun(&buf, server).await?;
+            socket.poll_stun(self.clone(), 0, wait).await?;
+            res = self.pending.lock().unwrap().get(&id).copied().flatten();
+            if res.is_some() {
+                break;
+            }
+            wait *= 2;
+        }
+        self.pending.lock().unwrap().remove(&id);
+        Ok(res)
+    }
+}
+
+/// Classifies mapping and filtering behaviour of `socket` per RFC 5780, section 4.3 and
+/// 4.4, with the `STUN` server at `server`.
+pub(crate) async fn detect(
+    socket: &(dyn IOSocket + Sync + Send),
+    server: SocketAddr,
+    sw_tag: Option<String>,
+    timeout: Duration,
+) -> Result<NatType> {
+    let probe = Probe::new(sw_tag, timeout);
+    let local = route(socket.get_lan_ip().await?, server)?;
+
+    trace!("running NAT behaviour tests with STUN at {:?}", server);
+    let first = probe
+        .transact(socket, server, None)
+        .await?
+        .ok_or(ERR_CONNECTION)?;
+    let open = first.mapped == local;
+    let Some(other) = first.other else {
+        info!("STUN at {:?} has no alternate address", server);
+        return Ok(NatType {
+            mapped: first.mapped,
+            open,
+            mapping: open.then_some(NatBehavior::EndpointIndependent),
+            filtering: None,
+        });
+    };
+
+    // filtering goes first, as later tests open the mapping to alternate addresses, and
+    // responses coming from the primary address mean the server ignored the change
+    let changed = probe
+        .transact(socket, server, Some(ChangeRequest::new(true, true)))
+        .await?;
+    let filtering = match changed {
+        Some(res) if res.source == other => NatBehavior::EndpointIndependent,
+        _ => {
+            let changed = probe
+                .transact(socket, server, Some(ChangeRequest::new(false, true)))
+                .await?;
+            match changed {
+                Some(res) if res.source == SocketAddr::new(server.ip(), other.port()) => {
+                    NatBehavior::AddressDependent
+                }
+                _ => NatBehavior::AddressAndPortDependent,
+            }
+        }
+    };
+
+    let mapping = match open {
+        true => Some(NatBehavior::EndpointIndependent),
+        false => {
+            let alternate = SocketAddr::new(other.ip(), server.port());
+            match probe.transact(socket, alternate, None).await? {
+                Some(res) if res.mapped == first.mapped => Some(NatBehavior::EndpointIndependent),
+                Some(res) => match probe.transact(socket, other, None).await? {
+                    Some(c) if c.mapped == res.mapped => Some(NatBehavior::AddressDependent),
+                    Some(_) => Some(NatBehavior::AddressAndPortDependent),
+                    None => None,
+                },
+                None => None,
+            }
+        }
+    };
+
+    Ok(NatType {
+        mapped: first.mapped,
+        open,
+        mapping,
+        filtering: Some(filtering),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use async_std::{
+        net::{IpAddr, Ipv4Addr, UdpSocket},
+        task,
+    };
+    use std::collections::HashSet;
+    use stun_codec::rfc5389;
+
+    use crate::server::{change_request, CHANGE_IP, CHANGE_PORT};
+    use crate::udp::UdpSocketHandle;
+    use crate::{SocketConfig, LOOPBACK_IP};
+
+    const ALTERNATE_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    /// Public address of emulated `NAT`s.
+    const PUBLIC_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+    const TIMEOUT: Duration = Duration::from_millis(2);
+
+    /// `StunServer` of RFC 5780 bound to two loopback addresses on two ports each.
+    ///
+    /// Emulates a `NAT` in front of clients when given its `(mapping, filtering)`
+    /// behaviour: mapped addresses depend on the server address a request came to, and
+    /// responses are dropped unless the client has sent to their source before.
+    pub(crate) struct StunServer {
+        sockets: Vec<Arc<UdpSocket>>,
+    }
+
+    impl StunServer {
+        pub(crate) async fn spawn(nat: Option<(NatBehavior, NatBehavior)>) -> StunServer {
+            let primary = UdpSocket::bind((LOOPBACK_IP, 0)).await.unwrap();
+            let secondary = UdpSocket::bind((LOOPBACK_IP, 0)).await.unwrap();
+            let ports = [
+                primary.local_addr().unwrap().port(),
+                secondary.local_addr().unwrap().port(),
+            ];
+            let sockets = vec![
+                Arc::new(primary),
+                Arc::new(secondary),
+                Arc::new(UdpSocket::bind((ALTERNATE_IP, ports[0])).await.unwrap()),
+                Arc::new(UdpSocket::bind((ALTERNATE_IP, ports[1])).await.unwrap()),
+            ];
+
+            let contacted = Arc::new(Mutex::new(HashSet::new()));
+            for idx in 0..sockets.len() {
+                let sockets = sockets.clone();
+                let contacted = contacted.clone();
+                task::spawn(async move {
+                    let mut buf = [0u8; 512];
+                    while let Ok((len, client)) = sockets[idx].recv_from(&mut buf).await {
+                        let Some((res, from)) = respond(&sockets, idx, &buf[..len], client, nat)
+                        else {
+                            continue;
+                        };
+                        let origin = sockets[from].local_addr().unwrap();
+                        contacted
+                            .lock()
+                            .unwrap()
+                            .insert(sockets[idx].local_addr().unwrap());
+                        let allowed = match nat.map(|(_, filtering)| filtering) {
+                            None | Some(NatBehavior::EndpointIndependent) => true,
+                            Some(NatBehavior::AddressDependent) => contacted
+                                .lock()
+                                .unwrap()
+                                .iter()
+                                .any(|c| c.ip() == origin.ip()),
+                            Some(NatBehavior::AddressAndPortDependent) => {
+                                contacted.lock().unwrap().contains(&origin)
+                            }
+                        };
+                        if allowed {
+                            sockets[from].send_to(&res, client).await.unwrap();
+                        }
+                    }
+                });
+            }
+            StunServer { sockets }
+        }
+
+        pub(crate) fn addr(&self) -> SocketAddr {
+            self.sockets[0].local_addr().unwrap()
+        }
+    }
+
+    /// Builds a response to the request in `buf` received on socket `idx`, returning it
+    /// along with the socket to send it from.
+    fn respond(
+        sockets: &[Arc<UdpSocket>],
+        idx: usize,
+        buf: &[u8],
+        client: SocketAddr,
+        nat: Option<(NatBehavior, NatBehavior)>,
+    ) -> Option<(Vec<u8>, usize)> {
+        let msg = MessageDecoder::<rfc5389::Attribute>::new()
+            .decode_from_bytes(buf)
+            .ok()?
+            .ok()?;
+        let change = change_request(&msg);
+        // sockets are ordered by address, then by port
+        let (ip, port) = (idx as u16 / 2, idx as u16 % 2);
+        let mapped = match nat.map(|(mapping, _)| mapping) {
+            None => client,
+            Some(NatBehavior::EndpointIndependent) => SocketAddr::new(PUBLIC_IP, client.port()),
+            Some(NatBehavior::AddressDependent) => SocketAddr::new(PUBLIC_IP, client.port() + ip),
+            Some(NatBehavior::AddressAndPortDependent) => {
+                SocketAddr::new(PUBLIC_IP, client.port() + ip * 2 + port)
+            }
+        };
+        let from =
+            idx ^ ((change & CHANGE_IP != 0) as usize) << 1 ^ (change & CHANGE_PORT != 0) as usize;
+
+        let mut res =
+            Message::<Attribute>::new(MessageClass::SuccessResponse, BINDING, msg.transaction_id());
+        res.add_attribute(XorMappedAddress::new(mapped).into());
+        res.add_attribute(ResponseOrigin::new(sockets[from].local_addr().unwrap()).into());
+        res.add_attribute(OtherAddress::new(sockets[idx ^ 3].local_addr().unwrap()).into());
+        Some((MessageEncoder::new().encode_into_bytes(res).ok()?, from))
+    }
+
+    async fn detect_with(nat: Option<(NatBehavior, NatBehavior)>) -> NatType {
+        let server = StunServer::spawn(nat).await;
+        let socket = UdpSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            vec![],
+            Arc::new(SocketConfig::new(1, TIMEOUT)),
+        )
+        .await
+        .unwrap();
+        detect(&socket, server.addr(), None, TIMEOUT).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn nat_open_works() {
+        let res = detect_with(None).await;
+        // socket is reached at its own address
+        assert!(res.open);
+        assert_eq!(res.mapping, Some(NatBehavior::EndpointIndependent));
+        assert_eq!(res.filtering, Some(NatBehavior::EndpointIndependent));
+        assert_eq!(res.is_punchable(), Some(true));
+    }
+
+    #[async_std::test]
+    async fn nat_behavior_works() {
+        use NatBehavior::*;
+
+        for mapping in [
+            EndpointIndependent,
+            AddressDependent,
+            AddressAndPortDependent,
+        ] {
+            for filtering in [
+                EndpointIndependent,
+                AddressDependent,
+                AddressAndPortDependent,
+            ] {
+                let res = detect_with(Some((mapping, filtering))).await;
+                // emulated behaviour is told apart
+                assert!(!res.open);
+                assert_eq!(res.mapping, Some(mapping));
+                assert_eq!(res.filtering, Some(filtering));
+                assert_eq!(res.is_punchable(), Some(mapping == EndpointIndependent));
+            }
+        }
+    }
+}
@@ -1,86 +1,247 @@
-use async_std::{
-    future,
-    net::{SocketAddr, ToSocketAddrs},
-};
+use async_std::{future, net::SocketAddr};
 use async_trait::async_trait;
 use err::{
-    consts::{ERR_CONNECTION, ERR_PIPE_BROKE, ERR_VALIDATION},
+    consts::{ERR_ADDRESS, ERR_CONNECTION, ERR_SECRET},
     Error, Result,
 };
+use hmac::{Hmac, Mac};
 use log::{error, info, trace, warn};
+use sha2::{Digest, Sha256};
 use std::time::Duration;
 
-use crate::SocketHandle;
+use crate::udp::{is_local, reaches};
+use crate::{CertHash, IOSocket};
 
 const P2P_REQ_TAG: &[u8] = b"p2p\0req\0";
+/// Domain separation of the key derived from the shared secret.
+const P2P_KEY_TAG: &[u8] = b"ensd\0p2p\0key\0";
 const REQUEST_MSG_TTL: u32 = 32;
+const STAGE_PREFIX_SIZE: usize = 2;
+const COOKIE_SIZE: usize = 16;
+const CERT_SIZE: usize = std::mem::size_of::<CertHash>();
+const MAC_SIZE: usize = 32;
+const STAGE_SIZE: usize =
+    STAGE_PREFIX_SIZE + P2P_REQ_TAG.len() + COOKIE_SIZE * 2 + CERT_SIZE + MAC_SIZE;
+
+type HmacSha256 = Hmac<Sha256>;
+type Cookie = [u8; COOKIE_SIZE];
+
+/// `Stage` of hole punching, moving up as each side learns the other one hears it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    A,
+    B,
+    C,
+}
+
+impl Stage {
+    fn prefix(self) -> &'static [u8] {
+        match self {
+            Stage::A => b"a\0",
+            Stage::B => b"b\0",
+            Stage::C => b"c\0",
+        }
+    }
+}
 
-#[async_trait(?Send)]
-trait SocketAddrsUtil {
-    async fn contains(&self, dest: &SocketAddr) -> bool;
+/// `Handshake` of hole punching, authenticating stage messages with a key derived from
+/// the shared secret and telling sessions apart by random cookies.
+///
+/// Every message is `stage | tag | cookie | echo | cert | mac`, carrying the sender's
+/// cookie and the last one it heard from the peer. Stages past the first have to echo the
+/// cookie of this side, proving the peer hears this session rather than replays an old one.
+///
+/// Hash of the sender's transport certificate, zeroed if it has none, is handed over
+/// along the way for the peer to pin.
+struct Handshake {
+    key: [u8; MAC_SIZE],
+    cookie: Cookie,
+    cert: CertHash,
+    /// Cookie of the peer, along with whether it's confirmed by echoing ours.
+    peer: Option<(Cookie, bool)>,
+    peer_cert: CertHash,
 }
 
-#[async_trait(?Send)]
-impl<T: ToSocketAddrs> SocketAddrsUtil for &T {
-    async fn contains(&self, dest: &SocketAddr) -> bool {
-        self.to_socket_addrs().await.unwrap().any(|c| c == *dest)
+impl Handshake {
+    fn new(secret: &[u8], cert: Option<CertHash>) -> Self {
+        Handshake {
+            key: Sha256::new_with_prefix(P2P_KEY_TAG)
+                .chain_update(secret)
+                .finalize()
+                .into(),
+            cookie: rand::random(),
+            cert: cert.unwrap_or_default(),
+            peer: None,
+            peer_cert: CertHash::default(),
+        }
+    }
+
+    /// Certificate hash handed over by the peer, none if it has no certificate.
+    fn peer_cert(&self) -> Option<CertHash> {
+        Some(self.peer_cert).filter(|c| *c != CertHash::default())
+    }
+
+    fn seal(&self, stage: Stage) -> Vec<u8> {
+        let echo = self.peer.map(|(c, _)| c).unwrap_or_default();
+        let mut msg = [stage.prefix(), P2P_REQ_TAG, &self.cookie, &echo, &self.cert].concat();
+        let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+        mac.update(&msg);
+        msg.extend_from_slice(&mac.finalize().into_bytes());
+        msg
+    }
+
+    /// Stage of the peer's message `buf`, none if it fails authentication, is reflected
+    /// back or belongs to another session.
+    fn open(&mut self, buf: &[u8]) -> Option<Stage> {
+        if buf.len() != STAGE_SIZE || !is_stage_like(buf) {
+            return None;
+        }
+        let (msg, tag) = buf.split_at(STAGE_SIZE - MAC_SIZE);
+        let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+        mac.update(msg);
+        mac.verify_slice(tag).ok()?;
+
+        let stage = [Stage::A, Stage::B, Stage::C]
+            .into_iter()
+            .find(|c| msg.starts_with(c.prefix()))?;
+        let (cookie, rest) = msg[STAGE_PREFIX_SIZE + P2P_REQ_TAG.len()..].split_at(COOKIE_SIZE);
+        let (echo, cert) = rest.split_at(COOKIE_SIZE);
+        let cookie: Cookie = cookie.try_into().ok()?;
+        let cert: CertHash = cert.try_into().ok()?;
+        let fresh = echo == self.cookie;
+        let confirmed = match self.peer {
+            Some((peer, true)) if peer != cookie => return None,
+            Some((_, confirmed)) => confirmed,
+            None => false,
+        };
+        if cookie == self.cookie || (stage != Stage::A && !fresh) {
+            return None;
+        }
+        self.peer = Some((cookie, confirmed || fresh));
+        self.peer_cert = cert;
+        Some(stage)
     }
 }
 
-#[async_trait(?Send)]
+/// Whether `buf` looks like a stage message, authenticated or not.
+fn is_stage_like(buf: &[u8]) -> bool {
+    buf.get(STAGE_PREFIX_SIZE..STAGE_PREFIX_SIZE + P2P_REQ_TAG.len()) == Some(P2P_REQ_TAG)
+}
+
+#[async_trait]
 pub(super) trait P2P {
-    async fn try_nat_tr(&self, addr: &[SocketAddr], retries: u16, timeout: Duration) -> Result<()>;
+    /// Punches holes to all `addr` of families the socket reaches, returning the one to
+    /// bind, which is a local or IPv6 one once any stage message arrives over it.
+    ///
+    /// No more than `rate` datagrams go out per second, so long lists of predicted or
+    /// sprayed addresses are sent in bursts over several rounds.
+    ///
+    /// Stage messages are authenticated with `secret` shared by peers, and those which
+    /// fail it are dropped, so others can't complete or break the handshake. Certificate
+    /// hash the peer hands over in them is pinned to the socket. Punching is refused with
+    /// an empty `secret`, as anyone could complete it then.
+    async fn try_nat_tr(
+        &self,
+        addr: &[SocketAddr],
+        retries: u16,
+        timeout: Duration,
+        rate: u32,
+        secret: &[u8],
+    ) -> Result<SocketAddr>;
 }
 
-// TODO: add NAT type check, see https://github.com/Azure/RDS-Templates/tree/master/AVD-TestShortpath
-#[async_trait(?Send)]
-impl P2P for SocketHandle {
-    async fn try_nat_tr(&self, addr: &[SocketAddr], retries: u16, timeout: Duration) -> Result<()> {
-        let ttl = self.socket.get_ttl().await?;
-        self.socket.set_ttl(REQUEST_MSG_TTL).await?;
-
-        let stage_a = [b"a\0".as_ref(), P2P_REQ_TAG].concat();
-        let stage_b = [b"b\0".as_ref(), P2P_REQ_TAG].concat();
-        let stage_c = [b"c\0".as_ref(), P2P_REQ_TAG].concat();
-
-        info!(
-            "hole punching to {:?}",
-            addr.to_socket_addrs()
-                .await
-                .unwrap()
-                .map(|c| c as SocketAddr)
-                .collect::<Vec<_>>()
-        );
+/// Prefers `dest` over the `peer` answered so far if it's a direct local address, or an
+/// IPv6 one of the same kind, as those need no `NAT` traversal more often than not.
+fn prefer(peer: Option<SocketAddr>, dest: SocketAddr) -> SocketAddr {
+    let rank = |c: SocketAddr| (is_local(c.ip()), c.is_ipv6());
+    match peer {
+        Some(peer) if rank(peer) >= rank(dest) => peer,
+        _ => dest,
+    }
+}
 
-        let mut msg = &stage_a;
+#[async_trait]
+impl<T: IOSocket + Sync + ?Sized> P2P for T {
+    async fn try_nat_tr(
+        &self,
+        addr: &[SocketAddr],
+        retries: u16,
+        timeout: Duration,
+        rate: u32,
+        secret: &[u8],
+    ) -> Result<SocketAddr> {
+        if secret.is_empty() {
+            error!("refused hole punching with no secret shared with the peer");
+            return Err(ERR_SECRET.into());
+        }
+        let local = self.get_lan_ip().await?;
+        let addr = &addr
+            .iter()
+            .copied()
+            .filter(|c| reaches(local, *c))
+            .collect::<Vec<_>>();
+        if addr.is_empty() {
+            return Err(ERR_ADDRESS.into());
+        }
+        let ttl = self.get_ttl().await?;
+        self.set_ttl(REQUEST_MSG_TTL).await?;
+
+        info!("hole punching to {:?}", addr);
+        let burst = ((rate as f64 * timeout.as_secs_f64()) as usize).clamp(1, addr.len());
+        let mut next = 0;
+
+        let mut handshake = Handshake::new(secret, self.cert_hash());
+        let mut stage = Stage::A;
         let mut iter = 0..retries;
+        let mut peer = None;
 
         let res = loop {
-            self.socket.push_to(msg, addr).await?;
-            if let Ok((res, dest)) = self.poll_at().await {
-                if addr.contains(&dest) {
-                    match res {
-                        res if res == stage_a => {
-                            trace!("moving up sync to 'stage_b' as got 'stage_a' message");
-                            msg = &stage_b
-                        }
-                        res if res == stage_b => {
-                            trace!("moving up sync to 'stage_c' as got 'stage_b' message");
-                            msg = &stage_c
-                        }
-                        res if res == stage_c => {
-                            if msg == &stage_c {
+            // every address is tried until one answers, a burst of them a round, then the
+            // preferred one only
+            let dest = match peer {
+                Some(peer) => vec![peer],
+                None => {
+                    let dest = (next..next + burst).map(|c| addr[c % addr.len()]).collect();
+                    next = (next + burst) % addr.len();
+                    dest
+                }
+            };
+            self.push_to(&handshake.seal(stage), &dest).await?;
+            if let Ok(Ok((res, dest))) = future::timeout(timeout, self.poll_at()).await {
+                let got = match addr.contains(&dest) {
+                    true => handshake.open(&res),
+                    false => None,
+                };
+                // messages over other than the preferred address count against retries,
+                // so they can't hold punching up
+                match got {
+                    Some(_) if prefer(peer, dest) != dest => {
+                        trace!("dropped stage message of {:?} in favor of {:?}", dest, peer)
+                    }
+                    Some(got) => {
+                        peer = Some(dest);
+                        match got {
+                            Stage::A => {
+                                trace!("moving up sync to 'stage_b' as got 'stage_a' message");
+                                stage = Stage::B
+                            }
+                            Stage::B => {
+                                trace!("moving up sync to 'stage_c' as got 'stage_b' message");
+                                stage = Stage::C
+                            }
+                            Stage::C if stage == Stage::C => {
                                 trace!("sync of 'stage_c' done - beginning socket buffer cleanup");
-                                self.socket.push_to(msg, addr).await?;
-                                break Ok(());
-                            } else {
+                                self.push_to(&handshake.seal(stage), &[dest]).await?;
+                                break Ok(dest);
+                            }
+                            Stage::C => {
                                 trace!("got 'stage_c' message out of order - syncing 'msg'");
-                                msg = &stage_c
+                                stage = Stage::C
                             }
                         }
-                        _ => break Err(ERR_VALIDATION),
+                        iter = 0..retries
                     }
-                    iter = 0..retries
+                    None => trace!("dropped unauthenticated message of {:?}", dest),
                 }
             }
 
@@ -91,27 +252,75 @@ impl P2P for SocketHandle {
         };
 
         let res = match res {
-            Ok(_) => loop {
-                let res = future::timeout(timeout, self.socket.peek_at()).await;
+            Ok(peer) => loop {
+                let res = future::timeout(timeout, self.peek_at()).await;
                 if res.is_err() {
-                    break Ok(());
+                    break Ok(peer);
                 }
                 let (res, dest) = res.unwrap()?;
-                if addr.contains(&dest) {
-                    match res {
-                        res if res == stage_a || res == stage_b => break Err(ERR_PIPE_BROKE),
-                        res if res == stage_c => {
-                            self.socket.poll().await?;
-                            warn!("received a message after hole punching stages");
-                        }
-                        _ => break Ok(()),
+                match handshake.open(&res) {
+                    Some(_) if dest != peer => {
+                        self.poll().await?;
+                        trace!("dropped stage message of {:?} in favor of {:?}", dest, peer);
                     }
+                    Some(Stage::B) => {
+                        self.poll().await?;
+                        trace!("peer missed 'stage_c' message - sending it again");
+                        self.push_to(&handshake.seal(Stage::C), &[peer]).await?;
+                    }
+                    Some(_) => {
+                        self.poll().await?;
+                        warn!("received a message after hole punching stages");
+                    }
+                    None if is_stage_like(&res) => {
+                        self.poll().await?;
+                        trace!("dropped unauthenticated stage message of {:?}", dest);
+                    }
+                    None => break Ok(peer),
                 }
             },
-            res => res,
+            Err(e) => Err(e),
         };
+        if let (Ok(_), Some(cert)) = (&res, handshake.peer_cert()) {
+            self.pin_cert(cert);
+        }
 
-        self.socket.set_ttl(ttl).await?;
+        self.set_ttl(ttl).await?;
         res.map_err(Error::into)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_auth_works() {
+        let (mut a, mut b) = (
+            Handshake::new(b"secret", Some([1; CERT_SIZE])),
+            Handshake::new(b"secret", None),
+        );
+        // peers sharing the secret hear each other, with later stages echoing cookies
+        assert_eq!(b.open(&a.seal(Stage::A)), Some(Stage::A));
+        assert_eq!(a.open(&b.seal(Stage::B)), Some(Stage::B));
+        assert_eq!(b.open(&a.seal(Stage::C)), Some(Stage::C));
+        // certificate hashes are handed over, none if the peer has no certificate
+        assert_eq!(b.peer_cert(), Some([1; CERT_SIZE]));
+        assert_eq!(a.peer_cert(), None);
+
+        let mut other = Handshake::new(b"guess", None);
+        let mut tampered = a.seal(Stage::C);
+        tampered[STAGE_PREFIX_SIZE + P2P_REQ_TAG.len()] ^= 1;
+        // forged, plaintext and reflected stages are dropped
+        assert_eq!(b.open(&other.seal(Stage::A)), None);
+        assert_eq!(b.open(&tampered), None);
+        assert_eq!(b.open(&[b"c\0".as_ref(), P2P_REQ_TAG].concat()), None);
+        assert_eq!(a.open(&a.seal(Stage::A)), None);
+
+        let mut c = Handshake::new(b"secret", None);
+        // stages of another session are dropped, as is a replay not echoing the cookie
+        assert_eq!(a.open(&c.seal(Stage::A)), None);
+        assert_eq!(c.open(&b.seal(Stage::B)), None);
+        assert_eq!(other.open(&b.seal(Stage::A)), None);
+    }
+}
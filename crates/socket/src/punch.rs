@@ -0,0 +1,236 @@
+use async_std::net::SocketAddr;
+use err::Result;
+use log::{info, trace};
+use rand::seq::index;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::nat::Probe;
+use crate::IOSocket;
+
+/// Ports below are taken by services rather than handed out by `NAT`s.
+const FIRST_PORT: u16 = 1024;
+
+/// `PunchConfig` for `.toml` config parsing.
+/// Sets port prediction and spraying which get through `NAT`s mapping every destination
+/// to another port, where the public address learned from `STUN` is of no use to the peer.
+///
+/// Birthday punching is tried once plain hole punching fails: one peer opens `sockets`
+/// mappings toward the other, which sprays `probes` random `ports` of the peer's address
+/// until one of them meets a mapping. It's off unless either is set, and the peer behind
+/// the harder `NAT` is to set `sockets`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PunchConfig {
+    /// Mappings sampled with distinct `STUN` servers to learn the port delta.
+    #[serde(default = "default_samples")]
+    samples: usize,
+    /// Ports predicted past the last sampled one, as other hosts behind the `NAT` take some.
+    #[serde(default = "default_spread")]
+    spread: u16,
+    #[serde(default)]
+    sockets: usize,
+    #[serde(default)]
+    probes: usize,
+    /// First and last port sprayed, all non-privileged ones unless the `NAT` is known to
+    /// allocate a block of them.
+    #[serde(default = "default_ports")]
+    ports: (u16, u16),
+    /// Most hole punching datagrams sent per second.
+    #[serde(default = "default_rate")]
+    rate: u32,
+}
+
+impl Default for PunchConfig {
+    fn default() -> Self {
+        PunchConfig {
+            samples: default_samples(),
+            spread: default_spread(),
+            sockets: 0,
+            probes: 0,
+            ports: default_ports(),
+            rate: default_rate(),
+        }
+    }
+}
+
+impl PunchConfig {
+    pub fn new(
+        samples: usize,
+        spread: u16,
+        sockets: usize,
+        probes: usize,
+        ports: (u16, u16),
+        rate: u32,
+    ) -> Self {
+        PunchConfig {
+            samples,
+            spread,
+            sockets,
+            probes,
+            ports,
+            rate,
+        }
+    }
+
+    pub(crate) fn samples(&self) -> usize {
+        self.samples
+    }
+
+    pub(crate) fn spread(&self) -> u16 {
+        self.spread
+    }
+
+    pub(crate) fn sockets(&self) -> usize {
+        self.sockets
+    }
+
+    pub(crate) fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    pub(crate) fn is_birthday(&self) -> bool {
+        self.sockets > 0 || self.probes > 0
+    }
+
+    /// Random distinct ports of every address in `addr` to spray in birthday punching.
+    pub(crate) fn spray(&self, addr: &[SocketAddr]) -> Vec<SocketAddr> {
+        let (first, last) = (self.ports.0.max(FIRST_PORT), self.ports.1);
+        let len = last.saturating_sub(first) as usize + 1;
+        let mut ips = addr.iter().map(SocketAddr::ip).collect::<Vec<_>>();
+        ips.sort();
+        ips.dedup();
+
+        let mut rng = rand::thread_rng();
+        ips.into_iter()
+            .flat_map(|ip| {
+                index::sample(&mut rng, len, self.probes.min(len))
+                    .into_iter()
+                    .map(move |c| SocketAddr::new(ip, first + c as u16))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+/// Mapped addresses of `socket` as seen by `servers` in turn, up to `count` of them,
+/// skipping servers which don't answer.
+pub(crate) async fn sample(
+    socket: &(dyn IOSocket + Sync + Send),
+    servers: &[SocketAddr],
+    count: usize,
+    sw_tag: Option<String>,
+    timeout: Duration,
+) -> Result<Vec<SocketAddr>> {
+    let probe = Probe::new(sw_tag, timeout);
+    let mut res = vec![];
+    for server in servers {
+        if res.len() >= count {
+            break;
+        }
+        match probe.transact(socket, *server, None).await? {
+            Some(c) => res.push(c.mapped),
+            None => trace!("STUN at {:?} didn't answer the sample", server),
+        }
+    }
+    info!("sampled mappings {:?}", res);
+    Ok(res)
+}
+
+/// Predicts addresses a `NAT` maps the socket to for its next destinations, from
+/// `samples` mapped in order to distinct ones, going with the most common port delta
+/// between them. A single address comes out when mappings don't depend on destination.
+pub(crate) fn predict(samples: &[SocketAddr], spread: u16) -> Vec<SocketAddr> {
+    let Some(last) = samples.last() else {
+        return vec![];
+    };
+    let mut deltas = HashMap::<i32, usize>::new();
+    for pair in samples.windows(2) {
+        *deltas
+            .entry(pair[1].port() as i32 - pair[0].port() as i32)
+            .or_default() += 1;
+    }
+    // ties go to the smaller delta, as larger ones are more likely other hosts' doing
+    let delta = deltas
+        .into_iter()
+        .max_by_key(|(delta, count)| (*count, Reverse(delta.abs())))
+        .map(|(delta, _)| delta);
+
+    match delta {
+        None | Some(0) => vec![*last],
+        Some(delta) => (1..=spread as i32)
+            .filter_map(|c| u16::try_from(last.port() as i32 + delta * c).ok())
+            .filter(|c| *c >= FIRST_PORT)
+            .map(|c| SocketAddr::new(last.ip(), c))
+            .collect(),
+    }
+}
+
+fn default_samples() -> usize {
+    3
+}
+
+fn default_spread() -> u16 {
+    8
+}
+
+fn default_ports() -> (u16, u16) {
+    (FIRST_PORT, u16::MAX)
+}
+
+fn default_rate() -> u32 {
+    1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::net::{IpAddr, Ipv4Addr};
+    use std::collections::HashSet;
+
+    const PUBLIC_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+
+    fn addr(ports: &[u16]) -> Vec<SocketAddr> {
+        ports
+            .iter()
+            .map(|c| SocketAddr::new(PUBLIC_IP, *c))
+            .collect()
+    }
+
+    #[test]
+    fn predict_works() {
+        // sequential mappings are followed past the last one
+        assert_eq!(
+            predict(&addr(&[4000, 4002, 4004]), 3),
+            addr(&[4006, 4008, 4010])
+        );
+        // a mapping taken by another host in between doesn't throw the delta off
+        assert_eq!(
+            predict(&addr(&[4000, 4001, 4003, 4004]), 2),
+            addr(&[4005, 4006])
+        );
+        // decreasing ports are followed down to the non-privileged ones
+        assert_eq!(predict(&addr(&[1030, 1027]), 4), addr(&[1024]));
+
+        // endpoint-independent mapping keeps the only address
+        assert_eq!(predict(&addr(&[4000, 4000]), 3), addr(&[4000]));
+        assert_eq!(predict(&addr(&[4000]), 3), addr(&[4000]));
+        assert!(predict(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn spray_works() {
+        let cfg = PunchConfig::new(3, 8, 0, 64, (5000, 5099), 1000);
+        let res = cfg.spray(&addr(&[4000, 4001]));
+        // distinct ports of the range are sprayed once per address
+        assert_eq!(res.len(), 64);
+        assert_eq!(res.iter().collect::<HashSet<_>>().len(), 64);
+        assert!(res.iter().all(|c| (5000..5100).contains(&c.port())));
+
+        let cfg = PunchConfig::new(3, 8, 0, 64, (0, 1039), 1000);
+        // privileged ports are left out, and there are no more probes than ports
+        assert_eq!(cfg.spray(&addr(&[4000])).len(), 16);
+    }
+}
@@ -0,0 +1,574 @@
+use async_std::{
+    channel::TrySendError,
+    future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    task,
+};
+use async_trait::async_trait;
+use err::{
+    consts::{ERR_DATAGRAM, ERR_FRAME_SIZE, ERR_NOT_BOUND, ERR_PIPE_BROKE, ERR_VALIDATION},
+    Error, Result,
+};
+use futures::future::Either;
+use log::{info, trace, warn};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, CryptoProvider},
+        pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+        server::danger::{ClientCertVerified, ClientCertVerifier},
+        CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme,
+    },
+    AsyncStdRuntime, ClientConfig, Connection, Endpoint, EndpointConfig, RecvStream, SendStream,
+    ServerConfig, TransportConfig,
+};
+use sha2::{Digest, Sha256};
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    OnceLock,
+};
+use std::time::Duration;
+
+use crate::frame::{fragment, inbox, Inboxes, Reassembler};
+use crate::udp::{bind_std, canonical, UdpSocketHandle};
+use crate::{CertHash, IOSocket, SocketConfig, StunHandler, CONTROL_STREAM};
+
+const ALPN: &[u8] = b"ensd";
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+/// Largest message accepted from a reliable stream.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// `Link` of two `QUIC` connections set up by [`bind`][IOSocket::bind], one opened by each
+/// peer. Own connection is used for sending, and the peer's one for receiving, so neither
+/// side has to play a server role and a socket may be bound to itself.
+struct Link {
+    endpoint: Endpoint,
+    tx: Connection,
+    rx: Connection,
+    streams: Mutex<HashMap<u8, SendStream>>,
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.endpoint.close(0u32.into(), b"");
+    }
+}
+
+/// `QuicSocketHandle` runs `QUIC` over the same `UDP` socket used for hole punching.
+///
+/// Until [`bind`][IOSocket::bind] everything goes through plain [`UdpSocketHandle`], then
+/// the socket is handed over to [`quinn`]. Streams listed as reliable, as well as
+/// [`CONTROL_STREAM`][CONTROL_STREAM], are sent over `QUIC` streams, the rest go as
+/// unreliable datagrams of RFC 9221.
+///
+/// Each peer presents a freshly generated self-signed certificate, on both connections,
+/// the hash of which is handed over while punching. Only the pinned one of the peer is
+/// accepted, so the socket refuses to connect if nothing is pinned.
+pub(super) struct QuicSocketHandle {
+    udp: UdpSocketHandle,
+    socket: std::sync::Mutex<Option<std::net::UdpSocket>>,
+    server_name: String,
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+    pinned: std::sync::Mutex<Option<CertHash>>,
+    reliable: Vec<u8>,
+    socket_cfg: Arc<SocketConfig>,
+    link: OnceLock<Link>,
+    inboxes: Inboxes,
+    peeked: std::sync::Mutex<VecDeque<Vec<u8>>>,
+    msg_id: AtomicU32,
+}
+
+impl QuicSocketHandle {
+    #[allow(dead_code)]
+    pub async fn new(
+        addr: Vec<SocketAddr>,
+        ttl: Option<u32>,
+        sw_tag: Option<String>,
+        reliable: Vec<u8>,
+        server_name: String,
+        socket_cfg: Arc<SocketConfig>,
+    ) -> Result<QuicSocketHandle> {
+        let socket = bind_std(&addr)?;
+        let udp = UdpSocketHandle::from_std(
+            socket.try_clone()?,
+            ttl,
+            sw_tag,
+            vec![],
+            socket_cfg.clone(),
+        )?;
+        let cert = rcgen::generate_simple_self_signed(vec![server_name.clone()])?;
+
+        Ok(QuicSocketHandle {
+            udp,
+            socket: std::sync::Mutex::new(Some(socket)),
+            server_name,
+            key: PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()),
+            cert: cert.cert.der().clone(),
+            pinned: std::sync::Mutex::new(None),
+            reliable,
+            socket_cfg,
+            link: OnceLock::new(),
+            inboxes: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            peeked: std::sync::Mutex::new(VecDeque::new()),
+            msg_id: AtomicU32::new(rand::random()),
+        })
+    }
+
+    fn link(&self) -> Result<&Link> {
+        self.link.get().ok_or_else(|| ERR_NOT_BOUND.into())
+    }
+
+    async fn connect(&self, addr: &[SocketAddr]) -> Result<Link> {
+        let Some(pinned) = *self.pinned.lock().unwrap() else {
+            warn!("refused to connect over QUIC as no certificate of the peer is pinned");
+            return Err(ERR_VALIDATION.into());
+        };
+        let remote = *addr.first().ok_or(ERR_VALIDATION)?;
+        let socket = self.socket.lock().unwrap().take().ok_or(ERR_PIPE_BROKE)?;
+
+        let verifier = Arc::new(PinnedCertVerification {
+            provider: Arc::new(ring::default_provider()),
+            pinned,
+        });
+        let mut endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(server_config(&self.cert, &self.key, verifier.clone())?),
+            socket,
+            Arc::new(AsyncStdRuntime),
+        )?;
+        endpoint.set_default_client_config(client_config(&self.cert, &self.key, verifier)?);
+
+        let connect = async { Ok::<_, Error>(endpoint.connect(remote, &self.server_name)?.await?) };
+        let accept = async {
+            loop {
+                let incoming = endpoint.accept().await.ok_or(ERR_PIPE_BROKE)?;
+                if addr.contains(&canonical(incoming.remote_address())) {
+                    break Ok::<_, Error>(incoming.await?);
+                }
+                warn!(
+                    "refused QUIC connection from unexpected {:?}",
+                    incoming.remote_address()
+                );
+                incoming.refuse();
+            }
+        };
+
+        let timeout = self.socket_cfg.timeout * self.socket_cfg.retries as u32;
+        let (tx, rx) =
+            future::timeout(timeout, async { futures::try_join!(connect, accept) }).await??;
+
+        Ok(Link {
+            endpoint,
+            tx,
+            rx,
+            streams: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn recv_on(&self, stream: u8) -> Result<Vec<u8>> {
+        let link = self.link()?;
+        let (_, rx) = inbox(&self.inboxes, stream);
+        match futures::future::select(Box::pin(rx.recv()), Box::pin(link.rx.closed())).await {
+            Either::Left((Ok(res), _)) => Ok(res),
+            Either::Left((Err(_), _)) => Err(ERR_PIPE_BROKE.into()),
+            Either::Right((err, _)) => Err(err.into()),
+        }
+    }
+
+    async fn recv_control(&self, peek: bool) -> Result<Vec<u8>> {
+        let res = {
+            let mut peeked = self.peeked.lock().unwrap();
+            match peek {
+                true => peeked.front().cloned(),
+                false => peeked.pop_front(),
+            }
+        };
+        if let Some(res) = res {
+            return Ok(res);
+        }
+
+        let res = self.recv_on(CONTROL_STREAM).await?;
+        if peek {
+            self.peeked.lock().unwrap().push_back(res.clone());
+        }
+        Ok(res)
+    }
+
+    async fn send_on(&self, stream: u8, buf: &[u8]) -> Result<()> {
+        let link = self.link()?;
+
+        if stream == CONTROL_STREAM || self.reliable.contains(&stream) {
+            let len = u32::try_from(buf.len())
+                .ok()
+                .filter(|c| *c as usize <= MAX_MESSAGE_SIZE)
+                .ok_or(ERR_FRAME_SIZE)?;
+
+            let mut streams = link.streams.lock().await;
+            let send = match streams.entry(stream) {
+                Entry::Occupied(c) => c.into_mut(),
+                Entry::Vacant(c) => {
+                    let mut send = link.tx.open_uni().await?;
+                    send.write_all(&[stream]).await?;
+                    c.insert(send)
+                }
+            };
+            send.write_all(&len.to_be_bytes()).await?;
+            send.write_all(buf).await?;
+        } else {
+            let size = link.tx.max_datagram_size().ok_or(ERR_DATAGRAM)?;
+            let id = self.msg_id.fetch_add(1, Ordering::Relaxed);
+            for buf in fragment(stream, id, buf, size)? {
+                link.tx.send_datagram(buf.into())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IOSocket for QuicSocketHandle {
+    async fn bind(&self, addr: &[SocketAddr]) -> Result<()> {
+        let link = self.connect(addr).await?;
+        spawn_readers(
+            link.rx.clone(),
+            self.inboxes.clone(),
+            self.socket_cfg.timeout,
+        );
+        info!(
+            "QUIC socket at :{} is connected to {:?}",
+            self.udp.get_lan_ip().await?.port(),
+            link.tx.remote_address()
+        );
+        self.link.set(link).map_err(|_| ERR_PIPE_BROKE.into())
+    }
+
+    async fn peer(&self) -> Result<SocketAddr> {
+        Ok(self.link()?.tx.remote_address())
+    }
+
+    async fn poll(&self) -> Result<Vec<u8>> {
+        match self.link.get() {
+            Some(_) => self.recv_control(false).await,
+            None => self.udp.poll().await,
+        }
+    }
+
+    async fn poll_at(&self) -> Result<(Vec<u8>, SocketAddr)> {
+        match self.link.get() {
+            Some(link) => Ok((self.recv_control(false).await?, link.rx.remote_address())),
+            None => self.udp.poll_at().await,
+        }
+    }
+
+    async fn poll_on(&self, stream: u8) -> Result<Vec<u8>> {
+        trace!("polling a message from QUIC connection on stream {stream}");
+        match stream {
+            CONTROL_STREAM => self.poll().await,
+            _ => self.recv_on(stream).await,
+        }
+    }
+
+    async fn peek(&self) -> Result<Vec<u8>> {
+        match self.link.get() {
+            Some(_) => self.recv_control(true).await,
+            None => self.udp.peek().await,
+        }
+    }
+
+    async fn peek_at(&self) -> Result<(Vec<u8>, SocketAddr)> {
+        match self.link.get() {
+            Some(link) => Ok((self.recv_control(true).await?, link.rx.remote_address())),
+            None => self.udp.peek_at().await,
+        }
+    }
+
+    async fn push(&self, buf: &[u8]) -> Result<()> {
+        match self.link.get() {
+            Some(_) => self.send_on(CONTROL_STREAM, buf).await,
+            None => self.udp.push(buf).await,
+        }
+    }
+
+    /// Once bound, messages go to the connected peer whatever `addr` is.
+    async fn push_to(&self, buf: &[u8], addr: &[SocketAddr]) -> Result<()> {
+        match self.link.get() {
+            Some(_) => self.send_on(CONTROL_STREAM, buf).await,
+            None => self.udp.push_to(buf, addr).await,
+        }
+    }
+
+    async fn push_on(&self, stream: u8, buf: &[u8]) -> Result<()> {
+        trace!(
+            "pushing {} bytes to QUIC connection on stream {stream}",
+            buf.len()
+        );
+        self.send_on(stream, buf).await
+    }
+
+    async fn push_stun(&self, buf: &[u8], addr: SocketAddr) -> Result<()> {
+        self.udp.push_stun(buf, addr).await
+    }
+
+    async fn poll_stun(
+        &self,
+        handler: Arc<dyn StunHandler>,
+        base: usize,
+        wait: Duration,
+    ) -> Result<()> {
+        self.udp.poll_stun(handler, base, wait).await
+    }
+
+    async fn get_ttl(&self) -> Result<u32> {
+        self.udp.get_ttl().await
+    }
+
+    async fn set_ttl(&self, ttl: u32) -> Result<()> {
+        self.udp.set_ttl(ttl).await
+    }
+
+    async fn get_lan_ip(&self) -> Result<SocketAddr> {
+        self.udp.get_lan_ip().await
+    }
+
+    async fn get_wan_ip(&self) -> Result<SocketAddr> {
+        self.udp.get_wan_ip().await
+    }
+
+    async fn get_wan_ips(&self) -> Result<Vec<SocketAddr>> {
+        self.udp.get_wan_ips().await
+    }
+
+    fn cert_hash(&self) -> Option<CertHash> {
+        Some(Sha256::digest(&self.cert).into())
+    }
+
+    fn pin_cert(&self, hash: CertHash) {
+        *self.pinned.lock().unwrap() = Some(hash);
+    }
+}
+
+/// `PinnedCertVerification` accepts the self-signed certificate of the peer pinned while
+/// punching only, checking handshake signatures too, as peers have no common authority to
+/// issue them.
+#[derive(Debug)]
+struct PinnedCertVerification {
+    provider: Arc<CryptoProvider>,
+    pinned: CertHash,
+}
+
+impl PinnedCertVerification {
+    fn verify(&self, end_entity: &CertificateDer<'_>) -> core::result::Result<(), rustls::Error> {
+        let hash: CertHash = Sha256::digest(end_entity).into();
+        if hash != self.pinned {
+            warn!("refused QUIC peer presenting a certificate other than the pinned one");
+            return Err(CertificateError::ApplicationVerificationFailure.into());
+        }
+        Ok(())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> core::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> core::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> core::result::Result<ServerCertVerified, rustls::Error> {
+        self.verify(end_entity)
+            .map(|_| ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> core::result::Result<HandshakeSignatureValid, rustls::Error> {
+        PinnedCertVerification::verify_tls12_signature(self, message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> core::result::Result<HandshakeSignatureValid, rustls::Error> {
+        PinnedCertVerification::verify_tls13_signature(self, message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        PinnedCertVerification::supported_verify_schemes(self)
+    }
+}
+
+impl ClientCertVerifier for PinnedCertVerification {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> core::result::Result<ClientCertVerified, rustls::Error> {
+        self.verify(end_entity)
+            .map(|_| ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> core::result::Result<HandshakeSignatureValid, rustls::Error> {
+        PinnedCertVerification::verify_tls12_signature(self, message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> core::result::Result<HandshakeSignatureValid, rustls::Error> {
+        PinnedCertVerification::verify_tls13_signature(self, message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        PinnedCertVerification::supported_verify_schemes(self)
+    }
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+    Arc::new(transport)
+}
+
+fn client_config(
+    cert: &CertificateDer<'static>,
+    key: &PrivatePkcs8KeyDer<'static>,
+    verifier: Arc<PinnedCertVerification>,
+) -> Result<ClientConfig> {
+    let provider = verifier.provider.clone();
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_client_auth_cert(vec![cert.clone()], key.clone_key().into())?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut cfg = ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+    cfg.transport_config(transport_config());
+    Ok(cfg)
+}
+
+fn server_config(
+    cert: &CertificateDer<'static>,
+    key: &PrivatePkcs8KeyDer<'static>,
+    verifier: Arc<PinnedCertVerification>,
+) -> Result<ServerConfig> {
+    let provider = verifier.provider.clone();
+    let mut crypto = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(vec![cert.clone()], key.clone_key().into())?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut cfg = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+    cfg.transport_config(transport_config());
+    Ok(cfg)
+}
+
+/// Spawns tasks handing messages of incoming streams and datagrams over to their inboxes,
+/// until the connection is closed.
+fn spawn_readers(conn: Connection, inboxes: Inboxes, timeout: Duration) {
+    let streams = (conn.clone(), inboxes.clone());
+    task::spawn(async move {
+        let (conn, inboxes) = streams;
+        while let Ok(stream) = conn.accept_uni().await {
+            let inboxes = inboxes.clone();
+            task::spawn(async move {
+                if let Err(e) = read_stream(stream, inboxes).await {
+                    trace!("QUIC stream is closed: {e}");
+                }
+            });
+        }
+    });
+
+    task::spawn(async move {
+        let mut assembly = Reassembler::new(timeout);
+        let addr = conn.remote_address();
+        while let Ok(datagram) = conn.read_datagram().await {
+            if let Some((stream, res)) = assembly.push(&datagram, addr) {
+                let (tx, _) = inbox(&inboxes, stream);
+                if let Err(TrySendError::Full(_)) = tx.try_send(res) {
+                    warn!("dropped message for stream {stream}: queue is full");
+                }
+            }
+        }
+    });
+}
+
+/// Reads length-prefixed messages of a stream opened by [`QuicSocketHandle::send_on`].
+async fn read_stream(mut stream: RecvStream, inboxes: Inboxes) -> Result<()> {
+    let mut id = [0u8; 1];
+    stream.read_exact(&mut id).await?;
+    let (tx, _) = inbox(&inboxes, id[0]);
+
+    loop {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).await?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(ERR_FRAME_SIZE.into());
+        }
+
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf).await?;
+        if tx.send(buf).await.is_err() {
+            return Ok(());
+        }
+    }
+}
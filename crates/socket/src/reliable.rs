@@ -0,0 +1,359 @@
+use err::{consts::ERR_DELIVERY, Result};
+use log::{trace, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+const KIND_DATA: u8 = 0;
+const KIND_ACK: u8 = 1;
+/// Number of messages past the first missing one the receiver buffers and acknowledges
+/// selectively.
+pub const WINDOW: u32 = 64;
+/// Number of times a message is sent again before the stream gives up on it.
+pub const MAX_RETRANSMITS: u16 = 10;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(10);
+
+struct Pending {
+    buf: Vec<u8>,
+    sent: Instant,
+    retransmits: u16,
+}
+
+/// `Rtt` estimator computing retransmission timeout as described in RFC 6298.
+struct Rtt {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl Rtt {
+    fn new() -> Rtt {
+        Rtt {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                self.rttvar = self.rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4;
+                srtt * 7 / 8 + rtt / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + (self.rttvar * 4).max(CLOCK_GRANULARITY)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Timeout of a message sent `retransmits` times already, doubled on every attempt.
+    fn timeout(&self, retransmits: u16) -> Duration {
+        self.rto
+            .saturating_mul(1 << retransmits.min(16))
+            .min(MAX_RTO)
+    }
+}
+
+/// `Reliable` keeps delivery state of one logical stream for both directions.
+///
+/// Outgoing messages are numbered and kept until the peer acknowledges them, either
+/// cumulatively or with a selective ACK bitmap, and are sent again once retransmission
+/// timeout derived from measured round-trip time runs out. Incoming messages are handed
+/// over strictly in order, with ones arriving early buffered up to [`WINDOW`][WINDOW].
+///
+/// Message layout is `kind | seq: u32 | payload` for data and `kind | next: u32 | sack: u64`
+/// for acknowledgements, where bit `i` of `sack` stands for message `next + 1 + i`.
+pub(crate) struct Reliable {
+    next: u32,
+    unacked: BTreeMap<u32, Pending>,
+    rtt: Rtt,
+    expected: u32,
+    early: BTreeMap<u32, Vec<u8>>,
+    ready: VecDeque<Vec<u8>>,
+}
+
+impl Reliable {
+    pub fn new() -> Reliable {
+        Reliable {
+            next: 0,
+            unacked: BTreeMap::new(),
+            rtt: Rtt::new(),
+            expected: 0,
+            early: BTreeMap::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Numbers `payload`, returning a message to be sent to the peer.
+    pub fn send(&mut self, payload: &[u8], now: Instant) -> Vec<u8> {
+        let seq = self.next;
+        self.next = self.next.wrapping_add(1);
+
+        let mut buf = Vec::with_capacity(payload.len() + 5);
+        buf.push(KIND_DATA);
+        buf.extend(seq.to_be_bytes());
+        buf.extend_from_slice(payload);
+        self.unacked.insert(
+            seq,
+            Pending {
+                buf: buf.clone(),
+                sent: now,
+                retransmits: 0,
+            },
+        );
+        buf
+    }
+
+    /// Feeds a message received from the peer, returning an acknowledgement to be sent back
+    /// when it carried data.
+    pub fn receive(&mut self, msg: &[u8], now: Instant) -> Option<Vec<u8>> {
+        match msg.split_first() {
+            Some((&KIND_DATA, body)) if body.len() >= 4 => {
+                let (seq, payload) = body.split_at(4);
+                self.accept(u32::from_be_bytes(seq.try_into().ok()?), payload);
+                Some(self.ack())
+            }
+            Some((&KIND_ACK, body)) if body.len() == 12 => {
+                let (next, sack) = body.split_at(4);
+                self.acknowledge(
+                    u32::from_be_bytes(next.try_into().ok()?),
+                    u64::from_be_bytes(sack.try_into().ok()?),
+                    now,
+                );
+                None
+            }
+            _ => {
+                warn!("dropped malformed message of {} bytes", msg.len());
+                None
+            }
+        }
+    }
+
+    /// Takes the next message delivered in order.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.ready.pop_front()
+    }
+
+    /// Moment the earliest unacknowledged message times out.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.unacked
+            .values()
+            .map(|c| c.sent + self.rtt.timeout(c.retransmits))
+            .min()
+    }
+
+    /// Returns messages to be sent again, or an error once one of them was given up.
+    ///
+    /// Given up message is dropped, but the peer keeps waiting for it, so the stream
+    /// can't make progress afterwards.
+    pub fn due(&mut self, now: Instant) -> Result<Vec<Vec<u8>>> {
+        let mut res = vec![];
+        let mut lost = None;
+        for (seq, c) in self.unacked.iter_mut() {
+            if now < c.sent + self.rtt.timeout(c.retransmits) {
+                continue;
+            }
+            if c.retransmits == MAX_RETRANSMITS {
+                lost = Some(*seq);
+                break;
+            }
+            trace!(
+                "retransmitting message {seq}, attempt {}",
+                c.retransmits + 1
+            );
+            c.retransmits += 1;
+            c.sent = now;
+            res.push(c.buf.clone());
+        }
+        match lost {
+            Some(seq) => {
+                self.unacked.remove(&seq);
+                Err(ERR_DELIVERY.into())
+            }
+            None => Ok(res),
+        }
+    }
+
+    fn accept(&mut self, seq: u32, payload: &[u8]) {
+        let offset = seq.wrapping_sub(self.expected);
+        if offset > WINDOW {
+            trace!("dropped message {seq} outside of receive window");
+            return;
+        }
+        self.early.entry(seq).or_insert_with(|| payload.to_vec());
+        while let Some(res) = self.early.remove(&self.expected) {
+            self.ready.push_back(res);
+            self.expected = self.expected.wrapping_add(1);
+        }
+    }
+
+    fn ack(&self) -> Vec<u8> {
+        let sack = self.early.keys().fold(0u64, |sack, seq| {
+            match seq.wrapping_sub(self.expected).checked_sub(1) {
+                Some(i) if i < u64::BITS => sack | 1 << i,
+                _ => sack,
+            }
+        });
+
+        let mut buf = Vec::with_capacity(13);
+        buf.push(KIND_ACK);
+        buf.extend(self.expected.to_be_bytes());
+        buf.extend(sack.to_be_bytes());
+        buf
+    }
+
+    fn acknowledge(&mut self, next: u32, sack: u64, now: Instant) {
+        let acked = self
+            .unacked
+            .keys()
+            .copied()
+            .filter(|seq| match next.wrapping_sub(*seq) {
+                // sequence numbers before `next` are acknowledged cumulatively
+                i if i > 0 && i <= u32::MAX / 2 => true,
+                _ => match seq.wrapping_sub(next).checked_sub(1) {
+                    Some(i) if i < u64::BITS => sack & 1 << i != 0,
+                    _ => false,
+                },
+            })
+            .collect::<Vec<_>>();
+
+        for seq in acked {
+            if let Some(c) = self.unacked.remove(&seq) {
+                // Karn's algorithm: retransmitted messages give ambiguous samples
+                if c.retransmits == 0 {
+                    self.rtt.sample(now.duration_since(c.sent));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    const TEST_RTT: Duration = Duration::from_millis(40);
+
+    fn messages(n: u8) -> Vec<Vec<u8>> {
+        (0..n).map(|c| vec![c; c as usize]).collect()
+    }
+
+    #[test]
+    fn reliable_works() {
+        let now = Instant::now();
+        let mut tx = Reliable::new();
+        let mut rx = Reliable::new();
+
+        let msgs = messages(8);
+        let sent = msgs.iter().map(|c| tx.send(c, now)).collect::<Vec<_>>();
+        let acks = sent
+            .iter()
+            .filter_map(|c| rx.receive(c, now))
+            .collect::<Vec<_>>();
+        let res = std::iter::from_fn(|| rx.pop()).collect::<Vec<_>>();
+        // messages are delivered in order
+        assert_eq!(res, msgs);
+
+        let now = now + TEST_RTT;
+        for c in acks {
+            assert_eq!(tx.receive(&c, now), None);
+        }
+        // acknowledged messages aren't sent again
+        assert_eq!(tx.deadline(), None);
+        assert!(tx.due(now + MAX_RTO).unwrap().is_empty());
+        // timeout follows measured round-trip time
+        assert!(tx.rtt.rto < INITIAL_RTO);
+        assert!(tx.rtt.rto >= MIN_RTO);
+
+        // malformed messages are dropped
+        assert_eq!(rx.receive(&[KIND_ACK, 0], now), None);
+        assert_eq!(rx.receive(&[], now), None);
+    }
+
+    #[test]
+    fn reliable_reorder() {
+        let now = Instant::now();
+        let mut tx = Reliable::new();
+        let mut rx = Reliable::new();
+
+        let msgs = messages(4);
+        let sent = msgs.iter().map(|c| tx.send(c, now)).collect::<Vec<_>>();
+
+        let ack = rx.receive(&sent[2], now).unwrap();
+        // early message waits for missing ones
+        assert_eq!(rx.pop(), None);
+        tx.receive(&ack, now + TEST_RTT);
+        // selectively acknowledged message isn't sent again
+        let res = tx.due(now + MAX_RTO).unwrap();
+        assert_eq!(res, vec![sent[0].clone(), sent[1].clone(), sent[3].clone()]);
+
+        rx.receive(&sent[2], now);
+        rx.receive(&sent[0], now);
+        rx.receive(&sent[3], now);
+        rx.receive(&sent[1], now);
+        let res = std::iter::from_fn(|| rx.pop()).collect::<Vec<_>>();
+        // duplicates are dropped and the rest is delivered in order
+        assert_eq!(res, msgs);
+    }
+
+    #[test]
+    fn reliable_loss() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let mut now = Instant::now();
+        let mut tx = Reliable::new();
+        let mut rx = Reliable::new();
+
+        let msgs = messages(64);
+        let mut wire = msgs.iter().map(|c| tx.send(c, now)).collect::<Vec<_>>();
+        let mut res = vec![];
+
+        for _ in 0..=MAX_RETRANSMITS {
+            // a third of messages in each direction is lost, the rest is reordered
+            wire.retain(|_| rng.gen_ratio(2, 3));
+            wire.shuffle(&mut rng);
+            let mut acks = wire
+                .iter()
+                .filter_map(|c| rx.receive(c, now))
+                .collect::<Vec<_>>();
+            res.extend(std::iter::from_fn(|| rx.pop()));
+
+            now += TEST_RTT;
+            acks.retain(|_| rng.gen_ratio(2, 3));
+            for c in acks {
+                tx.receive(&c, now);
+            }
+            let Some(deadline) = tx.deadline() else {
+                break;
+            };
+            now = now.max(deadline);
+            wire = tx.due(now).unwrap();
+        }
+        // lost messages are retransmitted and delivered in order
+        assert_eq!(res, msgs);
+    }
+
+    #[test]
+    fn reliable_gives_up() {
+        let mut now = Instant::now();
+        let mut tx = Reliable::new();
+        tx.send(b"lost", now);
+
+        for _ in 0..MAX_RETRANSMITS {
+            now = tx.deadline().unwrap();
+            assert_eq!(tx.due(now).unwrap().len(), 1);
+        }
+        now = tx.deadline().unwrap();
+        // message is dropped after the last attempt
+        assert!(tx.due(now).is_err());
+        assert_eq!(tx.deadline(), None);
+    }
+}
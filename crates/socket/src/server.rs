@@ -0,0 +1,232 @@
+use async_std::{
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+};
+use bytecodec::{DecodeExt, EncodeExt};
+use err::{consts::ERR_ADDRESS, Error, Result};
+use log::{info, trace};
+use serde::Deserialize;
+use stun_codec::{
+    rfc5389::{
+        self,
+        attributes::{Software, XorMappedAddress},
+        methods::BINDING,
+    },
+    rfc5780::attributes::{ChangeRequest, OtherAddress, ResponseOrigin},
+    Attribute as _, Message, MessageClass, MessageDecoder, MessageEncoder,
+};
+
+use crate::nat::Attribute;
+use crate::udp::{bind_std, canonical, to_family, PACKET_BUF_SIZE};
+
+/// Flag of `CHANGE-REQUEST` asking to respond from the alternate address.
+pub(crate) const CHANGE_IP: u32 = 0x4;
+/// Flag of `CHANGE-REQUEST` asking to respond from the alternate port.
+pub(crate) const CHANGE_PORT: u32 = 0x2;
+
+/// `StunServerConfig` for `.toml` config parsing.
+/// Sets address of [`StunServer`], along with the alternate one enabling RFC 5780.
+#[derive(Debug, Deserialize, Clone)]
+pub struct StunServerConfig {
+    #[serde(default = "default_addr")]
+    addr: SocketAddr,
+    /// Address of another IP and port, advertised as `OTHER-ADDRESS` and responded from
+    /// on `CHANGE-REQUEST`. Both addresses are to be specific then, as they're reported
+    /// to clients.
+    alternate: Option<SocketAddr>,
+    sw_tag: Option<String>,
+}
+
+impl Default for StunServerConfig {
+    fn default() -> Self {
+        StunServerConfig {
+            addr: default_addr(),
+            alternate: None,
+            sw_tag: None,
+        }
+    }
+}
+
+impl StunServerConfig {
+    pub fn new(addr: SocketAddr, alternate: Option<SocketAddr>, sw_tag: Option<String>) -> Self {
+        StunServerConfig {
+            addr,
+            alternate,
+            sw_tag,
+        }
+    }
+}
+
+/// `StunServer` answering RFC 5389 Binding requests with `XOR-MAPPED-ADDRESS`, so peers
+/// learn their public addresses without third-party servers.
+///
+/// With an alternate address it binds both addresses on both ports, responding with
+/// `RESPONSE-ORIGIN` and `OTHER-ADDRESS` and honoring `CHANGE-REQUEST` per RFC 5780, as
+/// [`detect_nat`][crate::SocketHandle::detect_nat] needs.
+pub struct StunServer {
+    /// Sockets ordered by address, then by port, the primary one first.
+    sockets: Vec<Arc<UdpSocket>>,
+    sw_tag: Option<String>,
+}
+
+impl StunServer {
+    pub async fn bind(cfg: StunServerConfig) -> Result<StunServer> {
+        let bind = |addr| Ok::<_, Error>(Arc::new(UdpSocket::from(bind_std(&[addr])?)));
+        let mut sockets = vec![bind(cfg.addr)?];
+        if let Some(alternate) = cfg.alternate {
+            if alternate.ip() == cfg.addr.ip() {
+                return Err(ERR_ADDRESS.into());
+            }
+            // ports of the primary address may be ephemeral, so alternate ones follow them
+            sockets.push(bind(SocketAddr::new(cfg.addr.ip(), alternate.port()))?);
+            for idx in 0..2 {
+                let port = sockets[idx].local_addr()?.port();
+                sockets.push(bind(SocketAddr::new(alternate.ip(), port))?);
+            }
+        }
+        info!(
+            "STUN server is up at {:?}",
+            sockets.iter().map(|c| c.local_addr()).collect::<Vec<_>>()
+        );
+
+        Ok(StunServer {
+            sockets,
+            sw_tag: cfg.sw_tag,
+        })
+    }
+
+    /// Primary address of the server.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.sockets[0].local_addr()?)
+    }
+
+    /// Answers requests on all addresses until one of sockets fails.
+    pub async fn run(&self) -> Result<()> {
+        let serve = (0..self.sockets.len()).map(|c| self.serve(c));
+        futures::future::try_join_all(serve).await.map(|_| ())
+    }
+
+    async fn serve(&self, idx: usize) -> Result<()> {
+        let mut buf = [0u8; PACKET_BUF_SIZE];
+        loop {
+            let (len, client) = self.sockets[idx].recv_from(&mut buf).await?;
+            let client = canonical(client);
+            let Some((res, from)) = self.respond(idx, &buf[..len], client) else {
+                trace!("dropped non-STUN request from {:?}", client);
+                continue;
+            };
+            let socket = &self.sockets[from];
+            socket
+                .send_to(&res, to_family(client, socket.local_addr()?))
+                .await?;
+        }
+    }
+
+    /// Builds a response to the request in `buf` received on socket `idx`, returning it
+    /// along with the socket to send it from.
+    fn respond(&self, idx: usize, buf: &[u8], client: SocketAddr) -> Option<(Vec<u8>, usize)> {
+        let msg = MessageDecoder::<rfc5389::Attribute>::new()
+            .decode_from_bytes(buf)
+            .ok()?
+            .ok()?;
+        if msg.class() != MessageClass::Request || msg.method() != BINDING {
+            return None;
+        }
+
+        let mut res =
+            Message::<Attribute>::new(MessageClass::SuccessResponse, BINDING, msg.transaction_id());
+        res.add_attribute(XorMappedAddress::new(client).into());
+        if let Some(sw_tag) = &self.sw_tag {
+            res.add_attribute(Software::new(sw_tag.to_owned()).ok()?.into());
+        }
+        let mut from = idx;
+        if self.sockets.len() > 1 {
+            let change = change_request(&msg);
+            from ^=
+                ((change & CHANGE_IP != 0) as usize) << 1 ^ (change & CHANGE_PORT != 0) as usize;
+            let origin = self.sockets[from].local_addr().ok()?;
+            let other = self.sockets[idx ^ 3].local_addr().ok()?;
+            res.add_attribute(ResponseOrigin::new(origin).into());
+            res.add_attribute(OtherAddress::new(other).into());
+        }
+        Some((MessageEncoder::new().encode_into_bytes(res).ok()?, from))
+    }
+}
+
+/// Flags of `CHANGE-REQUEST` in `msg`, read raw as its decoder expects them one bit off.
+pub(crate) fn change_request(msg: &Message<rfc5389::Attribute>) -> u32 {
+    msg.unknown_attributes()
+        .find(|c| c.get_type().as_u16() == ChangeRequest::CODEPOINT)
+        .and_then(|c| c.value().try_into().ok())
+        .map_or(0, u32::from_be_bytes)
+}
+
+fn default_addr() -> SocketAddr {
+    SocketAddr::new(std::net::Ipv6Addr::UNSPECIFIED.into(), 3478)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::{net::IpAddr, task};
+    use std::time::Duration;
+
+    use crate::nat;
+    use crate::udp::UdpSocketHandle;
+    use crate::{IOSocket, NatBehavior, SocketConfig, StunConfig, LOOPBACK_IP};
+
+    const ALTERNATE_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 2));
+    const TIMEOUT: Duration = Duration::from_millis(10);
+
+    async fn spawn(alternate: Option<SocketAddr>) -> SocketAddr {
+        let cfg = StunServerConfig::new(SocketAddr::new(LOOPBACK_IP, 0), alternate, None);
+        let server = StunServer::bind(cfg).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        task::spawn(async move { server.run().await });
+        addr
+    }
+
+    async fn client(server: SocketAddr) -> UdpSocketHandle {
+        let stun = StunConfig::new(vec![server.to_string()], 1, false, server.to_string());
+        UdpSocketHandle::new(
+            vec![SocketAddr::new(LOOPBACK_IP, 0)],
+            None,
+            None,
+            vec![],
+            std::sync::Arc::new(SocketConfig::new(10, TIMEOUT).with_stun(stun)),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[async_std::test]
+    async fn stun_server_works() {
+        let server = spawn(None).await;
+        let socket = client(server).await;
+        let res = socket.get_wan_ip().await.unwrap();
+        // client learns its own address with no NAT in between
+        assert_eq!(res, socket.get_lan_ip().await.unwrap());
+
+        let res = nat::detect(&socket, server, None, TIMEOUT).await.unwrap();
+        // plain server has no alternate address for behaviour tests
+        assert!(res.open);
+        assert_eq!(res.filtering, None);
+
+        let server = spawn(Some(SocketAddr::new(ALTERNATE_IP, 0))).await;
+        let socket = client(server).await;
+        let res = nat::detect(&socket, server, None, TIMEOUT).await.unwrap();
+        // alternate address lets `CHANGE-REQUEST` pass through
+        assert!(res.open);
+        assert_eq!(res.mapping, Some(NatBehavior::EndpointIndependent));
+        assert_eq!(res.filtering, Some(NatBehavior::EndpointIndependent));
+
+        let cfg = StunServerConfig::new(
+            SocketAddr::new(LOOPBACK_IP, 0),
+            Some(SocketAddr::new(LOOPBACK_IP, 0)),
+            None,
+        );
+        // alternate address has to differ from the primary one
+        assert!(StunServer::bind(cfg).await.is_err());
+    }
+}
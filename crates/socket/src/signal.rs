@@ -0,0 +1,321 @@
+use async_std::{
+    future,
+    io::{ReadExt, WriteExt},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    task,
+};
+use err::{
+    consts::{ERR_FRAME_SIZE, ERR_SIGNAL_AUTH, ERR_SIGNAL_TIMEOUT, ERR_VALIDATION},
+    Error, Result,
+};
+use hmac::{Hmac, Mac};
+use log::{info, trace, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const ROOM_SIZE: usize = 32;
+const ID_SIZE: usize = 16;
+const TIMESTAMP_SIZE: usize = 8;
+const MAC_SIZE: usize = 32;
+/// Largest frame accepted by either side, which is plenty for candidate lines.
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+/// Time given to connect and to send the registration frame.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Clock difference tolerated between peers on top of the time spent waiting.
+const CLOCK_SKEW: u64 = 60;
+/// Domain separation of values derived from the room secret.
+const ROOM_TAG: &[u8] = b"ensd\0signal\0room\0";
+const KEY_TAG: &[u8] = b"ensd\0signal\0key\0";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// `SignalConfig` for `.toml` config parsing.
+/// Points to a [`SignalServer`] pairing peers which know the same `room` secret.
+///
+/// The server only learns a hash of `room`, while peers authenticate their addresses
+/// with a key derived from it, so the server can't forge or redirect them. `room` is to
+/// be hard to guess then, e.g. a random phrase or a fingerprint kept between peers.
+#[derive(Deserialize, Clone)]
+pub struct SignalConfig {
+    server: String,
+    room: String,
+    /// Seconds to wait for the remote peer to join the room.
+    #[serde(default = "default_wait")]
+    wait: u64,
+}
+
+impl SignalConfig {
+    pub fn new(server: String, room: String, wait: u64) -> Self {
+        SignalConfig { server, room, wait }
+    }
+
+    /// Room id told to the server and the key authenticating messages of peers.
+    fn derive(&self) -> ([u8; ROOM_SIZE], [u8; MAC_SIZE]) {
+        let hash = |tag: &[u8]| {
+            Sha256::new_with_prefix(tag)
+                .chain_update(&self.room)
+                .finalize()
+        };
+        (hash(ROOM_TAG).into(), hash(KEY_TAG).into())
+    }
+}
+
+impl fmt::Debug for SignalConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignalConfig")
+            .field("server", &self.server)
+            .field("wait", &self.wait)
+            .finish_non_exhaustive()
+    }
+}
+
+/// `SignalServerConfig` for `.toml` config parsing.
+/// Sets address of [`SignalServer`].
+#[derive(Debug, Deserialize, Clone)]
+pub struct SignalServerConfig {
+    #[serde(default = "default_addr")]
+    addr: SocketAddr,
+}
+
+impl Default for SignalServerConfig {
+    fn default() -> Self {
+        SignalServerConfig {
+            addr: default_addr(),
+        }
+    }
+}
+
+impl SignalServerConfig {
+    pub fn new(addr: SocketAddr) -> Self {
+        SignalServerConfig { addr }
+    }
+}
+
+/// Peer waiting for another one to join its room.
+struct Waiting {
+    stream: TcpStream,
+    blob: Vec<u8>,
+    since: Instant,
+}
+
+/// `SignalServer` pairing peers over `TCP` by room id and handing each of them the
+/// message of the other one.
+///
+/// Every frame is a big-endian `u32` length followed by the payload, which is
+/// `room | message` when registering and the remote peer's `message` when answering.
+/// Messages are opaque to the server.
+pub struct SignalServer {
+    listener: TcpListener,
+    rooms: Arc<Mutex<HashMap<[u8; ROOM_SIZE], Waiting>>>,
+    /// Time rooms with a single peer are kept for.
+    ttl: Duration,
+}
+
+impl SignalServer {
+    pub async fn bind(cfg: SignalServerConfig) -> Result<SignalServer> {
+        let listener = TcpListener::bind(cfg.addr).await?;
+        info!("signaling server is up at {:?}", listener.local_addr());
+
+        Ok(SignalServer {
+            listener,
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            ttl: Duration::from_secs(default_wait()),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accepts peers until the listener fails.
+    pub async fn run(&self) -> Result<()> {
+        loop {
+            let (stream, peer) = self.listener.accept().await?;
+            let rooms = self.rooms.clone();
+            let ttl = self.ttl;
+            task::spawn(async move {
+                if let Err(e) = register(rooms, stream, ttl).await {
+                    trace!("dropped signaling peer {:?}: {e}", peer);
+                }
+            });
+        }
+    }
+}
+
+/// Reads the registration of `stream`, pairing it with the peer waiting in the same room
+/// or leaving it to wait for one.
+async fn register(
+    rooms: Arc<Mutex<HashMap<[u8; ROOM_SIZE], Waiting>>>,
+    mut stream: TcpStream,
+    ttl: Duration,
+) -> Result<()> {
+    let frame = future::timeout(CONNECT_TIMEOUT, read_frame(&mut stream)).await??;
+    if frame.len() < ROOM_SIZE {
+        return Err(ERR_VALIDATION.into());
+    }
+    let (room, blob) = frame.split_at(ROOM_SIZE);
+    let room: [u8; ROOM_SIZE] = room.try_into().unwrap();
+
+    let mut rooms = rooms.lock().await;
+    rooms.retain(|_, c| c.since.elapsed() < ttl);
+    match rooms.remove(&room) {
+        Some(mut other) => {
+            drop(rooms);
+            write_frame(&mut other.stream, blob).await?;
+            write_frame(&mut stream, &other.blob).await?;
+            trace!("paired signaling peers in room {:02x?}", &room[..4]);
+        }
+        None => {
+            let waiting = Waiting {
+                stream,
+                blob: blob.to_vec(),
+                since: Instant::now(),
+            };
+            rooms.insert(room, waiting);
+        }
+    }
+    Ok(())
+}
+
+/// Exchanges `payload` with the peer joining the same room of `cfg`, returning the
+/// remote one once it's authenticated.
+pub(crate) async fn exchange(cfg: &SignalConfig, payload: &[u8]) -> Result<Vec<u8>> {
+    let (room, key) = cfg.derive();
+    let id = rand::random::<[u8; ID_SIZE]>();
+    let blob = seal(&key, &id, timestamp(), payload);
+
+    let mut stream = future::timeout(CONNECT_TIMEOUT, TcpStream::connect(cfg.server.as_str()))
+        .await
+        .map_err(Error::from)
+        .and_then(|c| c.map_err(Error::from))?;
+    write_frame(&mut stream, &[room.as_slice(), &blob].concat()).await?;
+    info!("waiting for remote peer at signaling server {}", cfg.server);
+
+    let res = future::timeout(Duration::from_secs(cfg.wait), read_frame(&mut stream))
+        .await
+        .map_err(|_| ERR_SIGNAL_TIMEOUT)??;
+    open(&key, &id, cfg.wait + CLOCK_SKEW, &res)
+}
+
+/// Builds `id | timestamp | payload | mac` message.
+fn seal(key: &[u8], id: &[u8; ID_SIZE], timestamp: u64, payload: &[u8]) -> Vec<u8> {
+    let mut msg = [id.as_slice(), &timestamp.to_be_bytes(), payload].concat();
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(&msg);
+    msg.extend_from_slice(&mac.finalize().into_bytes());
+    msg
+}
+
+/// Verifies remote `msg` built by [`seal`], rejecting own ones reflected back and ones
+/// older than `max_age` seconds, and returns its payload.
+fn open(key: &[u8], id: &[u8; ID_SIZE], max_age: u64, msg: &[u8]) -> Result<Vec<u8>> {
+    let Some(len) = msg.len().checked_sub(MAC_SIZE) else {
+        return Err(ERR_SIGNAL_AUTH.into());
+    };
+    if len < ID_SIZE + TIMESTAMP_SIZE {
+        return Err(ERR_SIGNAL_AUTH.into());
+    }
+    let (msg, tag) = msg.split_at(len);
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(msg);
+    mac.verify_slice(tag).map_err(|_| ERR_SIGNAL_AUTH)?;
+
+    let (remote, msg) = msg.split_at(ID_SIZE);
+    let (sent, payload) = msg.split_at(TIMESTAMP_SIZE);
+    let sent = u64::from_be_bytes(sent.try_into().unwrap());
+    if remote == id || timestamp().abs_diff(sent) > max_age {
+        warn!("refused replayed signaling message");
+        return Err(ERR_SIGNAL_AUTH.into());
+    }
+    Ok(payload.to_vec())
+}
+
+async fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(ERR_FRAME_SIZE.into());
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn write_frame(stream: &mut TcpStream, buf: &[u8]) -> Result<()> {
+    if buf.len() > MAX_FRAME_SIZE {
+        return Err(ERR_FRAME_SIZE.into());
+    }
+    stream.write_all(&(buf.len() as u32).to_be_bytes()).await?;
+    stream.write_all(buf).await?;
+    Ok(stream.flush().await?)
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn default_wait() -> u64 {
+    300
+}
+
+fn default_addr() -> SocketAddr {
+    SocketAddr::new(std::net::Ipv6Addr::UNSPECIFIED.into(), 34255)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::LOOPBACK_IP;
+
+    async fn spawn() -> String {
+        let cfg = SignalServerConfig::new(SocketAddr::new(LOOPBACK_IP, 0));
+        let server = SignalServer::bind(cfg).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        task::spawn(async move { server.run().await });
+        addr.to_string()
+    }
+
+    #[async_std::test]
+    async fn signal_works() {
+        let server = spawn().await;
+        let a = SignalConfig::new(server.clone(), "room".to_owned(), 5);
+        let b = a.clone();
+        let (res_a, res_b) = futures::join!(exchange(&a, b"peer a"), exchange(&b, b"peer b"));
+        // peers in one room get messages of each other
+        assert_eq!(res_a.unwrap(), b"peer b");
+        assert_eq!(res_b.unwrap(), b"peer a");
+
+        let b = SignalConfig::new(server, "another room".to_owned(), 1);
+        // peer alone in its room gives up
+        assert!(matches!(exchange(&b, b"").await, Err(Error::TimedOut(_))));
+    }
+
+    #[test]
+    fn signal_auth_works() {
+        let (_, key) = SignalConfig::new(String::new(), "room".to_owned(), 5).derive();
+        let (a, b) = ([1u8; ID_SIZE], [2u8; ID_SIZE]);
+        let msg = seal(&key, &a, timestamp(), b"payload");
+        // untouched message of the remote peer passes
+        assert_eq!(open(&key, &b, 5, &msg).unwrap(), b"payload");
+
+        let mut tampered = msg.clone();
+        tampered[ID_SIZE + TIMESTAMP_SIZE] ^= 1;
+        // altered payload is refused, as is the one under another key
+        assert!(open(&key, &b, 5, &tampered).is_err());
+        assert!(open(&[0u8; MAC_SIZE], &b, 5, &msg).is_err());
+
+        // own message reflected back and stale one are refused
+        assert!(open(&key, &a, 5, &msg).is_err());
+        let msg = seal(&key, &a, timestamp() - 60, b"payload");
+        assert!(open(&key, &b, 5, &msg).is_err());
+    }
+}
@@ -0,0 +1,492 @@
+use async_std::{
+    channel::{self, Receiver, Sender},
+    net::{IpAddr, SocketAddr},
+    task,
+};
+use bytecodec::{DecodeExt, EncodeExt};
+use err::{
+    consts::{ERR_ADDRESS, ERR_CLOSED},
+    Result,
+};
+use log::trace;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+use stun_codec::{
+    rfc5389::{self, attributes::XorMappedAddress, methods::BINDING},
+    Message, MessageClass, MessageDecoder, MessageEncoder,
+};
+
+use crate::frame::Msg;
+use crate::nat::NatBehavior;
+
+/// Datagrams queued for a host before new ones are dropped, as with a full socket buffer.
+const QUEUE_SIZE: usize = 1024;
+/// Longest a datagram waits for bandwidth of its host before it's dropped.
+const QUEUE_DELAY: Duration = Duration::from_millis(500);
+/// Delay reordered datagrams get on top of the longest jitter, so later ones overtake them.
+const REORDER_DELAY: Duration = Duration::from_millis(10);
+/// First port handed out to hosts bound on port zero and to `NAT` mappings.
+const FIRST_PORT: u16 = 20000;
+const DEFAULT_TTL: u32 = 64;
+
+/// `Impairment` a simulated [`Network`] applies to every datagram, with `loss`, `reorder`
+/// and `duplicate` being fractions of datagrams affected.
+#[derive(Debug, Clone, Default)]
+pub struct Impairment {
+    loss: f64,
+    latency: Duration,
+    /// Longest delay added to `latency` at random.
+    jitter: Duration,
+    reorder: f64,
+    duplicate: f64,
+    /// Bytes per second each host sends at most, unlimited when zero.
+    bandwidth: u64,
+}
+
+impl Impairment {
+    pub fn new(
+        loss: f64,
+        latency: Duration,
+        jitter: Duration,
+        reorder: f64,
+        duplicate: f64,
+        bandwidth: u64,
+    ) -> Self {
+        Impairment {
+            loss: loss.clamp(0.0, 1.0),
+            latency,
+            jitter,
+            reorder: reorder.clamp(0.0, 1.0),
+            duplicate: duplicate.clamp(0.0, 1.0),
+            bandwidth,
+        }
+    }
+}
+
+/// `NAT` of RFC 4787 in front of hosts on a simulated [`Network`], handing out ports in
+/// sequence for new mappings.
+struct Nat {
+    mapping: NatBehavior,
+    filtering: NatBehavior,
+    next: u16,
+    /// Public ports by inner address and the part of destination the mapping depends on.
+    ports: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    /// Inner address of every public port, along with destinations it sent to.
+    mappings: HashMap<u16, (SocketAddr, HashSet<SocketAddr>)>,
+}
+
+impl Nat {
+    /// Public port `inner` is mapped to toward `dest`, mapping it anew if needed.
+    fn map(&mut self, inner: SocketAddr, dest: SocketAddr) -> u16 {
+        let key = match self.mapping {
+            NatBehavior::EndpointIndependent => None,
+            NatBehavior::AddressDependent => Some(SocketAddr::new(dest.ip(), 0)),
+            NatBehavior::AddressAndPortDependent => Some(dest),
+        };
+        let port = match self.ports.get(&(inner, key)) {
+            Some(port) => *port,
+            None => {
+                let port = self.next;
+                self.next = self.next.wrapping_add(1).max(FIRST_PORT);
+                self.ports.insert((inner, key), port);
+                self.mappings.insert(port, (inner, HashSet::new()));
+                port
+            }
+        };
+        self.mappings.get_mut(&port).unwrap().1.insert(dest);
+        port
+    }
+
+    /// Inner address public `port` is mapped to, none if the mapping filters out `source`.
+    fn admit(&self, port: u16, source: SocketAddr) -> Option<SocketAddr> {
+        let (inner, contacted) = self.mappings.get(&port)?;
+        let admitted = match self.filtering {
+            NatBehavior::EndpointIndependent => true,
+            NatBehavior::AddressDependent => contacted.iter().any(|c| c.ip() == source.ip()),
+            NatBehavior::AddressAndPortDependent => contacted.contains(&source),
+        };
+        admitted.then_some(*inner)
+    }
+}
+
+struct Host {
+    queue: Sender<Msg>,
+    /// Public address of the `NAT` the host is behind.
+    nat: Option<IpAddr>,
+}
+
+struct State {
+    impairment: Impairment,
+    rng: StdRng,
+    hosts: HashMap<SocketAddr, Host>,
+    nats: HashMap<IpAddr, Nat>,
+    stun: HashSet<SocketAddr>,
+    /// Time links of senders are busy until, when bandwidth is capped.
+    busy: HashMap<SocketAddr, Instant>,
+    next: u16,
+}
+
+impl State {
+    /// Carries `buf` from `source` to `dest`, translating addresses by `NAT`s on the way
+    /// and answering `STUN` requests, after `delay` it has spent so far.
+    fn route(&mut self, source: SocketAddr, buf: &[u8], dest: SocketAddr, delay: Duration) {
+        let behind = self.hosts.get(&source).and_then(|c| c.nat);
+        let peer = self.hosts.get(&dest).map(|c| c.nat);
+        let source = match (behind, peer) {
+            (Some(nat), Some(Some(peer))) if nat == peer => source,
+            (Some(nat), _) => {
+                SocketAddr::new(nat, self.nats.get_mut(&nat).unwrap().map(source, dest))
+            }
+            (None, _) => source,
+        };
+
+        let delays = self.impair(source, buf.len());
+        if delays.is_empty() {
+            trace!("dropped datagram of {:?} to {:?} on the way", source, dest);
+            return;
+        }
+        if self.stun.contains(&dest) {
+            if let Some(res) = respond(buf, source) {
+                self.route(dest, &res, source, delay + delays[0]);
+            }
+            return;
+        }
+
+        let (dest, inbound) = match self.nats.get(&dest.ip()) {
+            Some(nat) => match nat.admit(dest.port(), source) {
+                Some(inner) => (inner, true),
+                None => {
+                    trace!("NAT at {:?} filtered out datagram of {:?}", dest, source);
+                    return;
+                }
+            },
+            None => (dest, false),
+        };
+        let Some(host) = self.hosts.get(&dest) else {
+            trace!("dropped datagram of {:?} to unknown {:?}", source, dest);
+            return;
+        };
+        // hosts behind a `NAT` are reached through it, or directly from behind the same one
+        if host.nat.is_some() && !inbound && host.nat != behind {
+            trace!("dropped datagram of {:?} to private {:?}", source, dest);
+            return;
+        }
+        for c in delays {
+            deliver(host.queue.clone(), (buf.to_vec(), source), delay + c);
+        }
+    }
+
+    /// Delays of copies of a datagram of `len` bytes `source` sends, none if it's lost.
+    fn impair(&mut self, source: SocketAddr, len: usize) -> Vec<Duration> {
+        let cfg = &self.impairment;
+        let mut delay = cfg.latency;
+        if cfg.bandwidth > 0 {
+            let now = Instant::now();
+            let busy = self.busy.entry(source).or_insert(now);
+            let done =
+                (*busy).max(now) + Duration::from_secs_f64(len as f64 / cfg.bandwidth as f64);
+            if done - now > QUEUE_DELAY {
+                return vec![];
+            }
+            *busy = done;
+            delay += done - now;
+        }
+        if self.rng.gen_bool(cfg.loss) {
+            return vec![];
+        }
+        if !cfg.jitter.is_zero() {
+            delay += self.rng.gen_range(Duration::ZERO..=cfg.jitter);
+        }
+        if self.rng.gen_bool(cfg.reorder) {
+            delay += cfg.jitter + REORDER_DELAY;
+        }
+        vec![delay; 1 + self.rng.gen_bool(cfg.duplicate) as usize]
+    }
+}
+
+/// `Network` of in-memory sockets, standing for a real one in tests and experiments with
+/// no ports bound. Datagrams are impaired as set by [`Impairment`], with random choices
+/// drawn from a seeded generator, so runs sending alike are repeated alike.
+///
+/// Hosts may be put behind emulated `NAT`s, and `STUN` servers added to the network answer
+/// binding requests with the address they're seen at.
+#[derive(Clone)]
+pub struct Network {
+    state: Arc<Mutex<State>>,
+}
+
+impl Network {
+    pub fn new(impairment: Impairment, seed: u64) -> Self {
+        Network {
+            state: Arc::new(Mutex::new(State {
+                impairment,
+                rng: StdRng::seed_from_u64(seed),
+                hosts: HashMap::new(),
+                nats: HashMap::new(),
+                stun: HashSet::new(),
+                busy: HashMap::new(),
+                next: FIRST_PORT,
+            })),
+        }
+    }
+
+    /// Changes impairment of datagrams sent from now on.
+    pub fn impair(&self, impairment: Impairment) {
+        self.state.lock().unwrap().impairment = impairment;
+    }
+
+    /// Adds a `NAT` with public address `ip` of `mapping` and `filtering` behaviour, which
+    /// hosts are put behind as they're bound.
+    pub fn nat(&self, ip: IpAddr, mapping: NatBehavior, filtering: NatBehavior) {
+        let nat = Nat {
+            mapping,
+            filtering,
+            next: FIRST_PORT,
+            ports: HashMap::new(),
+            mappings: HashMap::new(),
+        };
+        self.state.lock().unwrap().nats.insert(ip, nat);
+    }
+
+    /// Drops every mapping of the `NAT` at `ip`, as when it restarts or they time out, so
+    /// hosts behind it are mapped to new public ports.
+    pub fn rebind(&self, ip: IpAddr) {
+        if let Some(nat) = self.state.lock().unwrap().nats.get_mut(&ip) {
+            nat.ports.clear();
+            nat.mappings.clear();
+        }
+    }
+
+    /// Adds a `STUN` server at `addr` answering binding requests, with no support of
+    /// RFC 5780 tests.
+    pub fn stun(&self, addr: SocketAddr) {
+        self.state.lock().unwrap().stun.insert(addr);
+    }
+
+    /// Binds a host at `addr`, or at a free port of its address for port zero, behind the
+    /// `NAT` at `nat` if given.
+    pub(crate) fn bind(&self, addr: SocketAddr, nat: Option<IpAddr>) -> Result<Endpoint> {
+        let mut state = self.state.lock().unwrap();
+        if nat.is_some_and(|c| !state.nats.contains_key(&c)) {
+            return Err(ERR_ADDRESS.into());
+        }
+        let addr = match addr.port() {
+            0 => loop {
+                let addr = SocketAddr::new(addr.ip(), state.next);
+                state.next = state.next.wrapping_add(1).max(FIRST_PORT);
+                if !state.hosts.contains_key(&addr) {
+                    break addr;
+                }
+            },
+            _ if state.hosts.contains_key(&addr) => {
+                return Err(io::Error::from(io::ErrorKind::AddrInUse).into())
+            }
+            _ => addr,
+        };
+
+        let (tx, rx) = channel::bounded(QUEUE_SIZE);
+        state.hosts.insert(addr, Host { queue: tx, nat });
+        Ok(Endpoint {
+            network: self.clone(),
+            addr,
+            queue: rx,
+            ttl: AtomicU32::new(DEFAULT_TTL),
+        })
+    }
+}
+
+/// `Endpoint` of a host on a simulated [`Network`], standing for a bound `UDP` socket.
+pub(crate) struct Endpoint {
+    network: Network,
+    addr: SocketAddr,
+    queue: Receiver<Msg>,
+    ttl: AtomicU32,
+}
+
+impl Endpoint {
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) {
+        let mut state = self.network.state.lock().unwrap();
+        state.route(self.addr, buf, addr, Duration::ZERO);
+    }
+
+    pub async fn recv_from(&self) -> Result<Msg> {
+        self.queue.recv().await.map_err(|_| ERR_CLOSED.into())
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn ttl(&self) -> u32 {
+        self.ttl.load(Ordering::Relaxed)
+    }
+
+    pub fn set_ttl(&self, ttl: u32) {
+        self.ttl.store(ttl, Ordering::Relaxed)
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.network.state.lock().unwrap().hosts.remove(&self.addr);
+    }
+}
+
+/// Puts `msg` into `queue` after `delay`, dropping it once the queue is full.
+fn deliver(queue: Sender<Msg>, msg: Msg, delay: Duration) {
+    let push = move || {
+        if queue.try_send(msg).is_err() {
+            trace!("dropped datagram as receive queue is full");
+        }
+    };
+    match delay.is_zero() {
+        true => push(),
+        false => {
+            task::spawn(async move {
+                task::sleep(delay).await;
+                push()
+            });
+        }
+    }
+}
+
+/// Response to the binding request in `buf`, reporting `client` as the mapped address.
+fn respond(buf: &[u8], client: SocketAddr) -> Option<Vec<u8>> {
+    let msg = MessageDecoder::<rfc5389::Attribute>::new()
+        .decode_from_bytes(buf)
+        .ok()?
+        .ok()?;
+    if msg.class() != MessageClass::Request || msg.method() != BINDING {
+        return None;
+    }
+    let mut res = Message::<rfc5389::Attribute>::new(
+        MessageClass::SuccessResponse,
+        BINDING,
+        msg.transaction_id(),
+    );
+    res.add_attribute(XorMappedAddress::new(client).into());
+    MessageEncoder::new().encode_into_bytes(res).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::future;
+    use async_std::net::Ipv4Addr;
+
+    const HOST_A: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 4000);
+    const HOST_B: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1)), 4000);
+    const SERVER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), 3478);
+    const NAT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+    const WAIT: Duration = Duration::from_millis(50);
+
+    /// Datagrams `endpoint` receives until none arrives for `wait`.
+    async fn drain(endpoint: &Endpoint, wait: Duration) -> Vec<Msg> {
+        let mut res = vec![];
+        while let Ok(msg) = future::timeout(wait, endpoint.recv_from()).await {
+            res.push(msg.unwrap());
+        }
+        res
+    }
+
+    #[async_std::test]
+    async fn impairment_works() {
+        let cfg = Impairment::new(0.2, Duration::ZERO, Duration::ZERO, 0.0, 0.1, 0);
+        let network = Network::new(cfg, 7);
+        let (a, b) = (
+            network.bind(HOST_A, None).unwrap(),
+            network.bind(HOST_B, None).unwrap(),
+        );
+        for c in 0..1000u16 {
+            a.send_to(&c.to_be_bytes(), HOST_B);
+        }
+        let res = drain(&b, WAIT).await;
+        let distinct = res.iter().map(|(c, _)| c).collect::<HashSet<_>>();
+        // datagrams get lost and duplicated about as often as set
+        assert!((750..850).contains(&distinct.len()));
+        assert!((50..150).contains(&(res.len() - distinct.len())));
+        assert!(res.iter().all(|(_, c)| *c == HOST_A));
+
+        let cfg = Impairment::new(0.0, WAIT, Duration::ZERO, 0.5, 0.0, 0);
+        network.impair(cfg);
+        let start = Instant::now();
+        for c in 0..100u16 {
+            a.send_to(&c.to_be_bytes(), HOST_B);
+        }
+        let first = b.recv_from().await.unwrap();
+        // datagrams are delayed, and some of them overtaken by later ones
+        assert!(start.elapsed() >= WAIT);
+        let mut res = vec![first];
+        res.extend(drain(&b, WAIT).await);
+        assert_eq!(res.len(), 100);
+        assert!(res.windows(2).any(|c| c[0].0 > c[1].0));
+
+        let cfg = Impairment::new(0.0, Duration::ZERO, Duration::ZERO, 0.0, 0.0, 10000);
+        network.impair(cfg);
+        for _ in 0..100 {
+            a.send_to(&[0; 1000], HOST_B);
+        }
+        // capped bandwidth drops what doesn't fit into the queue
+        assert_eq!(drain(&b, QUEUE_DELAY).await.len(), 5);
+    }
+
+    #[async_std::test]
+    async fn seed_works() {
+        let run = |seed| async move {
+            let cfg = Impairment::new(0.5, Duration::ZERO, Duration::ZERO, 0.0, 0.0, 0);
+            let network = Network::new(cfg, seed);
+            let (a, b) = (
+                network.bind(HOST_A, None).unwrap(),
+                network.bind(HOST_B, None).unwrap(),
+            );
+            for c in 0..100u16 {
+                a.send_to(&c.to_be_bytes(), HOST_B);
+            }
+            drain(&b, WAIT).await
+        };
+        // the same seed drops the same datagrams
+        assert_eq!(run(1).await, run(1).await);
+        assert_ne!(run(1).await, run(2).await);
+    }
+
+    #[async_std::test]
+    async fn nat_works() {
+        use NatBehavior::*;
+
+        let network = Network::new(Impairment::default(), 0);
+        network.stun(SERVER);
+        network.nat(NAT_IP, AddressAndPortDependent, AddressDependent);
+        let a = network.bind(HOST_A, Some(NAT_IP)).unwrap();
+        let b = network.bind(HOST_B, None).unwrap();
+        let other = network
+            .bind(SocketAddr::new(HOST_B.ip(), 4001), None)
+            .unwrap();
+
+        a.send_to(&crate::udp::build_request(&None).unwrap().1, SERVER);
+        let (res, _) = a.recv_from().await.unwrap();
+        let mapped = crate::udp::decode_address(&res).unwrap();
+        // `STUN` server reports the mapping of the `NAT`
+        assert_eq!(mapped, SocketAddr::new(NAT_IP, FIRST_PORT));
+
+        a.send_to(b"hello", HOST_B);
+        let (_, from) = b.recv_from().await.unwrap();
+        // every destination gets another mapping, in sequence
+        assert_eq!(from, SocketAddr::new(NAT_IP, FIRST_PORT + 1));
+        other.send_to(b"hi", from);
+        b.send_to(b"hi", mapped);
+        b.send_to(b"hi", HOST_A);
+        // other port of the contacted address passes, while other mapping and private
+        // address don't
+        assert_eq!(
+            drain(&a, WAIT).await,
+            [(b"hi".to_vec(), other.local_addr())]
+        );
+    }
+}
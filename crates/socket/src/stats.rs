@@ -0,0 +1,264 @@
+use async_std::channel::{self, Receiver, Sender};
+use log::trace;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of snapshots queued before new ones are dropped.
+const EVENTS_SIZE: usize = 16;
+/// Keepalives remembered behind the latest one to tell reordered ones from duplicates.
+const WINDOW_SIZE: u32 = 64;
+/// Gain of smoothed round-trip time per RFC 6298.
+const RTT_GAIN: f64 = 1.0 / 8.0;
+/// Gain of interarrival jitter per RFC 3550.
+const JITTER_GAIN: f64 = 1.0 / 16.0;
+
+/// `Stats` of the connection to the peer, see [`SocketHandle::stats`][crate::SocketHandle::stats].
+///
+/// Round-trip time, jitter, loss and reordering are measured over keepalives, so they're
+/// known once [`keepalive`][crate::SocketHandle::keepalive] runs, while traffic counts
+/// messages of all streams, keepalives included.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    /// Smoothed round-trip time per RFC 6298, none until the peer echoes a keepalive.
+    pub rtt: Option<Duration>,
+    /// Interarrival jitter of keepalives per RFC 3550.
+    pub jitter: Duration,
+    /// Keepalives of the peer received.
+    pub probes: u64,
+    /// Keepalives of the peer missing from its sequence.
+    pub lost: u64,
+    /// Keepalives of the peer arriving after later ones.
+    pub reordered: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Bytes per second sent since the previous periodic snapshot.
+    pub send_rate: u64,
+    /// Bytes per second received since the previous periodic snapshot.
+    pub receive_rate: u64,
+}
+
+impl Stats {
+    /// Fraction of keepalives of the peer lost.
+    pub fn loss(&self) -> f64 {
+        match self.probes + self.lost {
+            0 => 0.0,
+            total => self.lost as f64 / total as f64,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rtt {
+            Some(rtt) => write!(f, "rtt {}ms", rtt.as_millis())?,
+            None => write!(f, "rtt unknown")?,
+        }
+        write!(
+            f,
+            ", jitter {}ms, loss {:.1}%, reordered {}, sent {} packets / {} B ({} B/s), \
+             received {} packets / {} B ({} B/s)",
+            self.jitter.as_millis(),
+            self.loss() * 100.0,
+            self.reordered,
+            self.packets_sent,
+            self.bytes_sent,
+            self.send_rate,
+            self.packets_received,
+            self.bytes_received,
+            self.receive_rate
+        )
+    }
+}
+
+struct State {
+    stats: Stats,
+    /// Round-trip time and jitter in milliseconds, kept fractional between samples.
+    rtt: Option<f64>,
+    jitter: f64,
+    /// Transit time of the previous keepalive, as difference of the clocks of peers.
+    transit: Option<i64>,
+    /// First and latest sequence number of keepalives, along with a bit per one received
+    /// behind the latest.
+    first: u32,
+    latest: Option<u32>,
+    window: u64,
+    /// Time and traffic of the previous periodic snapshot, rates are measured since.
+    since: (Instant, u64, u64),
+}
+
+/// `Recorder` of [`Stats`], publishing their snapshots for the application.
+pub(crate) struct Recorder {
+    state: Mutex<State>,
+    events: (Sender<Stats>, Receiver<Stats>),
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder {
+            state: Mutex::new(State {
+                stats: Stats::default(),
+                rtt: None,
+                jitter: 0.0,
+                transit: None,
+                first: 0,
+                latest: None,
+                window: 0,
+                since: (Instant::now(), 0, 0),
+            }),
+            events: channel::bounded(EVENTS_SIZE),
+        }
+    }
+
+    pub fn sent(&self, len: usize) {
+        let stats = &mut self.state.lock().unwrap().stats;
+        stats.packets_sent += 1;
+        stats.bytes_sent += len as u64;
+    }
+
+    pub fn received(&self, len: usize) {
+        let stats = &mut self.state.lock().unwrap().stats;
+        stats.packets_received += 1;
+        stats.bytes_received += len as u64;
+    }
+
+    pub fn rtt(&self, sample: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let sample = sample.as_secs_f64() * 1000.0;
+        let rtt = match state.rtt {
+            Some(rtt) => rtt + (sample - rtt) * RTT_GAIN,
+            None => sample,
+        };
+        state.rtt = Some(rtt);
+        state.stats.rtt = Some(from_millis(rtt));
+    }
+
+    /// Records keepalive `seq` of the peer which took `transit` milliseconds as told by
+    /// clocks of both peers, returning whether it's new rather than a duplicate.
+    pub fn probe(&self, seq: u32, transit: i64) -> bool {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        match state.latest {
+            None => {
+                (state.first, state.latest, state.window) = (seq, Some(seq), 1);
+            }
+            Some(latest) if seq.wrapping_sub(latest) as i32 > 0 => {
+                let ahead = seq.wrapping_sub(latest);
+                state.window = state.window.checked_shl(ahead).unwrap_or_default() | 1;
+                state.latest = Some(seq);
+            }
+            Some(latest) => {
+                let behind = latest.wrapping_sub(seq);
+                if behind >= WINDOW_SIZE || state.window & 1 << behind != 0 {
+                    trace!("dropped duplicate or stale keepalive {seq}");
+                    return false;
+                }
+                state.window |= 1 << behind;
+                state.stats.reordered += 1;
+            }
+        }
+
+        if let Some(prev) = state.transit {
+            let diff = (transit - prev).abs() as f64;
+            state.jitter += (diff - state.jitter) * JITTER_GAIN;
+        }
+        state.transit = Some(transit);
+
+        let stats = &mut state.stats;
+        stats.jitter = from_millis(state.jitter);
+        stats.probes += 1;
+        let expected = state.latest.unwrap().wrapping_sub(state.first) as u64 + 1;
+        stats.lost = expected.saturating_sub(stats.probes);
+        true
+    }
+
+    /// Starts keepalive measures over, as the peer restarted its sequence.
+    pub fn rebase(&self) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        (state.transit, state.latest, state.window) = (None, None, 0);
+        let stats = &mut state.stats;
+        (stats.probes, stats.lost, stats.reordered) = (0, 0, 0);
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        let state = self.state.lock().unwrap();
+        let mut stats = state.stats;
+        let (since, sent, received) = state.since;
+        let elapsed = since.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            stats.send_rate = ((stats.bytes_sent - sent) as f64 / elapsed) as u64;
+            stats.receive_rate = ((stats.bytes_received - received) as f64 / elapsed) as u64;
+        }
+        stats
+    }
+
+    /// Sends a snapshot to readers of [`events`][Recorder::events], starting a new period
+    /// rates are measured over.
+    pub fn publish(&self) {
+        let stats = self.snapshot();
+        self.state.lock().unwrap().since = (Instant::now(), stats.bytes_sent, stats.bytes_received);
+        if self.events.0.try_send(stats).is_err() {
+            trace!("dropped stats snapshot as nobody reads them");
+        }
+    }
+
+    /// Periodic snapshots, for the application to show.
+    pub fn events(&self) -> Receiver<Stats> {
+        self.events.1.clone()
+    }
+}
+
+fn from_millis(millis: f64) -> Duration {
+    Duration::from_nanos((millis * 1e6).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_works() {
+        let recorder = Recorder::new();
+        for (seq, transit) in [
+            (10, 100),
+            (11, 110),
+            (13, 100),
+            (12, 130),
+            (12, 130),
+            (14, 100),
+        ] {
+            recorder.probe(seq, transit);
+        }
+        let stats = recorder.snapshot();
+        // late keepalive counts as reordered rather than lost, and duplicate is dropped
+        assert_eq!((stats.probes, stats.lost, stats.reordered), (5, 0, 1));
+        assert!(stats.jitter > Duration::ZERO);
+
+        // missing keepalive is lost
+        recorder.probe(17, 100);
+        let stats = recorder.snapshot();
+        assert_eq!((stats.probes, stats.lost), (6, 2));
+        assert_eq!(stats.loss(), 0.25);
+        // stale one behind the window is dropped
+        assert!(!recorder.probe(17u32.wrapping_sub(WINDOW_SIZE), 100));
+
+        recorder.rtt(Duration::from_millis(80));
+        recorder.rtt(Duration::from_millis(160));
+        // round-trip time is smoothed
+        assert_eq!(recorder.snapshot().rtt, Some(Duration::from_millis(90)));
+
+        recorder.sent(100);
+        recorder.received(40);
+        let events = recorder.events();
+        recorder.publish();
+        let stats = events.try_recv().unwrap();
+        // traffic is counted both ways
+        assert_eq!((stats.packets_sent, stats.bytes_sent), (1, 100));
+        assert_eq!((stats.packets_received, stats.bytes_received), (1, 40));
+        assert!(stats.send_rate > 0);
+        assert_eq!(recorder.snapshot().send_rate, 0);
+    }
+}
@@ -0,0 +1,258 @@
+use async_std::net::{SocketAddr, ToSocketAddrs};
+use err::{
+    consts::{ERR_STUN_CONSENSUS, ERR_STUN_UNREACHABLE},
+    Error, Result,
+};
+use log::{info, trace, warn};
+use serde::Deserialize;
+use std::future::Future;
+
+use crate::nat::BEHAVIOR_STUN_ADDRESS;
+use crate::udp::families;
+
+pub const STUN_ADDRESS: &str = "stun.l.google.com:19302";
+
+/// `StunConfig` for `.toml` config parsing.
+/// Lists `STUN` servers queried for the public address, in order of preference.
+///
+/// Servers are queried one by one until `agreement` of them report the same address, or
+/// all at once when `parallel` is set. Servers failing to resolve or answer are skipped.
+/// Dual-stack sockets query servers of each address family on their own.
+#[derive(Debug, Deserialize, Clone)]
+pub struct StunConfig {
+    #[serde(default = "default_servers")]
+    servers: Vec<String>,
+    #[serde(default = "default_agreement")]
+    agreement: usize,
+    #[serde(default)]
+    parallel: bool,
+    /// Server supporting RFC 5780 used by [`detect_nat`][crate::SocketHandle::detect_nat].
+    #[serde(default = "default_behavior")]
+    behavior: String,
+}
+
+impl Default for StunConfig {
+    fn default() -> Self {
+        StunConfig {
+            servers: default_servers(),
+            agreement: default_agreement(),
+            parallel: false,
+            behavior: default_behavior(),
+        }
+    }
+}
+
+impl StunConfig {
+    pub fn new(servers: Vec<String>, agreement: usize, parallel: bool, behavior: String) -> Self {
+        StunConfig {
+            servers,
+            agreement,
+            parallel,
+            behavior,
+        }
+    }
+
+    pub(crate) fn behavior(&self) -> &str {
+        &self.behavior
+    }
+
+    pub(crate) fn servers(&self) -> &[String] {
+        &self.servers
+    }
+
+    /// Queries servers for public addresses of a socket at `local` with `query`, which
+    /// takes a set of servers to ask at once and returns addresses reported by those that
+    /// answered. Addresses agreed on are returned one per address family, IPv4 first.
+    ///
+    /// Fails with [`ERR_STUN_UNREACHABLE`] when no server answers, so callers may go on
+    /// without a public address.
+    pub(crate) async fn query<F, Fut>(&self, local: SocketAddr, query: F) -> Result<Vec<SocketAddr>>
+    where
+        F: Fn(Vec<SocketAddr>) -> Fut,
+        Fut: Future<Output = Result<Vec<SocketAddr>>>,
+    {
+        let servers = resolve(&self.servers, local).await;
+        let mut res = vec![];
+        let mut err = Error::from(ERR_STUN_UNREACHABLE);
+        for family in families(local) {
+            let servers = servers
+                .iter()
+                .copied()
+                .filter(|c| c.is_ipv4() == family.is_ipv4())
+                .collect::<Vec<_>>();
+            match self.agree(servers, &query).await {
+                Ok(addr) => res.push(addr),
+                Err(Error::Unreachable(_)) => {}
+                Err(e) => err = e,
+            }
+        }
+        match res.is_empty() {
+            true => Err(err),
+            false => Ok(res),
+        }
+    }
+
+    /// Queries `servers` of the same address family until enough of them agree.
+    async fn agree<F, Fut>(&self, servers: Vec<SocketAddr>, query: &F) -> Result<SocketAddr>
+    where
+        F: Fn(Vec<SocketAddr>) -> Fut,
+        Fut: Future<Output = Result<Vec<SocketAddr>>>,
+    {
+        let rounds = match self.parallel {
+            true => vec![servers],
+            false => servers.into_iter().map(|c| vec![c]).collect(),
+        };
+
+        let mut votes: Vec<(SocketAddr, usize)> = vec![];
+        for round in rounds.into_iter().filter(|c| !c.is_empty()) {
+            trace!("querying STUN at {:?}", round);
+            let res = match query(round).await {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("STUN query failed: {e}");
+                    continue;
+                }
+            };
+            for addr in res {
+                let idx = match votes.iter().position(|(c, _)| *c == addr) {
+                    Some(idx) => idx,
+                    None => {
+                        votes.push((addr, 0));
+                        votes.len() - 1
+                    }
+                };
+                votes[idx].1 += 1;
+                if votes[idx].1 >= self.agreement.max(1) {
+                    return Ok(addr);
+                }
+            }
+        }
+
+        match votes.is_empty() {
+            true => Err(ERR_STUN_UNREACHABLE.into()),
+            false => {
+                info!("STUN servers reported {:?}", votes);
+                Err(ERR_STUN_CONSENSUS.into())
+            }
+        }
+    }
+}
+
+/// Resolves `servers` to an address of each family reachable from `local`, IPv4 first,
+/// skipping those which fail to resolve.
+pub(crate) async fn resolve(servers: &[String], local: SocketAddr) -> Vec<SocketAddr> {
+    let mut res = vec![];
+    for server in servers {
+        let addr = match server.to_socket_addrs().await {
+            Ok(addr) => addr.collect::<Vec<_>>(),
+            Err(e) => {
+                warn!("can't resolve STUN at '{server}': {e}");
+                continue;
+            }
+        };
+        for family in families(local) {
+            match addr.iter().find(|c| c.is_ipv4() == family.is_ipv4()) {
+                Some(addr) => res.push(*addr),
+                None => trace!("STUN at '{server}' has no address of {family} family"),
+            }
+        }
+    }
+    res
+}
+
+fn default_servers() -> Vec<String> {
+    vec![STUN_ADDRESS.to_owned()]
+}
+
+fn default_agreement() -> usize {
+    1
+}
+
+fn default_behavior() -> String {
+    BEHAVIOR_STUN_ADDRESS.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use err::Error;
+
+    use crate::LOOPBACK_IP;
+
+    const LOCAL: SocketAddr = SocketAddr::new(LOOPBACK_IP, 34254);
+    const LOCAL_V6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 34254);
+    const PUBLIC_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+    const PUBLIC_IP6: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+    fn config(servers: &[&str], agreement: usize, parallel: bool) -> StunConfig {
+        StunConfig::new(
+            servers.iter().map(|c| c.to_string()).collect(),
+            agreement,
+            parallel,
+            default_behavior(),
+        )
+    }
+
+    /// Reports the public address of server's family with port of the server, so servers
+    /// with equal ports agree while others don't.
+    async fn report(servers: Vec<SocketAddr>) -> Result<Vec<SocketAddr>> {
+        Ok(servers
+            .into_iter()
+            .filter(|c| c.port() != 9)
+            .map(|c| match c.is_ipv4() {
+                true => SocketAddr::new(PUBLIC_IP, c.port()),
+                false => SocketAddr::new(PUBLIC_IP6, c.port()),
+            })
+            .collect())
+    }
+
+    #[async_std::test]
+    async fn stun_consensus_works() {
+        let cfg = config(
+            &["127.0.0.1:9", "127.0.0.2:3478", "127.0.0.3:3478"],
+            2,
+            false,
+        );
+        let res = cfg.query(LOCAL, report).await.unwrap();
+        // silent servers are skipped until enough of them agree
+        assert_eq!(res, [SocketAddr::new(PUBLIC_IP, 3478)]);
+
+        let cfg = config(&["127.0.0.2:3478", "127.0.0.3:3479"], 2, true);
+        let res = cfg.query(LOCAL, report).await;
+        // disagreeing servers aren't trusted
+        assert!(matches!(res, Err(Error::InconsistentState(_))));
+
+        let cfg = config(&["127.0.0.1:9", "not a server"], 1, true);
+        let res = cfg.query(LOCAL, report).await;
+        // socket goes LAN-only with no servers
+        assert!(matches!(res, Err(Error::Unreachable(_))));
+        let res = config(&[], 1, false).query(LOCAL, report).await;
+        assert!(matches!(res, Err(Error::Unreachable(_))));
+    }
+
+    #[async_std::test]
+    async fn stun_dual_stack_works() {
+        let servers = ["127.0.0.2:3478", "[::1]:3479", "127.0.0.3:3480"];
+        let res = config(&servers, 1, true)
+            .query(LOCAL_V6, report)
+            .await
+            .unwrap();
+        // dual-stack socket learns an address of each family, IPv4 first
+        assert_eq!(
+            res,
+            [
+                SocketAddr::new(PUBLIC_IP, 3478),
+                SocketAddr::new(PUBLIC_IP6, 3479)
+            ]
+        );
+
+        let res = config(&servers, 1, true)
+            .query(LOCAL, report)
+            .await
+            .unwrap();
+        // IPv4 socket skips IPv6 servers
+        assert_eq!(res, [SocketAddr::new(PUBLIC_IP, 3478)]);
+    }
+}
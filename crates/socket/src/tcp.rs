@@ -0,0 +1,361 @@
+use async_std::{
+    future,
+    io::{ReadExt, WriteExt},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    task,
+};
+use async_trait::async_trait;
+use err::{
+    consts::{
+        ERR_ADDRESS, ERR_CLOSED, ERR_CONNECTION, ERR_FRAME_SIZE, ERR_NOT_BOUND, ERR_STUN_QUERY,
+        ERR_UNSUPPORTED,
+    },
+    Error, Result,
+};
+use log::{info, trace, warn};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    OnceLock,
+};
+use std::time::Duration;
+
+use crate::frame::{fragment, Demux, Msg};
+use crate::udp::{build_request, canonical, decode_address, is_dual_stack, to_family};
+use crate::{IOSocket, SocketConfig, StunHandler, CONTROL_STREAM};
+
+/// Largest frame written to the stream, header included.
+pub const FRAME_SIZE: usize = 16 * 1024;
+const READ_BUF_SIZE: usize = 4096;
+
+/// `TcpRole` for `.toml` config parsing.
+/// Offers [`Connect`][TcpRole::Connect] and [`Listen`][TcpRole::Listen] roles for peers
+/// reachable directly, and [`Punch`][TcpRole::Punch] simultaneous open for peers behind NAT.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TcpRole {
+    /// Connects to the remote host from an ephemeral port.
+    Connect,
+    /// Accepts a connection from the remote host on the configured address.
+    Listen,
+    /// Connects from the configured address while the remote host does the same, so
+    /// outgoing `SYN`s of both peers open mappings on their `NAT`s.
+    Punch,
+}
+
+/// `TcpSocketHandle` carries frames of [`fragment`] over a `TCP` connection, each prefixed
+/// with its `u32` big-endian length.
+pub(super) struct TcpSocketHandle {
+    addr: SocketAddr,
+    role: TcpRole,
+    listener: Option<TcpListener>,
+    ttl: std::sync::Mutex<Option<u32>>,
+    sw_tag: Option<String>,
+    socket_cfg: Arc<SocketConfig>,
+    stream: OnceLock<TcpStream>,
+    read_buf: std::sync::Mutex<Vec<u8>>,
+    write: Mutex<()>,
+    msg_id: AtomicU32,
+    demux: Demux,
+}
+
+impl TcpSocketHandle {
+    #[allow(dead_code)]
+    pub async fn new(
+        addr: Vec<SocketAddr>,
+        ttl: Option<u32>,
+        sw_tag: Option<String>,
+        role: TcpRole,
+        socket_cfg: Arc<SocketConfig>,
+    ) -> Result<TcpSocketHandle> {
+        let addr = *addr.first().ok_or(ERR_ADDRESS)?;
+        let listener = match role {
+            TcpRole::Listen => {
+                let socket = reusable(addr)?;
+                socket.listen(1)?;
+                socket.set_nonblocking(true)?;
+                Some(std::net::TcpListener::from(socket).into())
+            }
+            _ => None,
+        };
+
+        Ok(TcpSocketHandle {
+            addr,
+            role,
+            listener,
+            ttl: std::sync::Mutex::new(ttl),
+            sw_tag,
+            demux: Demux::new(socket_cfg.timeout),
+            socket_cfg,
+            stream: OnceLock::new(),
+            read_buf: std::sync::Mutex::new(vec![]),
+            write: Mutex::new(()),
+            msg_id: AtomicU32::new(rand::random()),
+        })
+    }
+
+    fn stream(&self) -> Result<&TcpStream> {
+        self.stream.get().ok_or_else(|| ERR_NOT_BOUND.into())
+    }
+
+    async fn connect(&self, addr: &[SocketAddr]) -> Result<TcpStream> {
+        let timeout = self.socket_cfg.timeout;
+        let mut iter = 0..self.socket_cfg.retries;
+
+        if let Some(listener) = &self.listener {
+            let wait = timeout * self.socket_cfg.retries as u32;
+            return future::timeout(wait, async {
+                loop {
+                    let (stream, peer) = listener.accept().await?;
+                    let peer = canonical(peer);
+                    if addr.iter().any(|c| c.ip() == peer.ip()) {
+                        break Ok(stream);
+                    }
+                    warn!("refused TCP connection from unexpected {:?}", peer);
+                }
+            })
+            .await?;
+        }
+
+        loop {
+            for remote in addr {
+                let res = match self.role {
+                    TcpRole::Punch => connect_from(self.addr, *remote, timeout).await,
+                    _ => future::timeout(timeout, TcpStream::connect(remote))
+                        .await
+                        .map_err(Error::from)
+                        .and_then(|c| c.map_err(Error::from)),
+                };
+                match res {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => trace!("TCP connection attempt to {:?} failed: {e}", remote),
+                }
+            }
+            if iter.next().is_none() {
+                return Err(ERR_CONNECTION.into());
+            }
+            task::sleep(timeout).await;
+        }
+    }
+
+    /// Reads next length-prefixed frame, keeping partially read ones between calls.
+    async fn read_frame(&self) -> Result<Msg> {
+        let stream = self.stream()?;
+        let peer = canonical(stream.peer_addr()?);
+        loop {
+            {
+                let mut read_buf = self.read_buf.lock().unwrap();
+                if let Some(len) = read_buf.first_chunk::<4>() {
+                    let len = u32::from_be_bytes(*len) as usize;
+                    if len > FRAME_SIZE {
+                        return Err(ERR_FRAME_SIZE.into());
+                    }
+                    if read_buf.len() >= len + 4 {
+                        let res = read_buf[4..len + 4].to_vec();
+                        read_buf.drain(..len + 4);
+                        return Ok((res, peer));
+                    }
+                }
+            }
+
+            let mut buf = [0u8; READ_BUF_SIZE];
+            let len = (&*stream).read(&mut buf).await?;
+            if len == 0 {
+                return Err(ERR_CLOSED.into());
+            }
+            self.read_buf.lock().unwrap().extend_from_slice(&buf[..len]);
+        }
+    }
+
+    /// Queries `STUN` over `TCP` from the configured address, so the mapping matches the
+    /// one used by [`Punch`][TcpRole::Punch].
+    async fn query_stun(&self, stun_addr: SocketAddr) -> Result<SocketAddr> {
+        trace!("querying STUN at {:?} over TCP", stun_addr);
+
+        let timeout = self.socket_cfg.timeout * self.socket_cfg.retries as u32;
+        let stream = connect_from(self.addr, stun_addr, timeout).await?;
+
+        let (_, msg) = build_request(&self.sw_tag)?;
+        (&stream).write_all(&msg).await?;
+
+        let mut buf = vec![];
+        future::timeout(timeout, async {
+            loop {
+                let mut chunk = [0u8; 256];
+                let len = (&stream).read(&mut chunk).await?;
+                if len == 0 {
+                    break Err(ERR_STUN_QUERY.into());
+                }
+                buf.extend_from_slice(&chunk[..len]);
+                if let Ok(res) = decode_address(&buf) {
+                    break Ok(res);
+                }
+            }
+        })
+        .await?
+    }
+
+    async fn recv_msg(&self, stream: u8, peek: bool) -> Result<Msg> {
+        self.stream()?;
+        self.demux.recv(stream, peek, || self.read_frame()).await
+    }
+
+    async fn send_msg(&self, stream: u8, buf: &[u8]) -> Result<()> {
+        let socket = self.stream()?;
+        let id = self.msg_id.fetch_add(1, Ordering::Relaxed);
+        let frames = fragment(stream, id, buf, FRAME_SIZE)?;
+
+        let _guard = self.write.lock().await;
+        for buf in frames {
+            let len = (buf.len() as u32).to_be_bytes();
+            (&*socket).write_all(&[len.as_ref(), &buf].concat()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IOSocket for TcpSocketHandle {
+    async fn bind(&self, addr: &[SocketAddr]) -> Result<()> {
+        let stream = self.connect(addr).await?;
+        stream.set_nodelay(true)?;
+        if let Some(ttl) = *self.ttl.lock().unwrap() {
+            stream.set_ttl(ttl)?;
+        }
+        info!(
+            "TCP socket at :{} is connected to {:?}",
+            stream.local_addr()?.port(),
+            stream.peer_addr()?
+        );
+        self.stream.set(stream).map_err(|_| ERR_CONNECTION.into())
+    }
+
+    async fn peer(&self) -> Result<SocketAddr> {
+        self.stream()?
+            .peer_addr()
+            .map(canonical)
+            .map_err(Error::from)
+    }
+
+    async fn poll(&self) -> Result<Vec<u8>> {
+        trace!("polling a message from TCP connection");
+        self.recv_msg(CONTROL_STREAM, false)
+            .await
+            .map(|(res, _)| res)
+    }
+
+    async fn poll_at(&self) -> Result<(Vec<u8>, SocketAddr)> {
+        self.recv_msg(CONTROL_STREAM, false).await
+    }
+
+    async fn poll_on(&self, stream: u8) -> Result<Vec<u8>> {
+        trace!("polling a message from TCP connection on stream {stream}");
+        self.recv_msg(stream, false).await.map(|(res, _)| res)
+    }
+
+    async fn peek(&self) -> Result<Vec<u8>> {
+        self.recv_msg(CONTROL_STREAM, true)
+            .await
+            .map(|(res, _)| res)
+    }
+
+    async fn peek_at(&self) -> Result<(Vec<u8>, SocketAddr)> {
+        self.recv_msg(CONTROL_STREAM, true).await
+    }
+
+    async fn push(&self, buf: &[u8]) -> Result<()> {
+        trace!("pushing {} bytes to TCP connection", buf.len());
+        self.send_msg(CONTROL_STREAM, buf).await
+    }
+
+    /// Messages go to the connected peer whatever `addr` is.
+    async fn push_to(&self, buf: &[u8], _addr: &[SocketAddr]) -> Result<()> {
+        self.push(buf).await
+    }
+
+    async fn push_on(&self, stream: u8, buf: &[u8]) -> Result<()> {
+        trace!(
+            "pushing {} bytes to TCP connection on stream {stream}",
+            buf.len()
+        );
+        self.send_msg(stream, buf).await
+    }
+
+    async fn push_stun(&self, _buf: &[u8], _addr: SocketAddr) -> Result<()> {
+        Err(ERR_UNSUPPORTED.into())
+    }
+
+    async fn poll_stun(
+        &self,
+        _handler: Arc<dyn StunHandler>,
+        _base: usize,
+        _wait: Duration,
+    ) -> Result<()> {
+        Err(ERR_UNSUPPORTED.into())
+    }
+
+    async fn get_ttl(&self) -> Result<u32> {
+        match self.stream.get() {
+            Some(stream) => stream.ttl().map_err(Error::from),
+            None => Ok(self.ttl.lock().unwrap().unwrap_or_default()),
+        }
+    }
+
+    async fn set_ttl(&self, ttl: u32) -> Result<()> {
+        *self.ttl.lock().unwrap() = Some(ttl);
+        match self.stream.get() {
+            Some(stream) => stream.set_ttl(ttl).map_err(Error::from),
+            None => Ok(()),
+        }
+    }
+
+    async fn get_lan_ip(&self) -> Result<SocketAddr> {
+        match (self.stream.get(), &self.listener) {
+            (Some(stream), _) => stream.local_addr().map_err(Error::from),
+            (None, Some(listener)) => listener.local_addr().map_err(Error::from),
+            (None, None) => Ok(self.addr),
+        }
+    }
+
+    async fn get_wan_ip(&self) -> Result<SocketAddr> {
+        self.get_wan_ips().await.map(|c| c[0])
+    }
+
+    async fn get_wan_ips(&self) -> Result<Vec<SocketAddr>> {
+        let query = |servers: Vec<SocketAddr>| async move {
+            let res = futures::future::join_all(servers.iter().map(|c| self.query_stun(*c))).await;
+            Ok(res.into_iter().filter_map(Result::ok).collect())
+        };
+        self.socket_cfg.stun().query(self.addr, query).await
+    }
+}
+
+/// Binds a socket allowing other sockets on the same address, as listening and
+/// connecting sockets of a peer share one port.
+fn reusable(addr: SocketAddr) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!is_dual_stack(addr))?;
+    }
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+async fn connect_from(
+    addr: SocketAddr,
+    remote: SocketAddr,
+    timeout: Duration,
+) -> Result<TcpStream> {
+    let stream = task::spawn_blocking(move || {
+        let socket = reusable(addr)?;
+        socket.connect_timeout(&to_family(remote, addr).into(), timeout)?;
+        socket.set_nonblocking(true)?;
+        Ok::<_, Error>(std::net::TcpStream::from(socket))
+    })
+    .await?;
+    Ok(stream.into())
+}
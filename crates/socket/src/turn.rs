@@ -0,0 +1,702 @@
+use async_std::{
+    channel::{self, Receiver, Sender, TrySendError},
+    future,
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::Arc,
+    task,
+};
+use bytecodec::{DecodeExt, EncodeExt};
+use err::{
+    consts::{ERR_ADDRESS, ERR_CLOSED, ERR_CONNECTION, ERR_FRAME_SIZE, ERR_RELAY},
+    Result,
+};
+use futures::future::Either;
+use log::{info, trace, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+use stun_codec::{
+    define_attribute_enums,
+    rfc5389::{
+        attributes::{
+            ErrorCode, MessageIntegrity, Nonce, Realm, Software, Username, XorMappedAddress,
+        },
+        errors::{StaleNonce, Unauthorized},
+    },
+    rfc5766::{
+        attributes::{
+            ChannelNumber, Data, Lifetime, RequestedTransport, XorPeerAddress, XorRelayAddress,
+        },
+        methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, DATA, REFRESH, SEND},
+    },
+    Message, MessageClass, MessageDecoder, MessageEncoder, Method, TransactionId,
+};
+
+use crate::frame::Msg;
+use crate::udp::{is_dual_stack, reaches, unspecified};
+use crate::SocketConfig;
+
+/// Lifetime asked for allocations, servers may grant a different one.
+const ALLOCATION_LIFETIME: Duration = Duration::from_secs(600);
+/// Interval of refreshing channel bindings along with their permissions, which expire
+/// after 5 minutes.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(120);
+/// Protocol number of `UDP` for `REQUESTED-TRANSPORT`.
+const TRANSPORT_UDP: u8 = 17;
+const CHANNEL_HEADER_SIZE: usize = 4;
+const RECV_BUF_SIZE: usize = 2048;
+/// Number of relayed datagrams queued before new ones are dropped.
+const INBOX_SIZE: usize = 1024;
+
+define_attribute_enums!(
+    Attribute,
+    AttributeDecoder,
+    AttributeEncoder,
+    [
+        Software,
+        XorMappedAddress,
+        ErrorCode,
+        Username,
+        Realm,
+        Nonce,
+        MessageIntegrity,
+        ChannelNumber,
+        Lifetime,
+        XorPeerAddress,
+        Data,
+        XorRelayAddress,
+        RequestedTransport
+    ]
+);
+
+/// `TurnConfig` for `.toml` config parsing.
+/// Points to a `TURN` server relaying traffic when hole punching fails, along with
+/// long-term credentials it accepts.
+#[derive(Deserialize, Clone)]
+pub struct TurnConfig {
+    server: String,
+    username: String,
+    password: String,
+}
+
+impl TurnConfig {
+    pub fn new(server: String, username: String, password: String) -> Self {
+        TurnConfig {
+            server,
+            username,
+            password,
+        }
+    }
+}
+
+impl fmt::Debug for TurnConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TurnConfig")
+            .field("server", &self.server)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// `Client` side of `TURN` transactions, shared by [`Relay`] and its background tasks.
+struct Client {
+    socket: UdpSocket,
+    server: SocketAddr,
+    turn: TurnConfig,
+    sw_tag: Option<String>,
+    socket_cfg: Arc<SocketConfig>,
+    auth: Mutex<Option<(Realm, Nonce)>>,
+    pending: Mutex<HashMap<TransactionId, Sender<Message<Attribute>>>>,
+    channels: Mutex<HashMap<SocketAddr, u16>>,
+}
+
+impl Client {
+    /// Runs a request, answering authentication challenges and stale nonce errors once.
+    async fn request(&self, method: Method, attrs: &[Attribute]) -> Result<Message<Attribute>> {
+        for _ in 0..2 {
+            let res = self.transact(method, attrs).await?;
+            if res.class() == MessageClass::SuccessResponse {
+                return Ok(res);
+            }
+
+            let code = res.get_attribute::<ErrorCode>().map(|c| c.code());
+            match (
+                code,
+                res.get_attribute::<Realm>(),
+                res.get_attribute::<Nonce>(),
+            ) {
+                (Some(Unauthorized::CODEPOINT | StaleNonce::CODEPOINT), realm, Some(nonce)) => {
+                    let mut auth = self.auth.lock().unwrap();
+                    let realm = match (realm, auth.take()) {
+                        (Some(realm), _) => realm.clone(),
+                        (None, Some((realm, _))) => realm,
+                        (None, None) => break,
+                    };
+                    *auth = Some((realm, nonce.clone()));
+                }
+                _ => break,
+            }
+        }
+        Err(ERR_RELAY.into())
+    }
+
+    async fn transact(&self, method: Method, attrs: &[Attribute]) -> Result<Message<Attribute>> {
+        let id = TransactionId::new(rand::random());
+        let mut msg = Message::new(MessageClass::Request, method, id);
+        for c in attrs {
+            msg.add_attribute(c.clone());
+        }
+        if let Some(s) = &self.sw_tag {
+            msg.add_attribute(Software::new(s.to_owned())?.into());
+        }
+        let auth = self.auth.lock().unwrap().clone();
+        if let Some((realm, nonce)) = auth {
+            let username = Username::new(self.turn.username.clone())?;
+            msg.add_attribute(username.clone().into());
+            msg.add_attribute(realm.clone().into());
+            msg.add_attribute(nonce.into());
+            let integrity = MessageIntegrity::new_long_term_credential(
+                &msg,
+                &username,
+                &realm,
+                &self.turn.password,
+            )?;
+            msg.add_attribute(integrity.into());
+        }
+        let buf = MessageEncoder::new().encode_into_bytes(msg)?;
+
+        let (tx, rx) = channel::bounded(1);
+        self.pending.lock().unwrap().insert(id, tx);
+        let res = async {
+            for _ in 0..self.socket_cfg.retries {
+                self.socket.send_to(&buf, self.server).await?;
+                if let Ok(Ok(res)) = future::timeout(self.socket_cfg.timeout, rx.recv()).await {
+                    return Ok(res);
+                }
+            }
+            Err(ERR_CONNECTION.into())
+        }
+        .await;
+        self.pending.lock().unwrap().remove(&id);
+        res
+    }
+
+    /// Binds a channel to `peer`, which also installs or refreshes a permission for it.
+    async fn bind_channel(&self, peer: SocketAddr) -> Result<()> {
+        let number = {
+            let channels = self.channels.lock().unwrap();
+            match channels.get(&peer) {
+                Some(number) => *number,
+                None => ChannelNumber::MIN + channels.len() as u16,
+            }
+        };
+        let attrs = [
+            ChannelNumber::new(number)?.into(),
+            XorPeerAddress::new(peer).into(),
+        ];
+        self.request(CHANNEL_BIND, &attrs).await?;
+        self.channels.lock().unwrap().insert(peer, number);
+        Ok(())
+    }
+
+    /// Reads datagrams from the server, handing relayed data over to `tx` and responses to
+    /// pending transactions, until `stop` is closed.
+    async fn pump(self: Arc<Self>, tx: Sender<Msg>, stop: Receiver<()>) {
+        let mut buf = [0u8; RECV_BUF_SIZE];
+        loop {
+            let res = match futures::future::select(
+                Box::pin(self.socket.recv_from(&mut buf)),
+                Box::pin(stop.recv()),
+            )
+            .await
+            {
+                Either::Left((res, _)) => res,
+                Either::Right(_) => break,
+            };
+            match res {
+                Ok((len, addr)) if addr == self.server => self.handle(&buf[..len], &tx),
+                Ok((_, addr)) => trace!("dropped datagram from unexpected {:?}", addr),
+                Err(e) => warn!("failed to receive from TURN server: {e}"),
+            }
+        }
+    }
+
+    fn handle(&self, buf: &[u8], tx: &Sender<Msg>) {
+        let res = match buf.split_first_chunk::<CHANNEL_HEADER_SIZE>() {
+            // channel numbers start with `0b01`, while STUN messages start with `0b00`
+            Some((head, body)) if head[0] & 0xC0 == 0x40 => {
+                let number = u16::from_be_bytes([head[0], head[1]]);
+                let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+                let peer = self
+                    .channels
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|(_, c)| **c == number)
+                    .map(|(peer, _)| *peer);
+                match (peer, body.get(..len)) {
+                    (Some(peer), Some(body)) => (body.to_vec(), peer),
+                    _ => return trace!("dropped data of unknown channel {number}"),
+                }
+            }
+            _ => {
+                let Ok(Ok(msg)) = MessageDecoder::<Attribute>::new().decode_from_bytes(buf) else {
+                    return trace!("dropped malformed message of {} bytes", buf.len());
+                };
+                if msg.class() != MessageClass::Indication {
+                    if let Some(tx) = self.pending.lock().unwrap().remove(&msg.transaction_id()) {
+                        tx.try_send(msg).ok();
+                    }
+                    return;
+                }
+                match (
+                    msg.method(),
+                    msg.get_attribute::<XorPeerAddress>(),
+                    msg.get_attribute::<Data>(),
+                ) {
+                    (DATA, Some(peer), Some(data)) => (data.data().to_vec(), peer.address()),
+                    _ => return trace!("dropped unexpected indication"),
+                }
+            }
+        };
+        if let Err(TrySendError::Full(_)) = tx.try_send(res) {
+            warn!("dropped relayed datagram: queue is full");
+        }
+    }
+
+    /// Keeps the allocation and channel bindings alive until `stop` is closed.
+    async fn refresh(self: Arc<Self>, lifetime: Duration, stop: Receiver<()>) {
+        let interval = REFRESH_INTERVAL.min(lifetime / 2);
+        while future::timeout(interval, stop.recv()).await.is_err() {
+            let attrs = [Attribute::from(Lifetime::from_u32(
+                lifetime.as_secs() as u32
+            ))];
+            if let Err(e) = self.request(REFRESH, &attrs).await {
+                warn!("failed to refresh TURN allocation: {e}");
+            }
+            let peers = self
+                .channels
+                .lock()
+                .unwrap()
+                .keys()
+                .copied()
+                .collect::<Vec<_>>();
+            for peer in peers {
+                if let Err(e) = self.bind_channel(peer).await {
+                    warn!("failed to refresh TURN channel to {:?}: {e}", peer);
+                }
+            }
+        }
+    }
+}
+
+/// `Relay` is an allocation on a `TURN` server of RFC 8656, sending and receiving
+/// datagrams on behalf of the client from its relayed transport address.
+///
+/// Peers get permissions and channels bound in [`permit`][Relay::permit], after which
+/// traffic to them goes as compact `ChannelData` messages, and as `Send` indications
+/// otherwise. Allocation, permissions and channels are refreshed in background until the
+/// relay is dropped.
+pub(crate) struct Relay {
+    client: Arc<Client>,
+    relayed: SocketAddr,
+    rx: Receiver<Msg>,
+    peer: Mutex<Option<SocketAddr>>,
+    _stop: Sender<()>,
+}
+
+impl Relay {
+    /// Allocates a relayed address on the server of `turn` from a new socket on `local`.
+    pub async fn allocate(
+        turn: TurnConfig,
+        local: IpAddr,
+        sw_tag: Option<String>,
+        socket_cfg: Arc<SocketConfig>,
+    ) -> Result<Relay> {
+        let server = turn
+            .server
+            .to_socket_addrs()
+            .await?
+            .find(|c| reaches(SocketAddr::new(local, 0), *c))
+            .ok_or(ERR_ADDRESS)?;
+        // dual-stack hosts reach the server from a socket of its own family
+        let local = match is_dual_stack(SocketAddr::new(local, 0)) {
+            true => unspecified(server),
+            false => local,
+        };
+        let client = Arc::new(Client {
+            socket: UdpSocket::bind(SocketAddr::new(local, 0)).await?,
+            server,
+            turn,
+            sw_tag,
+            socket_cfg,
+            auth: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+            channels: Mutex::new(HashMap::new()),
+        });
+
+        let (tx, rx) = channel::bounded(INBOX_SIZE);
+        let (stop_tx, stop) = channel::bounded(1);
+        task::spawn(client.clone().pump(tx, stop.clone()));
+
+        let attrs = [
+            RequestedTransport::new(TRANSPORT_UDP).into(),
+            Lifetime::new(ALLOCATION_LIFETIME)?.into(),
+        ];
+        let res = client.request(ALLOCATE, &attrs).await?;
+        let relayed = res
+            .get_attribute::<XorRelayAddress>()
+            .ok_or(ERR_RELAY)?
+            .address();
+        let lifetime = res
+            .get_attribute::<Lifetime>()
+            .map_or(ALLOCATION_LIFETIME, |c| c.lifetime());
+        task::spawn(client.clone().refresh(lifetime, stop));
+        info!("TURN server {:?} relays traffic from {:?}", server, relayed);
+
+        Ok(Relay {
+            client,
+            relayed,
+            rx,
+            peer: Mutex::new(None),
+            _stop: stop_tx,
+        })
+    }
+
+    /// Relayed transport address, which peers send their traffic to.
+    pub fn relayed_addr(&self) -> SocketAddr {
+        self.relayed
+    }
+
+    /// Local address of the socket talking to the server.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.client.socket.local_addr()?)
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.client.socket
+    }
+
+    /// Lets `peers` send traffic through the relay, binding a channel to each of them.
+    pub async fn permit(&self, peers: &[SocketAddr]) -> Result<()> {
+        let attrs = peers
+            .iter()
+            .map(|c| XorPeerAddress::new(*c).into())
+            .collect::<Vec<_>>();
+        self.client.request(CREATE_PERMISSION, &attrs).await?;
+        for peer in peers {
+            self.client.bind_channel(*peer).await?;
+        }
+        Ok(())
+    }
+
+    pub fn connect(&self, peer: SocketAddr) {
+        *self.peer.lock().unwrap() = Some(peer);
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        *self.peer.lock().unwrap()
+    }
+
+    pub async fn send_to(&self, buf: &[u8], peer: SocketAddr) -> Result<()> {
+        let number = self.client.channels.lock().unwrap().get(&peer).copied();
+        let msg = match number {
+            Some(number) => {
+                let len = u16::try_from(buf.len()).map_err(|_| ERR_FRAME_SIZE)?;
+                let mut msg = Vec::with_capacity(CHANNEL_HEADER_SIZE + buf.len());
+                msg.extend(number.to_be_bytes());
+                msg.extend(len.to_be_bytes());
+                msg.extend_from_slice(buf);
+                msg
+            }
+            None => {
+                let id = TransactionId::new(rand::random());
+                let mut msg = Message::new(MessageClass::Indication, SEND, id);
+                msg.add_attribute(Attribute::from(XorPeerAddress::new(peer)));
+                msg.add_attribute(Attribute::from(Data::new(buf.to_vec())?));
+                MessageEncoder::new().encode_into_bytes(msg)?
+            }
+        };
+        self.client.socket.send_to(&msg, self.client.server).await?;
+        Ok(())
+    }
+
+    pub async fn recv_from(&self) -> Result<Msg> {
+        self.rx.recv().await.map_err(|_| ERR_CLOSED.into())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use stun_codec::rfc5389::errors::BadRequest;
+    use stun_codec::rfc5766::errors::InsufficientCapacity;
+
+    use crate::LOOPBACK_IP;
+
+    const SOCKET_CFG: SocketConfig = SocketConfig {
+        retries: 1000,
+        timeout: Duration::from_millis(25),
+        stun: None,
+        punch: None,
+        secret: None,
+    };
+    pub const TEST_USERNAME: &str = "ensd";
+    pub const TEST_PASSWORD: &str = "alpha test password";
+    const TEST_REALM: &str = "ensd.test";
+    const TEST_NONCE: &str = "0123456789abcdef";
+    const TEST_STRING: &str = "alpha test string";
+
+    #[derive(Default)]
+    struct Allocation {
+        relay: Option<Arc<UdpSocket>>,
+        permissions: Vec<IpAddr>,
+        channels: HashMap<u16, SocketAddr>,
+    }
+
+    type Allocations = Arc<Mutex<HashMap<SocketAddr, Allocation>>>;
+
+    /// `TurnServer` is an in-process stand-in of a `TURN` server on loopback, handling
+    /// allocations, permissions, channel bindings and relaying between them.
+    pub struct TurnServer {
+        pub addr: SocketAddr,
+    }
+
+    impl TurnServer {
+        pub async fn spawn() -> TurnServer {
+            let socket = Arc::new(UdpSocket::bind((LOOPBACK_IP, 0)).await.unwrap());
+            let addr = socket.local_addr().unwrap();
+            task::spawn(serve(socket, Arc::new(Mutex::new(HashMap::new()))));
+            TurnServer { addr }
+        }
+
+        pub fn config(&self, password: &str) -> TurnConfig {
+            TurnConfig::new(
+                self.addr.to_string(),
+                TEST_USERNAME.to_owned(),
+                password.to_owned(),
+            )
+        }
+    }
+
+    async fn serve(socket: Arc<UdpSocket>, allocations: Allocations) {
+        let mut buf = [0u8; RECV_BUF_SIZE];
+        loop {
+            let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+            let buf = &buf[..len];
+            if buf[0] & 0xC0 == 0x40 {
+                let number = u16::from_be_bytes([buf[0], buf[1]]);
+                let relayed = {
+                    let allocations = allocations.lock().unwrap();
+                    allocations
+                        .get(&client)
+                        .and_then(|c| Some((c.relay.clone()?, *c.channels.get(&number)?)))
+                };
+                if let Some((relay, peer)) = relayed {
+                    relay
+                        .send_to(&buf[CHANNEL_HEADER_SIZE..], peer)
+                        .await
+                        .unwrap();
+                }
+                continue;
+            }
+
+            let Ok(Ok(msg)) = MessageDecoder::<Attribute>::new().decode_from_bytes(buf) else {
+                continue;
+            };
+            if msg.class() == MessageClass::Indication {
+                let relayed = {
+                    let allocations = allocations.lock().unwrap();
+                    allocations.get(&client).and_then(|c| c.relay.clone())
+                };
+                if let (Some(relay), Some(peer), Some(data)) = (
+                    relayed,
+                    msg.get_attribute::<XorPeerAddress>(),
+                    msg.get_attribute::<Data>(),
+                ) {
+                    relay.send_to(data.data(), peer.address()).await.unwrap();
+                }
+                continue;
+            }
+
+            let res = respond(&socket, &allocations, client, &msg).await;
+            let res = MessageEncoder::new().encode_into_bytes(res).unwrap();
+            socket.send_to(&res, client).await.unwrap();
+        }
+    }
+
+    async fn respond(
+        socket: &Arc<UdpSocket>,
+        allocations: &Allocations,
+        client: SocketAddr,
+        msg: &Message<Attribute>,
+    ) -> Message<Attribute> {
+        let id = msg.transaction_id();
+        let error = |code: ErrorCode| {
+            let mut res = Message::new(MessageClass::ErrorResponse, msg.method(), id);
+            res.add_attribute(code.into());
+            res.add_attribute(Realm::new(TEST_REALM.to_owned()).unwrap().into());
+            res.add_attribute(Nonce::new(TEST_NONCE.to_owned()).unwrap().into());
+            res
+        };
+
+        let authorized = match (
+            msg.get_attribute::<Username>(),
+            msg.get_attribute::<Realm>(),
+            msg.get_attribute::<MessageIntegrity>(),
+        ) {
+            (Some(username), Some(realm), Some(integrity)) => integrity
+                .check_long_term_credential(username, realm, TEST_PASSWORD)
+                .is_ok(),
+            _ => false,
+        };
+        if !authorized {
+            return error(Unauthorized.into());
+        }
+
+        let mut res = Message::new(MessageClass::SuccessResponse, msg.method(), id);
+        let peers = msg
+            .attributes()
+            .filter_map(|c| match c {
+                Attribute::XorPeerAddress(c) => Some(c.address()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        match msg.method() {
+            ALLOCATE => {
+                if allocations.lock().unwrap().contains_key(&client) {
+                    return error(InsufficientCapacity.into());
+                }
+                let relay = Arc::new(UdpSocket::bind((LOOPBACK_IP, 0)).await.unwrap());
+                let relayed = relay.local_addr().unwrap();
+                allocations.lock().unwrap().insert(
+                    client,
+                    Allocation {
+                        relay: Some(relay.clone()),
+                        ..Default::default()
+                    },
+                );
+                task::spawn(forward(socket.clone(), relay, client, allocations.clone()));
+                res.add_attribute(XorRelayAddress::new(relayed).into());
+                res.add_attribute(XorMappedAddress::new(client).into());
+                res.add_attribute(Lifetime::new(ALLOCATION_LIFETIME).unwrap().into());
+            }
+            CREATE_PERMISSION | CHANNEL_BIND => {
+                let mut allocations = allocations.lock().unwrap();
+                let Some(allocation) = allocations.get_mut(&client) else {
+                    return error(BadRequest.into());
+                };
+                allocation.permissions.extend(peers.iter().map(|c| c.ip()));
+                if let (Some(number), Some(peer)) =
+                    (msg.get_attribute::<ChannelNumber>(), peers.first())
+                {
+                    allocation.channels.insert(number.value(), *peer);
+                }
+            }
+            REFRESH => res.add_attribute(Lifetime::new(ALLOCATION_LIFETIME).unwrap().into()),
+            _ => return error(BadRequest.into()),
+        }
+        res
+    }
+
+    /// Hands datagrams arriving at a relayed address over to the client, either in a
+    /// channel or in a `Data` indication, when their source is permitted.
+    async fn forward(
+        socket: Arc<UdpSocket>,
+        relay: Arc<UdpSocket>,
+        client: SocketAddr,
+        allocations: Allocations,
+    ) {
+        let mut buf = [0u8; RECV_BUF_SIZE];
+        loop {
+            let (len, peer) = relay.recv_from(&mut buf).await.unwrap();
+            let number = {
+                let allocations = allocations.lock().unwrap();
+                let allocation = &allocations[&client];
+                if !allocation.permissions.contains(&peer.ip()) {
+                    continue;
+                }
+                allocation
+                    .channels
+                    .iter()
+                    .find(|(_, c)| **c == peer)
+                    .map(|(number, _)| *number)
+            };
+            let res = match number {
+                Some(number) => [
+                    number.to_be_bytes().as_ref(),
+                    &(len as u16).to_be_bytes(),
+                    &buf[..len],
+                ]
+                .concat(),
+                None => {
+                    let id = TransactionId::new(rand::random());
+                    let mut msg = Message::new(MessageClass::Indication, DATA, id);
+                    msg.add_attribute(Attribute::from(XorPeerAddress::new(peer)));
+                    msg.add_attribute(Attribute::from(Data::new(buf[..len].to_vec()).unwrap()));
+                    MessageEncoder::new().encode_into_bytes(msg).unwrap()
+                }
+            };
+            socket.send_to(&res, client).await.unwrap();
+        }
+    }
+
+    pub async fn allocate(server: &TurnServer) -> Relay {
+        Relay::allocate(
+            server.config(TEST_PASSWORD),
+            LOOPBACK_IP,
+            None,
+            Arc::new(SOCKET_CFG),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[async_std::test]
+    async fn relay_works() {
+        let server = TurnServer::spawn().await;
+        let relay_a = allocate(&server).await;
+        let relay_b = allocate(&server).await;
+        let addr_a = relay_a.relayed_addr();
+        let addr_b = relay_b.relayed_addr();
+        // relayed addresses are distinct from the server one
+        assert_ne!(addr_a, server.addr);
+        assert_ne!(addr_a, addr_b);
+
+        relay_a.send_to(TEST_STRING.as_ref(), addr_b).await.unwrap();
+        let res = future::timeout(SOCKET_CFG.timeout * 4, relay_b.recv_from()).await;
+        // traffic of peers without permission is dropped
+        assert!(res.is_err());
+
+        relay_b.permit(&[addr_a]).await.unwrap();
+        relay_a.send_to(TEST_STRING.as_ref(), addr_b).await.unwrap();
+        let (res, peer) = relay_b.recv_from().await.unwrap();
+        // `Send` indication reaches a permitted peer in a channel
+        assert_eq!(res, TEST_STRING.as_bytes());
+        assert_eq!(peer, addr_a);
+
+        relay_a.permit(&[addr_b]).await.unwrap();
+        relay_b.send_to(TEST_STRING.as_ref(), addr_a).await.unwrap();
+        let (res, peer) = relay_a.recv_from().await.unwrap();
+        // `ChannelData` works the other way
+        assert_eq!(res, TEST_STRING.as_bytes());
+        assert_eq!(peer, addr_b);
+    }
+
+    #[async_std::test]
+    async fn relay_rejects_credentials() {
+        let server = TurnServer::spawn().await;
+        let res = Relay::allocate(
+            server.config("wrong password"),
+            LOOPBACK_IP,
+            None,
+            Arc::new(SOCKET_CFG),
+        )
+        .await;
+        // server keeps answering with a challenge
+        assert!(res.is_err());
+    }
+}
@@ -1,16 +1,19 @@
 use async_std::{
+    channel::{self, Sender},
     future,
-    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
     sync::Arc,
 };
 use async_trait::async_trait;
 use bytecodec::{DecodeExt, EncodeExt};
 use err::{
-    consts::{ERR_CONNECTION, ERR_STUN_QUERY},
+    consts::{ERR_ADDRESS, ERR_NOT_BOUND, ERR_STUN_QUERY},
     Error, Result,
 };
+use futures::future::{select, Either};
 use log::{info, trace};
 use rand::Rng;
+use socket2::{Domain, SockRef, Socket, Type};
 use stun_codec::{
     rfc5389::{
         attributes::{MappedAddress, Software, XorMappedAddress},
@@ -20,16 +23,200 @@ use stun_codec::{
     Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId,
 };
 
-use crate::{IOSocket, SocketConfig};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Mutex,
+};
+use std::time::{Duration, Instant};
+
+use crate::frame::{fragment, Demux, Header, Msg};
+use crate::ice::is_stun;
+use crate::reliable::Reliable;
+use crate::sim::Endpoint;
+use crate::turn::Relay;
+use crate::{IOSocket, SocketConfig, StunHandler, CONTROL_STREAM, KEEPALIVE_STREAM};
+
+/// Largest datagram sent by the socket, picked to fit common path `MTU` without
+/// IP fragmentation.
+pub const PACKET_BUF_SIZE: usize = 1200;
+/// Logical stream no frames are sent on, polled only to drive reads of the socket.
+const IDLE_STREAM: u8 = u8::MAX;
+
+/// `Datagrams` carried by [`UdpSocketHandle`], either sent directly, through a `TURN`
+/// [`Relay`] or over a simulated [`Network`][crate::Network].
+///
+/// Direct and simulated ones keep the bound peer themselves rather than connecting the
+/// socket, so `STUN` answers and keepalives of the peer roaming to another address still
+/// arrive.
+enum Datagrams {
+    Direct(UdpSocket, Mutex<Option<SocketAddr>>),
+    Relayed(Relay),
+    Memory(Endpoint, Mutex<Option<SocketAddr>>),
+}
+
+impl Datagrams {
+    async fn recv_from(&self) -> Result<Msg> {
+        match self {
+            Datagrams::Direct(socket, peer) => loop {
+                let mut buf = [0; PACKET_BUF_SIZE];
+                let (len, addr) = socket.recv_from(&mut buf).await?;
+                let (buf, addr) = (&buf[..len], canonical(addr));
+                if admits(peer, buf, addr) {
+                    break Ok((buf.to_vec(), addr));
+                }
+            },
+            Datagrams::Relayed(relay) => relay.recv_from().await,
+            Datagrams::Memory(endpoint, peer) => loop {
+                let (buf, addr) = endpoint.recv_from().await?;
+                if admits(peer, &buf, addr) {
+                    break Ok((buf, addr));
+                }
+            },
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> Result<()> {
+        match self {
+            Datagrams::Direct(socket, _) => socket
+                .send_to(buf, to_family(*addr, socket.local_addr()?))
+                .await
+                .map(|_| ())
+                .map_err(Error::from),
+            Datagrams::Relayed(relay) => relay.send_to(buf, *addr).await,
+            Datagrams::Memory(endpoint, _) => {
+                endpoint.send_to(buf, *addr);
+                Ok(())
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<()> {
+        self.send_to(buf, &self.peer_addr()?).await
+    }
+
+    async fn connect(&self, addr: &[SocketAddr]) -> Result<()> {
+        let addr = canonical(*addr.first().ok_or(ERR_ADDRESS)?);
+        match self {
+            Datagrams::Direct(_, peer) | Datagrams::Memory(_, peer) => {
+                *peer.lock().unwrap() = Some(addr)
+            }
+            Datagrams::Relayed(relay) => relay.connect(addr),
+        }
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        match self {
+            Datagrams::Direct(_, peer) | Datagrams::Memory(_, peer) => *peer.lock().unwrap(),
+            Datagrams::Relayed(relay) => relay.peer_addr(),
+        }
+        .ok_or_else(|| ERR_NOT_BOUND.into())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        match self {
+            Datagrams::Direct(socket, _) => socket.local_addr().map_err(Error::from),
+            Datagrams::Relayed(relay) => relay.local_addr(),
+            Datagrams::Memory(endpoint, _) => Ok(endpoint.local_addr()),
+        }
+    }
+
+    fn ttl(&self) -> io::Result<u32> {
+        match self {
+            Datagrams::Direct(socket, _) => ttl(socket),
+            Datagrams::Relayed(relay) => ttl(relay.socket()),
+            Datagrams::Memory(endpoint, _) => Ok(endpoint.ttl()),
+        }
+    }
+
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        match self {
+            Datagrams::Direct(socket, _) => set_ttl(socket, ttl),
+            Datagrams::Relayed(relay) => set_ttl(relay.socket(), ttl),
+            Datagrams::Memory(endpoint, _) => {
+                endpoint.set_ttl(ttl);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Whether datagram `buf` of `addr` passes the socket bound to `peer`, see [`may_roam`].
+fn admits(peer: &Mutex<Option<SocketAddr>>, buf: &[u8], addr: SocketAddr) -> bool {
+    let peer = *peer.lock().unwrap();
+    let res = peer.is_none_or(|c| c == addr) || may_roam(buf);
+    if !res {
+        trace!("dropped datagram of {:?} as not of bound {:?}", addr, peer);
+    }
+    res
+}
+
+/// `Query` of public addresses, taking responses of `STUN` servers to its requests by
+/// transaction id and handing other `STUN` messages over to the handler it stands in for.
+struct Query {
+    /// Server each request went to, along with the address it reported.
+    pending: Mutex<HashMap<TransactionId, (SocketAddr, Option<SocketAddr>)>>,
+    next: Option<(Arc<dyn StunHandler>, usize)>,
+    /// Notified once every server answered.
+    done: Sender<()>,
+}
+
+impl StunHandler for Query {
+    fn input(&self, base: usize, buf: &[u8], source: SocketAddr) -> Option<Vec<u8>> {
+        let answered = decode_response(buf).ok().and_then(|(id, mapped)| {
+            let mut pending = self.pending.lock().unwrap();
+            let (_, res) = pending.get_mut(&id).filter(|(c, _)| *c == source)?;
+            *res = Some(mapped);
+            Some(pending.values().all(|(_, c)| c.is_some()))
+        });
+        match (answered, &self.next) {
+            (Some(done), _) => {
+                if done {
+                    let _ = self.done.try_send(());
+                }
+                None
+            }
+            (None, Some((next, base))) => next.input(*base, buf, source),
+            (None, None) => {
+                trace!("dropped STUN message from {:?} on base {base}", source);
+                None
+            }
+        }
+    }
+}
+
+impl Query {
+    fn unanswered(&self) -> Vec<TransactionId> {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .iter()
+            .filter(|(_, (_, c))| c.is_none())
+            .map(|(id, _)| *id)
+            .collect()
+    }
 
-pub const MSG_END_TAG: &[u8] = b"end\0msg\0";
-pub const PACKET_BUF_SIZE: usize = 256;
-pub const STUN_ADDRESS: &str = "stun.l.google.com:19302";
+    fn answer(&self, id: &TransactionId) -> Option<SocketAddr> {
+        self.pending.lock().unwrap().get(id).and_then(|(_, c)| *c)
+    }
+}
+
+/// Whether datagram `buf` passes the socket bound to another peer, which `STUN` messages
+/// and keepalives the peer authenticates after roaming do.
+fn may_roam(buf: &[u8]) -> bool {
+    is_stun(buf) || Header::decode(buf).is_some_and(|(c, _)| c.stream == KEEPALIVE_STREAM)
+}
 
 pub(super) struct UdpSocketHandle {
-    socket: UdpSocket,
+    socket: Datagrams,
     socket_cfg: Arc<SocketConfig>,
     sw_tag: Option<String>,
+    msg_id: AtomicU32,
+    demux: Demux,
+    reliable: HashMap<u8, Mutex<Reliable>>,
+    /// Handler of `STUN` messages, along with the base this socket is to it.
+    handler: Mutex<Option<(Arc<dyn StunHandler>, usize)>>,
 }
 
 impl UdpSocketHandle {
@@ -38,22 +225,237 @@ impl UdpSocketHandle {
         addr: Vec<SocketAddr>,
         ttl: Option<u32>,
         sw_tag: Option<String>,
+        reliable: Vec<u8>,
+        socket_cfg: Arc<SocketConfig>,
+    ) -> Result<UdpSocketHandle> {
+        let socket = bind_std(&addr)?;
+        socket.set_nonblocking(true)?;
+        UdpSocketHandle::with_socket(
+            Datagrams::Direct(socket.into(), Mutex::new(None)),
+            ttl,
+            sw_tag,
+            reliable,
+            socket_cfg,
+        )
+    }
+
+    /// Wraps an already bound `std` socket, so other transports can take it over later.
+    pub fn from_std(
+        socket: std::net::UdpSocket,
+        ttl: Option<u32>,
+        sw_tag: Option<String>,
+        reliable: Vec<u8>,
+        socket_cfg: Arc<SocketConfig>,
+    ) -> Result<UdpSocketHandle> {
+        socket.set_nonblocking(true)?;
+        UdpSocketHandle::with_socket(
+            Datagrams::Direct(socket.into(), Mutex::new(None)),
+            ttl,
+            sw_tag,
+            reliable,
+            socket_cfg,
+        )
+    }
+
+    /// Sends and receives everything through `relay`, reporting peers by their own
+    /// addresses as if they were reached directly.
+    pub fn relayed(
+        relay: Relay,
+        ttl: Option<u32>,
+        sw_tag: Option<String>,
+        reliable: Vec<u8>,
+        socket_cfg: Arc<SocketConfig>,
+    ) -> Result<UdpSocketHandle> {
+        UdpSocketHandle::with_socket(Datagrams::Relayed(relay), ttl, sw_tag, reliable, socket_cfg)
+    }
+
+    /// Sends and receives everything over `endpoint` of a simulated network.
+    pub fn simulated(
+        endpoint: Endpoint,
+        ttl: Option<u32>,
+        sw_tag: Option<String>,
+        reliable: Vec<u8>,
+        socket_cfg: Arc<SocketConfig>,
+    ) -> Result<UdpSocketHandle> {
+        UdpSocketHandle::with_socket(
+            Datagrams::Memory(endpoint, Mutex::new(None)),
+            ttl,
+            sw_tag,
+            reliable,
+            socket_cfg,
+        )
+    }
+
+    fn with_socket(
+        socket: Datagrams,
+        ttl: Option<u32>,
+        sw_tag: Option<String>,
+        reliable: Vec<u8>,
         socket_cfg: Arc<SocketConfig>,
     ) -> Result<UdpSocketHandle> {
-        let socket = UdpSocket::bind(&*addr).await?;
         if let Some(ttl) = ttl {
             socket.set_ttl(ttl)?;
         }
 
         Ok(UdpSocketHandle {
             socket,
+            demux: Demux::new(socket_cfg.timeout),
             socket_cfg,
             sw_tag,
+            msg_id: AtomicU32::new(rand::random()),
+            reliable: reliable
+                .into_iter()
+                .filter(|c| *c != CONTROL_STREAM)
+                .map(|c| (c, Mutex::new(Reliable::new())))
+                .collect(),
+            handler: Mutex::new(None),
         })
     }
+
+    /// Queries `STUN` at all `servers` at once, returning addresses reported by those which
+    /// answered in the attempts given.
+    ///
+    /// Responses are matched by transaction id as the socket reads them, whoever reads it,
+    /// so datagrams of the peer arriving meanwhile stay queued for their streams.
+    async fn query_stun(&self, servers: &[SocketAddr]) -> Result<Vec<SocketAddr>> {
+        let requests = servers
+            .iter()
+            .map(|c| Ok((*c, build_request(&self.sw_tag)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let (done, finished) = channel::bounded(1);
+        let query = Arc::new(Query {
+            pending: Mutex::new(
+                requests
+                    .iter()
+                    .map(|(server, (id, _))| (*id, (*server, None)))
+                    .collect(),
+            ),
+            next: self.handler.lock().unwrap().clone(),
+            done,
+        });
+        let handler: Arc<dyn StunHandler> = query.clone();
+        *self.handler.lock().unwrap() = Some((handler.clone(), 0));
+
+        let res = async {
+            for _ in 0..self.socket_cfg.retries {
+                let unanswered = query.unanswered();
+                if unanswered.is_empty() {
+                    break;
+                }
+                for (server, (_, msg)) in
+                    requests.iter().filter(|(_, (c, _))| unanswered.contains(c))
+                {
+                    self.socket.send_to(msg, server).await?;
+                }
+                let poll = select(
+                    Box::pin(self.recv_msg(IDLE_STREAM, false)),
+                    Box::pin(finished.recv()),
+                );
+                if let Ok(Either::Left((Err(e), _))) =
+                    future::timeout(self.socket_cfg.timeout, poll).await
+                {
+                    return Err(e);
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        let mut current = self.handler.lock().unwrap();
+        if current
+            .as_ref()
+            .is_some_and(|(c, _)| Arc::ptr_eq(c, &handler))
+        {
+            *current = query.next.clone();
+        }
+        drop(current);
+        res?;
+        Ok(requests
+            .iter()
+            .filter_map(|(_, (id, _))| query.answer(id))
+            .collect())
+    }
+
+    async fn recv_msg(&self, stream: u8, peek: bool) -> Result<Msg> {
+        self.demux.recv(stream, peek, || self.recv_datagram()).await
+    }
+
+    /// Receives next datagram which isn't `STUN`, handing `STUN` ones over to the handler.
+    async fn recv_datagram(&self) -> Result<Msg> {
+        loop {
+            let (buf, addr) = self.socket.recv_from().await?;
+            if !is_stun(&buf) {
+                return Ok((buf, addr));
+            }
+            let handler = self.handler.lock().unwrap().clone();
+            match handler {
+                Some((handler, base)) => {
+                    if let Some(res) = handler.input(base, &buf, addr) {
+                        self.socket.send_to(&res, &addr).await?;
+                    }
+                }
+                None => trace!("dropped STUN message from {:?}", addr),
+            }
+        }
+    }
+
+    /// Receives next in-order message of a reliable stream, acknowledging incoming data
+    /// and sending timed out messages again while waiting.
+    async fn recv_reliable(&self, stream: u8, reliable: &Mutex<Reliable>) -> Result<Vec<u8>> {
+        loop {
+            let deadline = {
+                let mut reliable = reliable.lock().unwrap();
+                if let Some(res) = reliable.pop() {
+                    return Ok(res);
+                }
+                reliable.deadline()
+            };
+            let res = match deadline {
+                Some(c) => {
+                    let timeout = c.saturating_duration_since(Instant::now());
+                    future::timeout(timeout, self.recv_msg(stream, false))
+                        .await
+                        .ok()
+                }
+                None => Some(self.recv_msg(stream, false).await),
+            };
+
+            match res {
+                Some(res) => {
+                    let (buf, _) = res?;
+                    let ack = reliable.lock().unwrap().receive(&buf, Instant::now());
+                    if let Some(ack) = ack {
+                        self.send_msg(stream, &ack, None).await?;
+                    }
+                }
+                None => {
+                    let due = reliable.lock().unwrap().due(Instant::now())?;
+                    for buf in due {
+                        self.send_msg(stream, &buf, None).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send_msg(&self, stream: u8, buf: &[u8], addr: Option<&[SocketAddr]>) -> Result<()> {
+        let id = self.msg_id.fetch_add(1, Ordering::Relaxed);
+        for buf in fragment(stream, id, buf, PACKET_BUF_SIZE)? {
+            match addr {
+                Some(addr) => {
+                    for addr in addr {
+                        self.socket.send_to(&buf, addr).await?;
+                    }
+                }
+                None => {
+                    self.socket.send(&buf).await?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
-// TODO: implement abstract buffer tagging utils
 #[async_trait]
 impl IOSocket for UdpSocketHandle {
     async fn bind(&self, addr: &[SocketAddr]) -> Result<()> {
@@ -67,119 +469,90 @@ impl IOSocket for UdpSocketHandle {
     }
 
     async fn peer(&self) -> Result<SocketAddr> {
-        self.socket.peer_addr().map_err(Error::from)
+        self.socket.peer_addr()
     }
 
     async fn poll(&self) -> Result<Vec<u8>> {
         trace!("polling a message from connected socket");
-
-        let mut buf = [0; PACKET_BUF_SIZE];
-        let mut res = Vec::<u8>::with_capacity(PACKET_BUF_SIZE * 4);
-
-        loop {
-            let fut = self.socket.recv(&mut buf);
-            let len = if res.is_empty() {
-                fut.await
-            } else {
-                future::timeout(self.socket_cfg.timeout, fut).await?
-            }?;
-            res.append(&mut buf[..len].to_vec());
-            let tail = &res[res.len() - MSG_END_TAG.len()..];
-
-            if tail == MSG_END_TAG {
-                break;
-            }
-        }
-        Ok(res[..res.len() - MSG_END_TAG.len()].to_vec())
+        self.recv_msg(CONTROL_STREAM, false)
+            .await
+            .map(|(res, _)| res)
     }
 
     async fn poll_at(&self) -> Result<(Vec<u8>, SocketAddr)> {
         trace!("polling a message from any socket");
+        self.recv_msg(CONTROL_STREAM, false).await
+    }
 
-        let mut buf = [0; PACKET_BUF_SIZE];
-        let mut res = Vec::<u8>::with_capacity(PACKET_BUF_SIZE * 4);
-
-        let addr = loop {
-            let fut = self.socket.recv_from(&mut buf);
-            let (len, addr) = if res.is_empty() {
-                fut.await
-            } else {
-                future::timeout(self.socket_cfg.timeout, fut).await?
-            }?;
-            res.append(&mut buf[..len].to_vec());
-            let tail = &res[res.len() - MSG_END_TAG.len()..];
-
-            if tail == MSG_END_TAG {
-                break addr;
-            }
-        };
-        Ok((res[..res.len() - MSG_END_TAG.len()].to_vec(), addr))
+    async fn poll_on(&self, stream: u8) -> Result<Vec<u8>> {
+        trace!("polling a message from connected socket on stream {stream}");
+        match self.reliable.get(&stream) {
+            Some(reliable) => self.recv_reliable(stream, reliable).await,
+            None => self.recv_msg(stream, false).await.map(|(res, _)| res),
+        }
+    }
+
+    async fn poll_on_at(&self, stream: u8) -> Result<(Vec<u8>, SocketAddr)> {
+        match self.reliable.get(&stream) {
+            Some(reliable) => Ok((
+                self.recv_reliable(stream, reliable).await?,
+                self.peer().await?,
+            )),
+            None => self.recv_msg(stream, false).await,
+        }
     }
 
     async fn peek(&self) -> Result<Vec<u8>> {
         trace!("peeking a message from connected socket");
-
-        let mut buf = [0; PACKET_BUF_SIZE];
-        let mut res = Vec::<u8>::with_capacity(PACKET_BUF_SIZE * 4);
-
-        loop {
-            let fut = self.socket.peek(&mut buf);
-            let len = if res.is_empty() {
-                fut.await
-            } else {
-                future::timeout(self.socket_cfg.timeout, fut).await?
-            }?;
-            res.append(&mut buf[..len].to_vec());
-            let tail = &res[res.len() - MSG_END_TAG.len()..];
-
-            if tail == MSG_END_TAG {
-                break;
-            }
-        }
-        Ok(res[..res.len() - MSG_END_TAG.len()].to_vec())
+        self.recv_msg(CONTROL_STREAM, true)
+            .await
+            .map(|(res, _)| res)
     }
 
     async fn peek_at(&self) -> Result<(Vec<u8>, SocketAddr)> {
         trace!("peeking a message from any socket");
-
-        let mut buf = [0; PACKET_BUF_SIZE];
-        let mut res = Vec::<u8>::with_capacity(PACKET_BUF_SIZE * 4);
-
-        let addr = loop {
-            let fut = self.socket.peek_from(&mut buf);
-            let (len, addr) = if res.is_empty() {
-                fut.await
-            } else {
-                future::timeout(self.socket_cfg.timeout, fut).await?
-            }?;
-            res.append(&mut buf[..len].to_vec());
-            let tail = &res[res.len() - MSG_END_TAG.len()..];
-
-            if tail == MSG_END_TAG {
-                break addr;
-            }
-        };
-        Ok((res[..res.len() - MSG_END_TAG.len()].to_vec(), addr))
+        self.recv_msg(CONTROL_STREAM, true).await
     }
 
     async fn push(&self, buf: &[u8]) -> Result<()> {
         trace!("pushing {} bytes to connected socket", buf.len());
-
-        for buf in [buf, MSG_END_TAG].concat().chunks(PACKET_BUF_SIZE) {
-            self.socket.send(buf).await?;
-        }
-        Ok(())
+        self.send_msg(CONTROL_STREAM, buf, None).await
     }
 
     async fn push_to(&self, buf: &[u8], addr: &[SocketAddr]) -> Result<()> {
         trace!("pushing {} bytes to desired socket", buf.len());
+        self.send_msg(CONTROL_STREAM, buf, Some(addr)).await
+    }
 
-        for buf in [buf, MSG_END_TAG].concat().chunks(PACKET_BUF_SIZE) {
-            for addr in addr {
-                self.socket.send_to(buf, addr).await?;
+    async fn push_on(&self, stream: u8, buf: &[u8]) -> Result<()> {
+        trace!(
+            "pushing {} bytes to connected socket on stream {stream}",
+            buf.len()
+        );
+        match self.reliable.get(&stream) {
+            Some(reliable) => {
+                let buf = reliable.lock().unwrap().send(buf, Instant::now());
+                self.send_msg(stream, &buf, None).await
             }
+            None => self.send_msg(stream, buf, None).await,
+        }
+    }
+
+    async fn push_stun(&self, buf: &[u8], addr: SocketAddr) -> Result<()> {
+        self.socket.send_to(buf, &addr).await
+    }
+
+    async fn poll_stun(
+        &self,
+        handler: Arc<dyn StunHandler>,
+        base: usize,
+        wait: Duration,
+    ) -> Result<()> {
+        *self.handler.lock().unwrap() = Some((handler, base));
+        match future::timeout(wait, self.recv_msg(IDLE_STREAM, false)).await {
+            Ok(Err(e)) => Err(e),
+            _ => Ok(()),
         }
-        Ok(())
     }
 
     async fn get_ttl(&self) -> Result<u32> {
@@ -191,53 +564,144 @@ impl IOSocket for UdpSocketHandle {
     }
 
     async fn get_lan_ip(&self) -> Result<SocketAddr> {
-        self.socket.local_addr().map_err(Error::from)
+        self.socket.local_addr()
     }
 
+    /// Relayed address of the `TURN` allocation when going through a relay.
     async fn get_wan_ip(&self) -> Result<SocketAddr> {
-        trace!("querying STUN at '{}'", STUN_ADDRESS);
+        self.get_wan_ips().await.map(|c| c[0])
+    }
 
-        let stun_addr = STUN_ADDRESS
-            .to_socket_addrs()
-            .await?
-            .find(|c| c.is_ipv4())
-            .unwrap();
+    async fn get_wan_ips(&self) -> Result<Vec<SocketAddr>> {
+        if let Datagrams::Relayed(relay) = &self.socket {
+            return Ok(vec![relay.relayed_addr()]);
+        }
+        let query = |servers: Vec<SocketAddr>| async move { self.query_stun(&servers).await };
+        self.socket_cfg
+            .stun()
+            .query(self.socket.local_addr()?, query)
+            .await
+    }
+}
 
-        let msg = build_request(&self.sw_tag)?;
-        let mut buf = [0u8; 256];
-        let mut iter = 0..self.socket_cfg.retries;
+/// Whether a socket at `local` reaches peers of both address families, as sockets on the
+/// unspecified IPv6 address are bound dual-stack by [`bind_std`].
+pub(crate) fn is_dual_stack(local: SocketAddr) -> bool {
+    local.is_ipv6() && local.ip().is_unspecified()
+}
 
-        loop {
-            self.socket.send_to(msg.as_ref(), stun_addr).await?;
-            if let Ok(res) =
-                future::timeout(self.socket_cfg.timeout, self.socket.recv_from(&mut buf)).await
-            {
-                match res? {
-                    (len, addr) if addr == stun_addr => {
-                        let buf = &buf[0..len];
-                        let external_addr = decode_address(buf)?;
-                        return Ok(external_addr);
-                    }
-                    _ => {}
-                }
-                iter = 0..self.socket_cfg.retries;
+/// Whether a socket at `local` may send datagrams to `addr`.
+pub(crate) fn reaches(local: SocketAddr, addr: SocketAddr) -> bool {
+    is_dual_stack(local) || local.is_ipv4() == addr.is_ipv4()
+}
+
+/// Unspecified addresses of families reachable from `local`, IPv4 first.
+pub(crate) fn families(local: SocketAddr) -> Vec<IpAddr> {
+    [Ipv4Addr::UNSPECIFIED.into(), Ipv6Addr::UNSPECIFIED.into()]
+        .into_iter()
+        .filter(|c: &IpAddr| reaches(local, SocketAddr::new(*c, 0)))
+        .collect()
+}
+
+/// Whether `ip` is reached directly within the local network rather than through `NAT`.
+pub(crate) fn is_local(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+/// Unspecified address of the same family as `addr`.
+pub(crate) fn unspecified(addr: SocketAddr) -> IpAddr {
+    match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Turns IPv4-mapped addresses dual-stack sockets report IPv4 peers by back to IPv4.
+pub(crate) fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Maps IPv4 `addr` to IPv4-mapped IPv6 one for a dual-stack socket at `local`.
+pub(crate) fn to_family(addr: SocketAddr, local: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if local.is_ipv6() => {
+            SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+/// Binds a socket on the first available of `addr`, keeping sockets on the unspecified
+/// IPv6 address dual-stack regardless of system defaults.
+pub(crate) fn bind_std(addr: &[SocketAddr]) -> Result<std::net::UdpSocket> {
+    let mut res = Err(ERR_ADDRESS.into());
+    for addr in addr {
+        let bind = || {
+            let socket = Socket::new(Domain::for_address(*addr), Type::DGRAM, None)?;
+            if addr.is_ipv6() {
+                socket.set_only_v6(!is_dual_stack(*addr))?;
             }
-            if iter.next().is_none() {
-                return Err(ERR_CONNECTION.into());
+            socket.bind(&(*addr).into())?;
+            Ok::<_, io::Error>(socket.into())
+        };
+        match bind() {
+            Ok(socket) => return Ok(socket),
+            Err(e) => {
+                trace!("can't bind UDP socket on {:?}: {e}", addr);
+                res = Err(e.into());
             }
         }
     }
+    res
 }
 
+/// Hop limit of outgoing datagrams, of the IPv6 header for IPv6 sockets.
+fn ttl(socket: &UdpSocket) -> io::Result<u32> {
+    match socket.local_addr()?.is_ipv6() {
+        true => with_ref(socket, |c| c.unicast_hops_v6()),
+        false => socket.ttl(),
+    }
+}
+
+/// Sets hop limit of outgoing datagrams, of both IP headers for dual-stack sockets.
+fn set_ttl(socket: &UdpSocket, ttl: u32) -> io::Result<()> {
+    let local = socket.local_addr()?;
+    if local.is_ipv6() {
+        with_ref(socket, |c| c.set_unicast_hops_v6(ttl))?;
+    }
+    match local.is_ipv4() || is_dual_stack(local) {
+        true => socket.set_ttl(ttl),
+        false => Ok(()),
+    }
+}
+
+/// Runs `f` on options of `socket`, which `async_std` sockets don't lend by themselves.
+fn with_ref<T>(socket: &UdpSocket, f: impl FnOnce(SockRef) -> io::Result<T>) -> io::Result<T> {
+    #[cfg(unix)]
+    let raw = {
+        use std::os::fd::{AsRawFd, BorrowedFd};
+        // SAFETY: descriptor is owned by `socket`, which outlives the borrow
+        unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) }
+    };
+    #[cfg(windows)]
+    let raw = {
+        use std::os::windows::io::{AsRawSocket, BorrowedSocket};
+        // SAFETY: handle is owned by `socket`, which outlives the borrow
+        unsafe { BorrowedSocket::borrow_raw(socket.as_raw_socket()) }
+    };
+    f(SockRef::from(&raw))
+}
+
+/// Binding request along with its transaction id.
 #[inline]
-fn build_request(software: &Option<String>) -> Result<Vec<u8>> {
+pub(crate) fn build_request(software: &Option<String>) -> Result<(TransactionId, Vec<u8>)> {
     let random_bytes = rand::thread_rng().gen::<[u8; 12]>();
+    let id = TransactionId::new(random_bytes);
 
-    let mut message = Message::new(
-        MessageClass::Request,
-        BINDING,
-        TransactionId::new(random_bytes),
-    );
+    let mut message = Message::new(MessageClass::Request, BINDING, id);
 
     if let Some(s) = software {
         message.add_attribute(Attribute::Software(Software::new(s.to_owned())?));
@@ -245,11 +709,16 @@ fn build_request(software: &Option<String>) -> Result<Vec<u8>> {
 
     let mut encoder = MessageEncoder::new();
     let bytes = encoder.encode_into_bytes(message.clone())?;
-    Ok(bytes)
+    Ok((id, bytes))
 }
 
 #[inline]
-fn decode_address(buf: &[u8]) -> Result<SocketAddr> {
+pub(crate) fn decode_address(buf: &[u8]) -> Result<SocketAddr> {
+    decode_response(buf).map(|(_, c)| c)
+}
+
+/// Mapped address of binding response `buf`, along with its transaction id.
+fn decode_response(buf: &[u8]) -> Result<(TransactionId, SocketAddr)> {
     let mut decoder = MessageDecoder::<Attribute>::new();
     let decoded = decoder.decode_from_bytes(buf)??;
 
@@ -262,5 +731,6 @@ fn decode_address(buf: &[u8]) -> Result<SocketAddr> {
 
     external_addr1
         .or(external_addr3)
+        .map(|c| (decoded.transaction_id(), c))
         .ok_or_else(|| ERR_STUN_QUERY.into())
 }
@@ -0,0 +1,534 @@
+use async_std::{
+    channel::{self, Receiver, Sender, TrySendError},
+    future,
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+    task,
+};
+use async_trait::async_trait;
+use err::{
+    consts::{ERR_CLOSED, ERR_CONNECTION, ERR_NOT_BOUND, ERR_PIPE_BROKE, ERR_VALIDATION},
+    Result,
+};
+use futures::future::Either;
+use log::{info, trace, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    OnceLock,
+};
+use std::time::{Duration, Instant};
+use str0m::{
+    channel::{ChannelConfig, ChannelId, Reliability},
+    config::Fingerprint,
+    net::{Protocol, Receive},
+    Candidate, Event, IceConnectionState, IceCreds, Input, Output, Rtc, RtcConfig, RtcError,
+};
+
+use crate::frame::{fragment, inbox, Inboxes, FRAME_MAGIC};
+use crate::ice::route;
+use crate::udp::{bind_std, canonical, to_family, UdpSocketHandle, PACKET_BUF_SIZE};
+use crate::{IOSocket, SocketConfig, StunHandler, CONTROL_STREAM};
+
+/// Leading bytes of session parameters sent over [`CONTROL_STREAM`][CONTROL_STREAM].
+const PARAMS_TAG: &[u8] = b"ensd-rtc\n";
+/// Out-of-band negotiated `SCTP` stream of the ordered reliable data channel.
+const RELIABLE_CHANNEL: u16 = 0;
+/// Out-of-band negotiated `SCTP` stream of the unordered data channel without retransmits.
+const UNRELIABLE_CHANNEL: u16 = 1;
+const RECV_BUF_SIZE: usize = 2048;
+/// Longest time the driver sleeps without checking for timers of the session.
+const MAX_WAIT: Duration = Duration::from_secs(1);
+
+/// `Params` of a session exchanged by peers before connecting, standing in for `SDP`.
+struct Params {
+    creds: IceCreds,
+    fingerprint: Fingerprint,
+    tie: u64,
+}
+
+impl Params {
+    fn encode(&self) -> Vec<u8> {
+        let body = format!(
+            "{}\n{}\n{}\n{}",
+            self.creds.ufrag, self.creds.pass, self.fingerprint, self.tie
+        );
+        [PARAMS_TAG, body.as_bytes()].concat()
+    }
+
+    fn decode(buf: &[u8]) -> Option<Params> {
+        let body = std::str::from_utf8(buf.strip_prefix(PARAMS_TAG)?).ok()?;
+        let mut lines = body.lines();
+        let creds = IceCreds {
+            ufrag: lines.next()?.to_owned(),
+            pass: lines.next()?.to_owned(),
+        };
+        let fingerprint = lines.next()?.parse().ok()?;
+        let tie = lines.next()?.parse().ok()?;
+        Some(Params {
+            creds,
+            fingerprint,
+            tie,
+        })
+    }
+}
+
+/// `Link` to the driver task owning the session set up by [`bind`][IOSocket::bind].
+/// Dropping it closes the session.
+struct Link {
+    peer: SocketAddr,
+    tx: Sender<(u8, Vec<u8>)>,
+    alive: Arc<AtomicBool>,
+}
+
+/// `WebRtcSocketHandle` runs `WebRTC` data channels over the same `UDP` socket used for
+/// hole punching.
+///
+/// Until [`bind`][IOSocket::bind] everything goes through plain [`UdpSocketHandle`], which
+/// also carries `ICE` credentials and `DTLS` fingerprints of both peers, then the socket is
+/// handed over to a [`str0m`] session driven by a background task. Streams listed as
+/// reliable, as well as [`CONTROL_STREAM`][CONTROL_STREAM], go over an ordered reliable
+/// channel, the rest over an unordered one without retransmits.
+///
+/// Peer with a greater random tie-breaker takes `ICE` controlling, `DTLS` client and
+/// `SCTP` client roles, so neither side has to be configured as an offerer.
+pub(super) struct WebRtcSocketHandle {
+    udp: UdpSocketHandle,
+    socket: std::sync::Mutex<Option<std::net::UdpSocket>>,
+    label: String,
+    reliable: Vec<u8>,
+    socket_cfg: Arc<SocketConfig>,
+    link: OnceLock<Link>,
+    inboxes: Inboxes,
+    peeked: std::sync::Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl WebRtcSocketHandle {
+    #[allow(dead_code)]
+    pub async fn new(
+        addr: Vec<SocketAddr>,
+        ttl: Option<u32>,
+        sw_tag: Option<String>,
+        reliable: Vec<u8>,
+        label: String,
+        socket_cfg: Arc<SocketConfig>,
+    ) -> Result<WebRtcSocketHandle> {
+        let socket = bind_std(&addr)?;
+        let udp = UdpSocketHandle::from_std(
+            socket.try_clone()?,
+            ttl,
+            sw_tag,
+            vec![],
+            socket_cfg.clone(),
+        )?;
+
+        Ok(WebRtcSocketHandle {
+            udp,
+            socket: std::sync::Mutex::new(Some(socket)),
+            label,
+            reliable,
+            socket_cfg,
+            link: OnceLock::new(),
+            inboxes: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            peeked: std::sync::Mutex::new(VecDeque::new()),
+        })
+    }
+
+    fn link(&self) -> Result<&Link> {
+        self.link.get().ok_or_else(|| ERR_NOT_BOUND.into())
+    }
+
+    /// Sends own session parameters until the ones of the remote host arrive.
+    async fn exchange(&self, params: &[u8], addr: &[SocketAddr]) -> Result<Params> {
+        for _ in 0..self.socket_cfg.retries {
+            self.udp.push_to(params, addr).await?;
+
+            let deadline = Instant::now() + self.socket_cfg.timeout;
+            while let Ok(res) = future::timeout(
+                deadline.saturating_duration_since(Instant::now()),
+                self.udp.poll_at(),
+            )
+            .await
+            {
+                let (res, peer) = res?;
+                if !addr.contains(&peer) {
+                    warn!("dropped session parameters from unexpected {:?}", peer);
+                    continue;
+                }
+                if let Some(res) = Params::decode(&res) {
+                    // the remote host may have missed ours
+                    self.udp.push_to(params, addr).await?;
+                    return Ok(res);
+                }
+            }
+        }
+        Err(ERR_CONNECTION.into())
+    }
+
+    async fn connect(&self, addr: &[SocketAddr]) -> Result<Link> {
+        let socket = self.socket.lock().unwrap().take().ok_or(ERR_PIPE_BROKE)?;
+        let remote = *addr.first().ok_or(ERR_VALIDATION)?;
+        let local = route(socket.local_addr()?, remote)?;
+
+        let mut rtc = RtcConfig::new()
+            .set_crypto_provider(Arc::new(str0m::crypto::from_feature_flags()))
+            .build(Instant::now());
+        let own = Params {
+            creds: rtc.direct_api().local_ice_credentials(),
+            fingerprint: rtc.direct_api().local_dtls_fingerprint().clone(),
+            tie: rand::random(),
+        };
+        let params = own.encode();
+
+        let peer = self.exchange(&params, addr).await?;
+        if peer.tie == own.tie {
+            return Err(ERR_VALIDATION.into());
+        }
+        let controlling = own.tie > peer.tie;
+
+        rtc.add_local_candidate(Candidate::host(local, "udp").map_err(RtcError::from)?);
+        for c in addr {
+            rtc.add_remote_candidate(Candidate::host(*c, "udp").map_err(RtcError::from)?);
+        }
+        let mut api = rtc.direct_api();
+        api.set_remote_ice_credentials(peer.creds);
+        api.set_remote_fingerprint(peer.fingerprint);
+        api.set_ice_controlling(controlling);
+        api.start_dtls(controlling)?;
+        api.start_sctp(controlling);
+        let channels = [
+            api.create_data_channel(ChannelConfig {
+                label: self.label.clone(),
+                negotiated: Some(RELIABLE_CHANNEL),
+                ..Default::default()
+            }),
+            api.create_data_channel(ChannelConfig {
+                label: format!("{}-unordered", self.label),
+                ordered: false,
+                reliability: Reliability::MaxRetransmits { retransmits: 0 },
+                negotiated: Some(UNRELIABLE_CHANNEL),
+                ..Default::default()
+            }),
+        ];
+
+        socket.set_nonblocking(true)?;
+        let driver = Driver {
+            rtc,
+            socket: UdpSocket::from(socket),
+            local,
+            reply: fragment(CONTROL_STREAM, rand::random(), &params, PACKET_BUF_SIZE)?,
+            channels,
+            reliable: self.reliable.clone(),
+            inboxes: self.inboxes.clone(),
+        };
+
+        let (tx, rx) = channel::unbounded();
+        let (ready_tx, ready_rx) = channel::bounded(1);
+        let alive = Arc::new(AtomicBool::new(true));
+        let (flag, inboxes) = (alive.clone(), self.inboxes.clone());
+        task::spawn(async move {
+            if let Err(e) = driver.run(rx, ready_tx).await {
+                warn!("WebRTC session failed: {e}");
+            }
+            flag.store(false, Ordering::Release);
+            // dropping senders wakes up pending receivers
+            inboxes.lock().unwrap().clear();
+        });
+
+        let timeout = self.socket_cfg.timeout * self.socket_cfg.retries as u32;
+        match future::timeout(timeout, ready_rx.recv()).await? {
+            Ok(()) => Ok(Link {
+                peer: remote,
+                tx,
+                alive,
+            }),
+            Err(_) => Err(ERR_CONNECTION.into()),
+        }
+    }
+
+    async fn recv_on(&self, stream: u8) -> Result<Vec<u8>> {
+        let link = self.link()?;
+        let (_, rx) = inbox(&self.inboxes, stream);
+        if !link.alive.load(Ordering::Acquire) {
+            return Err(ERR_CLOSED.into());
+        }
+        rx.recv().await.map_err(|_| ERR_CLOSED.into())
+    }
+
+    async fn recv_control(&self, peek: bool) -> Result<Vec<u8>> {
+        let res = {
+            let mut peeked = self.peeked.lock().unwrap();
+            match peek {
+                true => peeked.front().cloned(),
+                false => peeked.pop_front(),
+            }
+        };
+        if let Some(res) = res {
+            return Ok(res);
+        }
+
+        let res = self.recv_on(CONTROL_STREAM).await?;
+        if peek {
+            self.peeked.lock().unwrap().push_back(res.clone());
+        }
+        Ok(res)
+    }
+
+    async fn send_on(&self, stream: u8, buf: &[u8]) -> Result<()> {
+        let link = self.link()?;
+        link.tx
+            .send((stream, buf.to_vec()))
+            .await
+            .map_err(|_| ERR_CLOSED.into())
+    }
+}
+
+#[async_trait]
+impl IOSocket for WebRtcSocketHandle {
+    async fn bind(&self, addr: &[SocketAddr]) -> Result<()> {
+        let link = self.connect(addr).await?;
+        info!(
+            "WebRTC socket at :{} is connected to {:?}",
+            self.udp.get_lan_ip().await?.port(),
+            link.peer
+        );
+        self.link.set(link).map_err(|_| ERR_PIPE_BROKE.into())
+    }
+
+    async fn peer(&self) -> Result<SocketAddr> {
+        Ok(self.link()?.peer)
+    }
+
+    async fn poll(&self) -> Result<Vec<u8>> {
+        match self.link.get() {
+            Some(_) => self.recv_control(false).await,
+            None => self.udp.poll().await,
+        }
+    }
+
+    async fn poll_at(&self) -> Result<(Vec<u8>, SocketAddr)> {
+        match self.link.get() {
+            Some(link) => Ok((self.recv_control(false).await?, link.peer)),
+            None => self.udp.poll_at().await,
+        }
+    }
+
+    async fn poll_on(&self, stream: u8) -> Result<Vec<u8>> {
+        trace!("polling a message from WebRTC session on stream {stream}");
+        match stream {
+            CONTROL_STREAM => self.poll().await,
+            _ => self.recv_on(stream).await,
+        }
+    }
+
+    async fn peek(&self) -> Result<Vec<u8>> {
+        match self.link.get() {
+            Some(_) => self.recv_control(true).await,
+            None => self.udp.peek().await,
+        }
+    }
+
+    async fn peek_at(&self) -> Result<(Vec<u8>, SocketAddr)> {
+        match self.link.get() {
+            Some(link) => Ok((self.recv_control(true).await?, link.peer)),
+            None => self.udp.peek_at().await,
+        }
+    }
+
+    async fn push(&self, buf: &[u8]) -> Result<()> {
+        match self.link.get() {
+            Some(_) => self.send_on(CONTROL_STREAM, buf).await,
+            None => self.udp.push(buf).await,
+        }
+    }
+
+    /// Once bound, messages go to the connected peer whatever `addr` is.
+    async fn push_to(&self, buf: &[u8], addr: &[SocketAddr]) -> Result<()> {
+        match self.link.get() {
+            Some(_) => self.send_on(CONTROL_STREAM, buf).await,
+            None => self.udp.push_to(buf, addr).await,
+        }
+    }
+
+    async fn push_on(&self, stream: u8, buf: &[u8]) -> Result<()> {
+        trace!(
+            "pushing {} bytes to WebRTC session on stream {stream}",
+            buf.len()
+        );
+        self.send_on(stream, buf).await
+    }
+
+    async fn push_stun(&self, buf: &[u8], addr: SocketAddr) -> Result<()> {
+        self.udp.push_stun(buf, addr).await
+    }
+
+    async fn poll_stun(
+        &self,
+        handler: Arc<dyn StunHandler>,
+        base: usize,
+        wait: Duration,
+    ) -> Result<()> {
+        self.udp.poll_stun(handler, base, wait).await
+    }
+
+    async fn get_ttl(&self) -> Result<u32> {
+        self.udp.get_ttl().await
+    }
+
+    async fn set_ttl(&self, ttl: u32) -> Result<()> {
+        self.udp.set_ttl(ttl).await
+    }
+
+    async fn get_lan_ip(&self) -> Result<SocketAddr> {
+        self.udp.get_lan_ip().await
+    }
+
+    async fn get_wan_ip(&self) -> Result<SocketAddr> {
+        self.udp.get_wan_ip().await
+    }
+
+    async fn get_wan_ips(&self) -> Result<Vec<SocketAddr>> {
+        self.udp.get_wan_ips().await
+    }
+}
+
+/// Event waking up the [`Driver`].
+enum Wake {
+    Datagram(usize, SocketAddr),
+    Command(Option<(u8, Vec<u8>)>),
+    Timeout,
+}
+
+/// `Driver` feeds a sans-IO [`Rtc`] session with datagrams, messages and time, carrying out
+/// whatever it outputs.
+struct Driver {
+    rtc: Rtc,
+    socket: UdpSocket,
+    local: SocketAddr,
+    reply: Vec<Vec<u8>>,
+    channels: [ChannelId; 2],
+    reliable: Vec<u8>,
+    inboxes: Inboxes,
+}
+
+impl Driver {
+    async fn run(mut self, rx: Receiver<(u8, Vec<u8>)>, ready: Sender<()>) -> Result<()> {
+        let mut open = 0;
+        let mut backlog = VecDeque::new();
+        let mut buf = vec![0u8; RECV_BUF_SIZE];
+
+        loop {
+            self.rtc.handle_input(Input::Timeout(Instant::now()))?;
+            self.flush(&mut backlog)?;
+
+            let deadline = loop {
+                match self.rtc.poll_output()? {
+                    Output::Transmit(c) => {
+                        let addr = to_family(c.destination, self.socket.local_addr()?);
+                        self.socket.send_to(&c.contents, addr).await?;
+                    }
+                    Output::Timeout(c) => break c,
+                    Output::Event(Event::ChannelOpen(_, label)) => {
+                        trace!("WebRTC data channel '{label}' is open");
+                        open += 1;
+                        if open == self.channels.len() {
+                            ready.try_send(()).ok();
+                        }
+                    }
+                    Output::Event(Event::ChannelData(c)) => self.dispatch(&c.data),
+                    Output::Event(Event::IceConnectionStateChange(state)) => {
+                        trace!("ICE connection state changed to {:?}", state);
+                        if state == IceConnectionState::Disconnected {
+                            self.rtc.disconnect();
+                        }
+                    }
+                    Output::Event(_) => {}
+                }
+            };
+            if !self.rtc.is_alive() {
+                return Ok(());
+            }
+
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let wake = match future::timeout(
+                wait.min(MAX_WAIT),
+                futures::future::select(
+                    Box::pin(self.socket.recv_from(&mut buf)),
+                    Box::pin(rx.recv()),
+                ),
+            )
+            .await
+            {
+                Ok(Either::Left((res, _))) => {
+                    let (len, addr) = res?;
+                    Wake::Datagram(len, canonical(addr))
+                }
+                Ok(Either::Right((res, _))) => Wake::Command(res.ok()),
+                Err(_) => Wake::Timeout,
+            };
+
+            match wake {
+                // the remote host is still waiting for session parameters
+                Wake::Datagram(len, addr) if buf[..len].first() == Some(&FRAME_MAGIC) => {
+                    for c in &self.reply {
+                        let addr = to_family(addr, self.socket.local_addr()?);
+                        self.socket.send_to(c, addr).await?;
+                    }
+                }
+                Wake::Datagram(len, source) => {
+                    let Ok(contents) = buf[..len].try_into() else {
+                        trace!("dropped unknown datagram of {len} bytes from {:?}", source);
+                        continue;
+                    };
+                    self.rtc.handle_input(Input::Receive(
+                        Instant::now(),
+                        Receive {
+                            proto: Protocol::Udp,
+                            source,
+                            destination: self.local,
+                            contents,
+                        },
+                    ))?;
+                }
+                Wake::Command(Some((stream, res))) => {
+                    let reliable = stream == CONTROL_STREAM || self.reliable.contains(&stream);
+                    backlog.push_back((reliable, [&[stream], &res[..]].concat()));
+                }
+                Wake::Command(None) => {
+                    self.rtc.disconnect();
+                    return Ok(());
+                }
+                Wake::Timeout => {}
+            }
+        }
+    }
+
+    /// Writes queued messages to their channels. Reliable ones wait for buffer space of the
+    /// session, others are dropped when it's full.
+    fn flush(&mut self, backlog: &mut VecDeque<(bool, Vec<u8>)>) -> Result<()> {
+        while let Some((reliable, buf)) = backlog.front() {
+            let id = self.channels[usize::from(!reliable)];
+            let Some(mut channel) = self.rtc.channel(id) else {
+                return Ok(());
+            };
+            if !channel.write(true, buf)? {
+                if *reliable {
+                    return Ok(());
+                }
+                warn!("dropped message of {} bytes: channel is full", buf.len());
+            }
+            backlog.pop_front();
+        }
+        Ok(())
+    }
+
+    fn dispatch(&self, buf: &[u8]) {
+        let Some((&stream, res)) = buf.split_first() else {
+            return;
+        };
+        let (tx, _) = inbox(&self.inboxes, stream);
+        if let Err(TrySendError::Full(_)) = tx.try_send(res.to_vec()) {
+            warn!("dropped message for stream {stream}: queue is full");
+        }
+    }
+}
@@ -1,12 +1,22 @@
 mod err;
 
-use async_std::{channel, fs, io, io::WriteExt, net::SocketAddr, path::Path, sync::Arc, task};
+use async_std::{
+    channel, fs, future, io, io::WriteExt, net::SocketAddr, path::Path, sync::Arc, task,
+};
 use common::{
-    cipher::{AppRng, CipherHandle, Encryption, SeedableRng},
-    socket::{Client, SocketConfig, SocketHandle, LOOPBACK_IP},
+    cipher::{
+        derive_secret, negotiate, offer, AppRng, CipherConfig, CipherHandle, Encryption,
+        Error as CipherError, Message, Retryable, SeedableRng, OFFER_SIZE,
+    },
+    howler::Error as HowlerError,
+    socket::{
+        Candidates, Client, KeepaliveConfig, LanPeer, MdnsConfig, PeerState, PunchConfig,
+        SignalConfig, SignalServer, SignalServerConfig, SocketConfig, SocketHandle, SocketStream,
+        Stats, StunConfig, StunServer, StunServerConfig, TurnConfig, LOOPBACK_IP, LOOPBACK_IP6,
+    },
     stream::{DeviceType, StreamHandle},
 };
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use serde::Deserialize;
 use std::env;
 use std::time::Duration;
@@ -16,18 +26,54 @@ use crate::err::{Error, Result};
 const RESOURCES_PATH: &str = "res";
 const UNICODE_WHITE_SQUARE: char = '\u{25A0}';
 const UNICODE_BLACK_SQUARE: char = '\u{25A1}';
+const REKEY_COMMAND: &str = "/rekey";
+const STATS_COMMAND: &str = "/stats";
+/// Mode answering `STUN` requests of peers instead of chatting.
+const STUN_SERVER_MODE: &str = "stun-server";
+/// Mode pairing peers of `[signal]` config instead of chatting.
+const SIGNAL_SERVER_MODE: &str = "signal-server";
+/// Text stream carrying rekey control messages as well, delivered reliably when listed in
+/// `reliable` of `[client]` config.
+const MSG_STREAM: u8 = 1;
+const SND_STREAM: u8 = 2;
+/// Stream of cipher suite offers exchanged before any encrypted traffic.
+const OFFER_STREAM: u8 = 3;
+/// Kind of offer the peer has to answer with its own.
+const OFFER_REQUEST: u8 = 0;
+/// Kind of offer answering the one of the peer.
+const OFFER_REPLY: u8 = 1;
+/// Time to wait for the offer of the peer before making another request.
+const OFFER_INTERVAL: Duration = Duration::from_millis(500);
+/// Domain separation of secrets derived from the seed phrase, one for each use of it.
+const CIPHER_SECRET_INFO: &[u8] = b"ensd\0secret\0cipher\0";
+const PUNCH_SECRET_INFO: &[u8] = b"ensd\0secret\0punch\0";
+const KEEPALIVE_SECRET_INFO: &[u8] = b"ensd\0secret\0keepalive\0";
 
 #[derive(Debug, Deserialize)]
 struct Config {
     encryption: Encryption,
-    client: ClientConfig,
+    #[serde(default)]
+    cipher: CipherConfig,
+    client: Client,
     socket: SocketConfigRaw,
-}
-
-#[derive(Debug, Deserialize)]
-struct ClientConfig {
-    msg: Client,
-    snd: Client,
+    turn: Option<TurnConfig>,
+    #[serde(default)]
+    stun: StunConfig,
+    #[serde(default)]
+    stun_server: StunServerConfig,
+    /// Port prediction and spraying getting through symmetric `NAT`s.
+    #[serde(default)]
+    punch: PunchConfig,
+    /// Keepalives holding `NAT` mappings open and telling whether the peer is there.
+    #[serde(default)]
+    keepalive: KeepaliveConfig,
+    /// Advertising and browsing peers on the local network.
+    #[serde(default)]
+    mdns: MdnsConfig,
+    /// Signaling server exchanging addresses with the peer instead of asking for them.
+    signal: Option<SignalConfig>,
+    #[serde(default)]
+    signal_server: SignalServerConfig,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -42,20 +88,41 @@ impl From<SocketConfigRaw> for SocketConfig {
     }
 }
 
-async fn request_phrase() -> String {
+/// Requests seed phrase until a non-empty one is entered, as secrets of the cipher, hole
+/// punching and keepalives are derived from it.
+async fn request_phrase() -> Result<String> {
     let msg = format!("[{UNICODE_WHITE_SQUARE}] enter seed phrase: ");
     let mut out = io::stdout();
-    out.write_all(msg.as_ref()).await.unwrap();
-    out.flush().await.unwrap();
+    loop {
+        out.write_all(msg.as_ref()).await?;
+        out.flush().await?;
 
-    let mut phrase = String::new();
-    io::stdin().read_line(&mut phrase).await.unwrap();
+        let mut phrase = String::new();
+        if io::stdin().read_line(&mut phrase).await? == 0 {
+            let e = "no seed phrase entered before end of input";
+            return Err(Error::AsyncIOFailed(e.to_owned()));
+        }
+        if !phrase.trim().is_empty() {
+            return Ok(phrase);
+        }
+        warn!("seed phrase can't be empty");
+    }
+}
 
-    phrase
+/// `Remote` peer given either by addresses of any family or by `ICE` candidates line, or
+/// found through the signaling server.
+enum Remote {
+    Addr(Vec<SocketAddr>),
+    Candidates(Candidates),
+    Signal(SignalConfig),
 }
 
-async fn request_named_remote(name: &str) -> Result<SocketAddr> {
-    let msg = format!("[{UNICODE_WHITE_SQUARE}] enter remote addr for socket '{name}': ");
+/// Asks for the remote peer, which may be one of `peers` found on the local network,
+/// giving none on empty input.
+async fn request_remote(peers: &[LanPeer]) -> Result<Option<Remote>> {
+    let msg = format!(
+        "[{UNICODE_WHITE_SQUARE}] enter remote addr, candidates or LAN peer number, nothing to refresh: "
+    );
     let mut out = io::stdout();
     out.write_all(msg.as_ref()).await.unwrap();
     out.flush().await.unwrap();
@@ -63,7 +130,21 @@ async fn request_named_remote(name: &str) -> Result<SocketAddr> {
     let mut phrase = String::new();
     io::stdin().read_line(&mut phrase).await.unwrap();
 
-    phrase.trim().parse().map_err(Error::from)
+    let phrase = phrase.trim();
+    if phrase.is_empty() {
+        return Ok(None);
+    }
+    if let Some(peer) = phrase.parse::<usize>().ok().and_then(|c| peers.get(c)) {
+        return Ok(Some(Remote::Addr(peer.addr.clone())));
+    }
+    let addr = phrase.split_whitespace().map(str::parse).collect();
+    match addr {
+        Ok(addr) => Ok(Some(Remote::Addr(addr))),
+        Err(e) => match phrase.parse() {
+            Ok(candidates) => Ok(Some(Remote::Candidates(candidates))),
+            Err(_) => Err(Error::from(e)),
+        },
+    }
 }
 
 #[async_std::main]
@@ -74,59 +155,131 @@ async fn main() {
     let path = Path::new(RESOURCES_PATH).join("cfg.toml");
     let conf = toml::from_str::<Config>(&fs::read_to_string(path).await.unwrap()).unwrap();
 
-    debug!("{:?}", conf.encryption);
+    let args = env::args().collect::<Vec<String>>();
+    let arg_mode = args.get(1).map(|c| c.trim());
 
-    let cipher = Arc::new(CipherHandle::new(
-        &conf.encryption,
-        AppRng::from_seed(request_phrase().await.into()),
-    ));
+    if let Some(STUN_SERVER_MODE) = arg_mode {
+        let server = StunServer::bind(conf.stun_server).await.unwrap();
+        server.run().await.unwrap();
+        return;
+    }
+    if let Some(SIGNAL_SERVER_MODE) = arg_mode {
+        let server = SignalServer::bind(conf.signal_server).await.unwrap();
+        server.run().await.unwrap();
+        return;
+    }
 
-    let msg_stream = Arc::new(
-        SocketHandle::new(conf.client.msg, conf.socket.clone().into())
-            .await
-            .unwrap(),
-    );
-    let snd_stream = Arc::new(
-        SocketHandle::new(conf.client.snd, conf.socket.into())
-            .await
-            .unwrap(),
-    );
+    debug!("{:?}", conf.encryption);
 
-    info!(
-        "socket 'msg' at :{} extern address: {:?}",
-        msg_stream.loc_ip.port(),
-        msg_stream.pub_ip
+    let phrase = match request_phrase().await {
+        Ok(res) => res,
+        Err(e) => {
+            error!("can't read seed phrase: {e}");
+            return;
+        }
+    };
+    let secret = |info| derive_secret(phrase.as_bytes(), info);
+
+    let socket = Arc::new(
+        SocketHandle::new(
+            conf.client,
+            SocketConfig::from(conf.socket)
+                .with_stun(conf.stun)
+                .with_punch(conf.punch)
+                .with_secret(&secret(PUNCH_SECRET_INFO)),
+            conf.turn,
+        )
+        .await
+        .unwrap(),
     );
+
     info!(
-        "socket 'snd' at :{} extern address: {:?}",
-        snd_stream.loc_ip.port(),
-        snd_stream.pub_ip
+        "socket at :{} extern addresses: {:?}",
+        socket.loc_ip.port(),
+        socket.pub_ips()
     );
+    if let Some(addr) = socket.relay_ip {
+        info!("socket relayed address: {:?}", addr);
+    }
+    match socket.detect_nat().await {
+        Ok(nat) if nat.is_punchable() == Some(false) => {
+            warn!("{nat}, hole punching is unlikely to work");
+            match socket.predict().await {
+                Ok(addr) => println!("[{UNICODE_WHITE_SQUARE}] predicted addresses: {addr:?}"),
+                Err(e) => warn!("can't predict public addresses: {e}"),
+            }
+        }
+        Ok(nat) => info!("{nat}"),
+        Err(e) => warn!("can't detect NAT type: {e}"),
+    }
+    if let (Some(candidates), None) = (socket.candidates(), &conf.signal) {
+        println!("[{UNICODE_WHITE_SQUARE}] local candidates: {candidates}");
+    }
 
-    let args = env::args().collect::<Vec<String>>();
-    let arg_mode = args.get(1).map(|c| c.trim());
-
-    let (msg_remote, snd_remote) = if let Some("loopback") = arg_mode {
-        let msg_remote = SocketAddr::new(LOOPBACK_IP, msg_stream.loc_ip.port());
-        let snd_remote = SocketAddr::new(LOOPBACK_IP, snd_stream.loc_ip.port());
-        (msg_remote, snd_remote)
+    let remote = if let Some(signal) = conf.signal {
+        Remote::Signal(signal)
+    } else if let Some("loopback") = arg_mode {
+        let ip = match socket.loc_ip.is_ipv4() {
+            true => LOOPBACK_IP,
+            false => LOOPBACK_IP6,
+        };
+        Remote::Addr(vec![SocketAddr::new(ip, socket.loc_ip.port())])
     } else {
-        let msg_remote = loop {
-            match request_named_remote("msg").await {
-                Ok(res) => break res,
-                Err(e) => error!("invalid address: {e}"),
+        let advertise = task::spawn({
+            let (socket, mdns) = (socket.clone(), conf.mdns.clone());
+            async move {
+                if let Err(e) = socket.advertise(&mdns).await {
+                    warn!("can't advertise socket on LAN: {e}");
+                }
             }
-        };
-        let snd_remote = loop {
-            match request_named_remote("snd").await {
-                Ok(res) => break res,
+        });
+        let remote = loop {
+            let peers = socket.browse(&conf.mdns).await.unwrap_or_else(|e| {
+                warn!("can't browse LAN peers: {e}");
+                vec![]
+            });
+            for (idx, peer) in peers.iter().enumerate() {
+                println!("[{UNICODE_WHITE_SQUARE}] LAN peer {idx}: {peer}");
+            }
+            match request_remote(&peers).await {
+                Ok(Some(res)) => break res,
+                Ok(None) => {}
                 Err(e) => error!("invalid address: {e}"),
             }
         };
-        (msg_remote, snd_remote)
+        advertise.cancel().await;
+        remote
     };
 
-    futures::try_join!(msg_stream.bind(&msg_remote), snd_stream.bind(&snd_remote)).unwrap();
+    match remote {
+        Remote::Addr(addr) => socket.bind(&addr.as_slice()).await.unwrap(),
+        Remote::Candidates(candidates) => socket.bind_candidates(&candidates).await.unwrap(),
+        Remote::Signal(signal) => socket.bind_signaled(&signal).await.unwrap(),
+    }
+
+    socket.keepalive(&conf.keepalive, &secret(KEEPALIVE_SECRET_INFO));
+    task::spawn(peer_state_loop(socket.peer_states()));
+    task::spawn(stats_loop(socket.stats_events()));
+
+    let offer_stream = socket.stream(OFFER_STREAM);
+    let encryption = match negotiate_encryption(&conf.encryption, offer_stream).await {
+        Ok(res) => res,
+        Err(e) => {
+            error!("can't agree on cipher suite with remote peer: {e}");
+            return;
+        }
+    };
+    let rng = AppRng::from_seed(secret(CIPHER_SECRET_INFO).into());
+    let cipher = match CipherHandle::new(&encryption, conf.cipher, rng) {
+        Ok(res) => Arc::new(res),
+        Err(e) => {
+            error!("can't set up cipher: {e}");
+            return;
+        }
+    };
+
+    let msg_stream = socket.stream(MSG_STREAM);
+    let snd_stream = socket.stream(SND_STREAM);
 
     println!();
 
@@ -154,6 +307,50 @@ async fn main() {
     futures::try_join!(t1, t2, t3, t4, t5, t6).unwrap();
 }
 
+/// Agrees on the cipher suite of `cfg` with the peer, requesting its offer on `socket`
+/// until one arrives and answering its requests from then on.
+async fn negotiate_encryption(cfg: &Encryption, socket: SocketStream) -> Result<Encryption> {
+    let offer = offer(cfg).map_err(HowlerError::from)?;
+    let request = [[OFFER_REQUEST].as_ref(), offer.as_ref()].concat();
+    let remote = loop {
+        socket.push(&request).await?;
+        if let Ok(res) = future::timeout(OFFER_INTERVAL, socket.poll()).await {
+            break res?;
+        }
+        trace!("waiting for cipher suite offer of remote peer");
+    };
+
+    let reply = [[OFFER_REPLY].as_ref(), offer.as_ref()].concat();
+    if remote.first() == Some(&OFFER_REQUEST) {
+        socket.push(&reply).await?;
+    }
+    // peer keeps requesting until it gets a reply, which may be lost
+    task::spawn(offer_loop(socket, reply));
+
+    let remote = remote.get(1..).unwrap_or_default();
+    negotiate(&offer, remote)
+        .map_err(HowlerError::from)
+        .map_err(Error::from)
+}
+
+#[inline]
+async fn offer_loop(socket: SocketStream, reply: Vec<u8>) -> Result<()> {
+    loop {
+        match socket.poll().await {
+            Ok(buf) if buf.len() == 1 + OFFER_SIZE && buf[0] == OFFER_REQUEST => {
+                if let Err(e) = socket.push(reply.as_slice()).await {
+                    error!("failed to push packets with cipher suite offer: {e}")
+                }
+            }
+            Ok(_) => {}
+            Err(_) if socket.socket().peer_state() == PeerState::Lost => {
+                socket.socket().wait_alive().await
+            }
+            Err(e) => error!("failed to poll data from network stream: {e}"),
+        }
+    }
+}
+
 #[inline]
 async fn run_stream(stream: StreamHandle) -> Result<()> {
     stream.play().await.map_err(Error::from)
@@ -162,7 +359,7 @@ async fn run_stream(stream: StreamHandle) -> Result<()> {
 #[inline]
 async fn msg_put_loop(
     cipher: Arc<CipherHandle>,
-    socket: Arc<SocketHandle>,
+    socket: SocketStream,
     prompt: String,
 ) -> Result<()> {
     loop {
@@ -176,8 +373,16 @@ async fn msg_put_loop(
         drop(out);
 
         io::stdin().read_line(&mut buf).await?;
+        if buf.trim() == STATS_COMMAND {
+            println!("{}", socket.socket().stats());
+            continue;
+        }
 
-        match cipher.encrypt(buf.trim().as_ref()).await {
+        let res = match buf.trim() {
+            REKEY_COMMAND => cipher.rekey_request().await,
+            buf => cipher.encrypt_msg(buf.as_ref()).await,
+        };
+        match res {
             Ok(msg) => {
                 if let Err(e) = socket.push(msg.as_slice()).await {
                     error!("failed to push packets with text data: {e}")
@@ -193,19 +398,28 @@ async fn msg_put_loop(
 #[inline]
 async fn snd_put_loop(
     cipher: Arc<CipherHandle>,
-    socket: Arc<SocketHandle>,
+    socket: SocketStream,
     rx: channel::Receiver<Vec<u8>>,
 ) -> Result<()> {
     loop {
         match rx.recv().await {
-            Ok(res) => match cipher.encrypt(res.as_ref()).await {
-                Ok(res) => {
-                    if let Err(err) = socket.push(res.as_ref()).await {
-                        error!("failed to push packets with audio data: {err}");
+            Ok(res) => {
+                // frames piled up while pushing are encrypted in one batch
+                let mut frames = vec![res];
+                while let Ok(res) = rx.try_recv() {
+                    frames.push(res);
+                }
+                match cipher.encrypt_batch(frames).await {
+                    Ok(res) => {
+                        for res in res {
+                            if let Err(err) = socket.push(res.as_ref()).await {
+                                error!("failed to push packets with audio data: {err}");
+                            }
+                        }
                     }
+                    Err(err) => error!("failed to encrypt data received from channel: {err}"),
                 }
-                Err(err) => error!("failed to encrypt data received from channel: {err}"),
-            },
+            }
             Err(err) => error!("failed to receive from async channel: {err}"),
         }
     }
@@ -214,7 +428,7 @@ async fn snd_put_loop(
 #[inline]
 async fn msg_get_loop(
     cipher: Arc<CipherHandle>,
-    socket: Arc<SocketHandle>,
+    socket: SocketStream,
     prompt: String,
 ) -> Result<()> {
     loop {
@@ -223,8 +437,8 @@ async fn msg_get_loop(
                 let mut out = io::stdout();
                 trace!("RX-CRYPT*: '{:?}'", buf);
 
-                match cipher.decrypt(buf.as_ref()).await {
-                    Ok(msg) => {
+                match cipher.decrypt_msg(buf.as_ref()).await {
+                    Ok(Message::Data(msg)) => {
                         let del = prompt.chars().map(|_| '\u{8}').collect::<String>();
                         let msg = format!(
                             "[{UNICODE_BLACK_SQUARE}] RX: '{}'\n\n",
@@ -234,11 +448,20 @@ async fn msg_get_loop(
                         out.write_all(msg.as_bytes()).await?;
                         out.write_all(prompt.as_bytes()).await?;
                     }
-                    Err(e) => error!("failed to decrypt data from network stream: {e}"),
+                    Ok(Message::Control(Some(reply))) => {
+                        if let Err(e) = socket.push(reply.as_slice()).await {
+                            error!("failed to push packets with control data: {e}")
+                        }
+                    }
+                    Ok(Message::Control(None)) => {}
+                    Err(e) => log_decrypt_error("text data", e),
                 }
                 out.flush().await?;
                 drop(out);
             }
+            Err(_) if socket.socket().peer_state() == PeerState::Lost => {
+                socket.socket().wait_alive().await
+            }
             Err(e) => error!("failed to poll data from network stream: {e}"),
         }
         log::logger().flush();
@@ -248,7 +471,7 @@ async fn msg_get_loop(
 #[inline]
 async fn snd_get_loop(
     cipher: Arc<CipherHandle>,
-    socket: Arc<SocketHandle>,
+    socket: SocketStream,
     tx: channel::Sender<Vec<u8>>,
 ) -> Result<()> {
     loop {
@@ -259,13 +482,47 @@ async fn snd_get_loop(
                         error!("failed to send audio data to async channel: {err}");
                     }
                 }
-                Err(err) => error!("failed to decrypt packets with audio data: {err}"),
+                Err(err) => log_decrypt_error("audio data", err),
             },
+            Err(_) if socket.socket().peer_state() == PeerState::Lost => {
+                socket.socket().wait_alive().await
+            }
             Err(err) => error!("failed to poll data from network stream: {err}"),
         }
     }
 }
 
+#[inline]
+async fn peer_state_loop(states: channel::Receiver<PeerState>) {
+    while let Ok(state) = states.recv().await {
+        match state {
+            PeerState::Lost => warn!("remote peer is lost, waiting for it to come back"),
+            PeerState::Alive => info!("remote peer is back"),
+        }
+    }
+}
+
+async fn stats_loop(stats: channel::Receiver<Stats>) {
+    while let Ok(stats) = stats.recv().await {
+        info!("connection stats: {stats}");
+    }
+}
+
+/// Tells tampered or damaged packets (dropped with a warning) apart from cipher
+/// misconfiguration, which won't go away until the peers agree on [`Encryption`] settings.
+#[inline]
+fn log_decrypt_error(kind: &str, err: HowlerError) {
+    match &err {
+        HowlerError::CipherError(CipherError::AuthenticationFailed(_)) => {
+            warn!("dropped packet with {kind}: possible tampering or key mismatch: {err}")
+        }
+        HowlerError::CipherError(e) if e.retryable() == Retryable::Yes => {
+            warn!("dropped packet with {kind}: {err}")
+        }
+        _ => error!("failed to decrypt packets with {kind}: {err}"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -281,4 +538,80 @@ mod tests {
         // config is a valid `.toml`
         assert!(res.is_ok());
     }
+
+    /// Audio loops run along with `stream`, which builds on these platforms only.
+    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    mod snd {
+        use super::*;
+
+        use async_std::future;
+        use async_std::net::{IpAddr, Ipv4Addr};
+        use common::socket::{ClientAddress, Impairment, Network, STUN_ADDRESS};
+
+        const TEST_PHRASE: &str = "alpha test phrase";
+        const IP_A: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        const IP_B: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        async fn config() -> Config {
+            let path = Path::new(RESOURCES_PATH).join("cfg.toml");
+            toml::from_str(&fs::read_to_string(path).await.unwrap()).unwrap()
+        }
+
+        /// Socket on simulated `network`, LAN-only as there's no `STUN` server.
+        async fn sim_socket(network: &Network, ip: IpAddr) -> Arc<SocketHandle> {
+            let stun = StunConfig::new(vec![], 1, false, STUN_ADDRESS.to_owned());
+            let socket = SocketHandle::simulated(
+                Client::UDP {
+                    addr: ClientAddress::Single(SocketAddr::new(ip, 0)),
+                    ttl: None,
+                    sw_tag: None,
+                    reliable: None,
+                },
+                SocketConfig::new(100, Duration::from_millis(25))
+                    .with_stun(stun)
+                    .with_secret(TEST_PHRASE.as_bytes()),
+                network,
+                None,
+            );
+            Arc::new(socket.await.unwrap())
+        }
+
+        #[async_std::test]
+        async fn snd_loops_work() {
+            let conf = config().await;
+            let cipher = || {
+                let rng = AppRng::from_seed(TEST_PHRASE.to_owned().into());
+                Arc::new(CipherHandle::new(&conf.encryption, conf.cipher.clone(), rng).unwrap())
+            };
+            let ms = Duration::from_millis;
+            let network = Network::new(Impairment::new(0.0, ms(5), ms(5), 0.0, 0.0, 0), 1);
+            let (socket_a, socket_b) = (
+                sim_socket(&network, IP_A).await,
+                sim_socket(&network, IP_B).await,
+            );
+            futures::try_join!(
+                socket_a.bind(&socket_b.loc_ip),
+                socket_b.bind(&socket_a.loc_ip)
+            )
+            .unwrap();
+
+            let (mic, rx) = channel::unbounded();
+            let (tx, out) = channel::unbounded();
+            let put = task::spawn(snd_put_loop(cipher(), socket_a.stream(SND_STREAM), rx));
+            let get = task::spawn(snd_get_loop(cipher(), socket_b.stream(SND_STREAM), tx));
+            for c in 0..10u8 {
+                mic.send(vec![c; 480]).await.unwrap();
+            }
+            let mut res = vec![];
+            for _ in 0..10 {
+                let frame = future::timeout(Duration::from_secs(1), out.recv()).await;
+                res.push(frame.unwrap().unwrap());
+            }
+            res.sort();
+            // audio frames go through encrypted to the output of the peer, jitter aside
+            assert_eq!(res, (0..10u8).map(|c| vec![c; 480]).collect::<Vec<_>>());
+            put.cancel().await;
+            get.cancel().await;
+        }
+    }
 }